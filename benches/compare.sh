@@ -0,0 +1,52 @@
+#!/bin/sh
+# Runs the script benchmarks on a baseline revision and on another one, the
+# working tree by default, side by side. For the change of `Value` to `Copy`:
+#
+#     benches/compare.sh 42d6feb^ 42d6feb
+#
+# Revisions are checked out in target/bench-* and removed after.
+set -e
+
+baseline=${1:?usage: benches/compare.sh <baseline revision> [revision]}
+revision=$2
+root=$(git rev-parse --show-toplevel)
+worktrees=""
+trap 'for w in $worktrees; do git worktree remove --force "$w"; done' EXIT
+
+# Checks out revision $1 in $2 with the current benchmark, built against the
+# crate of that revision
+checkout() {
+    git worktree remove --force "$2" 2>/dev/null || true
+    git worktree add --quiet --detach "$2" "$1"
+    worktrees="$worktrees $2"
+    mkdir -p "$2/benches"
+    cp "$root/benches/scripts.rs" "$2/benches/scripts.rs"
+    if ! grep -q 'name = "scripts"' "$2/Cargo.toml"; then
+        printf '\n[[bench]]\nname = "scripts"\nharness = false\n' >> "$2/Cargo.toml"
+    fi
+    if [ -f "$root/Cargo.lock" ]; then
+        cp "$root/Cargo.lock" "$2/Cargo.lock"
+    fi
+}
+
+# Older revisions print from the collector too, only the results are kept
+run() {
+    (cd "$1" && cargo bench --quiet --bench scripts) | grep '^[a-z ]* *[0-9.]* ms$'
+}
+
+checkout "$baseline" "$root/target/bench-baseline"
+run "$root/target/bench-baseline" > "$root/target/bench-before.txt"
+if [ -n "$revision" ]; then
+    checkout "$revision" "$root/target/bench-revision"
+    run "$root/target/bench-revision" > "$root/target/bench-after.txt"
+else
+    revision="current"
+    run "$root" > "$root/target/bench-after.txt"
+fi
+
+printf '%-24s %13s %13s %9s\n' "script" "$baseline" "$revision" "speedup"
+paste "$root/target/bench-before.txt" "$root/target/bench-after.txt" | awk -F '\t' '{
+    name = substr($1, 1, 24); sub(/ +$/, "", name)
+    split(substr($1, 25), before, " "); split(substr($2, 25), after, " ")
+    printf "%-24s %10.3f ms %10.3f ms %8.2fx\n", name, before[1], after[1], before[1] / after[1]
+}'
@@ -0,0 +1,61 @@
+//! Timings of whole scripts, run with `cargo bench --bench scripts`.
+//!
+//! Only the tokenizer, parser and `Interpreter::eval` are used, which older
+//! revisions have too, so `benches/compare.sh` can run the same scripts on
+//! one of them, like the one before `Value` became `Copy`.
+
+use std::{ hint::black_box, time::Instant };
+
+use lua_rs::{ eval::interpreter::Interpreter, parser::{ AstNode, Parser }, tokenizer::Tokenizer };
+
+const NUMERIC_LOOP: &str = r#"
+x = 0
+for i in 0, 100000 do
+    x = x + i * 2 - 1
+end
+"#;
+
+const TABLE_HEAVY: &str = r#"
+t = {}
+for i in 0, 10000 do
+    t:append({i})
+end
+count = 0
+for v in t do
+    count = count + 1
+end
+"#;
+
+const STRING_BUILDING: &str = r#"
+s = ""
+for i in 0, 2000 do
+    s = s .. "x"
+end
+"#;
+
+fn parse(code: &str) -> AstNode {
+    let mut tokenizer = Tokenizer::new();
+    tokenizer.tokenize(code.to_string());
+    let mut parser = Parser::new(tokenizer.get_tokens().to_vec());
+    parser.parse().expect("benchmark script should parse")
+}
+
+// Prints the time of one run in milliseconds, the best of a few so that
+// noise from other processes doesn't count
+fn bench_script(name: &str, code: &str) {
+    let program = parse(code);
+    let mut best = f64::MAX;
+    for _ in 0..10 {
+        let start = Instant::now();
+        let mut interpreter = Interpreter::new();
+        black_box(interpreter.eval(&program).unwrap());
+        best = best.min(start.elapsed().as_secs_f64() * 1000.0);
+    }
+    println!("{name:<24} {best:>10.3} ms");
+}
+
+fn main() {
+    bench_script("numeric loop", NUMERIC_LOOP);
+    bench_script("table heavy", TABLE_HEAVY);
+    bench_script("string building", STRING_BUILDING);
+}
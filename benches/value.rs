@@ -0,0 +1,57 @@
+//! Compares copying the `Copy` [`Value`] against the previous representation,
+//! which stored strings inline as `String`, run with `cargo bench --bench value`.
+//! Whole scripts on both are timed by `benches/compare.sh`.
+
+use std::{ hint::black_box, mem, time::{ Duration, Instant } };
+
+use lua_rs::eval::{ interpreter::Interpreter, value::Value };
+
+#[allow(dead_code)]
+#[derive(Clone)]
+enum LegacyValue {
+    Nil,
+    Number(i64),
+    Float(f64),
+    String(String),
+    Bool(bool),
+    GcObject(u32),
+}
+
+fn bench<F: FnMut()>(name: &str, iterations: u32, mut f: F) -> Duration {
+    // Warm up once so allocator effects don't skew the first sample
+    f();
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let per_iteration = start.elapsed() / iterations;
+    eprintln!("{name:<24} {per_iteration:>12.2?}");
+    per_iteration
+}
+
+fn main() {
+    eprintln!(
+        "size_of::<Value>() = {}, size_of::<LegacyValue>() = {}",
+        mem::size_of::<Value>(),
+        mem::size_of::<LegacyValue>()
+    );
+
+    let mut interpreter = Interpreter::new();
+    let mut values = vec![];
+    let mut legacy = vec![];
+    for i in 0..10_000 {
+        let s = format!("string number {i}");
+        values.push(interpreter.create_string(&s).unwrap());
+        values.push(Value::Number(i));
+        legacy.push(LegacyValue::String(s));
+        legacy.push(LegacyValue::Number(i));
+    }
+
+    let copied = bench("copy values", 100, || {
+        black_box(values.to_vec());
+    });
+    let cloned = bench("clone legacy values", 100, || {
+        black_box(legacy.to_vec());
+    });
+    eprintln!("copying is {:.1}x faster", cloned.as_secs_f64() / copied.as_secs_f64());
+}
@@ -1,66 +1,404 @@
-use std::any::Any;
-
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{ parse_macro_input, FnArg, ItemFn, Pat };
+use proc_macro2::Span;
+use quote::{ format_ident, quote };
+use syn::{
+    parse_macro_input,
+    Attribute,
+    FnArg,
+    Ident,
+    ImplItem,
+    ItemFn,
+    ItemImpl,
+    LitStr,
+    Signature,
+    Type,
+};
 
+/// Turns a Rust function into one scripts can call, with the signature of
+/// `lua_rs::eval::types::InterpreterFunction`.
+///
+/// Parameters are converted from the script's arguments with `FromLua`,
+/// failing with Lua's "bad argument #2 to 'f' (number expected, got string)".
+/// Missing arguments are nil, so trailing `Option<T>` parameters are
+/// optional. The last one may be a `Variadic<T>` or `&[Value]` taking the
+/// rest of the arguments. `&mut Interpreter` and `&mut GarbageCollector`
+/// parameters don't consume an argument, other references borrow a
+/// `UserData` argument. The result is converted with `IntoLuaMulti`, so
+/// tuples return several values and an `Err` becomes a script error.
+///
+/// Errors name the function after its identifier, or the string given as
+/// in `#[interpreter_function("name")]`.
 #[proc_macro_attribute]
 pub fn interpreter_function(args: TokenStream, item: TokenStream) -> TokenStream {
     let item = parse_macro_input!(item as ItemFn);
-    let sig = item.sig;
-    let name = sig.ident;
-    let return_type = sig.output;
-    let vis = item.vis;
-    let inputs = sig.inputs;
-
-    let mut arg_conversion = Vec::new();
-    let mut identifiers = Vec::new();
-
-    for (i, arg) in inputs.clone().into_iter().enumerate() {
-        if let FnArg::Typed(pattern) = arg {
-            let ident = match *pattern.pat {
-                Pat::Ident(i) => i,
-                _ => panic!("Expected identifier"),
-            };
-
-            let ty = pattern.ty;
-            let conv =
-                // Ty ... function input type
-                quote! {
-                    let #ident: #ty;
-                
-
-                    #ident = args[#i].clone().into();
-                
-            };
-            identifiers.push(ident);
-
-            arg_conversion.push(conv);
-        }
+    let name = if args.is_empty() {
+        item.sig.ident.to_string()
+    } else {
+        parse_macro_input!(args as LitStr).value()
+    };
+    match expand_function(item, &name) {
+        Ok(output) => output.into(),
+        Err(e) => e.to_compile_error().into(),
     }
+}
 
-    let body = item.block;
-    let inputs_len = inputs.len();
-    let output =
-        quote! {
-        #vis fn #name(gc: &mut GarbageCollector, args: &[Value]) -> Value {
-            if args.len() != #inputs_len {
-                panic!("Expected {} args got {}", #inputs_len, args.len())
+/// Implements `lua_rs::eval::userdata::UserData` for the type of an `impl`
+/// block, named after the type or the string given as in
+/// `#[user_data("Name")]`.
+///
+/// Every function taking `&self` or `&mut self` is a method scripts call
+/// as `value:name(...)`, its other parameters work like in
+/// `#[interpreter_function]`. Marking one `#[getter]` or `#[setter]` makes
+/// it read or assign a field instead, named after the function without
+/// `set_` or as in `#[getter("name")]`. `#[meta("__add")]` puts it in the
+/// metatable. Functions without `self` are left alone.
+///
+/// Methods that borrow a value mutably can't take `&mut Interpreter`, a
+/// script running meanwhile could reach the value.
+#[proc_macro_attribute]
+pub fn user_data(args: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemImpl);
+    let name = if args.is_empty() {
+        None
+    } else {
+        Some(parse_macro_input!(args as LitStr).value())
+    };
+    match expand_user_data(item, name) {
+        Ok(output) => output.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+// How a parameter of the wrapped function gets its value
+enum Parameter {
+    Argument,
+    Interpreter,
+    GarbageCollector,
+    // `&T` or `&mut T` of a `UserData` argument
+    Borrow {
+        ty: Type,
+        mutable: bool,
+    },
+    // `Variadic<T>` or `&[Value]`, everything that's left
+    Rest {
+        raw: bool,
+    },
+}
+
+fn classify(ty: &Type) -> Parameter {
+    let last_segment = |ty: &Type| {
+        match ty {
+            Type::Path(path) => path.path.segments.last().map(|s| s.ident.to_string()),
+            _ => None,
+        }
+    };
+    match ty {
+        Type::Reference(reference) => {
+            if let Type::Slice(_) = *reference.elem {
+                return Parameter::Rest { raw: true };
+            }
+            let elem = last_segment(&reference.elem);
+            if reference.mutability.is_some() {
+                match elem.as_deref() {
+                    Some("Interpreter") => {
+                        return Parameter::Interpreter;
+                    }
+                    Some("GarbageCollector") => {
+                        return Parameter::GarbageCollector;
+                    }
+                    _ => {}
+                }
             }
+            if elem.as_deref() == Some("str") {
+                return Parameter::Argument;
+            }
+            Parameter::Borrow {
+                ty: (*reference.elem).clone(),
+                mutable: reference.mutability.is_some(),
+            }
+        }
+        _ if last_segment(ty).as_deref() == Some("Variadic") => Parameter::Rest { raw: false },
+        _ => Parameter::Argument,
+    }
+}
+
+// A function with the signature of `InterpreterFunction` called `ident`
+// that converts the arguments, calls `target` with them and converts its
+// results. `self_ty` is the type of a `self` parameter.
+fn wrapper(
+    ident: &Ident,
+    sig: &Signature,
+    self_ty: Option<&Type>,
+    name: &str,
+    target: proc_macro2::TokenStream
+) -> syn::Result<proc_macro2::TokenStream> {
+    if let Some(asyncness) = sig.asyncness {
+        return Err(syn::Error::new_spanned(asyncness, "interpreter functions can't be async"));
+    }
 
-            #(#arg_conversion)*
+    let mut conversions = Vec::new();
+    let mut borrows = Vec::new();
+    let mut call_args = Vec::new();
+    // Index of the next script argument
+    let mut next = 0usize;
+    let mut rest = None;
+    let mut borrows_interpreter = false;
+    let mut borrows_mutably = false;
+    let mut takes_interpreter = false;
 
-            fn original(#inputs) #return_type {
-                #body
+    for (i, input) in sig.inputs.iter().enumerate() {
+        let parameter = match (input, self_ty) {
+            (FnArg::Receiver(receiver), Some(self_ty)) => {
+                Parameter::Borrow { ty: self_ty.clone(), mutable: receiver.mutability.is_some() }
+            }
+            (FnArg::Receiver(_), None) => {
+                return Err(syn::Error::new_spanned(input, "interpreter functions can't take self"));
+            }
+            (FnArg::Typed(pattern), _) => classify(&pattern.ty),
+        };
+        if let Some(rest) = rest {
+            return Err(
+                syn::Error::new_spanned(input, format!("{rest} has to be the last parameter"))
+            );
+        }
+        let ident = format_ident!("__arg{}", i);
+        match parameter {
+            Parameter::Argument => {
+                let FnArg::Typed(pattern) = input else {
+                    unreachable!();
+                };
+                let ty = &pattern.ty;
+                conversions.push(
+                    quote! {
+                    let #ident: #ty = ::lua_rs::eval::convert::argument(
+                        __interpreter.gc(), __args, #next, #name
+                    )?;
+                }
+                );
+                call_args.push(quote! { #ident });
+                next += 1;
+            }
+            Parameter::Borrow { ty, mutable } => {
+                conversions.push(
+                    quote! {
+                    let #ident: ::lua_rs::eval::userdata::UserDataRef<#ty> =
+                        ::lua_rs::eval::convert::argument(
+                            __interpreter.gc(), __args, #next, #name
+                        )?;
+                }
+                );
+                let borrow = format_ident!("__borrow{}", i);
+                if mutable {
+                    borrows.push(quote! { let mut #borrow = #ident.borrow_mut()?; });
+                    call_args.push(quote! { &mut *#borrow });
+                    borrows_mutably = true;
+                } else {
+                    borrows.push(quote! { let #borrow = #ident.borrow()?; });
+                    call_args.push(quote! { &*#borrow });
+                }
+                next += 1;
+            }
+            Parameter::Rest { raw } => {
+                if raw {
+                    conversions.push(quote! {
+                        let #ident = __args.get(#next..).unwrap_or(&[]);
+                    });
+                    rest = Some("&[Value]");
+                } else {
+                    let FnArg::Typed(pattern) = input else {
+                        unreachable!();
+                    };
+                    let ty = &pattern.ty;
+                    conversions.push(
+                        quote! {
+                        let #ident: #ty = ::lua_rs::eval::convert::variadic(
+                            __interpreter.gc(), __args, #next, #name
+                        )?;
+                    }
+                    );
+                    rest = Some("Variadic");
+                }
+                call_args.push(quote! { #ident });
+            }
+            Parameter::Interpreter | Parameter::GarbageCollector if borrows_interpreter => {
+                return Err(
+                    syn::Error::new_spanned(
+                        input,
+                        "only one of &mut Interpreter and &mut GarbageCollector can be taken"
+                    )
+                );
+            }
+            Parameter::Interpreter => {
+                borrows_interpreter = true;
+                takes_interpreter = true;
+                call_args.push(quote! { &mut *__interpreter });
+            }
+            Parameter::GarbageCollector => {
+                borrows_interpreter = true;
+                call_args.push(quote! { __interpreter.gc_mut() });
             }
+        }
+    }
+    if borrows_mutably && takes_interpreter {
+        return Err(
+            syn::Error::new_spanned(
+                &sig.inputs,
+                "a function borrowing userdata mutably can't take &mut Interpreter"
+            )
+        );
+    }
 
-            let out = original(#(#identifiers)*);
+    Ok(
+        quote! {
+        fn #ident(
+            __interpreter: &mut ::lua_rs::eval::interpreter::Interpreter,
+            __args: &[::lua_rs::eval::value::Value]
+        ) -> ::std::result::Result<
+            ::std::vec::Vec<::lua_rs::eval::value::Value>,
+            ::lua_rs::errors::RuntimeError
+        > {
+            #(#conversions)*
+            #(#borrows)*
+            let results = #target(#(#call_args),*);
+            ::lua_rs::eval::convert::IntoLuaMulti::into_lua_multi(results, __interpreter.gc_mut())
+        }
+    }
+    )
+}
 
+fn expand_function(item: ItemFn, name: &str) -> syn::Result<proc_macro2::TokenStream> {
+    let vis = &item.vis;
+    let attrs = &item.attrs;
+    let mut original = item.clone();
+    original.vis = syn::Visibility::Inherited;
+    original.sig.ident = syn::Ident::new("original", Span::call_site());
+    let function = wrapper(&item.sig.ident, &item.sig, None, name, quote! { original })?;
 
-            return Value::from(out);
+    // The original function goes inside the wrapper
+    let mut function: syn::ItemFn = syn::parse2(function)?;
+    function.block.stmts.insert(0, syn::Stmt::Item(syn::Item::Fn(original)));
+    function.vis = vis.clone();
+    function.attrs = attrs.clone();
+    Ok(quote! { #function })
+}
+
+// What a function of a `#[user_data]` impl is registered as
+enum Member {
+    Method,
+    Getter,
+    Setter,
+    Meta,
+}
+
+// Removes the `#[getter]`, `#[setter]` or `#[meta]` attribute of a function
+// and returns what it is registered as and under which name
+fn member(attrs: &mut Vec<Attribute>, ident: &Ident) -> syn::Result<(Member, String)> {
+    let mut found = None;
+    let mut error = None;
+    attrs.retain(|attr| {
+        let kind = if attr.path().is_ident("getter") {
+            Member::Getter
+        } else if attr.path().is_ident("setter") {
+            Member::Setter
+        } else if attr.path().is_ident("meta") {
+            Member::Meta
+        } else {
+            return true;
+        };
+        let name = match &attr.meta {
+            syn::Meta::Path(_) => None,
+            _ => {
+                match attr.parse_args::<LitStr>() {
+                    Ok(name) => Some(name.value()),
+                    Err(e) => {
+                        error.get_or_insert(e);
+                        None
+                    }
+                }
+            }
+        };
+        if found.is_some() {
+            error.get_or_insert(syn::Error::new_spanned(attr, "a function is registered once"));
+        }
+        found = Some((kind, name));
+        false
+    });
+    if let Some(error) = error {
+        return Err(error);
+    }
+    let ident = ident.to_string();
+    match found {
+        None => Ok((Member::Method, ident)),
+        Some((Member::Meta, None)) => {
+            Err(syn::Error::new_spanned(ident, "#[meta] needs the event, like #[meta(\"__add\")]"))
+        }
+        Some((Member::Setter, None)) => {
+            let name = ident.strip_prefix("set_").unwrap_or(&ident).to_string();
+            Ok((Member::Setter, name))
+        }
+        Some((kind, name)) => Ok((kind, name.unwrap_or(ident))),
+    }
+}
 
+fn expand_user_data(
+    mut item: ItemImpl,
+    name: Option<String>
+) -> syn::Result<proc_macro2::TokenStream> {
+    if let Some((_, path, _)) = &item.trait_ {
+        return Err(syn::Error::new_spanned(path, "#[user_data] goes on an inherent impl"));
+    }
+    if !item.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(&item.generics, "userdata types can't be generic"));
+    }
+    let self_ty = (*item.self_ty).clone();
+    let name = match name {
+        Some(name) => name,
+        None => {
+            match &self_ty {
+                Type::Path(path) => path.path.segments.last().unwrap().ident.to_string(),
+                _ => {
+                    let message = "name the type, like #[user_data(\"Name\")]";
+                    return Err(syn::Error::new_spanned(self_ty, message));
+                }
+            }
         }
     };
 
-    output.into()
+    let mut wrappers = Vec::new();
+    let mut registrations = Vec::new();
+    for impl_item in item.items.iter_mut() {
+        let ImplItem::Fn(function) = impl_item else {
+            continue;
+        };
+        if function.sig.receiver().is_none() {
+            continue;
+        }
+        let (member, lua_name) = member(&mut function.attrs, &function.sig.ident)?;
+        let ident = &function.sig.ident;
+        let wrapper_ident = format_ident!("__user_data_{}", ident);
+        let target = quote! { <#self_ty>::#ident };
+        wrappers.push(wrapper(&wrapper_ident, &function.sig, Some(&self_ty), &lua_name, target)?);
+        let register = match member {
+            Member::Method => quote! { add_method },
+            Member::Getter => quote! { add_getter },
+            Member::Setter => quote! { add_setter },
+            Member::Meta => quote! { add_meta_method },
+        };
+        registrations.push(quote! { registry.#register(#lua_name, #wrapper_ident); });
+    }
+
+    Ok(
+        quote! {
+        #item
+
+        impl ::lua_rs::eval::userdata::UserData for #self_ty {
+            const NAME: &'static str = #name;
+
+            fn register(registry: &mut ::lua_rs::eval::userdata::Registry) {
+                #(#wrappers)*
+                #(#registrations)*
+            }
+        }
+    }
+    )
 }
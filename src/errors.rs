@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug)]
 pub struct ParserError {
     message: String,
@@ -9,8 +11,60 @@ impl ParserError {
         ParserError { message, line }
     }
 
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
     pub fn get_message(&self) -> String {
         let out = format!("{}\nAt line {}", self.message, self.line);
         out
     }
 }
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    message: String,
+    // The chunk that raised it, like the path of a required module
+    chunk: Option<String>,
+}
+
+impl RuntimeError {
+    pub fn new(message: String) -> RuntimeError {
+        RuntimeError { message, chunk: None }
+    }
+
+    /// Attributes the error to `chunk` unless a nested chunk raised it
+    pub fn with_chunk(mut self, chunk: &str) -> RuntimeError {
+        if self.chunk.is_none() {
+            self.chunk = Some(chunk.to_string());
+        }
+        self
+    }
+
+    pub fn chunk(&self) -> Option<&str> {
+        self.chunk.as_deref()
+    }
+
+    pub fn out_of_memory() -> RuntimeError {
+        RuntimeError::new("not enough memory".to_string())
+    }
+
+    pub fn get_message(&self) -> String {
+        match &self.chunk {
+            Some(chunk) => format!("{chunk}: {}", self.message),
+            None => self.message.clone(),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.get_message())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
@@ -0,0 +1,153 @@
+// Host functions returning futures. `exec_async` runs the script on its own
+// stack, and a call to an async function suspends it there until the future
+// the executor polls is ready, so the evaluator doesn't have to know.
+
+use std::{
+    future::Future,
+    pin::Pin,
+    ptr::NonNull,
+    task::{ Context, Poll },
+};
+
+use corosensei::{ CoroutineResult, ScopedCoroutine, Yielder };
+
+use crate::{ errors::RuntimeError, sync::MaybeSend };
+
+use super::{
+    chunk::Chunk,
+    convert::{ FromLuaMulti, IntoLuaMulti },
+    gc::GarbageCollector,
+    interpreter::Interpreter,
+    types::Function,
+    value::Value,
+};
+
+// Makes the results of a finished future into values, once the interpreter
+// is back
+pub(crate) type Completion = Box<
+    dyn FnOnce(&mut GarbageCollector) -> Result<Vec<Value>, RuntimeError>
+>;
+pub(crate) type HostFuture = Pin<Box<dyn Future<Output = Completion>>>;
+#[cfg(not(feature = "send"))]
+pub(crate) type AsyncFunction = Box<
+    dyn Fn(&GarbageCollector, &[Value]) -> Result<HostFuture, RuntimeError>
+>;
+#[cfg(feature = "send")]
+pub(crate) type AsyncFunction = Box<
+    dyn Fn(&GarbageCollector, &[Value]) -> Result<HostFuture, RuntimeError> + Send
+>;
+pub(crate) type ScriptYielder = Yielder<Option<Completion>, HostFuture>;
+
+// Where the interpreter finds the yielder of the script it runs. It is only
+// set while a resume of the coroutine runs the script, never while it is
+// suspended, so leaking the future of `exec_async` can't leave it dangling.
+#[derive(Clone, Copy)]
+pub(crate) struct YielderPtr(NonNull<ScriptYielder>);
+
+// SAFETY: it is only set during a resume, which can't move to another thread
+#[cfg(feature = "send")]
+unsafe impl Send for YielderPtr {}
+
+impl Interpreter {
+    /// A function value that calls `f` and waits for its future. Scripts can
+    /// only call it from `exec_async`. Arguments and results convert like
+    /// `create_function`'s. The value isn't rooted.
+    pub fn create_async_function<A: FromLuaMulti, R: IntoLuaMulti + 'static, Fut>(
+        &mut self,
+        f: impl Fn(A) -> Fut + MaybeSend + 'static
+    ) -> Result<Value, RuntimeError>
+        where Fut: Future<Output = R> + 'static
+    {
+        let function: AsyncFunction = Box::new(move |gc, args| {
+            let future = f(A::from_arguments(args, gc, "?")?);
+            Ok(Box::pin(async move {
+                let results = future.await;
+                Box::new(move |gc: &mut GarbageCollector| results.into_lua_multi(gc)) as Completion
+            }))
+        });
+        let r = self.allocate(Box::new(Function::Async(function)))?;
+        Ok(Value::GcObject(r))
+    }
+
+    /// `exec` for scripts calling async functions, it is pending while one
+    /// of their futures is. Dropping it before it is ready abandons the
+    /// script where it was suspended.
+    pub fn exec_async<'a>(
+        &'a mut self,
+        chunk: &'a Chunk
+    ) -> impl Future<Output = Result<Vec<Value>, RuntimeError>> + 'a {
+        let coroutine = ScopedCoroutine::new(move |yielder: &ScriptYielder, _| {
+            let running = Running {
+                previous: self.yielder.replace(YielderPtr(NonNull::from(yielder))),
+                depth: self.depth(),
+                interpreter: self,
+            };
+            running.interpreter.exec(chunk)
+        });
+        Execution { coroutine, pending: None }
+    }
+
+    // Waits for `future` on the executor, from inside `exec_async`
+    pub(crate) fn suspend(&mut self, future: HostFuture) -> Result<Completion, RuntimeError> {
+        let Some(yielder) = self.yielder.take() else {
+            let message = "attempt to call an async function outside of exec_async";
+            return Err(RuntimeError::new(message.to_string()));
+        };
+        // SAFETY: `yielder` is set while the coroutine of `exec_async` runs,
+        // on whose stack the yielder lives, and this is only reachable from it
+        let completion = unsafe { yielder.0.as_ref() }.suspend(future);
+        // Resumed, the script runs again
+        self.yielder = Some(yielder);
+        Ok(completion.expect("resumed without the result of a future"))
+    }
+}
+
+// The interpreter inside `exec_async`, put back the way it was when the
+// script ends or its future is dropped. It lives on the coroutine's stack,
+// dropping the future unwinds it there.
+struct Running<'a> {
+    interpreter: &'a mut Interpreter,
+    previous: Option<YielderPtr>,
+    depth: (usize, usize),
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.interpreter.yielder = self.previous;
+        self.interpreter.unwind_to(self.depth);
+    }
+}
+
+struct Execution<'a> {
+    coroutine: ScopedCoroutine<
+        'a,
+        Option<Completion>,
+        HostFuture,
+        Result<Vec<Value>, RuntimeError>,
+        corosensei::stack::DefaultStack
+    >,
+    // The future the script waits for
+    pending: Option<HostFuture>,
+}
+
+impl Future for Execution<'_> {
+    type Output = Result<Vec<Value>, RuntimeError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let execution = self.get_mut();
+        let mut completion = None;
+        loop {
+            if let Some(future) = &mut execution.pending {
+                match future.as_mut().poll(cx) {
+                    Poll::Ready(done) => completion = Some(done),
+                    Poll::Pending => return Poll::Pending,
+                }
+                execution.pending = None;
+            }
+            match execution.coroutine.resume(completion.take()) {
+                CoroutineResult::Yield(future) => execution.pending = Some(future),
+                CoroutineResult::Return(results) => return Poll::Ready(results),
+            }
+        }
+    }
+}
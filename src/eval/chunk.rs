@@ -0,0 +1,100 @@
+// Parsed chunks the host can run many times, and the function values `load`
+// makes of them
+
+use std::mem;
+
+use crate::{
+    errors::{ ParserError, RuntimeError },
+    parser::{ AstNode, Parser },
+    sync::Rc,
+    tokenizer::Tokenizer,
+};
+
+use super::{ gc::{ GarbageCollector, GcRef, GcValue }, interpreter::Interpreter, value::Value };
+
+/// Source code parsed once, running it again doesn't tokenize it. Clones
+/// share the syntax tree.
+#[derive(Clone)]
+pub struct Chunk {
+    name: Rc<str>,
+    ast: Rc<AstNode>,
+}
+
+impl Chunk {
+    /// Parses `source`, `name` identifies the chunk in error messages
+    pub fn compile(source: &str, name: &str) -> Result<Chunk, ParserError> {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.tokenize(source.to_string());
+        let ast = Parser::new(tokenizer.get_tokens().to_vec()).parse()?;
+        Ok(Chunk { name: Rc::from(name), ast: Rc::new(ast) })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn ast(&self) -> &AstNode {
+        &self.ast
+    }
+}
+
+/// "name:line: message" for a chunk that failed to parse
+pub(crate) fn syntax_error(name: &str, error: &ParserError) -> String {
+    format!("{name}:{}: {}", error.line(), error.message())
+}
+
+/// How Lua shows a chunk name given to `load`: "=name" and "@file" as
+/// written, a source string as `[string "first line..."]`
+pub(crate) fn chunk_id(name: &str) -> String {
+    const MAX_SOURCE: usize = 40;
+    if let Some(name) = name.strip_prefix('=').or_else(|| name.strip_prefix('@')) {
+        return name.to_string();
+    }
+    let line = name.lines().next().unwrap_or("");
+    if line.len() < name.len() || line.len() > MAX_SOURCE {
+        let mut end = line.len().min(MAX_SOURCE);
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        return format!("[string \"{}...\"]", &line[..end]);
+    }
+    format!("[string \"{line}\"]")
+}
+
+/// A chunk as a function value, calling it runs the chunk with `env` as its
+/// globals or the interpreter's. Arguments are dropped, there is no `...`
+/// in the language for the chunk to read them with.
+pub(crate) struct CompiledFunction {
+    pub(crate) chunk: Chunk,
+    pub(crate) env: Option<GcRef>,
+}
+
+impl GcValue for CompiledFunction {
+    fn get_referenced_children(&self, _gc: &GarbageCollector) -> Vec<GcRef> {
+        self.env.into_iter().collect()
+    }
+
+    fn get_referenced_edges(&self, _gc: &GarbageCollector) -> Vec<(String, GcRef)> {
+        self.env.into_iter().map(|r| ("_ENV".to_string(), r)).collect()
+    }
+
+    fn name(&self) -> &'static str {
+        "function"
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>() + self.chunk.name.len()
+    }
+
+    fn str(&self, _gc: &GarbageCollector) -> String {
+        "function".to_string()
+    }
+
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        _args: &[Value]
+    ) -> Result<Vec<Value>, RuntimeError> {
+        interpreter.eval_chunk(&self.chunk, self.env)
+    }
+}
@@ -0,0 +1,454 @@
+// Conversions between script values and Rust types, for host functions
+// and for reading globals from the host. Reading a value only needs the
+// collector to look at strings and tables, making one may allocate.
+
+use std::{ collections::HashMap, hash::Hash, ops::Deref };
+
+use crate::{ errors::RuntimeError, sync::Rc };
+
+use super::{
+    gc::GarbageCollector,
+    stdlib,
+    types::{ Function, InterpreterFunction, Table },
+    value::Value,
+};
+
+/// Why `FromLua` rejected a value
+#[derive(Debug, Clone, PartialEq)]
+pub enum FromLuaError {
+    /// The value has the wrong type, this names the expected one like
+    /// "number"
+    Expected(&'static str),
+    /// The type is right but not the value, like "number has no integer
+    /// representation"
+    Invalid(String),
+}
+
+impl FromLuaError {
+    /// Describes the error for the rejected `value`, like "number expected,
+    /// got string"
+    pub fn describe(&self, value: Value, gc: &GarbageCollector) -> String {
+        match self {
+            FromLuaError::Expected(expected) => {
+                format!("{expected} expected, got {}", value.type_name(gc))
+            }
+            FromLuaError::Invalid(message) => message.clone(),
+        }
+    }
+}
+
+/// Converts a script value to a Rust type
+pub trait FromLua: Sized {
+    fn from_lua(value: Value, gc: &GarbageCollector) -> Result<Self, FromLuaError>;
+}
+
+/// Converts a Rust value to a script value, strings and tables are
+/// allocated but not rooted
+pub trait IntoLua {
+    fn into_lua(self, gc: &mut GarbageCollector) -> Result<Value, RuntimeError>;
+}
+
+/// Several values, like the results of a call. Single values are the first
+/// one, tuples take one each and `Variadic` the rest.
+pub trait FromLuaMulti: Sized {
+    fn from_lua_multi(values: &[Value], gc: &GarbageCollector) -> Result<Self, FromLuaError>;
+
+    /// The arguments of a call to `function`, failing with Lua's "bad
+    /// argument" message for the first one that doesn't convert
+    fn from_arguments(
+        args: &[Value],
+        gc: &GarbageCollector,
+        function: &str
+    ) -> Result<Self, RuntimeError>;
+}
+
+/// What a host function returns to the script: any number of values, or an
+/// error for `Result`s
+pub trait IntoLuaMulti {
+    fn into_lua_multi(self, gc: &mut GarbageCollector) -> Result<Vec<Value>, RuntimeError>;
+}
+
+// Numbers convert like Lua's arithmetic does, numeric strings included
+fn to_number(value: Value, gc: &GarbageCollector) -> Option<Value> {
+    match value {
+        Value::Number(_) | Value::Float(_) => Some(value),
+        Value::String(r) => stdlib::str_to_number(&gc.get_string(r)?),
+        _ => None,
+    }
+}
+
+fn to_integer(value: Value, gc: &GarbageCollector) -> Result<i64, FromLuaError> {
+    match to_number(value, gc) {
+        Some(Value::Number(n)) => Ok(n),
+        Some(Value::Float(f)) =>
+            stdlib::float_to_integer(f).ok_or_else(||
+                FromLuaError::Invalid("number has no integer representation".to_string())
+            ),
+        _ => Err(FromLuaError::Expected("number")),
+    }
+}
+
+// Integers of every width, checked against the range of the Rust type
+macro_rules! impl_integer {
+    ($($ty:ty),*) => {
+        $(
+            impl FromLua for $ty {
+                fn from_lua(value: Value, gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+                    let n = to_integer(value, gc)?;
+                    <$ty>::try_from(n).map_err(|_| {
+                        FromLuaError::Invalid(
+                            format!("number {n} out of range for {}", stringify!($ty))
+                        )
+                    })
+                }
+            }
+            impl IntoLua for $ty {
+                fn into_lua(self, _gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+                    match i64::try_from(self) {
+                        Ok(n) => Ok(Value::Number(n)),
+                        Err(_) => Err(RuntimeError::new(format!("integer {self} out of range"))),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_integer!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl FromLua for f64 {
+    fn from_lua(value: Value, gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        match to_number(value, gc) {
+            Some(Value::Number(n)) => Ok(n as f64),
+            Some(Value::Float(f)) => Ok(f),
+            _ => Err(FromLuaError::Expected("number")),
+        }
+    }
+}
+impl FromLua for f32 {
+    fn from_lua(value: Value, gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        f64::from_lua(value, gc).map(|f| f as f32)
+    }
+}
+impl IntoLua for f64 {
+    fn into_lua(self, _gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        Ok(Value::Float(self))
+    }
+}
+impl IntoLua for f32 {
+    fn into_lua(self, _gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        Ok(Value::Float(self as f64))
+    }
+}
+
+/// Truthiness, every value converts
+impl FromLua for bool {
+    fn from_lua(value: Value, _gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        Ok(value.is_truthy())
+    }
+}
+impl IntoLua for bool {
+    fn into_lua(self, _gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        Ok(Value::Bool(self))
+    }
+}
+
+impl FromLua for () {
+    fn from_lua(value: Value, _gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        match value {
+            Value::Nil => Ok(()),
+            _ => Err(FromLuaError::Expected("nil")),
+        }
+    }
+}
+impl IntoLua for () {
+    fn into_lua(self, _gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        Ok(Value::Nil)
+    }
+}
+
+/// Numbers are converted to their text like Lua does
+impl FromLua for Rc<str> {
+    fn from_lua(value: Value, gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        match value {
+            Value::String(r) => Ok(gc.get_string(r).unwrap()),
+            Value::Number(_) | Value::Float(_) => Ok(Rc::from(value.to_string(gc))),
+            _ => Err(FromLuaError::Expected("string")),
+        }
+    }
+}
+impl FromLua for String {
+    fn from_lua(value: Value, gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        Rc::<str>::from_lua(value, gc).map(|s| s.to_string())
+    }
+}
+impl IntoLua for String {
+    fn into_lua(self, gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        gc.create_string(&self)
+    }
+}
+impl IntoLua for &str {
+    fn into_lua(self, gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        gc.create_string(self)
+    }
+}
+
+impl FromLua for Value {
+    fn from_lua(value: Value, _gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        Ok(value)
+    }
+}
+impl IntoLua for Value {
+    fn into_lua(self, _gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        Ok(self)
+    }
+}
+
+/// Nil and missing arguments are None
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(value: Value, gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        match value {
+            Value::Nil => Ok(None),
+            _ => T::from_lua(value, gc).map(Some),
+        }
+    }
+}
+impl<T: IntoLua> IntoLua for Option<T> {
+    fn into_lua(self, gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        match self {
+            Some(value) => value.into_lua(gc),
+            None => Ok(Value::Nil),
+        }
+    }
+}
+
+// Reads `value` as a table without metamethods, like the collections below
+fn with_table<T>(
+    value: Value,
+    gc: &GarbageCollector,
+    read: impl FnOnce(&Table) -> Result<T, FromLuaError>
+) -> Result<T, FromLuaError> {
+    let Value::GcObject(r) = value else {
+        return Err(FromLuaError::Expected("table"));
+    };
+    let object = gc.get(r).ok_or(FromLuaError::Expected("table"))?;
+    let object = object.borrow();
+    match object.downcast_ref::<Table>() {
+        Some(table) => read(table),
+        None => Err(FromLuaError::Expected("table")),
+    }
+}
+
+// An element of a table that failed to convert, `at` names its key
+fn element<T: FromLua>(value: Value, gc: &GarbageCollector, at: &str) -> Result<T, FromLuaError> {
+    T::from_lua(value, gc).map_err(|e| {
+        FromLuaError::Invalid(format!("{} at {at}", e.describe(value, gc)))
+    })
+}
+
+/// The sequence `t[1]..t[#t]`
+impl<T: FromLua> FromLua for Vec<T> {
+    fn from_lua(value: Value, gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        with_table(value, gc, |table| {
+            table
+                .array_part()
+                .iter()
+                .enumerate()
+                .map(|(i, v)| element(*v, gc, &format!("index {}", i + 1)))
+                .collect()
+        })
+    }
+}
+impl<T: IntoLua> IntoLua for Vec<T> {
+    fn into_lua(self, gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        let values = self
+            .into_iter()
+            .map(|v| v.into_lua(gc))
+            .collect::<Result<Vec<Value>, RuntimeError>>()?;
+        let table = gc.allocate(Box::new(Table::new(values, HashMap::new())))?;
+        Ok(Value::GcObject(table))
+    }
+}
+
+/// Every entry, including the sequence part
+impl<K: FromLua + Eq + Hash, V: FromLua> FromLua for HashMap<K, V> {
+    fn from_lua(value: Value, gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        with_table(value, gc, |table| {
+            let sequence = table
+                .array_part()
+                .iter()
+                .enumerate()
+                .map(|(i, v)| (Value::Number((i as i64) + 1), *v));
+            let entries = table
+                .map_part()
+                .iter()
+                .map(|(k, v)| (*k, *v));
+            let mut map = HashMap::new();
+            for (k, v) in sequence.chain(entries) {
+                let key = element(k, gc, "a key")?;
+                let value = element(v, gc, &format!("key {}", k.dbg_string(gc)))?;
+                map.insert(key, value);
+            }
+            Ok(map)
+        })
+    }
+}
+impl<K: IntoLua, V: IntoLua> IntoLua for HashMap<K, V> {
+    fn into_lua(self, gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        let mut map = HashMap::with_capacity(self.len());
+        for (k, v) in self {
+            let key = k.into_lua(gc)?;
+            match key {
+                Value::Nil => {
+                    return Err(RuntimeError::new("index is nil".to_string()));
+                }
+                Value::Float(f) if f.is_nan() => {
+                    return Err(RuntimeError::new("index is NaN".to_string()));
+                }
+                _ => {}
+            }
+            map.insert(key, v.into_lua(gc)?);
+        }
+        let table = gc.allocate(Box::new(Table::new(vec![], map)))?;
+        Ok(Value::GcObject(table))
+    }
+}
+
+/// A built-in function, made with `#[interpreter_function]` or by hand
+impl IntoLua for InterpreterFunction {
+    fn into_lua(self, gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        let function = gc.allocate(Box::new(Function::FnPointerInterpreter(self)))?;
+        Ok(Value::GcObject(function))
+    }
+}
+
+/// The rest of the arguments of a host function, or any number of results,
+/// each converted to `T`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Variadic<T>(pub Vec<T>);
+
+impl<T> Deref for Variadic<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.0
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(values: &[Value], gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        T::from_lua(values.first().copied().unwrap_or(Value::Nil), gc)
+    }
+
+    fn from_arguments(
+        args: &[Value],
+        gc: &GarbageCollector,
+        function: &str
+    ) -> Result<Self, RuntimeError> {
+        argument(gc, args, 0, function)
+    }
+}
+impl<T: FromLua> FromLuaMulti for Variadic<T> {
+    fn from_lua_multi(values: &[Value], gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        values
+            .iter()
+            .map(|v| T::from_lua(*v, gc))
+            .collect::<Result<Vec<T>, FromLuaError>>()
+            .map(Variadic)
+    }
+
+    fn from_arguments(
+        args: &[Value],
+        gc: &GarbageCollector,
+        function: &str
+    ) -> Result<Self, RuntimeError> {
+        variadic(gc, args, 0, function)
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for T {
+    fn into_lua_multi(self, gc: &mut GarbageCollector) -> Result<Vec<Value>, RuntimeError> {
+        Ok(vec![self.into_lua(gc)?])
+    }
+}
+impl<T: IntoLua> IntoLuaMulti for Variadic<T> {
+    fn into_lua_multi(self, gc: &mut GarbageCollector) -> Result<Vec<Value>, RuntimeError> {
+        self.0
+            .into_iter()
+            .map(|v| v.into_lua(gc))
+            .collect()
+    }
+}
+/// `Err` raises its message as a script error
+impl<T: IntoLuaMulti, E: std::fmt::Display> IntoLuaMulti for Result<T, E> {
+    fn into_lua_multi(self, gc: &mut GarbageCollector) -> Result<Vec<Value>, RuntimeError> {
+        match self {
+            Ok(value) => value.into_lua_multi(gc),
+            Err(e) => Err(RuntimeError::new(e.to_string())),
+        }
+    }
+}
+
+// Tuples are one value per element, missing ones are nil
+macro_rules! impl_tuple {
+    ($($name:ident $i:tt),+) => {
+        impl<$($name: FromLua),+> FromLuaMulti for ($($name,)+) {
+            fn from_lua_multi(
+                values: &[Value],
+                gc: &GarbageCollector
+            ) -> Result<Self, FromLuaError> {
+                Ok(($($name::from_lua(values.get($i).copied().unwrap_or(Value::Nil), gc)?,)+))
+            }
+
+            fn from_arguments(
+                args: &[Value],
+                gc: &GarbageCollector,
+                function: &str
+            ) -> Result<Self, RuntimeError> {
+                Ok(($(argument::<$name>(gc, args, $i, function)?,)+))
+            }
+        }
+        impl<$($name: IntoLua),+> IntoLuaMulti for ($($name,)+) {
+            fn into_lua_multi(self, gc: &mut GarbageCollector) -> Result<Vec<Value>, RuntimeError> {
+                Ok(vec![$(self.$i.into_lua(gc)?),+])
+            }
+        }
+    };
+}
+
+impl_tuple!(A 0);
+impl_tuple!(A 0, B 1);
+impl_tuple!(A 0, B 1, C 2);
+impl_tuple!(A 0, B 1, C 2, D 3);
+impl_tuple!(A 0, B 1, C 2, D 3, E 4);
+impl_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
+
+/// Converts argument `i` of `function` for `#[interpreter_function]`,
+/// failing with Lua's "bad argument" message
+#[doc(hidden)]
+pub fn argument<T: FromLua>(
+    gc: &GarbageCollector,
+    args: &[Value],
+    i: usize,
+    function: &str
+) -> Result<T, RuntimeError> {
+    let value = args.get(i).copied().unwrap_or(Value::Nil);
+    T::from_lua(value, gc).map_err(|e| match e {
+        FromLuaError::Expected(expected) => stdlib::type_error(gc, args, i, function, expected),
+        FromLuaError::Invalid(message) => stdlib::bad_argument(i, function, &message),
+    })
+}
+
+/// Converts the arguments from `start` on for `#[interpreter_function]`
+#[doc(hidden)]
+pub fn variadic<T: FromLua>(
+    gc: &GarbageCollector,
+    args: &[Value],
+    start: usize,
+    function: &str
+) -> Result<Variadic<T>, RuntimeError> {
+    (start..args.len())
+        .map(|i| argument(gc, args, i, function))
+        .collect::<Result<Vec<T>, RuntimeError>>()
+        .map(Variadic)
+}
@@ -1,6 +1,6 @@
-use std::{ cell::RefCell, collections::HashMap, rc::Rc };
+use std::collections::HashMap;
 
-use crate::eval::value;
+use crate::sync::{ Rc, RefCell };
 
 use super::value::Value;
 
@@ -9,20 +9,36 @@ use super::gc::{ GarbageCollector, GcRef };
 pub struct Environment {
     variables: HashMap<String, Value>,
     parent: Option<Rc<RefCell<Environment>>>,
+    // The table holding the globals of a chunk loaded with its own
+    // environment, variables that aren't local end up there
+    globals: Option<GcRef>,
+}
+
+/// Where a variable lives, see [`Environment::lookup`]
+pub enum Lookup {
+    Found(Value),
+    /// Not a local, it's a field of this globals table
+    Table(GcRef),
+    Missing,
 }
 
 impl Environment {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        Environment { variables: HashMap::new(), parent: None }
+        Environment { variables: HashMap::new(), parent: None, globals: None }
     }
     pub fn with_parent(parent: &Rc<RefCell<Environment>>) -> Self {
         let parent = Rc::clone(parent);
-        Environment { variables: HashMap::new(), parent: Some(parent) }
+        Environment { variables: HashMap::new(), parent: Some(parent), globals: None }
+    }
+    /// The scope of a chunk whose globals are the fields of `table`
+    pub fn with_globals(table: GcRef) -> Self {
+        Environment { variables: HashMap::new(), parent: None, globals: Some(table) }
     }
 
     pub fn get_variable(&self, name: &String) -> Option<Value> {
         if let Some(v) = self.variables.get(name) {
-            return Some(v.clone());
+            return Some(*v);
         } else if let Some(parent) = &self.parent {
             let borrowed = parent.borrow();
             let v = borrowed.get_variable(name);
@@ -31,12 +47,50 @@ impl Environment {
 
         None
     }
+    /// Like `get_variable`, but stops at a globals table
+    pub fn lookup(&self, name: &String) -> Lookup {
+        if let Some(v) = self.variables.get(name) {
+            return Lookup::Found(*v);
+        }
+        if let Some(table) = self.globals {
+            return Lookup::Table(table);
+        }
+        match &self.parent {
+            Some(parent) => parent.borrow().lookup(name),
+            None => Lookup::Missing,
+        }
+    }
+    /// The globals table of the innermost chunk loaded with one
+    pub fn globals_table(&self) -> Option<GcRef> {
+        match &self.parent {
+            _ if self.globals.is_some() => self.globals,
+            Some(parent) => parent.borrow().globals_table(),
+            None => None,
+        }
+    }
     pub fn get_roots(&self) -> Vec<GcRef> {
         let mut gc_refs = vec![];
+        gc_refs.extend(self.globals);
 
         for v in self.variables.values() {
-            if let Value::GcObject(r) = v {
-                gc_refs.push(*r);
+            if let Some(r) = v.gc_ref() {
+                gc_refs.push(r);
+            }
+        }
+
+        gc_refs
+    }
+
+    /// Like `get_roots`, keeping the name of the variable holding each root
+    pub fn get_named_roots(&self) -> Vec<(String, GcRef)> {
+        let mut gc_refs = vec![];
+        if let Some(table) = self.globals {
+            gc_refs.push(("_ENV".to_string(), table));
+        }
+
+        for (name, v) in self.variables.iter() {
+            if let Some(r) = v.gc_ref() {
+                gc_refs.push((name.clone(), r));
             }
         }
 
@@ -1,29 +1,210 @@
-use std::{ any::Any, cell::RefCell, collections::HashMap, mem, rc::Rc };
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    hash::{ BuildHasher, RandomState },
+    mem,
+    time::{ Duration, Instant },
+};
 
 use downcast_rs::{ Downcast, impl_downcast };
 use rand::{ rngs::SmallRng, RngCore, SeedableRng };
-use super::{ interpreter::{ self, Interpreter }, types::Iterable, value::Value };
+use crate::{
+    errors::RuntimeError,
+    sync::{ MaybeSend, Rc, RefCell },
+    trace::{ trace, Category, EventSink },
+};
+
+use super::{
+    interpreter::Interpreter,
+    snapshot::{ HeapObject, HeapSnapshot },
+    handle::Handles,
+    types::Iterable,
+    userdata::UserDataType,
+    value::Value,
+};
+
+// Heap size below which no automatic collection is scheduled
+const MIN_THRESHOLD: usize = 64 * 1024;
 
 pub struct GarbageCollector {
     heap: HashMap<GcRef, GcObject>,
+    // Strings live next to the heap, every string is interned so two equal
+    // strings always share a GcRef and compare by it
+    strings: HashMap<GcRef, StringObject>,
+    interned: HashMap<u64, Vec<GcRef>>,
+    hasher: RandomState,
     rng: SmallRng,
+    bytes: usize,
+    limit: Option<usize>,
+    threshold: usize,
+    collections: usize,
+    last_pause: Duration,
+    max_pause: Duration,
+    total_pause: Duration,
+    sink: Option<Rc<dyn EventSink>>,
+    // Metatables and methods of `UserData` types, rooted by the interpreter
+    pub(crate) user_data: HashMap<TypeId, UserDataType>,
+    // Objects held by the host through handles like `ScriptFunction`, with
+    // their number of handles. Rooted by the interpreter too.
+    pub(crate) handles: Handles,
+}
+
+/// Snapshot of the heap returned by [`GarbageCollector::memory_stats`]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MemoryStats {
+    pub objects: usize,
+    pub bytes: usize,
+    /// Object count and bytes keyed by `GcValue::name`
+    pub by_type: HashMap<&'static str, TypeStats>,
+    pub limit: Option<usize>,
+    pub collections: usize,
+    pub last_pause: Duration,
+    pub max_pause: Duration,
+    pub total_pause: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TypeStats {
+    pub objects: usize,
+    pub bytes: usize,
 }
 
 impl GarbageCollector {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        GarbageCollector { heap: HashMap::new(), rng: SmallRng::seed_from_u64(0x13b156d4) }
+        GarbageCollector {
+            heap: HashMap::new(),
+            strings: HashMap::new(),
+            interned: HashMap::new(),
+            hasher: RandomState::new(),
+            rng: SmallRng::seed_from_u64(0x13b156d4),
+            bytes: 0,
+            limit: None,
+            threshold: MIN_THRESHOLD,
+            collections: 0,
+            last_pause: Duration::ZERO,
+            max_pause: Duration::ZERO,
+            total_pause: Duration::ZERO,
+            sink: None,
+            user_data: HashMap::new(),
+            handles: Handles::default(),
+        }
+    }
+
+    pub fn set_event_sink(&mut self, sink: Rc<dyn EventSink>) {
+        self.sink = Some(sink);
     }
 
-    pub fn allocate(&mut self, value: Box<dyn GcValue>) -> GcRef {
-        let id = self.rng.next_u32();
-        println!("Id: {}", id);
+    /// Allocates `value` on the heap, failing with "not enough memory" if it
+    /// would push the heap over the configured limit.
+    pub fn allocate(&mut self, value: Box<dyn GcValue>) -> Result<GcRef, RuntimeError> {
+        let size = value.size();
+        if !self.can_allocate(size) {
+            return Err(RuntimeError::out_of_memory());
+        }
+
+        let id = self.next_id();
+        trace!(self.sink, Category::Gc, "allocated {} {} ({} bytes)", value.name(), id.0, size);
 
-        self.heap.insert(GcRef(id), GcObject {
+        self.heap.insert(id, GcObject {
             value: Rc::new(RefCell::new(value)),
             marked: false,
-            children: vec![],
+            size,
         });
-        GcRef(id)
+        self.bytes += size;
+        Ok(id)
+    }
+
+    fn next_id(&mut self) -> GcRef {
+        loop {
+            let id = GcRef(self.rng.next_u32());
+            if !self.heap.contains_key(&id) && !self.strings.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    /// Returns the interned string equal to `s`, allocating it if it
+    /// doesn't exist yet.
+    pub fn intern(&mut self, s: &str) -> Result<GcRef, RuntimeError> {
+        self.intern_bytes(s.as_bytes())
+    }
+
+    /// Like `intern` for any bytes, strings are byte strings like in Lua and
+    /// don't have to be valid UTF-8
+    pub fn intern_bytes(&mut self, bytes: &[u8]) -> Result<GcRef, RuntimeError> {
+        let hash = self.hasher.hash_one(bytes);
+        if let Some(r) = self.find_bytes(bytes, hash) {
+            return Ok(r);
+        }
+
+        let string = StringObject::new(bytes, hash);
+        let size = string.size();
+        if !self.can_allocate(size) {
+            return Err(RuntimeError::out_of_memory());
+        }
+        let id = self.next_id();
+        self.strings.insert(id, string);
+        self.interned.entry(hash).or_default().push(id);
+        self.bytes += size;
+        Ok(id)
+    }
+
+    pub fn create_string(&mut self, s: &str) -> Result<Value, RuntimeError> {
+        Ok(Value::String(self.intern(s)?))
+    }
+
+    pub fn create_byte_string(&mut self, bytes: &[u8]) -> Result<Value, RuntimeError> {
+        Ok(Value::String(self.intern_bytes(bytes)?))
+    }
+
+    /// The interned string equal to `s` if there is one, unlike `intern` this
+    /// never allocates
+    pub fn find_string(&self, s: &str) -> Option<GcRef> {
+        self.find_bytes(s.as_bytes(), self.hasher.hash_one(s.as_bytes()))
+    }
+
+    fn find_bytes(&self, bytes: &[u8], hash: u64) -> Option<GcRef> {
+        self.interned
+            .get(&hash)?
+            .iter()
+            .find(|r| self.strings[*r].bytes() == bytes)
+            .copied()
+    }
+
+    /// The string as text, bytes that aren't valid UTF-8 are shown as
+    /// replacement characters. `get_bytes` has the string as it is.
+    pub fn get_string(&self, gc_ref: GcRef) -> Option<Rc<str>> {
+        self.strings.get(&gc_ref).map(|s| Rc::clone(&s.value))
+    }
+
+    pub fn get_bytes(&self, gc_ref: GcRef) -> Option<Rc<[u8]>> {
+        self.strings.get(&gc_ref).map(|s| {
+            match &s.raw {
+                Some(raw) => Rc::clone(raw),
+                None => Rc::from(Rc::clone(&s.value)),
+            }
+        })
+    }
+
+    pub fn can_allocate(&self, size: usize) -> bool {
+        match self.limit {
+            Some(limit) => self.bytes + size <= limit,
+            None => true,
+        }
+    }
+
+    /// Re-measures an object after it was mutated (e.g. a table grew).
+    pub fn resize(&mut self, gc_ref: GcRef) -> Result<(), RuntimeError> {
+        if let Some(obj) = self.heap.get_mut(&gc_ref) {
+            let size = obj.value.borrow().size();
+            self.bytes = self.bytes - obj.size + size;
+            obj.size = size;
+        }
+        if self.is_over_limit() {
+            return Err(RuntimeError::out_of_memory());
+        }
+        Ok(())
     }
 
     pub fn get(&self, gc_ref: GcRef) -> Option<Rc<RefCell<Box<dyn GcValue>>>> {
@@ -40,54 +221,165 @@ impl GarbageCollector {
         None
     }
 
-    // Does not work for fn ptr's
-    fn get_id(value: &Box<dyn GcValue>) -> u32 {
-        let ptr = value as *const Box<dyn GcValue>;
-        let id = ptr as u32;
-        id >> 1
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.limit = limit;
+    }
+
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Estimated number of bytes currently held by live and unswept objects
+    pub fn bytes_in_use(&self) -> usize {
+        self.bytes
     }
-    fn mark_root(&mut self, root: &GcRef) {
-        if let Some(obj) = self.heap.get_mut(root) {
-            obj.mark();
-            let children = mem::take(&mut obj.children);
 
-            for c in children.iter() {
-                self.mark_root(c);
+    pub fn is_over_limit(&self) -> bool {
+        matches!(self.limit, Some(limit) if self.bytes > limit)
+    }
+
+    /// True once enough was allocated since the last collection to make
+    /// another one worthwhile.
+    pub fn should_collect(&self) -> bool {
+        self.bytes >= self.threshold
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        let mut by_type: HashMap<&'static str, TypeStats> = HashMap::new();
+        for obj in self.heap.values() {
+            let entry = by_type.entry(obj.value.borrow().name()).or_default();
+            entry.objects += 1;
+            entry.bytes += obj.size;
+        }
+
+        if !self.strings.is_empty() {
+            let entry = by_type.entry("string").or_default();
+            for string in self.strings.values() {
+                entry.objects += 1;
+                entry.bytes += string.size();
             }
-            // Needed so we don't have second mut borrow
-            self.heap.get_mut(root).unwrap().children = children;
+        }
+
+        MemoryStats {
+            objects: self.heap.len() + self.strings.len(),
+            bytes: self.bytes,
+            by_type,
+            limit: self.limit,
+            collections: self.collections,
+            last_pause: self.last_pause,
+            max_pause: self.max_pause,
+            total_pause: self.total_pause,
         }
     }
-    pub fn add_children_ref(&mut self, parent: GcRef, child: GcRef) {
-        if let Some(obj) = self.heap.get_mut(&parent) {
-            obj.children.push(child);
+
+    /// Captures every object on the heap with its outgoing references, `roots`
+    /// are labeled with what holds them (usually a variable name)
+    pub fn heap_snapshot(&self, roots: Vec<(String, GcRef)>) -> HeapSnapshot {
+        let mut objects = vec![];
+
+        for (r, obj) in self.heap.iter() {
+            let value = obj.value.borrow();
+            objects.push(HeapObject {
+                id: *r,
+                type_name: value.name(),
+                name: value.name().to_string(),
+                size: obj.size,
+                references: value.get_referenced_edges(self),
+            });
+        }
+        for (r, string) in self.strings.iter() {
+            objects.push(HeapObject {
+                id: *r,
+                type_name: "string",
+                name: string.value.to_string(),
+                size: string.size(),
+                references: vec![],
+            });
         }
+        objects.sort_by_key(|o| o.id.id());
+
+        HeapSnapshot::new(roots, objects)
     }
+
+    fn mark(&mut self, roots: &[GcRef]) {
+        let mut stack = roots.to_vec();
+
+        while let Some(r) = stack.pop() {
+            if let Some(string) = self.strings.get_mut(&r) {
+                string.marked = true;
+                continue;
+            }
+            let value = match self.heap.get_mut(&r) {
+                Some(obj) if !obj.marked => {
+                    obj.mark();
+                    Rc::clone(&obj.value)
+                }
+                _ => {
+                    continue;
+                }
+            };
+            stack.extend(value.borrow().get_referenced_children(self));
+        }
+    }
+
     pub fn collect_garbage(&mut self, roots: &[GcRef]) {
-        println!("Collected garbage");
+        let start = Instant::now();
+
         // Mark phase
-        for root in roots {
-            self.mark_root(root);
-        }
+        self.mark(roots);
 
-        let before = self.heap.len();
+        let before = self.heap.len() + self.strings.len();
 
         // Sweep phase
         self.heap.retain(|_, v| v.marked);
+        let interned = &mut self.interned;
+        self.strings.retain(|r, s| {
+            if !s.marked {
+                if let Some(bucket) = interned.get_mut(&s.hash) {
+                    bucket.retain(|b| b != r);
+                    if bucket.is_empty() {
+                        interned.remove(&s.hash);
+                    }
+                }
+            }
+            s.marked
+        });
 
-        println!("Collected {} heaps.", before - self.heap.len());
+        let collected = before - self.heap.len() - self.strings.len();
 
-        //Reset
+        //Reset and re-measure, objects may have grown since they were allocated
+        self.bytes = 0;
         for v in self.heap.values_mut() {
             v.reset_marked();
+            v.size = v.value.borrow().size();
+            self.bytes += v.size;
+        }
+        for s in self.strings.values_mut() {
+            s.marked = false;
+            self.bytes += s.size();
         }
+        self.threshold = (self.bytes * 2).max(MIN_THRESHOLD);
+
+        let pause = start.elapsed();
+        self.collections += 1;
+        self.last_pause = pause;
+        self.max_pause = self.max_pause.max(pause);
+        self.total_pause += pause;
+        trace!(
+            self.sink,
+            Category::Gc,
+            "collected {} objects, {} bytes live, pause {:?}",
+            collected,
+            self.bytes,
+            pause
+        );
     }
 }
 
 pub struct GcObject {
     value: Rc<RefCell<Box<dyn GcValue>>>,
     marked: bool,
-    children: Vec<GcRef>,
+    size: usize,
 }
 
 impl GcObject {
@@ -99,41 +391,104 @@ impl GcObject {
     }
 }
 
+struct StringObject {
+    value: Rc<str>,
+    // Only kept when the bytes aren't valid UTF-8, `value` is lossy then
+    raw: Option<Rc<[u8]>>,
+    hash: u64,
+    marked: bool,
+}
+
+impl StringObject {
+    fn new(bytes: &[u8], hash: u64) -> Self {
+        let (value, raw) = match std::str::from_utf8(bytes) {
+            Ok(s) => (Rc::from(s), None),
+            Err(_) => (Rc::from(String::from_utf8_lossy(bytes)), Some(Rc::from(bytes))),
+        };
+        StringObject { value, raw, hash, marked: false }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match &self.raw {
+            Some(raw) => raw,
+            None => self.value.as_bytes(),
+        }
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>() + self.value.len() + self.raw.as_ref().map_or(0, |raw| raw.len())
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct GcRef(u32);
 
-pub trait GcValue: Downcast {
+impl GcRef {
+    pub fn id(&self) -> u32 {
+        self.0
+    }
+}
+
+pub trait GcValue: Downcast + MaybeSend {
+    /// Objects directly referenced by this one, used by the mark phase
     fn get_referenced_children(&self, gc: &GarbageCollector) -> Vec<GcRef>;
     fn name(&self) -> &'static str;
-    fn index(&self, index: Value) -> Option<Value> {
+
+    /// Children labeled with how they are reachable (a key, a field name),
+    /// used by heap snapshots
+    fn get_referenced_edges(&self, gc: &GarbageCollector) -> Vec<(String, GcRef)> {
+        self.get_referenced_children(gc)
+            .into_iter()
+            .enumerate()
+            .map(|(i, r)| (format!("[{i}]"), r))
+            .collect()
+    }
+
+    /// Estimated number of bytes owned by this object, including its heap
+    /// allocations but not other gc objects it references
+    fn size(&self) -> usize {
+        mem::size_of_val(self)
+    }
+
+    fn index(&self, _index: Value) -> Option<Value> {
         unimplemented!("Cannot index on type {}", self.name())
     }
-    fn set_index(&mut self, index: Value, new_value: Value) {
+    fn set_index(&mut self, _index: Value, _new_value: Value) {
         unimplemented!("Cannot set index on type {}", self.name())
     }
 
-    fn str(&self, gc: &GarbageCollector) -> String {
+    fn str(&self, _gc: &GarbageCollector) -> String {
         "<gc object>".to_string()
     }
 
+    /// Built-in methods called with `obj:name()` when `obj.name` is nil
     fn run_meta_function(
         &mut self,
         name: &str,
-        gc: &mut GarbageCollector,
-        args: &[Value]
-    ) -> Value {
-        Value::Nil
+        _gc: &mut GarbageCollector,
+        _args: &[Value]
+    ) -> Result<Value, RuntimeError> {
+        Err(RuntimeError::new(format!("attempt to call a nil value (method '{name}')")))
+    }
+
+    fn metatable(&self) -> Option<GcRef> {
+        None
     }
 
     fn next(&mut self) -> Option<Value> {
         unimplemented!("Function next not implemented on {}", self.name())
     }
 
-    fn iter(&self) -> Iterable {
-        unimplemented!("Cannot iter over {}", self.name())
+    /// The values a generic `for` over this object goes through
+    fn iter(&self) -> Result<Iterable, RuntimeError> {
+        Err(RuntimeError::new(format!("attempt to iterate a {} value", self.name())))
     }
-    fn call(&self, interpreter: &mut Interpreter, args: &[Value]) -> Value {
-        unimplemented!("Type {} is not callable", self.name())
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        _args: &[Value]
+    ) -> Result<Vec<Value>, RuntimeError> {
+        Err(RuntimeError::new(format!("Type {} is not callable", self.name())))
     }
 
     // Add more function if needed
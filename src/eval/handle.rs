@@ -0,0 +1,112 @@
+// Script values held by Rust code. A handle roots its object until it is
+// dropped, the collector can't see the host's stack otherwise.
+
+use std::{ collections::HashMap, fmt };
+
+use crate::{ errors::RuntimeError, sync::{ Rc, RefCell } };
+
+use super::{
+    convert::{ FromLua, FromLuaError, FromLuaMulti, IntoLua, IntoLuaMulti },
+    gc::{ GarbageCollector, GcRef },
+    interpreter::Interpreter,
+    value::Value,
+};
+
+/// The objects that have handles, with how many each. With the `send`
+/// feature handles can be dropped on another thread than the interpreter's,
+/// which then waits for the count to be updated.
+pub(crate) type Handles = Rc<RefCell<HashMap<GcRef, usize>>>;
+
+// One handle to `object`, counted in `handles` while it exists
+struct Pinned {
+    handles: Handles,
+    object: GcRef,
+}
+
+impl Pinned {
+    fn new(handles: &Handles, object: GcRef) -> Self {
+        *handles.borrow_mut().entry(object).or_insert(0) += 1;
+        Pinned { handles: Rc::clone(handles), object }
+    }
+}
+
+impl Clone for Pinned {
+    fn clone(&self) -> Self {
+        Pinned::new(&self.handles, self.object)
+    }
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        let mut handles = self.handles.borrow_mut();
+        if let Some(count) = handles.get_mut(&self.object) {
+            *count -= 1;
+            if *count == 0 {
+                handles.remove(&self.object);
+            }
+        }
+    }
+}
+
+/// A function of the script that Rust code can keep and call, read with
+/// `FromLua` like `get_global_as::<ScriptFunction>("update")`. It isn't
+/// collected while a handle to it exists.
+#[derive(Clone)]
+pub struct ScriptFunction {
+    function: Pinned,
+}
+
+impl ScriptFunction {
+    /// Calls the function with `args` converted like a host function's
+    /// results, and converts what it returns to `R`. Errors raised by the
+    /// script are returned, as is calling it on another interpreter than
+    /// the one it came from.
+    pub fn call<A: IntoLuaMulti, R: FromLuaMulti>(
+        &self,
+        interpreter: &mut Interpreter,
+        args: A
+    ) -> Result<R, RuntimeError> {
+        if !Rc::ptr_eq(&self.function.handles, &interpreter.gc().handles) {
+            let message = "attempt to call a script function of another interpreter";
+            return Err(RuntimeError::new(message.to_string()));
+        }
+        let args = args.into_lua_multi(interpreter.gc_mut())?;
+        let results = interpreter.call_from_host(self.value(), &args)?;
+        R::from_lua_multi(&results, interpreter.gc()).map_err(|e| {
+            let message = match e {
+                FromLuaError::Expected(expected) => format!("{expected} expected"),
+                FromLuaError::Invalid(message) => message,
+            };
+            RuntimeError::new(format!("bad result of script function ({message})"))
+        })
+    }
+
+    pub fn value(&self) -> Value {
+        Value::GcObject(self.function.object)
+    }
+}
+
+impl fmt::Debug for ScriptFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ScriptFunction").field(&self.function.object).finish()
+    }
+}
+
+impl FromLua for ScriptFunction {
+    fn from_lua(value: Value, gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        let is_function = |r: &GcRef| {
+            gc.get(*r).is_some_and(|o| o.try_borrow().is_ok_and(|o| o.name() == "function"))
+        };
+        let function = value.gc_ref().filter(is_function);
+        match function {
+            Some(r) => Ok(ScriptFunction { function: Pinned::new(&gc.handles, r) }),
+            None => Err(FromLuaError::Expected("function")),
+        }
+    }
+}
+
+impl IntoLua for ScriptFunction {
+    fn into_lua(self, _gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        Ok(self.value())
+    }
+}
@@ -1,24 +1,88 @@
-use std::{ cell::RefCell, collections::HashMap, rc::Rc, sync::atomic };
+use std::{
+    any::{ Any, TypeId },
+    collections::HashMap,
+    io::{ self, BufRead, BufReader, Write },
+    time::{ SystemTime, UNIX_EPOCH },
+};
+
+use rand::{ rngs::SmallRng, SeedableRng };
+
+use crate::{
+    errors::RuntimeError,
+    parser::{ AstNode, ForType, ParsedValue, UnaryOp },
+    sync::{ MaybeSend, Rc, RefCell },
+    tokenizer::Operator,
+    trace::{ trace, Category, EventSink },
+};
+
+// Like Lua's MAXTAGLOOP, bounds `__index`/`__newindex` chains
+const MAX_META_CHAIN: usize = 2000;
 
-use crate::{ parser::{ AstNode, ForType, ParsedValue, UnaryOp }, tokenizer::Operator };
+#[cfg(not(feature = "send"))]
+type AppData = Box<dyn Any>;
+#[cfg(feature = "send")]
+type AppData = Box<dyn Any + Send>;
 
+#[cfg(feature = "async")]
+use super::async_host::YielderPtr;
 use super::{
-    environment::Environment,
-    gc::{ GarbageCollector, GcRef, GcValue },
-    types::{ self, Table, Function },
+    chunk::{ Chunk, CompiledFunction },
+    convert::{ FromLua, FromLuaMulti, IntoLuaMulti },
+    environment::{ Environment, Lookup },
+    stdlib::{ self, Capabilities, Searcher },
+    gc::{ GarbageCollector, GcRef, GcValue, MemoryStats },
+    scope::Scope,
+    snapshot::{ HeapSnapshot, RetainerStep },
+    stdio::{ Captured, Input, Output },
+    types::{ Table, Function, HostFunction, InterpreterFunction },
     value::Value,
 };
 
 pub struct Interpreter {
     global_env: Rc<RefCell<Environment>>,
     env_stack: Vec<Rc<RefCell<Environment>>>,
+    // Values held by Rust code while other expressions are evaluated,
+    // they are treated as roots so a collection can't free them
+    temporaries: Vec<Value>,
     pub(crate) gc: GarbageCollector,
+    // Shared by every string value, its `__index` makes `s:upper()` work
+    pub(crate) string_metatable: Option<GcRef>,
+    // `json.null`, decoding has to produce the same value after the script
+    // replaced the json table
+    pub(crate) json_null: Option<GcRef>,
+    // Behind `math.random`, separate from the gc's id generator so seeding
+    // it makes scripts reproducible
+    pub(crate) rng: SmallRng,
+    // Shared by every userdata of a Rust type, like the methods of io files
+    pub(crate) metatables: HashMap<TypeId, GcRef>,
+    // The handles of `io.stdin`, `io.stdout` and `io.stderr` once io is open
+    pub(crate) standard_files: Vec<GcRef>,
+    // The `package` table, `require` keeps working after the script
+    // replaced the global
+    pub(crate) package: Option<GcRef>,
+    // Asked for modules by `require` after `package.preload`
+    pub(crate) searchers: Vec<Box<dyn Searcher>>,
+    // Modules whose chunk is running, to catch circular requires
+    pub(crate) loading: Vec<String>,
+    // Table for values the host keeps away from scripts, made when first used
+    registry: Option<GcRef>,
+    // Host state for host functions, one value per type
+    app_data: HashMap<TypeId, AppData>,
+    // Suspends the script running in `exec_async`
+    #[cfg(feature = "async")]
+    pub(crate) yielder: Option<YielderPtr>,
+    capabilities: Capabilities,
+    // Standard streams of the script, the process' ones unless redirected
+    pub(crate) stdout: Box<dyn Output>,
+    pub(crate) stderr: Box<dyn Output>,
+    pub(crate) stdin: Box<dyn Input>,
+    sink: Option<Rc<dyn EventSink>>,
 }
 
 #[derive(Debug, Clone)]
 pub enum ControlFlow {
     Normal(Value),
-    Return(Value),
+    Return(Vec<Value>),
     Continue,
     Break,
     // TODO:  Maybe Throw(Value) variant ??
@@ -27,13 +91,20 @@ pub enum ControlFlow {
 impl ControlFlow {
     pub fn get_normal(&self) -> Value {
         if let ControlFlow::Normal(n) = self {
-            return n.clone();
+            return *n;
         }
         panic!("{:?} is not ControlFlow::Normal", &self)
     }
 }
 
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
 impl Interpreter {
+    #[allow(clippy::needless_return)]
     pub fn new() -> Self {
         let global_env = Rc::new(RefCell::new(Environment::new()));
         let gc = GarbageCollector::new();
@@ -41,62 +112,706 @@ impl Interpreter {
         return Interpreter {
             global_env: Rc::clone(&global_env),
             env_stack: vec![Rc::clone(&global_env)],
+            temporaries: vec![],
             gc,
+            string_metatable: None,
+            json_null: None,
+            rng: SmallRng::seed_from_u64(time_seed()),
+            metatables: HashMap::new(),
+            standard_files: vec![],
+            package: None,
+            searchers: vec![],
+            loading: vec![],
+            registry: None,
+            app_data: HashMap::new(),
+            #[cfg(feature = "async")]
+            yielder: None,
+            capabilities: Capabilities::NONE,
+            stdout: Box::new(io::stdout()),
+            stderr: Box::new(io::stderr()),
+            stdin: Box::new(BufReader::new(io::stdin())),
+            sink: None,
         };
     }
+    pub fn gc(&self) -> &GarbageCollector {
+        &self.gc
+    }
+    pub fn gc_mut(&mut self) -> &mut GarbageCollector {
+        &mut self.gc
+    }
+    /// Grants scripts access to the host through `os` and `io`, nothing is
+    /// allowed by default
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+    /// Redirects what `print`, `io.write` and `io.stdout` write
+    pub fn set_stdout(&mut self, stdout: impl Write + MaybeSend + 'static) {
+        self.stdout = Box::new(stdout);
+    }
+    pub fn set_stderr(&mut self, stderr: impl Write + MaybeSend + 'static) {
+        self.stderr = Box::new(stderr);
+    }
+    /// Where `input`, `io.read` and `io.stdin` read from
+    pub fn set_stdin(&mut self, stdin: impl BufRead + MaybeSend + 'static) {
+        self.stdin = Box::new(stdin);
+    }
+    /// Sends stdout to a buffer and returns a handle to read it
+    pub fn capture_stdout(&mut self) -> Captured {
+        let captured = Captured::new();
+        self.set_stdout(captured.clone());
+        captured
+    }
+    pub fn capture_stderr(&mut self) -> Captured {
+        let captured = Captured::new();
+        self.set_stderr(captured.clone());
+        captured
+    }
+    /// Lets `require` find modules with `searcher`, after `package.preload`
+    /// and the searchers added before it but ahead of `package.path`
+    pub fn add_searcher(&mut self, searcher: impl Searcher + 'static) {
+        self.searchers.push(Box::new(searcher));
+    }
+    /// Seeds the generator behind `math.random`, like `math.randomseed(seed)`
+    pub fn set_random_seed(&mut self, seed: u64) {
+        self.rng = SmallRng::seed_from_u64(seed);
+    }
+    /// Routes interpreter and gc events to `sink`, see [`crate::trace`]
+    pub fn set_event_sink(&mut self, sink: Rc<dyn EventSink>) {
+        self.gc.set_event_sink(Rc::clone(&sink));
+        self.sink = Some(sink);
+    }
     pub fn print_vars(&mut self) {
         self.env_stack.last().unwrap().borrow().print_vars(&mut self.gc);
     }
     pub fn add_global_function(
         &mut self,
         name: &str,
-        fn_ptr: fn(&mut GarbageCollector, &[Value]) -> Value
-    ) {
-        let func = Function::FnPointer(fn_ptr);
-        let r = self.gc.allocate(Box::new(func));
+        fn_ptr: fn(&mut GarbageCollector, &[Value]) -> Result<Value, RuntimeError>
+    ) -> Result<(), RuntimeError> {
+        self.add_global(name, Function::FnPointer(fn_ptr))
+    }
+    pub fn add_global_interpreter_function(
+        &mut self,
+        name: &str,
+        fn_ptr: InterpreterFunction
+    ) -> Result<(), RuntimeError> {
+        self.add_global(name, Function::FnPointerInterpreter(fn_ptr))
+    }
+    /// Creates the global table `name` holding built-in functions, like `string`
+    pub(crate) fn add_library(
+        &mut self,
+        name: &str,
+        functions: &[(&str, InterpreterFunction)]
+    ) -> Result<GcRef, RuntimeError> {
+        let library = self.allocate(Box::new(Table::new(vec![], HashMap::new())))?;
+        self.set_variable(false, &name.to_owned(), Value::GcObject(library));
+        for (name, function) in functions {
+            let function = self.allocate(Box::new(Function::FnPointerInterpreter(*function)))?;
+            self.set_field(library, name, Value::GcObject(function))?;
+        }
+        Ok(library)
+    }
+    /// `table[key] = value` for tables built from Rust, `table` has to be
+    /// reachable already
+    pub(crate) fn set_field(
+        &mut self,
+        table: GcRef,
+        key: &str,
+        value: Value
+    ) -> Result<(), RuntimeError> {
+        let temporaries = self.temporaries.len();
+        self.temporaries.push(value);
+        let key = self.create_string(key);
+        self.temporaries.truncate(temporaries);
+
+        self.gc.get(table).unwrap().borrow_mut().set_index(key?, value);
+        self.resize(table)
+    }
+    /// A function value that calls `f`, which can keep state between calls.
+    /// Arguments convert like `#[interpreter_function]`'s, with '?' as the
+    /// function's name in errors. The value isn't rooted.
+    pub fn create_function<A: FromLuaMulti, R: IntoLuaMulti>(
+        &mut self,
+        mut f: impl FnMut(A) -> R + MaybeSend + 'static
+    ) -> Result<Value, RuntimeError> {
+        self.create_interpreter_function(move |_: &mut Interpreter, args| f(args))
+    }
+    /// Like `create_function`, with the interpreter to call back into the script
+    pub fn create_interpreter_function<A: FromLuaMulti, R: IntoLuaMulti>(
+        &mut self,
+        f: impl FnMut(&mut Interpreter, A) -> R + MaybeSend + 'static
+    ) -> Result<Value, RuntimeError> {
+        let closure = Rc::new(RefCell::new(Some(host_function(f))));
+        let r = self.allocate(Box::new(Function::Closure(closure)))?;
+        Ok(Value::GcObject(r))
+    }
+    /// Runs `f` with a [`Scope`] that can make functions borrowing from the
+    /// caller's stack. They can't be called once `scope` returns.
+    pub fn scope<'env, R>(
+        &mut self,
+        f: impl for<'scope> FnOnce(&mut Scope<'scope, 'env>) -> R
+    ) -> R {
+        f(&mut Scope::new(self))
+    }
+    fn add_global(&mut self, name: &str, func: Function) -> Result<(), RuntimeError> {
+        let r = self.allocate(Box::new(func))?;
         self.set_variable(false, &name.to_owned(), Value::GcObject(r));
+        Ok(())
     }
-    pub fn eval(&mut self, node: &AstNode) -> ControlFlow {
-        match node {
+
+    pub fn get_global(&self, name: &str) -> Value {
+        self.global_env.borrow().get_variable(&name.to_owned()).unwrap_or(Value::Nil)
+    }
+
+    /// The global `name` converted to `T`, like `get_global_as::<Vec<String>>`
+    pub fn get_global_as<T: FromLua>(&self, name: &str) -> Result<T, RuntimeError> {
+        let value = self.get_global(name);
+        T::from_lua(value, &self.gc).map_err(|e|
+            RuntimeError::new(format!("global '{name}': {}", e.describe(value, &self.gc)))
+        )
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.set_variable(false, &name.to_owned(), value);
+    }
+
+    /// The registry, a table for values the host holds on to that scripts
+    /// can't reach. Like in Lua, it is shared by all host code so keys
+    /// should be specific, e.g. prefixed with the library's name.
+    pub fn registry(&mut self) -> Result<GcRef, RuntimeError> {
+        if let Some(registry) = self.registry {
+            return Ok(registry);
+        }
+        let registry = self.allocate(Box::new(Table::new(vec![], HashMap::new())))?;
+        self.registry = Some(registry);
+        Ok(registry)
+    }
+
+    /// `registry[key]`, nil if it was never set
+    pub fn registry_value(&self, key: &str) -> Value {
+        let (Some(registry), Some(key)) = (self.registry, self.gc.find_string(key)) else {
+            return Value::Nil;
+        };
+        let registry = self.gc.get(registry).unwrap();
+        let value = registry.borrow().index(Value::String(key));
+        value.unwrap_or(Value::Nil)
+    }
+
+    /// `registry[key] = value`, keeping `value` alive until it is replaced
+    pub fn set_registry_value(&mut self, key: &str, value: Value) -> Result<(), RuntimeError> {
+        let temporaries = self.temporaries.len();
+        self.temporaries.push(value);
+        let registry = self.registry();
+        self.temporaries.truncate(temporaries);
+        self.set_field(registry?, key, value)
+    }
+
+    /// Stores `data` for host functions to find with `app_data`, returning
+    /// the value of the same type it replaces. Scripts can't see it.
+    pub fn set_app_data<T: MaybeSend + 'static>(&mut self, data: T) -> Option<T> {
+        let previous = self.app_data.insert(TypeId::of::<T>(), Box::new(data))?;
+        Some(*previous.downcast().unwrap())
+    }
+
+    pub fn app_data<T: 'static>(&self) -> Option<&T> {
+        self.app_data.get(&TypeId::of::<T>()).map(|data| data.downcast_ref().unwrap())
+    }
+
+    pub fn app_data_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.app_data.get_mut(&TypeId::of::<T>()).map(|data| data.downcast_mut().unwrap())
+    }
+
+    pub fn remove_app_data<T: 'static>(&mut self) -> Option<T> {
+        let data = self.app_data.remove(&TypeId::of::<T>())?;
+        Some(*data.downcast().unwrap())
+    }
+
+    /// Registers the standard library globals. Fails if they don't fit in
+    /// the memory limit.
+    pub fn open_libs(&mut self) -> Result<(), RuntimeError> {
+        stdlib::open_libs(self)
+    }
+
+    /// Limits the estimated heap size in bytes, allocations past it raise a
+    /// "not enough memory" error after a full collection failed to make room.
+    /// Scripts can catch it with `pcall`, otherwise it is returned as `Err`
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.gc.set_memory_limit(limit);
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.gc.memory_stats()
+    }
+
+    pub fn heap_snapshot(&self) -> HeapSnapshot {
+        let mut roots: Vec<(String, GcRef)> = vec![];
+
+        for env in self.env_stack.iter() {
+            roots.extend(env.borrow().get_named_roots());
+        }
+        for value in self.temporaries.iter() {
+            if let Some(r) = value.gc_ref() {
+                roots.push(("<temporary>".to_string(), r));
+            }
+        }
+        if let Some(r) = self.string_metatable {
+            roots.push(("<string metatable>".to_string(), r));
+        }
+        if let Some(r) = self.json_null {
+            roots.push(("<json.null>".to_string(), r));
+        }
+        if let Some(r) = self.package {
+            roots.push(("<package>".to_string(), r));
+        }
+        if let Some(r) = self.registry {
+            roots.push(("<registry>".to_string(), r));
+        }
+        for r in self.metatables.values() {
+            roots.push(("<userdata metatable>".to_string(), *r));
+        }
+        for ty in self.gc.user_data.values() {
+            roots.push(("<userdata metatable>".to_string(), ty.metatable));
+            roots.push(("<userdata methods>".to_string(), ty.methods));
+        }
+        for r in self.standard_files.iter() {
+            roots.push(("<standard file>".to_string(), *r));
+        }
+        for r in self.gc.handles.borrow().keys() {
+            roots.push(("<handle>".to_string(), *r));
+        }
+
+        self.gc.heap_snapshot(roots)
+    }
+
+    /// Shortest chain of references keeping `target` alive, None if it is
+    /// unreachable and will be freed by the next collection
+    pub fn retainer_path(&self, target: GcRef) -> Option<Vec<RetainerStep>> {
+        self.heap_snapshot().retainer_path(target)
+    }
+
+    pub fn collect_garbage(&mut self) {
+        let mut roots: Vec<GcRef> = vec![];
+
+        for env in self.env_stack.iter() {
+            roots.extend_from_slice(env.borrow().get_roots().as_slice());
+        }
+        for value in self.temporaries.iter() {
+            if let Some(r) = value.gc_ref() {
+                roots.push(r);
+            }
+        }
+        roots.extend(self.string_metatable);
+        roots.extend(self.json_null);
+        roots.extend(self.package);
+        roots.extend(self.registry);
+        roots.extend(self.metatables.values());
+        for ty in self.gc.user_data.values() {
+            roots.extend([ty.metatable, ty.methods]);
+        }
+        roots.extend(self.standard_files.iter());
+        roots.extend(self.gc.handles.borrow().keys());
+
+        self.gc.collect_garbage(roots.as_slice());
+    }
+
+    /// Keeps `value` alive until the statement being evaluated finishes, for
+    /// built-ins that allocate while holding values the script can't reach
+    pub(crate) fn keep_alive(&mut self, value: Value) {
+        self.temporaries.push(value);
+    }
+
+    // How deep the evaluation is, to get back to with `unwind_to`
+    pub(crate) fn depth(&self) -> (usize, usize) {
+        (self.env_stack.len(), self.temporaries.len())
+    }
+
+    // Drops the scopes and temporaries of an evaluation that was abandoned
+    // or failed
+    pub(crate) fn unwind_to(&mut self, depth: (usize, usize)) {
+        self.env_stack.truncate(depth.0);
+        self.temporaries.truncate(depth.1);
+    }
+
+    /// `call` for the host, whose values the collector can't see: the
+    /// arguments are kept alive until it returns
+    pub(crate) fn call_from_host(
+        &mut self,
+        function: Value,
+        args: &[Value]
+    ) -> Result<Vec<Value>, RuntimeError> {
+        let temporaries = self.temporaries.len();
+        self.temporaries.extend_from_slice(args);
+        let results = self.call(function, args);
+        self.temporaries.truncate(temporaries);
+        results
+    }
+
+    /// Calls a function value. The arguments have to be reachable or kept
+    /// alive by the caller.
+    pub fn call(&mut self, function: Value, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+        if let Value::GcObject(r) = function {
+            // Tables and userdata with `__call` get themselves as the first argument
+            let handler = self.get_metamethod(function, "__call");
+            if !matches!(handler, Value::Nil) {
+                self.temporaries.push(function);
+                let args = [&[function], args].concat();
+                return self.call(handler, &args);
+            }
+            if let Some(f) = self.get_gc_value(r) {
+                return f.borrow().call(self, args);
+            }
+        }
+        Err(RuntimeError::new(format!("attempt to call a {} value", function.type_name(&self.gc))))
+    }
+
+    /// The metamethod `event` (like "__index") of `value`, nil if it has none
+    pub fn metatable_of(&self, value: Value) -> Option<GcRef> {
+        match value {
+            Value::String(_) => self.string_metatable,
+            Value::GcObject(r) => self.gc.get(r).and_then(|o| o.borrow().metatable()),
+            _ => None,
+        }
+    }
+
+    pub fn get_metamethod(&self, value: Value, event: &str) -> Value {
+        let metatable = self.metatable_of(value);
+        // A key that was never interned can't be in any table
+        let (Some(metatable), Some(event)) = (metatable, self.gc.find_string(event)) else {
+            return Value::Nil;
+        };
+        self.gc
+            .get(metatable)
+            .and_then(|t| t.borrow().index(Value::String(event)))
+            .unwrap_or(Value::Nil)
+    }
+
+    pub(crate) fn is_table(&self, value: Value) -> bool {
+        match value {
+            Value::GcObject(r) => self.gc.get(r).is_some_and(|o| o.borrow().is::<Table>()),
+            _ => false,
+        }
+    }
+
+    pub(crate) fn is_function(&self, value: Value) -> bool {
+        match value {
+            Value::GcObject(r) => self.gc.get(r).is_some_and(|o| o.borrow().is::<Function>()),
+            _ => false,
+        }
+    }
+
+    fn index_error(&self, value: Value) -> RuntimeError {
+        RuntimeError::new(format!("attempt to index a {} value", value.type_name(&self.gc)))
+    }
+
+    /// `object[key]`, honouring `__index`
+    pub fn index_value(&mut self, object: Value, key: Value) -> Result<Value, RuntimeError> {
+        let mut object = object;
+        for _ in 0..MAX_META_CHAIN {
+            let handler = if self.is_table(object) {
+                let table = self.gc.get(object.gc_ref().unwrap()).unwrap();
+                let raw = table.borrow().index(key).unwrap_or(Value::Nil);
+                if !matches!(raw, Value::Nil) {
+                    return Ok(raw);
+                }
+                let handler = self.get_metamethod(object, "__index");
+                if let Value::Nil = handler {
+                    return Ok(Value::Nil);
+                }
+                handler
+            } else {
+                match self.get_metamethod(object, "__index") {
+                    Value::Nil => {
+                        return Err(self.index_error(object));
+                    }
+                    handler => handler,
+                }
+            };
+            if self.is_function(handler) {
+                self.temporaries.extend([object, key]);
+                let results = self.call(handler, &[object, key])?;
+                return Ok(results.first().copied().unwrap_or(Value::Nil));
+            }
+            object = handler;
+        }
+        Err(RuntimeError::new("'__index' chain too long; possibly a loop".to_string()))
+    }
+
+    /// `table[key] = value` without `__newindex`, like `rawset`
+    pub(crate) fn raw_set(&mut self, table: GcRef, key: Value, value: Value) -> Result<(), RuntimeError> {
+        match key {
+            Value::Nil => {
+                return Err(RuntimeError::new("index is nil".to_string()));
+            }
+            Value::Float(f) if f.is_nan() => {
+                return Err(RuntimeError::new("index is NaN".to_string()));
+            }
+            _ => {}
+        }
+        self.gc.get(table).unwrap().borrow_mut().set_index(key, value);
+        self.resize(table)
+    }
+
+    /// `object[key] = value`, honouring `__newindex`
+    pub fn set_value(&mut self, object: Value, key: Value, value: Value) -> Result<(), RuntimeError> {
+        let mut object = object;
+        for _ in 0..MAX_META_CHAIN {
+            let handler = if self.is_table(object) {
+                let r = object.gc_ref().unwrap();
+                let table = self.gc.get(r).unwrap();
+                let existing = table.borrow().index(key).unwrap_or(Value::Nil);
+                let handler = match existing {
+                    Value::Nil => self.get_metamethod(object, "__newindex"),
+                    _ => Value::Nil,
+                };
+                if let Value::Nil = handler {
+                    trace!(self.sink, Category::Interpreter, "set {:?}[{:?}] = {:?}", object, key, value);
+                    return self.raw_set(r, key, value);
+                }
+                handler
+            } else {
+                match self.get_metamethod(object, "__newindex") {
+                    Value::Nil => {
+                        return Err(self.index_error(object));
+                    }
+                    handler => handler,
+                }
+            };
+            if self.is_function(handler) {
+                self.temporaries.extend([object, key, value]);
+                self.call(handler, &[object, key, value])?;
+                return Ok(());
+            }
+            object = handler;
+        }
+        Err(RuntimeError::new("'__newindex' chain too long; possibly a loop".to_string()))
+    }
+
+    /// `#value`, honouring `__len`
+    pub fn length(&mut self, value: Value) -> Result<Value, RuntimeError> {
+        if let Value::String(r) = value {
+            return Ok(Value::Number(self.gc.get_bytes(r).unwrap().len() as i64));
+        }
+        let handler = self.get_metamethod(value, "__len");
+        if !matches!(handler, Value::Nil) {
+            self.temporaries.push(value);
+            let results = self.call(handler, &[value])?;
+            return Ok(results.first().copied().unwrap_or(Value::Nil));
+        }
+        if let Some(table) = value.gc_ref().and_then(|r| self.gc.get(r)) {
+            if let Some(table) = table.borrow().downcast_ref::<Table>() {
+                return Ok(Value::Number(table.length()));
+            }
+        }
+        Err(
+            RuntimeError::new(
+                format!("attempt to get length of a {} value", value.type_name(&self.gc))
+            )
+        )
+    }
+
+    /// `a < b`, strings compare by bytes and anything else but numbers
+    /// needs `__lt`
+    pub fn less_than(&mut self, a: Value, b: Value) -> Result<bool, RuntimeError> {
+        self.compare(a, b, "__lt")
+    }
+
+    /// `a <= b`, like `less_than` with `__le`
+    pub fn less_or_equal(&mut self, a: Value, b: Value) -> Result<bool, RuntimeError> {
+        self.compare(a, b, "__le")
+    }
+
+    fn compare(&mut self, a: Value, b: Value, event: &str) -> Result<bool, RuntimeError> {
+        let strict = event == "__lt";
+        match (a, b) {
+            (Value::Number(_) | Value::Float(_), Value::Number(_) | Value::Float(_)) => {
+                let result = if strict { a.less(&b) } else { a.less_or_equal(&b) };
+                return Ok(result.is_truthy());
+            }
+            (Value::String(x), Value::String(y)) => {
+                let x = self.gc.get_bytes(x).unwrap();
+                let y = self.gc.get_bytes(y).unwrap();
+                return Ok(if strict { x < y } else { x <= y });
+            }
+            _ => {}
+        }
+        let mut handler = self.get_metamethod(a, event);
+        if let Value::Nil = handler {
+            handler = self.get_metamethod(b, event);
+        }
+        if let Value::Nil = handler {
+            let (a, b) = (a.type_name(&self.gc), b.type_name(&self.gc));
+            return Err(
+                RuntimeError::new(
+                    if a == b {
+                        format!("attempt to compare two {a} values")
+                    } else {
+                        format!("attempt to compare {a} with {b}")
+                    }
+                )
+            );
+        }
+        self.temporaries.extend([a, b]);
+        let results = self.call(handler, &[a, b])?;
+        Ok(results.first().is_some_and(|v| v.is_truthy()))
+    }
+
+    pub fn allocate(&mut self, value: Box<dyn GcValue>) -> Result<GcRef, RuntimeError> {
+        if !self.gc.can_allocate(value.size()) {
+            self.collect_garbage();
+        }
+        self.gc.allocate(value)
+    }
+
+    /// Interns `s`, running a collection first if the memory limit is hit
+    pub fn create_string(&mut self, s: &str) -> Result<Value, RuntimeError> {
+        self.create_byte_string(s.as_bytes())
+    }
+
+    /// A string of any bytes, see `GarbageCollector::intern_bytes`
+    pub fn create_byte_string(&mut self, bytes: &[u8]) -> Result<Value, RuntimeError> {
+        if !self.gc.can_allocate(bytes.len()) {
+            self.collect_garbage();
+        }
+        self.gc.create_byte_string(bytes)
+    }
+
+    // Runs `f` once more after a collection if it ran out of memory. Only for
+    // primitive operations, a metamethod would run twice.
+    fn retry_after_collection(
+        &mut self,
+        f: impl Fn(&mut GarbageCollector) -> Result<Value, RuntimeError>
+    ) -> Result<Value, RuntimeError> {
+        match f(&mut self.gc) {
+            Err(e) if e == RuntimeError::out_of_memory() => {
+                self.collect_garbage();
+                f(&mut self.gc)
+            }
+            result => result,
+        }
+    }
+
+    // Call after mutating a gc object so its new size is accounted for
+    fn resize(&mut self, gc_ref: GcRef) -> Result<(), RuntimeError> {
+        if self.gc.resize(gc_ref).is_err() {
+            self.collect_garbage();
+            if self.gc.is_over_limit() {
+                return Err(RuntimeError::out_of_memory());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn eval(&mut self, node: &AstNode) -> Result<ControlFlow, RuntimeError> {
+        let temporaries = self.temporaries.len();
+        let evaled = self.eval_node(node);
+        self.temporaries.truncate(temporaries);
+        evaled
+    }
+
+    /// Runs `chunk` with the interpreter's globals and returns what it
+    /// returns, the results stay alive until the next collection
+    pub fn exec(&mut self, chunk: &Chunk) -> Result<Vec<Value>, RuntimeError> {
+        let temporaries = self.temporaries.len();
+        let evaled = self.eval_chunk(chunk, None);
+        self.temporaries.truncate(temporaries);
+        evaled
+    }
+
+    /// Runs `chunk` with the fields of the table `env` as its globals
+    pub fn exec_with_env(&mut self, chunk: &Chunk, env: GcRef) -> Result<Vec<Value>, RuntimeError> {
+        if !self.is_table(Value::GcObject(env)) {
+            return Err(RuntimeError::new("the environment of a chunk must be a table".to_string()));
+        }
+        let temporaries = self.temporaries.len();
+        let evaled = self.eval_chunk(chunk, Some(env));
+        self.temporaries.truncate(temporaries);
+        evaled
+    }
+
+    /// `chunk` as a function value that scripts can call, with the globals
+    /// of `env` if given. It's only reachable from the result, and calling it
+    /// ignores the arguments.
+    pub fn load(&mut self, chunk: &Chunk, env: Option<GcRef>) -> Result<Value, RuntimeError> {
+        let function = CompiledFunction { chunk: chunk.clone(), env };
+        Ok(Value::GcObject(self.allocate(Box::new(function))?))
+    }
+
+    /// Runs a parsed chunk in its own scope, below the globals or `env`,
+    /// and returns what it returns. Errors are attributed to the chunk.
+    pub(crate) fn eval_chunk(
+        &mut self,
+        chunk: &Chunk,
+        env: Option<GcRef>
+    ) -> Result<Vec<Value>, RuntimeError> {
+        let AstNode::Program(stmts) = chunk.ast() else {
+            panic!("Expected a program for chunk {}", chunk.name());
+        };
+        let scope = match env {
+            Some(table) => Environment::with_globals(table),
+            None => Environment::with_parent(&self.global_env),
+        };
+        self.env_stack.push(Rc::new(RefCell::new(scope)));
+        let evaled = self.eval_multiple(stmts);
+        self.env_stack.pop();
+        match evaled {
+            Ok(ControlFlow::Return(values)) => Ok(values),
+            Ok(_) => Ok(vec![]),
+            Err(e) => Err(e.with_chunk(chunk.name())),
+        }
+    }
+
+    fn eval_node(&mut self, node: &AstNode) -> Result<ControlFlow, RuntimeError> {
+        Ok(match node {
+            AstNode::Program(stmts) => self.eval_multiple(stmts)?,
+            AstNode::Literal(ParsedValue::String(s)) => ControlFlow::Normal(self.create_string(s)?),
             AstNode::Literal(e) if !matches!(e, ParsedValue::Table { array: _, map: _ }) =>
                 ControlFlow::Normal(Value::from(e.clone())),
             AstNode::Literal(e) if matches!(e, ParsedValue::Table { array: _, map: _ }) =>
-                ControlFlow::Normal(self.eval_table(e)),
-            AstNode::Variable(s) => ControlFlow::Normal(self.get_variable(s)),
+                ControlFlow::Normal(self.eval_table(e)?),
+            AstNode::Variable(s) => ControlFlow::Normal(self.get_variable(s)?),
             AstNode::Assignment { is_local, target, rhs } => {
-                self.eval_assignment(*is_local, target, rhs);
+                self.eval_assignment(*is_local, target, rhs)?;
+                ControlFlow::Normal(Value::Nil)
+            }
+            AstNode::MultipleAssignment { is_local, targets, values } => {
+                let values = self.eval_expressions(values)?;
+                for (i, target) in targets.iter().enumerate() {
+                    self.assign(*is_local, target, values.get(i).copied().unwrap_or(Value::Nil))?;
+                }
                 ControlFlow::Normal(Value::Nil)
             }
             AstNode::BinaryOp { op, lhs, rhs } =>
-                ControlFlow::Normal(self.eval_bin_op(op, lhs, rhs)),
-            AstNode::UnaryOp { op, value } => ControlFlow::Normal(self.eval_unary_op(op, &value)),
-            AstNode::Index { base, index } =>
-                ControlFlow::Normal(
-                    self.eval_table_index(
-                        &(AstNode::Index { base: base.to_owned(), index: index.to_owned() }) // FIXME: Joj me ne
-                    )
-                ),
+                ControlFlow::Normal(self.eval_bin_op(op, lhs, rhs)?),
+            AstNode::UnaryOp { op, value } => ControlFlow::Normal(self.eval_unary_op(op, value)?),
+            AstNode::Index { .. } => ControlFlow::Normal(self.eval_table_index(node)?),
             AstNode::While { condition, scope } => {
                 return self.eval_while(condition, scope);
             }
             AstNode::If { condition, scope, elseif, else_scope } => {
-                return self
-                    .eval_if(condition, scope, elseif, else_scope)
-                    .unwrap_or(ControlFlow::Normal(Value::Nil));
+                return Ok(
+                    self
+                        .eval_if(condition, scope, elseif, else_scope)?
+                        .unwrap_or(ControlFlow::Normal(Value::Nil))
+                );
             }
             AstNode::Scope { stmts } => {
                 return self.eval_scope(stmts);
             }
             AstNode::Continue => ControlFlow::Continue,
             AstNode::Break => ControlFlow::Break,
-            AstNode::Return { expr } => ControlFlow::Return(self.eval(&expr).get_normal()),
+            AstNode::Return { exprs } => ControlFlow::Return(self.eval_expressions(exprs)?),
             AstNode::For { variable, for_type, scope } => {
                 match &for_type {
-                    &ForType::Generic(i) => { self.eval_for_generic(variable, scope, &i) }
-                    &ForType::Range { start: s, end: e, step: st } => {
-                        let start = self.eval(s).get_normal();
-                        let end = self.eval(e).get_normal();
-                        let step = self.eval(st).get_normal();
+                    ForType::Generic(i) => {
+                        return self.eval_for_generic(variable, scope, i);
+                    }
+                    ForType::Range { start: s, end: e, step: st } => {
+                        let start = self.eval(s)?.get_normal();
+                        let end = self.eval(e)?.get_normal();
+                        let step = self.eval(st)?.get_normal();
                         match (start, end, step) {
                             (Value::Number(start), Value::Number(end), Value::Number(step)) => {
                                 return self.eval_for_numeric(variable, scope, (start, end, step));
@@ -110,79 +825,126 @@ impl Interpreter {
                 }
             }
             AstNode::FunctionDeclaration { name, arguments, body } => {
-                self.declare_function(name, arguments, &body);
+                self.declare_function(name, arguments, body)?;
                 ControlFlow::Normal(Value::Nil)
             }
-            AstNode::FunctionCall { target, args } => {
-                let base = self.eval(&target).get_normal();
+            AstNode::FunctionCall { .. } | AstNode::MethodCall { .. } => {
+                let results = self.eval_call(node)?;
+                ControlFlow::Normal(results.first().copied().unwrap_or(Value::Nil))
+            }
+            _ => unimplemented!("Fucking wait a bit I am implementing this shit now"),
+        })
+    }
+    /// Evaluates a comma separated list of expressions, a call in the last
+    /// position adds all of its results. The values are kept alive.
+    fn eval_expressions(&mut self, exprs: &[AstNode]) -> Result<Vec<Value>, RuntimeError> {
+        let mut values = Vec::with_capacity(exprs.len());
+        for (i, expr) in exprs.iter().enumerate() {
+            let is_call = matches!(expr, AstNode::FunctionCall { .. } | AstNode::MethodCall { .. });
+            if is_call && i == exprs.len() - 1 {
+                let results = self.eval_call(expr)?;
+                self.temporaries.extend_from_slice(&results);
+                values.extend(results);
+            } else {
+                let value = self.eval(expr)?.get_normal();
+                self.temporaries.push(value);
+                values.push(value);
+            }
+        }
+        Ok(values)
+    }
 
-                if let Value::GcObject(r) = base {
-                    if let Some(v) = self.get_gc_value(r) {
-                        let mut evaled_args = vec![];
+    fn eval_call(&mut self, node: &AstNode) -> Result<Vec<Value>, RuntimeError> {
+        match node {
+            AstNode::FunctionCall { target, args } => {
+                let function = self.eval(target)?.get_normal();
+                self.temporaries.push(function);
+                let args = self.eval_expressions(args)?;
 
-                        for a in args {
-                            evaled_args.push(self.eval(a).get_normal());
-                        }
-                        return ControlFlow::Normal(v.borrow().call(self, evaled_args.as_slice()));
-                    }
-                }
-                panic!("{:?} can't be called!", base)
+                trace!(self.sink, Category::Interpreter, "call {:?} with {:?}", target, args);
+                self.call(function, &args)
             }
             AstNode::MethodCall { base, name, args } => {
-                let base = self.eval(&base).get_normal();
-
-                if let Value::GcObject(r) = base {
-                    if let Some(v) = self.get_gc_value(r) {
-                        let mut evaled_args = vec![];
-                        // if *include_self {
-                        //     evaled_args.push(base);
-                        // }
-                        for a in args {
-                            evaled_args.push(self.eval(a).get_normal());
-                        }
-                        return ControlFlow::Normal(
-                            v
-                                .borrow_mut()
-                                .run_meta_function(
-                                    name.as_str(),
-                                    &mut self.gc,
-                                    evaled_args.as_slice()
-                                )
-                            // v
-                            //     .borrow()
-                            //     ::run_meta_function(
-                            //         name.as_str(),
-                            //         &mut self.gc,
-                            //         evaled_args.as_slice()
-                            //     )
-                        );
+                let base = self.eval(base)?.get_normal();
+                self.temporaries.push(base);
+                let mut args = self.eval_expressions(args)?;
+
+                trace!(
+                    self.sink,
+                    Category::Interpreter,
+                    "method {} on {:?} with {:?}",
+                    name,
+                    base,
+                    args
+                );
+                // `obj:name(...)` is `obj.name(obj, ...)`
+                let indexable =
+                    matches!(base, Value::String(_)) ||
+                    self.is_table(base) ||
+                    !matches!(self.get_metamethod(base, "__index"), Value::Nil);
+                let method = if indexable {
+                    let key = self.create_string(name)?;
+                    self.index_value(base, key)?
+                } else {
+                    Value::Nil
+                };
+                match base {
+                    _ if !matches!(method, Value::Nil) => {
+                        args.insert(0, base);
+                        self.call(method, &args)
+                    }
+                    // Built-in methods like `t:append(v)`
+                    Value::GcObject(r) if self.get_gc_value(r).is_some() => {
+                        let result = self
+                            .get_gc_value(r)
+                            .unwrap()
+                            .borrow_mut()
+                            .run_meta_function(name.as_str(), &mut self.gc, args.as_slice())?;
+                        self.resize(r)?;
+                        Ok(vec![result])
                     }
+                    _ =>
+                        Err(
+                            RuntimeError::new(
+                                format!(
+                                    "attempt to call method '{name}' on a {} value",
+                                    base.type_name(&self.gc)
+                                )
+                            )
+                        ),
                 }
-                panic!("{:?} can't be called!", base)
             }
-            _ => unimplemented!("Fucking wait a bit I am implementing this shit now"),
+            _ => unreachable!("{:?} is not a call", node),
         }
     }
-    fn declare_function(&mut self, name: &String, args: &Vec<String>, body: &AstNode) {
+
+    fn declare_function(
+        &mut self,
+        name: &String,
+        args: &[String],
+        body: &AstNode
+    ) -> Result<(), RuntimeError> {
         let function = Function::new(args.to_owned(), body.to_owned());
-        let r = self.gc.allocate(Box::new(function));
+        let r = self.allocate(Box::new(function))?;
         self.set_variable(true, name, Value::GcObject(r));
+        Ok(())
     }
 
     pub(crate) fn eval_function_scope(
         &mut self,
         scope: &AstNode,
-        args: Vec<(&String, &Value)>
-    ) -> ControlFlow {
+        args: Vec<(&String, Value)>
+    ) -> Result<Vec<Value>, RuntimeError> {
         if let AstNode::Scope { stmts } = scope {
             self.add_stack_frame();
             for (name, value) in args.iter() {
-                self.set_variable(true, name, value.to_owned().to_owned());
+                self.set_variable(true, name, *value);
             }
             let evaled = match self.eval_multiple(stmts) {
-                ControlFlow::Return(v) => ControlFlow::Normal(v),
-                ControlFlow::Normal(_) => ControlFlow::Normal(Value::Nil),
-                _ => panic!("Cannot use break and continue directly in function"),
+                Ok(ControlFlow::Return(values)) => Ok(values),
+                Ok(ControlFlow::Normal(_)) => Ok(vec![]),
+                Ok(_) => panic!("Cannot use break and continue directly in function"),
+                Err(e) => Err(e),
             };
             self.pop_stack_frame();
             return evaled;
@@ -195,7 +957,7 @@ impl Interpreter {
         name: &String,
         scope: &AstNode,
         range: (i64, i64, i64)
-    ) -> ControlFlow {
+    ) -> Result<ControlFlow, RuntimeError> {
         let mut i = range.0;
 
         loop {
@@ -203,34 +965,30 @@ impl Interpreter {
                 if i >= range.1 {
                     break;
                 }
-            } else {
-                if i <= range.1 {
-                    break;
-                }
+            } else if i <= range.1 {
+                break;
             }
 
             if let AstNode::Scope { stmts } = scope {
                 self.add_stack_frame();
                 self.set_variable(true, name, Value::Number(i));
-                match self.eval_multiple(&stmts) {
-                    ControlFlow::Normal(_) => {}
+                let evaled = self.eval_multiple(stmts);
+                self.pop_stack_frame();
+                match evaled? {
+                    ControlFlow::Normal(_) | ControlFlow::Continue => {}
                     ControlFlow::Return(value) => {
-                        return ControlFlow::Return(value);
-                    }
-                    ControlFlow::Continue => {
-                        continue;
+                        return Ok(ControlFlow::Return(value));
                     }
                     ControlFlow::Break => {
                         break;
                     }
                 }
-                self.pop_stack_frame();
                 i += range.2;
             } else {
                 panic!("Expected scope for For scope");
             }
         }
-        ControlFlow::Normal(Value::Nil)
+        Ok(ControlFlow::Normal(Value::Nil))
     }
 
     fn eval_for_generic(
@@ -238,40 +996,66 @@ impl Interpreter {
         name: &String,
         scope: &AstNode,
         iterable: &AstNode
-    ) -> ControlFlow {
-        let iterable = self.eval(iterable).get_normal();
-        let iterable = iterable.iter(&mut self.gc);
-        let iterable = self.gc.get(iterable).unwrap();
-        let mut iterable = iterable.borrow_mut();
-        while let Some(v) = iterable.next() {
-            // I dont like this -> ^^
+    ) -> Result<ControlFlow, RuntimeError> {
+        let iterable = self.eval(iterable)?.get_normal();
+        self.temporaries.push(iterable);
+        // A function is called for each value until it returns nil, like
+        // `f:lines()`, anything else is turned into an iterable
+        let function = match iterable.type_name(&self.gc) {
+            "function" => Some(iterable),
+            _ => None,
+        };
+        let values = match function {
+            Some(_) => None,
+            None => {
+                let values = iterable.iter(&mut self.gc)?;
+                self.temporaries.push(Value::GcObject(values));
+                self.gc.get(values)
+            }
+        };
+        loop {
+            let next = match (function, &values) {
+                (Some(function), _) =>
+                    match self.call(function, &[])?.first() {
+                        None | Some(Value::Nil) => None,
+                        Some(v) => Some(*v),
+                    }
+                // Not borrowed across the body, a collection in there borrows it to mark
+                (None, Some(values)) => values.borrow_mut().next(),
+                (None, None) => None,
+            };
+            let Some(v) = next else {
+                break;
+            };
 
             if let AstNode::Scope { stmts } = scope {
                 self.add_stack_frame();
                 self.set_variable(true, name, v);
-                match self.eval_multiple(&stmts) {
-                    ControlFlow::Normal(value) => {}
+                let evaled = self.eval_multiple(stmts);
+                self.pop_stack_frame();
+                match evaled? {
+                    ControlFlow::Normal(_) | ControlFlow::Continue => {}
                     ControlFlow::Return(value) => {
-                        return ControlFlow::Return(value);
-                    }
-                    ControlFlow::Continue => {
-                        continue;
+                        return Ok(ControlFlow::Return(value));
                     }
                     ControlFlow::Break => {
                         break;
                     }
                 }
-                self.pop_stack_frame();
             }
         }
-        ControlFlow::Normal(Value::Nil)
+        Ok(ControlFlow::Normal(Value::Nil))
     }
-    fn eval_while(&mut self, condition: &AstNode, scope: &AstNode) -> ControlFlow {
-        while self.eval(condition).get_normal().is_truthy() {
+    fn eval_while(
+        &mut self,
+        condition: &AstNode,
+        scope: &AstNode
+    ) -> Result<ControlFlow, RuntimeError> {
+        while self.eval(condition)?.get_normal().is_truthy() {
             if let AstNode::Scope { stmts } = scope {
-                match self.eval_scope(stmts) {
+                match self.eval_scope(stmts)? {
                     ControlFlow::Return(value) => {
-                        return ControlFlow::Return(value);
+                        return Ok(ControlFlow::Return(value));
                     }
                     ControlFlow::Continue => {
                         continue;
@@ -279,13 +1063,13 @@ impl Interpreter {
                     ControlFlow::Break => {
                         break;
                     }
-                    ControlFlow::Normal(value) => {/* Do nothing */}
+                    ControlFlow::Normal(_) => {/* Do nothing */}
                 }
             } else {
                 panic!("Expected Scope");
             }
         }
-        ControlFlow::Normal(Value::Nil)
+        Ok(ControlFlow::Normal(Value::Nil))
     }
 
     fn eval_if(
@@ -294,86 +1078,181 @@ impl Interpreter {
         scope: &AstNode,
         elseif: &Vec<AstNode>,
         else_scope: &Option<AstNode>
-    ) -> Option<ControlFlow> {
-        if self.eval(&condition).get_normal().is_truthy() {
+    ) -> Result<Option<ControlFlow>, RuntimeError> {
+        if self.eval(condition)?.get_normal().is_truthy() {
             if let AstNode::Scope { stmts } = scope {
-                return Some(self.eval_scope(stmts));
+                return Ok(Some(self.eval_scope(stmts)?));
             }
         }
         for elif in elseif {
             if let AstNode::If { condition, scope, elseif, else_scope } = elif {
-                if let Some(flow) = self.eval_if(condition, scope, elseif, else_scope) {
-                    return Some(flow);
+                if let Some(flow) = self.eval_if(condition, scope, elseif, else_scope)? {
+                    return Ok(Some(flow));
                 }
             }
         }
 
         if let Some(AstNode::Scope { stmts }) = else_scope {
-            return Some(self.eval_scope(&stmts));
+            return Ok(Some(self.eval_scope(stmts)?));
         }
 
-        None
+        Ok(None)
     }
-    fn eval_unary_op(&mut self, op: &UnaryOp, value: &AstNode) -> Value {
-        let value = self.eval(value).get_normal();
+    fn eval_unary_op(&mut self, op: &UnaryOp, value: &AstNode) -> Result<Value, RuntimeError> {
+        let value = self.eval(value)?.get_normal();
+
+        let event = match op {
+            UnaryOp::Negative => Some("__unm"),
+            UnaryOp::BitwiseNot => Some("__bnot"),
+            _ => None,
+        };
+        if let (Some(event), Value::GcObject(_)) = (event, value) {
+            let handler = self.get_metamethod(value, event);
+            if !matches!(handler, Value::Nil) {
+                self.temporaries.push(value);
+                // Like Lua, the operand is passed twice
+                let results = self.call(handler, &[value, value])?;
+                return Ok(results.first().copied().unwrap_or(Value::Nil));
+            }
+        }
 
-        match op {
+        Ok(match op {
             UnaryOp::Negative => value.unary_negative(),
-            UnaryOp::Length => value.unary_length(),
+            UnaryOp::Length => self.length(value)?,
             UnaryOp::Not => value.unary_not(),
             UnaryOp::BitwiseNot => value.bitwise_not(),
-        }
+        })
+    }
+
+    fn eval_bin_op(
+        &mut self,
+        op: &Operator,
+        lhs: &AstNode,
+        rhs: &AstNode
+    ) -> Result<Value, RuntimeError> {
+        let lhs = self.eval(lhs)?.get_normal();
+        self.temporaries.push(lhs);
+        let rhs = self.eval(rhs)?.get_normal();
+        self.temporaries.push(rhs);
+
+        self.apply_bin_op(op, &lhs, &rhs)
     }
 
-    fn eval_bin_op(&mut self, op: &Operator, lhs: &AstNode, rhs: &AstNode) -> Value {
-        let lhs = self.eval(lhs).get_normal();
-        let rhs = self.eval(rhs).get_normal();
-
-        match op {
-            Operator::Add => lhs.add(&rhs),
-            Operator::Subtract => lhs.sub(&rhs),
-            Operator::Multiply => lhs.mul(&rhs),
-            Operator::Divide => lhs.div(&rhs),
-            Operator::FloorDivide => lhs.floor_div(&rhs),
-            Operator::Mod => lhs.modulo(&rhs),
-            Operator::Power => lhs.power(&rhs),
-            Operator::Concatenation => lhs.concat(&rhs, &self.gc),
-            Operator::Equals => lhs.equal(&rhs),
-            Operator::NotEquals => lhs.not_equal(&rhs),
-            Operator::And => lhs.add(&rhs),
-            Operator::Or => lhs.or(&rhs),
-            Operator::BitwiseOr => lhs.bitwise_or(&rhs),
-            Operator::BitwiseAnd => lhs.bitwise_and(&rhs),
-            Operator::BitwiseXOR => lhs.bitwise_xor(&rhs),
-
-            Operator::BitwiseLShift => lhs.bitwise_left_shift(&rhs),
-            Operator::BitwiseRShift => lhs.bitwise_right_shift(&rhs),
+    fn apply_bin_op(
+        &mut self,
+        op: &Operator,
+        lhs: &Value,
+        rhs: &Value
+    ) -> Result<Value, RuntimeError> {
+        if let Some(result) = self.bin_op_metamethod(op, *lhs, *rhs)? {
+            return Ok(result);
+        }
+        // Both operands are rooted, a collection may make room for the result
+        Ok(match op {
+            Operator::Add => self.retry_after_collection(|gc| lhs.add(rhs, gc))?,
+            Operator::Subtract => lhs.sub(rhs),
+            Operator::Multiply => self.retry_after_collection(|gc| lhs.mul(rhs, gc))?,
+            Operator::Divide => lhs.div(rhs),
+            Operator::FloorDivide => lhs.floor_div(rhs),
+            Operator::Mod => lhs.modulo(rhs),
+            Operator::Power => lhs.power(rhs),
+            Operator::Concatenation => self.retry_after_collection(|gc| lhs.concat(rhs, gc))?,
+            Operator::Equals => lhs.equal(rhs),
+            Operator::NotEquals => lhs.not_equal(rhs),
+            Operator::And => self.retry_after_collection(|gc| lhs.add(rhs, gc))?,
+            Operator::Or => lhs.or(rhs),
+            Operator::BitwiseOr => lhs.bitwise_or(rhs),
+            Operator::BitwiseAnd => lhs.bitwise_and(rhs),
+            Operator::BitwiseXOR => lhs.bitwise_xor(rhs),
+
+            Operator::BitwiseLShift => lhs.bitwise_left_shift(rhs),
+            Operator::BitwiseRShift => lhs.bitwise_right_shift(rhs),
             Operator::Relational(comparison) => {
-                match comparison {
-                    crate::tokenizer::Comparison::Less => lhs.less(&rhs),
-                    crate::tokenizer::Comparison::LessOrEqual => lhs.less_or_equal(&rhs),
-                    crate::tokenizer::Comparison::More => lhs.greater(&rhs),
-                    crate::tokenizer::Comparison::MoreOrEqual => lhs.greater_or_equal(&rhs),
-                }
+                let result = match comparison {
+                    crate::tokenizer::Comparison::Less => self.less_than(*lhs, *rhs)?,
+                    crate::tokenizer::Comparison::LessOrEqual => self.less_or_equal(*lhs, *rhs)?,
+                    crate::tokenizer::Comparison::More => self.less_than(*rhs, *lhs)?,
+                    crate::tokenizer::Comparison::MoreOrEqual => self.less_or_equal(*rhs, *lhs)?,
+                };
+                Value::Bool(result)
             }
             _ => panic!("Not a binary op"),
+        })
+    }
+    // Operators on tables and userdata call the metamethod of the first
+    // operand that has one. `__eq` is only tried for two different objects.
+    fn bin_op_metamethod(
+        &mut self,
+        op: &Operator,
+        lhs: Value,
+        rhs: Value
+    ) -> Result<Option<Value>, RuntimeError> {
+        let event = match op {
+            Operator::Add => "__add",
+            Operator::Subtract => "__sub",
+            Operator::Multiply => "__mul",
+            Operator::Divide => "__div",
+            Operator::FloorDivide => "__idiv",
+            Operator::Mod => "__mod",
+            Operator::Power => "__pow",
+            Operator::Concatenation => "__concat",
+            Operator::BitwiseAnd => "__band",
+            Operator::BitwiseOr => "__bor",
+            Operator::BitwiseXOR => "__bxor",
+            Operator::BitwiseLShift => "__shl",
+            Operator::BitwiseRShift => "__shr",
+            Operator::Equals | Operator::NotEquals => "__eq",
+            _ => {
+                return Ok(None);
+            }
+        };
+        let objects = (matches!(lhs, Value::GcObject(_)), matches!(rhs, Value::GcObject(_)));
+        let applies = if event == "__eq" {
+            objects == (true, true) && lhs != rhs
+        } else {
+            objects.0 || objects.1
+        };
+        if !applies {
+            return Ok(None);
+        }
+        let mut handler = self.get_metamethod(lhs, event);
+        if let Value::Nil = handler {
+            handler = self.get_metamethod(rhs, event);
+        }
+        if let Value::Nil = handler {
+            return Ok(None);
         }
+        let results = self.call(handler, &[lhs, rhs])?;
+        let result = results.first().copied().unwrap_or(Value::Nil);
+        Ok(
+            Some(match op {
+                Operator::Equals => Value::Bool(result.is_truthy()),
+                Operator::NotEquals => Value::Bool(!result.is_truthy()),
+                _ => result,
+            })
+        )
     }
-    fn eval_multiple(&mut self, list: &[AstNode]) -> ControlFlow {
+
+    fn eval_multiple(&mut self, list: &[AstNode]) -> Result<ControlFlow, RuntimeError> {
         for node in list {
-            let evaled = self.eval(&node);
+            let evaled = self.eval(node)?;
             match evaled {
                 ControlFlow::Normal(_) => {
+                    // Between statements is a safe point, every live value
+                    // is reachable from an environment or the temporaries
+                    if self.gc.should_collect() {
+                        self.collect_garbage();
+                    }
                     continue;
                 }
                 _ => {
-                    return evaled;
+                    return Ok(evaled);
                 }
             }
         }
-        ControlFlow::Normal(Value::Nil)
+        Ok(ControlFlow::Normal(Value::Nil))
     }
-    fn eval_scope(&mut self, stmts: &[AstNode]) -> ControlFlow {
+    fn eval_scope(&mut self, stmts: &[AstNode]) -> Result<ControlFlow, RuntimeError> {
         self.add_stack_frame();
         let y = self.eval_multiple(stmts);
         self.pop_stack_frame();
@@ -388,68 +1267,41 @@ impl Interpreter {
             panic!("Cannot pop global scope");
         }
         let _ = self.env_stack.pop();
-        let mut roots: Vec<GcRef> = vec![];
-
-        for env in self.env_stack.iter() {
-            roots.extend_from_slice(env.borrow().get_roots().as_slice());
-        }
-
-        self.gc.collect_garbage(roots.as_slice());
     }
+    #[allow(clippy::needless_return)]
     fn get_last_scope(&self) -> Rc<RefCell<Environment>> {
         return Rc::clone(self.env_stack.last().unwrap());
     }
-    fn get_variable(&self, name: &String) -> Value {
-        // println!(
-        //     "Got var ({name}): {:?}",
-        //     self.env_stack.last().unwrap().borrow().get_variable(name)
-        // );
-
-        return self.env_stack.last().unwrap().borrow().get_variable(name).unwrap_or(Value::Nil);
+    fn get_variable(&mut self, name: &String) -> Result<Value, RuntimeError> {
+        let lookup = self.env_stack.last().unwrap().borrow().lookup(name);
+        match lookup {
+            Lookup::Found(value) => Ok(value),
+            Lookup::Missing => Ok(Value::Nil),
+            // Inside a chunk loaded with its own environment
+            Lookup::Table(table) => {
+                let key = self.create_string(name)?;
+                self.temporaries.push(key);
+                self.index_value(Value::GcObject(table), key)
+            }
+        }
     }
 
     fn get_gc_value(&mut self, gc_ref: GcRef) -> Option<Rc<RefCell<Box<dyn GcValue>>>> {
         self.gc.get(gc_ref)
     }
 
-    fn eval_table_index(&mut self, index: &AstNode) -> Value {
+    fn eval_table_index(&mut self, index: &AstNode) -> Result<Value, RuntimeError> {
         if let AstNode::Index { base, index } = index {
-            let base = self.eval_table_index(&base);
-
-            let index = self.eval(&index).get_normal();
-            match base {
-                Value::GcObject(r) => {
-                    if let Some(t) = self.get_gc_value(r) {
-                        println!("Trying to index to {:?} with {:?}", base, index);
-                        if let Some(indexed) = t.borrow().index(index.clone()) {
-                            return indexed;
-                        }
-                    }
-                    return Value::Nil;
-                }
-                Value::String(s) => {
-                    if let Value::Number(n) = index {
-                        if n >= 0 && n < (s.len() as i64) {
-                            return Value::String(
-                                s
-                                    .chars()
-                                    .nth(n as usize)
-                                    .unwrap()
-                                    .to_string()
-                            );
-                        }
-                        panic!(
-                            "String can only be indexed with a positive Number and a Number less then len of string"
-                        );
-                    }
-                    panic!("String can only be indexed with Number");
-                }
-                _ => panic!("base should be Table or String got {:?}", base),
-            }
+            let base = self.eval_table_index(base)?;
+            self.temporaries.push(base);
+
+            let index = self.eval(index)?.get_normal();
+            trace!(self.sink, Category::Interpreter, "index {:?} with {:?}", base, index);
+            self.temporaries.push(index);
+            return self.index_value(base, index);
         }
         //panic!("Should not reach")
-        println!("as asd{:?}", index);
-        return self.eval(index).get_normal();
+        Ok(self.eval(index)?.get_normal())
     }
 
     fn set_variable(&mut self, is_local: bool, name: &String, value: Value) {
@@ -461,61 +1313,78 @@ impl Interpreter {
         env.borrow_mut().set_variable(name, value);
     }
 
-    fn eval_assignment(&mut self, is_local: bool, target: &AstNode, rhs: &AstNode) {
+    fn eval_assignment(
+        &mut self,
+        is_local: bool,
+        target: &AstNode,
+        rhs: &AstNode
+    ) -> Result<(), RuntimeError> {
+        let value = self.eval(rhs)?.get_normal();
+        self.temporaries.push(value);
+        self.assign(is_local, target, value)
+    }
+    fn assign(&mut self, is_local: bool, target: &AstNode, value: Value) -> Result<(), RuntimeError> {
         match target {
             AstNode::Variable(name) => {
-                let value = self.eval(rhs).get_normal();
-                self.set_variable(is_local, name, value);
-            }
-            AstNode::Index { base, index } => {
-                println!("Jello");
-                let base = self.eval_table_index(&base);
-                println!("Base: {:?}", base);
-                if let Value::GcObject(r) = base {
-                    println!("If gc obj");
-                    let value = self.eval(rhs).get_normal();
-                    let index = self.eval(index).get_normal();
-                    if let Some(t) = self.get_gc_value(r) {
-                        println!("If table obj");
-                        println!("Setting target {:?} with {:?},  to {:?}", target, base, &value);
-                        t.borrow_mut().set_index(index, value);
+                let globals = self.env_stack.last().unwrap().borrow().globals_table();
+                match globals {
+                    Some(table) if !is_local => {
+                        let key = self.create_string(name)?;
+                        self.temporaries.push(key);
+                        self.set_value(Value::GcObject(table), key, value)?;
                     }
+                    _ => self.set_variable(is_local, name, value),
                 }
             }
+            AstNode::Index { base, index } => {
+                let base = self.eval_table_index(base)?;
+                self.temporaries.push(base);
+                let index = self.eval(index)?.get_normal();
+                self.temporaries.push(index);
+                self.set_value(base, index, value)?;
+            }
             _ => panic!("Wrong target format, expected Index or Variable got {:?}", target),
         }
+        Ok(())
     }
-    fn eval_table(&mut self, e: &ParsedValue) -> Value {
+    fn eval_table(&mut self, e: &ParsedValue) -> Result<Value, RuntimeError> {
         let mut arr: Vec<Value> = vec![];
         let mut map: HashMap<Value, Value> = HashMap::new();
-        let mut refs: Vec<GcRef> = vec![];
         if let ParsedValue::Table { array, map: m } = e {
-            for element in array {
-                let element = self.eval(element).get_normal();
-                if let Value::GcObject(r) = element {
-                    refs.push(r);
-                }
-
-                arr.push(element);
-            }
+            // `{f()}` holds every result of the call
+            arr = self.eval_expressions(array)?;
             for (k, v) in m.iter() {
-                let k = self.eval(k).get_normal();
-                let v = self.eval(v).get_normal();
+                let k = self.eval(k)?.get_normal();
+                self.temporaries.push(k);
+                let v = self.eval(v)?.get_normal();
 
-                match k {
-                    Value::GcObject(_) => {
-                        continue;
-                    }
-
-                    _ => {}
-                }
-                if let Value::GcObject(r) = v {
-                    refs.push(r);
+                if let Value::GcObject(_) = k {
+                    continue;
                 }
+                self.temporaries.push(v);
                 map.insert(k, v);
             }
         }
         let table = Table::new(arr, map);
-        Value::GcObject(self.gc.allocate(Box::new(table)))
+        Ok(Value::GcObject(self.allocate(Box::new(table))?))
     }
 }
+
+/// A seed that differs between runs, used until a script or the embedder
+/// picks one
+pub(crate) fn time_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+// Converts the arguments and results of a typed host function
+pub(crate) fn host_function<'a, A: FromLuaMulti, R: IntoLuaMulti>(
+    mut f: impl FnMut(&mut Interpreter, A) -> R + MaybeSend + 'a
+) -> HostFunction<'a> {
+    Box::new(move |interpreter, args| {
+        let args = A::from_arguments(args, &interpreter.gc, "?")?;
+        f(interpreter, args).into_lua_multi(&mut interpreter.gc)
+    })
+}
@@ -1,26 +1,43 @@
-use std::io::{ Read, Write };
-
-use function_macro::interpreter_function;
-use gc::GarbageCollector;
-use value::Value;
-
-mod interpreter;
-mod gc;
-mod environment;
-mod value;
-mod types;
+pub mod interpreter;
+pub mod chunk;
+pub mod convert;
+pub mod gc;
+pub mod environment;
+pub mod value;
+pub mod types;
+pub mod userdata;
+pub mod scope;
+pub mod handle;
+pub mod snapshot;
+pub mod stdio;
+pub mod stdlib;
+#[cfg(feature = "serde")]
+pub mod serde;
+#[cfg(feature = "async")]
+mod async_host;
 
 #[cfg(test)]
 mod tests {
-    use std::cell::RefCell;
-    use std::rc::Rc;
+    use std::collections::HashMap;
+    use std::fs;
     use crate::tokenizer::Tokenizer;
 
     use crate::parser::{ AstNode, Parser };
+    use crate::sync::{ Rc, RefCell };
+    use crate::trace::{ Category, Event, EventSink };
 
     use super::*;
+    use crate::errors::RuntimeError;
+    use crate::{ interpreter_function, user_data };
+    use chunk::Chunk;
     use environment::Environment;
+    use gc::GarbageCollector;
     use interpreter::Interpreter;
+    use stdlib::Capabilities;
+    use types::Table;
+    use userdata::UserDataRef;
+    use handle::ScriptFunction;
+    use convert::{ FromLua, FromLuaMulti, IntoLua, IntoLuaMulti, Variadic };
     use value::Value;
     #[test]
     fn environment() {
@@ -52,39 +69,2035 @@ mod tests {
         println!("{:#?}", parsed);
 
         let mut interpreter = Interpreter::new();
-        interpreter.add_global_function("print", print);
-        interpreter.add_global_function("input", input);
+        interpreter.open_libs().unwrap();
+        let output = interpreter.capture_stdout();
+        interpreter.set_stdin(std::io::Cursor::new("Ada\n"));
 
         interpreter.print_vars();
         if let Ok(AstNode::Program(p)) = parsed {
             for stmt in p {
-                interpreter.eval(&stmt);
+                interpreter.eval(&stmt).unwrap();
             }
         }
+        assert_eq!(output.contents(), "Example program\nEnter your name: Hello\tAda!\n");
     }
-}
 
-fn print(gc: &mut GarbageCollector, args: &[Value]) -> Value {
-    let mut i = 0usize;
-    for el in args {
-        print!("{}", el.to_string(gc));
-        if args.len() - 1 != i {
-            print!(" ");
+    fn run(interpreter: &mut Interpreter, code: &str) -> Result<(), crate::errors::RuntimeError> {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.tokenize(code.to_string());
+        let mut parser = Parser::new(tokenizer.get_tokens().to_vec());
+        let parsed = parser.parse().unwrap();
+        interpreter.eval(&parsed).map(|_| ())
+    }
+
+    #[test]
+    fn memory_limit() {
+        let code =
+            r#"
+            t = {}
+            while true do
+                t:append("Some string that takes up space")
+            end
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.set_memory_limit(Some(16 * 1024));
+
+        let err = run(&mut interpreter, code).unwrap_err();
+        assert_eq!(err.get_message(), "not enough memory");
+        assert!(interpreter.memory_stats().collections > 0);
+
+        // A metamethod running out of memory isn't run again
+        let code =
+            r#"
+            calls = 0
+            function grow(a, b)
+                calls = calls + 1
+                local t = {}
+                while true do
+                    t:append("Some string that takes up space")
+                end
+            end
+            x = setmetatable({}, { __concat = grow })
+            y = x .. "a"
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        interpreter.set_memory_limit(Some(interpreter.memory_stats().bytes + 16 * 1024));
+        let err = run(&mut interpreter, code).unwrap_err();
+        assert!(err.get_message().contains("not enough memory"));
+        assert_eq!(interpreter.get_global("calls"), Value::Number(1));
+
+        // pcall gives the error to the script, which carries on once what
+        // the failed call allocated is collected
+        let code =
+            r#"
+            ok, message = pcall(grow)
+            after = "still" .. " " .. "running"
+        "#;
+        run(&mut interpreter, code).unwrap();
+        assert_eq!(interpreter.get_global("ok"), Value::Bool(false));
+        assert_eq!(interpreter.get_global_as::<String>("message").unwrap(), "not enough memory");
+        assert_eq!(interpreter.get_global_as::<String>("after").unwrap(), "still running");
+
+        let mut interpreter = Interpreter::new();
+        interpreter.set_memory_limit(Some(1024));
+        assert_eq!(interpreter.open_libs().unwrap_err().get_message(), "not enough memory");
+
+        // Repeating a string is refused before the bytes are built
+        let mut interpreter = Interpreter::new();
+        interpreter.set_memory_limit(Some(64 * 1024));
+        let codes = [
+            "s = \"x\" * 4611686018427387904",
+            "s = 4611686018427387904 * \"xy\"",
+            "s = \"x\" * 100000",
+        ];
+        for code in codes {
+            assert_eq!(run(&mut interpreter, code).unwrap_err().get_message(), "not enough memory");
         }
-        i += 1;
+        run(&mut interpreter, "s = \"ab\" * 3 e = \"ab\" * -1").unwrap();
+        assert_eq!(interpreter.get_global_as::<String>("s").unwrap(), "ababab");
+        assert_eq!(interpreter.get_global_as::<String>("e").unwrap(), "");
     }
-    println!();
-    let _ = std::io::stdout().flush();
-    Value::Nil
-}
 
-#[interpreter_function]
-fn input(msg: String) -> String {
-    print!("{msg}");
-    let _ = std::io::stdout().flush();
+    #[test]
+    fn collect_garbage() {
+        let code =
+            r#"
+            t = {1, 2, {3}}
+            kept = {4}
+            t = nil
+            collectgarbage()
+            count = collectgarbage("count")
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        // Library tables are there from the start
+        let tables = interpreter.memory_stats().by_type["table"].objects;
+        run(&mut interpreter, code).unwrap();
+
+        let stats = interpreter.memory_stats();
+        assert_eq!(stats.collections, 1);
+        assert_eq!(stats.by_type["table"].objects, tables + 1);
+        assert_eq!(stats.objects, stats.by_type.values().map(|t| t.objects).sum::<usize>());
+        assert!(stats.bytes > 0);
+    }
+
+    #[test]
+    fn interned_strings() {
+        let code =
+            r#"
+            t = {}
+            t["key" .. 1] = "value"
+            found = t.key1
+            same = "ab" == "a" .. "b"
+        "#;
+        let mut interpreter = Interpreter::new();
+        run(&mut interpreter, code).unwrap();
+        assert_eq!(interpreter.get_global("found").to_string(&interpreter.gc), "value");
+        assert_eq!(interpreter.get_global("same"), Value::Bool(true));
+
+        let mut gc = GarbageCollector::new();
+        let a = gc.create_string("hello").unwrap();
+        let b = gc.create_string(&"hello world"[..5]).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(gc.memory_stats().by_type["string"].objects, 1);
+
+        // Unreachable strings are dropped from the intern table
+        gc.collect_garbage(&[]);
+        assert_eq!(gc.memory_stats().objects, 0);
+        let c = gc.create_string("hello").unwrap();
+        assert_eq!(c.to_string(&gc), "hello");
+    }
+
+    #[test]
+    fn multiple_returns() {
+        let code =
+            r#"
+            function pair()
+                return 1, 2
+            end
+            function nothing()
+                return
+            end
+            a, b = pair()
+            local c, d, e = pair()
+            first = pair()
+            t = {0, pair()}
+            last = t[3]
+            missing = nothing()
+        "#;
+        let mut interpreter = Interpreter::new();
+        run(&mut interpreter, code).unwrap();
+        let number = |n| Value::Number(n);
+        assert_eq!(interpreter.get_global("a"), number(1));
+        assert_eq!(interpreter.get_global("b"), number(2));
+        assert_eq!(interpreter.get_global("d"), number(2));
+        assert_eq!(interpreter.get_global("e"), Value::Nil);
+        assert_eq!(interpreter.get_global("first"), number(1));
+        assert_eq!(interpreter.get_global("last"), number(2));
+        assert_eq!(interpreter.get_global("missing"), Value::Nil);
+    }
+
+    #[test]
+    fn string_library() {
+        let code =
+            r#"
+            s = "Hello World"
+            upper = s:upper()
+            lower = string.lower(s)
+            sub = s:sub(1, 5)
+            tail = s:sub(-5)
+            rep = string.rep("ab", 3, "-")
+            rev = "abc":reverse()
+            b1, b2 = string.byte("AB", 1, 2)
+            chr = string.char(72, 105)
+            len = s:len()
+            start, finish = s:find("World")
+            plain = s:find(".", 1, true)
+            key, value = string.match("name=lua", "(%w+)=(%w+)")
+            date = string.match("on 2024-01-15", "%d+-%d+-%d+")
+            anchored = string.match("hello", "^h(.)")
+            balanced = string.match("f(a(b)c) d", "%b()")
+            paren = string.find("a)b", "%)")
+            frontier = string.gsub("THE (quick) fox", "%f[%a]%a+", "W")
+            replaced, count = string.gsub("hello world", "o", "0")
+            swapped = string.gsub("hello world", "(%w+) (%w+)", "%2 %1")
+            words = ""
+            for w in string.gmatch("one two three", "%a+") do
+                words = words .. w .. ","
+            end
+            function shout(w)
+                return w:upper()
+            end
+            shouted = string.gsub("a b", "%a", shout)
+            looked_up = string.gsub("$x and $y", "%$(%w+)", {x = "1", y = "2"})
+            f1 = string.format("%d items at %.2f", 3, 1.5)
+            f2 = string.format("%5s|%-5s|%05d|%+d", "ab", "cd", 42, 7)
+            f3 = string.format("%x %X %#o %c", 255, 255, 8, 65)
+            f4 = string.format("%g %g %g %e", 100000, 1000000, 0.0001, 1234.5)
+            f5 = string.format("%q %q %5.1s|", 1.5, "a\b", "xyz")
+            function show(p)
+                return "point"
+            end
+            point = setmetatable({}, {__tostring = show})
+            f6 = string.format("%s %6s %s", point, true, nil)
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        run(&mut interpreter, code).unwrap();
+
+        let get = |name: &str| interpreter.get_global(name).to_string(&interpreter.gc);
+        assert_eq!(get("upper"), "HELLO WORLD");
+        assert_eq!(get("lower"), "hello world");
+        assert_eq!(get("sub"), "Hello");
+        assert_eq!(get("tail"), "World");
+        assert_eq!(get("rep"), "ab-ab-ab");
+        assert_eq!(get("rev"), "cba");
+        assert_eq!((get("b1"), get("b2")), ("65".to_string(), "66".to_string()));
+        assert_eq!(get("chr"), "Hi");
+        assert_eq!(get("len"), "11");
+        assert_eq!((get("start"), get("finish")), ("7".to_string(), "11".to_string()));
+        assert_eq!(interpreter.get_global("plain"), Value::Nil);
+        assert_eq!((get("key"), get("value")), ("name".to_string(), "lua".to_string()));
+        assert_eq!(get("date"), "2024-01-15");
+        assert_eq!(get("anchored"), "e");
+        assert_eq!(get("balanced"), "(a(b)c)");
+        assert_eq!(get("paren"), "2");
+        assert_eq!(get("frontier"), "W (W) W");
+        assert_eq!((get("replaced"), get("count")), ("hell0 w0rld".to_string(), "2".to_string()));
+        assert_eq!(get("swapped"), "world hello");
+        assert_eq!(get("words"), "one,two,three,");
+        assert_eq!(get("shouted"), "A B");
+        assert_eq!(get("looked_up"), "1 and 2");
+        assert_eq!(get("f1"), "3 items at 1.50");
+        assert_eq!(get("f2"), "   ab|cd   |00042|+7");
+        assert_eq!(get("f3"), "ff FF 010 A");
+        assert_eq!(get("f4"), "100000 1e+06 0.0001 1.234500e+03");
+        assert_eq!(get("f5"), r#"0x1.8p+0 "a\\b"     x|"#);
+        assert_eq!(get("f6"), "point   true nil");
+    }
+
+    #[test]
+    fn string_library_errors() {
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+
+        assert_eq!(
+            error("x = string.rep()"),
+            "bad argument #1 to 'rep' (string expected, got no value)"
+        );
+        assert_eq!(
+            error("x = string.sub(\"x\", {})"),
+            "bad argument #2 to 'sub' (number expected, got table)"
+        );
+        assert_eq!(
+            error("x = string.format(\"%d\", 1.5)"),
+            "bad argument #2 to 'format' (number has no integer representation)"
+        );
+        assert_eq!(error("x = string.find(\"x\", \"[a\")"), "malformed pattern (missing ']')");
+        assert_eq!(error("x = string.gsub(\"x\", \"x\", \"%2\")"), "invalid capture index %2");
+        // Malformed patterns are errors even where matching wouldn't get to them
+        let patterns = [
+            (r#"x = string.find("x", ")")"#, "invalid pattern capture"),
+            (r#"x = string.match("abc", "z)")"#, "invalid pattern capture"),
+            (r#"x = string.find("abc", "z[a")"#, "malformed pattern (missing ']')"),
+            (r#"x = string.match("abc", "z%")"#, "malformed pattern (ends with '%')"),
+            (r#"x = string.match("abc", "z%b(")"#, "malformed pattern (missing arguments to '%b')"),
+            (r#"x = string.gsub("abc", "z%1", "")"#, "invalid capture index %1"),
+            (r#"x = string.gmatch("abc", "(z")"#, "unfinished capture"),
+        ];
+        for (code, message) in patterns {
+            assert_eq!(error(code), message, "{code}");
+        }
+    }
+
+    #[test]
+    fn base_library() {
+        let code =
+            r##"
+            kinds = type(nil) .. type(1) .. type("") .. type({}) .. type(print)
+            function show(p)
+                return "point"
+            end
+            point = setmetatable({}, {__tostring = show})
+            named = setmetatable({}, {__name = "Point"})
+            strings = tostring(nil) .. tostring(true) .. tostring(3) .. tostring(1.5) .. tostring(1e16) .. tostring(point)
+            float = tostring(10 / 2)
+            name = tostring(named)
+            n1 = tonumber(" 0x10 ")
+            n2 = tonumber("1e2")
+            n3 = tonumber("ff", 16)
+            n4 = tonumber("-zz", 36)
+            n5 = tonumber("12a")
+            n6 = tonumber({})
+            n7 = tonumber("0x1p4")
+            n8 = tonumber("inf")
+            count = select("#", 1, nil, 3)
+            s1, s2 = select(2, "a", "b", "c")
+            last = select(-1, "a", "b", "c")
+            a1, a2 = assert(1, "unused")
+            ok1, r1 = pcall(select, 2, "a", "b")
+            ok2, r2 = pcall(error, "boom")
+            ok3, r3 = pcall(rawlen, 5)
+            ok4, r4 = pcall(error, {})
+            proxied = setmetatable({}, {__index = show, __newindex = show})
+            proxied.x = 1
+            rawset(proxied, "y", 2)
+            raw = rawget(proxied, "y")
+            hidden = rawget(proxied, "x")
+            same = rawequal(proxied, proxied)
+            different = rawequal(proxied, {})
+            lengths = rawlen({1, 2, 3}) + rawlen("ab")
+            entries = 0
+            t = {10, 20, a = 1, b = 2}
+            k, v = next(t)
+            while k do
+                entries = entries + v
+                k, v = next(t, k)
+            end
+            empty = next({})
+            x, y = unpack({1, 2})
+            _G.created = "yes"
+            global = _G.created
+            listed = _G.kinds
+            _G[1] = "raw"
+            first = _G[1]
+        "##;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        run(&mut interpreter, code).unwrap();
+
+        let get = |name: &str| interpreter.get_global(name).to_string(&interpreter.gc);
+        let number = |n: i64| Value::Number(n);
+        assert_eq!(get("kinds"), "nilnumberstringtablefunction");
+        assert_eq!(get("strings"), "niltrue31.51e+16point");
+        assert_eq!(get("float"), "5.0");
+        assert!(get("name").starts_with("Point: 0x"), "{}", get("name"));
+        assert_eq!(interpreter.get_global("n1"), number(16));
+        assert_eq!(interpreter.get_global("n2"), Value::Float(100.0));
+        assert_eq!(interpreter.get_global("n3"), number(255));
+        assert_eq!(interpreter.get_global("n4"), number(-1295));
+        assert_eq!(interpreter.get_global("n5"), Value::Nil);
+        assert_eq!(interpreter.get_global("n6"), Value::Nil);
+        assert_eq!(interpreter.get_global("n7"), Value::Float(16.0));
+        assert_eq!(interpreter.get_global("n8"), Value::Nil);
+        assert_eq!(interpreter.get_global("count"), number(3));
+        assert_eq!(get("s1") + &get("s2"), "bc");
+        assert_eq!(get("last"), "c");
+        assert_eq!((get("a1"), get("a2")), ("1".to_string(), "unused".to_string()));
+        assert_eq!((get("ok1"), get("r1")), ("true".to_string(), "b".to_string()));
+        assert_eq!((get("ok2"), get("r2")), ("false".to_string(), "boom".to_string()));
+        assert_eq!(get("r3"), "bad argument #1 to 'rawlen' (table or string expected)");
+        assert_eq!(get("r4"), "(error object is a table value)");
+        assert_eq!(interpreter.get_global("raw"), number(2));
+        assert_eq!(interpreter.get_global("hidden"), Value::Nil);
+        assert_eq!(interpreter.get_global("same"), Value::Bool(true));
+        assert_eq!(interpreter.get_global("different"), Value::Bool(false));
+        assert_eq!(interpreter.get_global("lengths"), number(5));
+        assert_eq!(interpreter.get_global("entries"), number(33));
+        assert_eq!(interpreter.get_global("empty"), Value::Nil);
+        assert_eq!((interpreter.get_global("x"), interpreter.get_global("y")), (number(1), number(2)));
+        assert_eq!(get("created"), "yes");
+        assert_eq!(get("global"), "yes");
+        assert_eq!(get("listed"), "nilnumberstringtablefunction");
+        assert_eq!(get("first"), "raw");
+        assert_eq!(get("_VERSION"), "Lua 5.4");
+
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+        assert_eq!(error("x = type()"), "bad argument #1 to 'type' (value expected)");
+        assert_eq!(error("x = tonumber(10, 16)"), "bad argument #1 to 'tonumber' (string expected, got number)");
+        assert_eq!(error("x = tonumber(\"10\", 99)"), "bad argument #2 to 'tonumber' (base out of range)");
+        assert_eq!(error("x = select(-5, 1)"), "bad argument #1 to 'select' (index out of range)");
+        assert_eq!(error("assert(false)"), "assertion failed!");
+        assert_eq!(error("assert(nil, \"broken\")"), "broken");
+        assert_eq!(error("error(\"raised\")"), "raised");
+        assert_eq!(error("x = pcall()"), "bad argument #1 to 'pcall' (value expected)");
+        assert_eq!(error("x = rawget(1, 2)"), "bad argument #1 to 'rawget' (table expected, got number)");
+        assert_eq!(error("rawset({}, nil, 1)"), "index is nil");
+        assert_eq!(error("x = rawlen(5)"), "bad argument #1 to 'rawlen' (table or string expected)");
+        assert_eq!(error("x = rawequal(1)"), "bad argument #2 to 'rawequal' (value expected)");
+        assert_eq!(error("x = next({}, \"missing\")"), "invalid key to 'next'");
+        assert_eq!(
+            error("x = tostring(setmetatable({}, {__tostring = rawlen}))"),
+            "'__tostring' must return a string"
+        );
+    }
+
+    #[test]
+    fn utf8_library() {
+        let code =
+            r#"
+            s = "héllo"
+            bytes = #s
+            chars = utf8.len(s)
+            first_half = string.sub(s, 2, 2)
+            rejoined = first_half .. string.sub(s, 3, 3) == "é"
+            reversed = string.reverse(string.reverse(s)) == s
+            upper = string.upper(s)
+            width = string.format("[%6s]", "é")
+            indexed = s[1]
+            encoded = utf8.char(72, 233, 8364, 128512)
+            encoded_len = #encoded
+            h, e = utf8.codepoint(s, 1, 2)
+            third = utf8.offset(s, 3)
+            last = utf8.offset(s, -1)
+            containing = utf8.offset(s, 0, 3)
+            beyond = utf8.offset(s, 10)
+            invalid, at = utf8.len("a" .. string.char(255) .. "b")
+            surrogate = utf8.char(55296)
+            strict = utf8.len(surrogate)
+            lax = utf8.len(surrogate, 1, -1, true)
+            positions = ""
+            for p in utf8.codes(s) do
+                positions = positions .. p .. ","
+            end
+            next_code = utf8.codes(s)
+            p1, c1 = next_code()
+            p2, c2 = next_code()
+            matched = 0
+            for c in string.gmatch(encoded, utf8.charpattern) do
+                matched = matched + 1
+            end
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        run(&mut interpreter, code).unwrap();
+
+        let get = |name: &str| interpreter.get_global(name).to_string(&interpreter.gc);
+        let number = |n: i64| Value::Number(n);
+        assert_eq!(interpreter.get_global("bytes"), number(6));
+        assert_eq!(interpreter.get_global("chars"), number(5));
+        let half = interpreter.get_global("first_half").gc_ref().unwrap();
+        assert_eq!(&*interpreter.gc.get_bytes(half).unwrap(), &[0xc3]);
+        assert_eq!(interpreter.get_global("rejoined"), Value::Bool(true));
+        assert_eq!(interpreter.get_global("reversed"), Value::Bool(true));
+        assert_eq!(get("upper"), "HéLLO");
+        assert_eq!(get("width"), "[    é]");
+        assert_eq!(interpreter.get_global("indexed"), Value::Nil);
+        assert_eq!(get("encoded"), "Hé€😀");
+        assert_eq!(interpreter.get_global("encoded_len"), number(10));
+        assert_eq!((interpreter.get_global("h"), interpreter.get_global("e")), (number(104), number(233)));
+        assert_eq!(interpreter.get_global("third"), number(4));
+        assert_eq!(interpreter.get_global("last"), number(6));
+        assert_eq!(interpreter.get_global("containing"), number(2));
+        assert_eq!(interpreter.get_global("beyond"), Value::Nil);
+        assert_eq!((interpreter.get_global("invalid"), interpreter.get_global("at")), (Value::Nil, number(2)));
+        assert_eq!(interpreter.get_global("strict"), Value::Nil);
+        assert_eq!(interpreter.get_global("lax"), number(1));
+        assert_eq!(get("positions"), "1,2,4,5,6,");
+        assert_eq!((interpreter.get_global("p1"), interpreter.get_global("c1")), (number(1), number(104)));
+        assert_eq!((interpreter.get_global("p2"), interpreter.get_global("c2")), (number(2), number(233)));
+        assert_eq!(interpreter.get_global("matched"), number(4));
+
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+        assert_eq!(error("x = utf8.codepoint(string.char(255))"), "invalid UTF-8 code");
+        assert_eq!(error("x = utf8.codepoint(s, 0)"), "bad argument #2 to 'codepoint' (out of bounds)");
+        assert_eq!(error("x = utf8.char(-1)"), "bad argument #1 to 'char' (value out of range)");
+        assert_eq!(error("x = utf8.offset(s, 1, 3)"), "initial position is a continuation byte");
+        assert_eq!(
+            error("for p in utf8.codes(\"a\" .. string.char(255)) do end"),
+            "invalid UTF-8 code"
+        );
+    }
+
+    #[test]
+    fn table_library() {
+        let code =
+            r#"
+            t = {10, 20, 30}
+            table.insert(t, 40)
+            table.insert(t, 1, 5)
+            inserted = table.concat(t, ",")
+            removed = table.remove(t, 2)
+            last = table.remove(t)
+            after_remove = table.concat(t, ",")
+            range = table.concat({1, 2, 3, 4}, "-", 2, 3)
+            numbers = {5, 2, 8, 1, 9, 3}
+            table.sort(numbers)
+            ascending = table.concat(numbers, " ")
+            function greater(a, b)
+                return a > b
+            end
+            table.sort(numbers, greater)
+            descending = table.concat(numbers, " ")
+            words = {"pear", "apple", "fig"}
+            table.sort(words)
+            sorted_words = table.concat(words, " ")
+            a, b, c = table.unpack({1, 2, 3})
+            packed = table.pack(1, nil, 3)
+            n = packed.n
+            moved = table.move({1, 2, 3}, 1, 3, 2)
+            shifted = table.concat(moved, ",")
+            copy = table.concat(table.move({1, 2}, 1, 2, 1, {}), ",")
+            length = #t
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        run(&mut interpreter, code).unwrap();
+
+        let get = |name: &str| interpreter.get_global(name).to_string(&interpreter.gc);
+        assert_eq!(get("inserted"), "5,10,20,30,40");
+        assert_eq!((get("removed"), get("last")), ("10".to_string(), "40".to_string()));
+        assert_eq!(get("after_remove"), "5,20,30");
+        assert_eq!(get("range"), "2-3");
+        assert_eq!(get("ascending"), "1 2 3 5 8 9");
+        assert_eq!(get("descending"), "9 8 5 3 2 1");
+        assert_eq!(get("sorted_words"), "apple fig pear");
+        assert_eq!((get("a"), get("b"), get("c")), ("1".into(), "2".into(), "3".into()));
+        assert_eq!(get("n"), "3");
+        assert_eq!(get("shifted"), "1,1,2,3");
+        assert_eq!(get("copy"), "1,2");
+        assert_eq!(get("length"), "3");
+    }
+
+    #[test]
+    fn metatables() {
+        let code =
+            r#"
+            function exclaim(t, k)
+                return k .. "!"
+            end
+            defaults = setmetatable({}, {__index = exclaim})
+            fallback = defaults.hi
+            base = {greeting = "hello"}
+            derived = setmetatable({}, {__index = base})
+            inherited = derived.greeting
+            log = {}
+            function record(t, k, v)
+                table.insert(log, k)
+            end
+            proxy = setmetatable({}, {__newindex = record})
+            proxy.x = 1
+            proxy.y = 2
+            logged = table.concat(log, ",")
+            raw = proxy.x
+            function four(t)
+                return 4
+            end
+            sized = setmetatable({}, {__len = four})
+            size = #sized
+            function version_less(a, b)
+                return a.v < b.v
+            end
+            versions = {__lt = version_less}
+            list = {}
+            for v in {3, 1, 2} do
+                table.insert(list, setmetatable({v = v}, versions))
+            end
+            table.sort(list)
+            order = list[1].v .. list[2].v .. list[3].v
+            mt = getmetatable(derived).__index == base
+            string_mt = getmetatable("").__index == string
+            function add_v(a, b)
+                return {v = a.v + b.v}
+            end
+            total = setmetatable({v = 1}, {__add = add_v}) + {v = 2}
+            sum = total.v
+            function double(t, x)
+                return x * 2
+            end
+            callable = setmetatable({}, {__call = double})
+            doubled = callable(21)
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        run(&mut interpreter, code).unwrap();
+
+        let get = |name: &str| interpreter.get_global(name).to_string(&interpreter.gc);
+        assert_eq!(get("fallback"), "hi!");
+        assert_eq!(get("inherited"), "hello");
+        assert_eq!(get("logged"), "x,y");
+        assert_eq!(interpreter.get_global("raw"), Value::Nil);
+        assert_eq!(get("size"), "4");
+        assert_eq!(get("order"), "123");
+        assert_eq!(interpreter.get_global("mt"), Value::Bool(true));
+        assert_eq!(interpreter.get_global("string_mt"), Value::Bool(true));
+        assert_eq!(interpreter.get_global("sum"), Value::Number(3));
+        assert_eq!(interpreter.get_global("doubled"), Value::Number(42));
+    }
+
+    #[test]
+    fn table_library_errors() {
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+
+        assert_eq!(
+            error("table.insert(1, 2)"),
+            "bad argument #1 to 'insert' (table expected, got number)"
+        );
+        assert_eq!(
+            error("table.insert({}, 5, 1)"),
+            "bad argument #2 to 'insert' (position out of bounds)"
+        );
+        assert_eq!(error("table.insert({}, 1, 2, 3)"), "wrong number of arguments to 'insert'");
+        assert_eq!(
+            error("x = table.remove({1}, 5)"),
+            "bad argument #2 to 'remove' (position out of bounds)"
+        );
+        assert_eq!(
+            error("x = table.concat({1, {}})"),
+            "invalid value (at index 2) in table for 'concat' (table found)"
+        );
+        assert_eq!(
+            error("table.sort({1, \"x\"})"),
+            "attempt to compare string with number"
+        );
+        assert_eq!(
+            error("function always(a, b)\n return true\n end\n table.sort({1, 2, 3, 4, 5}, always)"),
+            "invalid order function for sorting"
+        );
+        assert_eq!(
+            error("table.sort({3, 2}, 1)"),
+            "bad argument #2 to 'sort' (function expected, got number)"
+        );
+        assert_eq!(error("x = {}\n x:missing()"), "attempt to call a nil value (method 'missing')");
+        assert_eq!(
+            error("x = setmetatable({}, {__metatable = 1})\n setmetatable(x, {})"),
+            "cannot change a protected metatable"
+        );
+    }
+
+    #[test]
+    fn math_library() {
+        let code =
+            r#"
+            abs = math.abs(-3)
+            floor = math.floor(2.5)
+            ceil = math.ceil(2.5)
+            huge_floor = math.floor(math.huge)
+            fmod = math.fmod(-7, 3)
+            ip, fp = math.modf(3.75)
+            sqrt = math.sqrt(16)
+            log2 = math.log(8, 2)
+            log10 = math.log(1000, 10)
+            max = math.max(1, 7.5, 3)
+            min = math.min(4, -2, 9)
+            ult = math.ult(1, -1)
+            int = math.tointeger(3.0)
+            not_int = math.tointeger(3.5)
+            from_string = math.tointeger("8")
+            t1 = math.type(1)
+            t2 = math.type(1.0)
+            t3 = math.type("1")
+            wraps = math.maxinteger + 1 == math.mininteger
+            atan = math.atan(1, 1) * 4 == math.pi
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        run(&mut interpreter, code).unwrap();
+
+        let get = |name: &str| interpreter.get_global(name);
+        assert_eq!(get("abs"), Value::Number(3));
+        assert_eq!(get("floor"), Value::Number(2));
+        assert_eq!(get("ceil"), Value::Number(3));
+        assert_eq!(get("huge_floor"), Value::Float(f64::INFINITY));
+        assert_eq!(get("fmod"), Value::Number(-1));
+        assert_eq!((get("ip"), get("fp")), (Value::Float(3.0), Value::Float(0.75)));
+        assert_eq!(get("sqrt"), Value::Float(4.0));
+        assert_eq!(get("log2"), Value::Float(3.0));
+        assert_eq!(get("log10"), Value::Float(3.0));
+        assert_eq!(get("max"), Value::Float(7.5));
+        assert_eq!(get("min"), Value::Number(-2));
+        assert_eq!(get("ult"), Value::Bool(true));
+        assert_eq!(get("int"), Value::Number(3));
+        assert_eq!(get("not_int"), Value::Nil);
+        assert_eq!(get("from_string"), Value::Nil);
+        let name = |name: &str| get(name).to_string(&interpreter.gc);
+        assert_eq!((name("t1"), name("t2")), ("integer".to_string(), "float".to_string()));
+        assert_eq!(get("t3"), Value::Nil);
+        assert_eq!(get("atan"), Value::Bool(true));
+    }
+
+    #[test]
+    fn math_random() {
+        let code =
+            r#"
+            math.randomseed(42)
+            rolls = {}
+            for i in 0, 100 do
+                table.insert(rolls, math.random(6))
+            end
+            sequence = table.concat(rolls, ",")
+            in_range = true
+            for i in 0, 100 do
+                x = math.random(-3, 3)
+                f = math.random()
+                if x < -3 or x > 3 or f < 0 or f >= 1 then
+                    in_range = false
+                end
+            end
+        "#;
+        let sequence = |seed: Option<u64>| {
+            let mut interpreter = Interpreter::new();
+            interpreter.open_libs().unwrap();
+            run(&mut interpreter, code).unwrap();
+            assert_eq!(interpreter.get_global("in_range"), Value::Bool(true));
+            if let Some(seed) = seed {
+                interpreter.set_random_seed(seed);
+                run(&mut interpreter, "sequence = math.random(1000000)").unwrap();
+            }
+            interpreter.get_global("sequence").to_string(&interpreter.gc)
+        };
+        assert_eq!(sequence(None), sequence(None));
+        assert_eq!(sequence(Some(7)), sequence(Some(7)));
+
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+        assert_eq!(error("x = math.random(3, 1)"), "bad argument #1 to 'random' (interval is empty)");
+        assert_eq!(error("x = math.random(1, 2, 3)"), "wrong number of arguments");
+        assert_eq!(error("x = math.fmod(1, 0)"), "bad argument #2 to 'fmod' (zero)");
+        assert_eq!(
+            error("x = math.floor(\"a\")"),
+            "bad argument #1 to 'floor' (number expected, got string)"
+        );
+    }
+
+    #[test]
+    fn os_and_io_need_capabilities() {
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        let error = |interpreter: &mut Interpreter, code: &str| {
+            run(interpreter, code).unwrap_err().get_message()
+        };
+        assert_eq!(
+            error(&mut interpreter, "x = os.time()"),
+            "'os.time' is not allowed without the TIME capability"
+        );
+        assert_eq!(
+            error(&mut interpreter, "x = os.getenv(\"HOME\")"),
+            "'os.getenv' is not allowed without the ENV capability"
+        );
+        assert_eq!(
+            error(&mut interpreter, "x = io.open(\"file\")"),
+            "'io.open' is not allowed without the READ_FILES capability"
+        );
+        assert_eq!(
+            error(&mut interpreter, "x = os.remove(\"file\")"),
+            "'os.remove' is not allowed without the WRITE_FILES capability"
+        );
+
+        interpreter.set_capabilities(Capabilities::READ_FILES);
+        assert_eq!(
+            error(&mut interpreter, "x = io.open(\"file\", \"w\")"),
+            "'io.open' is not allowed without the WRITE_FILES capability"
+        );
+        interpreter.set_capabilities(Capabilities::TIME | Capabilities::ENV);
+        let code = "x = os.time()\n y = os.clock()\n z = os.getenv(\"PATH\")";
+        assert!(run(&mut interpreter, code).is_ok());
+    }
+
+    #[test]
+    fn os_library() {
+        let code =
+            r#"
+            epoch = os.date("!%Y-%m-%d %H:%M:%S", 0)
+            formatted = os.date("%a %b %e %j %p %%", 951782400)
+            y2k = os.time({year = 2000, month = 1, day = 1, hour = 0})
+            carried = os.time({year = 1999, month = 13, day = 1, hour = 0})
+            t = os.date("*t", 951782400)
+            parts = t.year .. "/" .. t.month .. "/" .. t.day .. " " .. t.wday .. " " .. t.yday
+            path = os.tmpname()
+            removed = os.remove(path)
+            missing, message = os.remove(path)
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        interpreter.set_capabilities(Capabilities::ALL);
+        run(&mut interpreter, code).unwrap();
+
+        let get = |name: &str| interpreter.get_global(name).to_string(&interpreter.gc);
+        assert_eq!(get("epoch"), "1970-01-01 00:00:00");
+        assert_eq!(get("formatted"), "Tue Feb 29 060 AM %");
+        assert_eq!(get("y2k"), "946684800");
+        assert_eq!(get("carried"), "946684800");
+        assert_eq!(get("parts"), "2000/2/29 3 60");
+        assert_eq!(interpreter.get_global("removed"), Value::Bool(true));
+        assert_eq!(interpreter.get_global("missing"), Value::Nil);
+        assert!(get("message").ends_with("No such file or directory"));
+        assert_eq!(
+            run(&mut interpreter, "x = os.date(\"%Q\")").unwrap_err().get_message(),
+            "bad argument #1 to 'date' (invalid conversion specifier '%Q')"
+        );
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+        let field = "os.time({year = math.maxinteger, month = 1, day = 1})";
+        assert_eq!(error(field), "field 'year' is out-of-bound");
+        let field = "os.time({year = 2000, month = math.mininteger, day = 1})";
+        assert_eq!(error(field), "field 'month' is out-of-bound");
+        for name in ["day", "hour", "min", "sec"] {
+            let code = format!("os.time({{year = 2000, month = 1, day = 1, {name} = math.maxinteger}})");
+            assert_eq!(error(&code), format!("field '{name}' is out-of-bound"));
+        }
+        let code = "t = os.time({year = -2147481648, month = -2147483647, day = -2147483648, \
+            hour = -2147483648, min = -2147483648, sec = -2147483648})";
+        run(&mut interpreter, code).unwrap();
+        assert_eq!(interpreter.get_global("t"), Value::Number(-73608778512393728));
+    }
+
+    #[test]
+    fn io_library() {
+        let code =
+            r#"
+            nl = string.char(10)
+            path = os.tmpname()
+            f = io.open(path, "w")
+            kind = io.type(f)
+            f:write("first", nl, 42, " 3.5", nl, "last")
+            f:close()
+            closed = io.type(f)
+
+            f = io.open(path)
+            line = f:read()
+            n, x = f:read("n", "n")
+            rest = f:read("a")
+            at_end = f:read("l")
+            size = f:seek("end")
+            f:seek("set", 2)
+            bytes = f:read(3)
+            f:close()
+
+            lines = ""
+            for l in io.lines(path) do
+                lines = lines .. "[" .. l .. "]"
+            end
+            f = io.open(path, "a+")
+            f:write(nl, "appended")
+            f:seek("set")
+            count = 0
+            for l in f:lines("L") do
+                count = count + 1
+            end
+            f:close()
+            os.remove(path)
+            nothing, message = io.open(path)
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        interpreter.set_capabilities(Capabilities::ALL);
+        run(&mut interpreter, code).unwrap();
+
+        let get = |name: &str| interpreter.get_global(name).to_string(&interpreter.gc);
+        assert_eq!((get("kind"), get("closed")), ("file".to_string(), "closed file".to_string()));
+        assert_eq!(get("line"), "first");
+        assert_eq!(interpreter.get_global("n"), Value::Number(42));
+        assert_eq!(interpreter.get_global("x"), Value::Float(3.5));
+        assert_eq!(get("rest"), "\nlast");
+        assert_eq!(interpreter.get_global("at_end"), Value::Nil);
+        assert_eq!(get("size"), "17");
+        assert_eq!(get("bytes"), "rst");
+        assert_eq!(get("lines"), "[first][42 3.5][last]");
+        assert_eq!(get("count"), "4");
+        assert_eq!(interpreter.get_global("nothing"), Value::Nil);
+        assert!(get("message").ends_with(": No such file or directory"));
+        assert_eq!(
+            run(&mut interpreter, "f:read()").unwrap_err().get_message(),
+            "attempt to use a closed file"
+        );
+        assert_eq!(
+            run(&mut interpreter, "x = io.open(path, \"rw\")").unwrap_err().get_message(),
+            "bad argument #2 to 'open' (invalid mode)"
+        );
+    }
+
+    #[test]
+    fn standard_streams() {
+        let code =
+            r#"
+            print("a", 1, true)
+            same = io.write("b", 2) == io.stdout
+            io.stdout:write("c")
+            io.stderr:write("oops")
+            name = input("name? ")
+            n, rest = io.read("n", "l")
+            lines = ""
+            for l in io.lines() do
+                lines = lines .. "[" .. l .. "]"
+            end
+            done = io.read()
+            closed, message = io.stdout:close()
+            stdin_type = io.type(io.stdin)
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        let output = interpreter.capture_stdout();
+        let errors = interpreter.capture_stderr();
+        let mut chunks = vec!["Ada\n12 ", "apples\nx", "\ny\n"].into_iter();
+        interpreter.set_stdin(stdio::reader_fn(move || chunks.next().map(String::from)));
+        run(&mut interpreter, code).unwrap();
+
+        let get = |name: &str| interpreter.get_global(name).to_string(&interpreter.gc);
+        assert_eq!(output.take(), "a\t1\ttrue\nb2cname? ");
+        assert_eq!(errors.contents(), "oops");
+        assert_eq!(interpreter.get_global("same"), Value::Bool(true));
+        assert_eq!(get("name"), "Ada");
+        assert_eq!(interpreter.get_global("n"), Value::Number(12));
+        assert_eq!(get("rest"), " apples");
+        assert_eq!(get("lines"), "[x][y]");
+        assert_eq!(interpreter.get_global("done"), Value::Nil);
+        assert_eq!(interpreter.get_global("closed"), Value::Nil);
+        assert_eq!(get("message"), "cannot close standard file");
+        assert_eq!(get("stdin_type"), "file");
+
+        // Redirecting again affects the existing handles
+        let mut written = String::new();
+        interpreter.set_stdout(stdio::writer_fn(move |text| written.push_str(text)));
+        run(&mut interpreter, "io.write(\"gone\")").unwrap();
+        assert_eq!(output.contents(), "");
+
+        let mut error = |code| run(&mut interpreter, code).unwrap_err().get_message();
+        assert_eq!(error("for x in io.stdout do end"), "attempt to iterate a userdata value");
+        assert_eq!(error("for x in 5 do end"), "attempt to iterate a number value");
+        assert_eq!(error("io.stdout.read()"), "bad argument #1 to 'read' (FILE* expected, got no value)");
+        assert_eq!(error("io.stdout.read(1)"), "bad argument #1 to 'read' (FILE* expected, got number)");
+    }
+
+    #[test]
+    fn json_library() {
+        let code =
+            r#"
+            doc = json.decode(input())
+            name = doc.name
+            tag_count = #doc.tags
+            first_tag = doc.tags[1]
+            is_null = doc.missing == json.null
+            deep = doc.nested.deep[2].x
+            text = doc.text
+            roundtrip = json.encode(doc, {sort_keys = true})
+            compact = json.encode({1, 2, "three", true, json.null})
+            sorted = json.encode({b = 1, a = {x = 1.5}, c = {}}, {sort_keys = true})
+            pretty = json.encode({list = {1, 2}, empty = {}}, {indent = 2, sort_keys = true})
+            scalar = json.decode(input())
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        let document =
+            r#"{"name": "Ada", "tags": ["x", "y"], "missing": null, "nested": {"deep": [{}, {"x": -0.5e1}]}, "text": "a\"b\né😀", "big": 12345678901234567890}"#;
+        interpreter.set_stdin(std::io::Cursor::new(format!("{document}\n 42 \n")));
+        run(&mut interpreter, code).unwrap();
+
+        let get = |name: &str| interpreter.get_global(name).to_string(&interpreter.gc);
+        assert_eq!(get("name"), "Ada");
+        assert_eq!(interpreter.get_global("tag_count"), Value::Number(2));
+        assert_eq!(get("first_tag"), "x");
+        assert_eq!(interpreter.get_global("is_null"), Value::Bool(true));
+        assert_eq!(interpreter.get_global("deep"), Value::Float(-5.0));
+        assert_eq!(get("text"), "a\"b\né😀");
+        assert_eq!(
+            get("roundtrip"),
+            r#"{"big":1.2345678901234567e19,"missing":null,"name":"Ada","nested":{"deep":[{},{"x":-5.0}]},"tags":["x","y"],"text":"a\"b\né😀"}"#
+        );
+        assert_eq!(get("compact"), r#"[1,2,"three",true,null]"#);
+        assert_eq!(get("sorted"), r#"{"a":{"x":1.5},"b":1,"c":{}}"#);
+        assert_eq!(get("pretty"), "{\n  \"empty\": {},\n  \"list\": [\n    1,\n    2\n  ]\n}");
+        assert_eq!(interpreter.get_global("scalar"), Value::Number(42));
+
+        let documents = [
+            (r#"{"a": 1,}"#, "expected a string key at byte 9"),
+            ("[1, 2", "unexpected end of input at byte 6"),
+            ("01", "invalid number at byte 1"),
+            (r#""\x""#, "invalid escape at byte 2"),
+            (r#""\ud800""#, "invalid unicode escape at byte 2"),
+            ("[1] x", "unexpected character 'x' at byte 5"),
+            ("nul", "invalid literal at byte 1"),
+        ];
+        for (document, message) in documents {
+            interpreter.set_stdin(std::io::Cursor::new(document.to_string()));
+            let error = run(&mut interpreter, "x = json.decode(input())").unwrap_err();
+            assert_eq!(error.get_message(), message, "{document}");
+        }
+
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+        assert_eq!(
+            error("t = {list = {1}} t.list[2] = t x = json.encode(t)"),
+            "cannot encode a table with a cycle at $.list[2]"
+        );
+        assert_eq!(error("x = json.encode({f = {print}})"), "cannot encode a function value at $.f[1]");
+        assert_eq!(error("x = json.encode({x = 0/0})"), "cannot encode NaN at $.x");
+        assert_eq!(
+            error("x = json.encode({[true] = 1})"),
+            "cannot encode a boolean key at $"
+        );
+    }
+
+    #[test]
+    fn require_modules() {
+        let mut modules = HashMap::new();
+        let mut add = |name: &str, source: &str| modules.insert(name.to_string(), source.to_string());
+        add("greeting", r#"
+            M = {}
+            function hello(name)
+                return "hello " .. name
+            end
+            M.hello = hello
+            loads = loads + 1
+            return M
+        "#);
+        add("silent", "quiet = true");
+        add("a", r#"b = require("b") return 1"#);
+        add("b", r#"a = require("a") return 2"#);
+        add("broken", "x = missing()");
+        add("invalid", "x = = 1");
+        let code =
+            r#"
+            loads = 0
+            g = require("greeting")
+            same = require("greeting") == g
+            greeting = g.hello("Ada")
+            function make(name, origin)
+                return name .. origin
+            end
+            package.preload.pre = make
+            pre, origin = require("pre")
+            silent = require("silent")
+            cached = package.loaded.silent
+            libs = package.loaded.string == string
+            path = package.path
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        interpreter.add_searcher(modules);
+        run(&mut interpreter, code).unwrap();
+
+        let get = |name: &str| interpreter.get_global(name).to_string(&interpreter.gc);
+        assert_eq!(interpreter.get_global("loads"), Value::Number(1));
+        assert_eq!(interpreter.get_global("same"), Value::Bool(true));
+        assert_eq!(get("greeting"), "hello Ada");
+        assert_eq!(get("pre"), "pre:preload:");
+        assert_eq!(get("origin"), ":preload:");
+        assert_eq!(interpreter.get_global("silent"), Value::Bool(true));
+        assert_eq!(interpreter.get_global("cached"), Value::Bool(true));
+        assert_eq!(interpreter.get_global("quiet"), Value::Bool(true));
+        assert_eq!(interpreter.get_global("libs"), Value::Bool(true));
+        assert_eq!(get("path"), "?.lua;?/init.lua");
+
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+        for _ in 0..2 {
+            assert_eq!(error(r#"require("a")"#), "b: circular require of module 'a' (a -> b -> a)");
+        }
+        assert_eq!(error(r#"require("broken")"#), "broken: attempt to call a nil value");
+        assert!(error(r#"require("invalid")"#).starts_with("error loading module 'invalid' from 'invalid':"));
+        assert_eq!(
+            error(r#"require("missing")"#),
+            "module 'missing' not found:\n\tno field package.preload['missing']\n\t\
+             no module 'missing' in memory\n\tno file search without the READ_FILES capability"
+        );
+
+        let dir = std::env::temp_dir().join(format!("lua-rs-require-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib/util.lua"), "return 42").unwrap();
+        interpreter.set_capabilities(Capabilities::READ_FILES);
+        let dir = dir.to_str().unwrap();
+        let code = format!(r#"package.path = "{dir}/?.lua" util, file = require("lib.util")"#);
+        run(&mut interpreter, &code).unwrap();
+        assert_eq!(interpreter.get_global("util"), Value::Number(42));
+        assert_eq!(
+            interpreter.get_global("file").to_string(&interpreter.gc),
+            format!("{dir}/lib/util.lua")
+        );
+        let missing = run(&mut interpreter, r#"require("other")"#).unwrap_err().get_message();
+        assert!(missing.ends_with(&format!("no file '{dir}/other.lua'")), "{missing}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn load_chunks() {
+        let code =
+            r#"
+            f = load("x = 1 + 1 return x * 10")
+            r = f()
+            again = f()
+            with_args = f(1, 2)
+            env = {y = 5}
+            g = load("y = y + 1 z = 3 return y", "=custom", "t", env)
+            first = g()
+            second = g()
+            from_env = env.y
+            leaked = z
+            sandbox = setmetatable({}, {__index = _G})
+            load("kind = type(print)", nil, nil, sandbox)()
+            sandboxed = sandbox.kind
+            bad, message = load("x = = 1", "=broken")
+            binary, why = load("return 1", "c", "b")
+            pieces = {"return ", "4", "2"}
+            i = 0
+            function reader()
+                i = i + 1
+                return pieces[i]
+            end
+            h = load(reader)
+            from_reader = h()
+            s = loadstring("return 7")
+            from_string = s()
+            from_stdin = dofile()
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        interpreter.set_stdin(std::io::Cursor::new("return 6 * 7"));
+        run(&mut interpreter, code).unwrap();
+
+        let get = |name: &str| interpreter.get_global(name).to_string(&interpreter.gc);
+        let number = |n: i64| Value::Number(n);
+        assert_eq!(interpreter.get_global("r"), number(20));
+        assert_eq!(interpreter.get_global("again"), number(20));
+        assert_eq!(interpreter.get_global("with_args"), number(20));
+        assert_eq!(interpreter.get_global("first"), number(6));
+        assert_eq!(interpreter.get_global("second"), number(7));
+        assert_eq!(interpreter.get_global("from_env"), number(7));
+        assert_eq!(interpreter.get_global("leaked"), Value::Nil);
+        assert_eq!(get("sandboxed"), "function");
+        assert_eq!(interpreter.get_global("kind"), Value::Nil);
+        assert_eq!(interpreter.get_global("bad"), Value::Nil);
+        assert!(get("message").starts_with("broken:1: "), "{}", get("message"));
+        assert_eq!(get("why"), "attempt to load a text chunk (mode is 'b')");
+        assert_eq!(interpreter.get_global("from_reader"), number(42));
+        assert_eq!(interpreter.get_global("from_string"), number(7));
+        assert_eq!(interpreter.get_global("from_stdin"), number(42));
+
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+        assert_eq!(error(r#"load("missing()", "=chunky")()"#), "chunky: attempt to call a nil value");
+        assert_eq!(
+            error(r#"load("missing()")()"#),
+            r#"[string "missing()"]: attempt to call a nil value"#
+        );
+        assert_eq!(error("x = load(1)"), "bad argument #1 to 'load' (string expected, got number)");
+        assert_eq!(
+            error(r#"x = load("", nil, nil, 1)"#),
+            "bad argument #4 to 'load' (table expected, got number)"
+        );
+        assert_eq!(
+            error(r#"dofile("script.lua")"#),
+            "'dofile' is not allowed without the READ_FILES capability"
+        );
 
-    let mut buf = String::new();
-    let _ = std::io::stdin().read_line(&mut buf);
+        // The host compiles once and runs the chunk with different globals
+        let chunk = Chunk::compile("n = n + 1 return n", "counter").unwrap();
+        let counter = |interpreter: &mut Interpreter, start: i64| {
+            let env = interpreter.allocate(Box::new(Table::new(vec![], HashMap::new()))).unwrap();
+            interpreter.keep_alive(Value::GcObject(env));
+            let n = interpreter.create_string("n").unwrap();
+            interpreter.set_value(Value::GcObject(env), n, Value::Number(start)).unwrap();
+            env
+        };
+        let a = counter(&mut interpreter, 0);
+        let b = counter(&mut interpreter, 100);
+        assert_eq!(interpreter.exec_with_env(&chunk, a).unwrap(), vec![number(1)]);
+        assert_eq!(interpreter.exec_with_env(&chunk, a).unwrap(), vec![number(2)]);
+        assert_eq!(interpreter.exec_with_env(&chunk, b).unwrap(), vec![number(101)]);
+        interpreter.set_global("n", number(10));
+        assert_eq!(interpreter.exec(&chunk).unwrap(), vec![number(11)]);
+        assert_eq!(interpreter.get_global("n"), number(11));
 
-    return buf.trim().to_string();
+        let function = interpreter.load(&chunk, Some(b)).unwrap();
+        interpreter.set_global("step", function);
+        run(&mut interpreter, "stepped = step()").unwrap();
+        assert_eq!(interpreter.get_global("stepped"), number(102));
+    }
+
+    #[interpreter_function]
+    fn clamp(x: f64, low: Option<f64>, high: Option<f64>) -> f64 {
+        x.max(low.unwrap_or(f64::MIN)).min(high.unwrap_or(f64::MAX))
+    }
+
+    #[interpreter_function]
+    fn join(separator: String, parts: Variadic<String>) -> String {
+        parts.join(&separator)
+    }
+
+    #[interpreter_function]
+    fn count(_first: Value, rest: &[Value]) -> i64 {
+        rest.len() as i64
+    }
+
+    #[interpreter_function("div")]
+    fn checked_div(a: i64, b: i64) -> Result<i64, String> {
+        a.checked_div(b).ok_or_else(|| "division by zero".to_string())
+    }
+
+    #[interpreter_function]
+    fn call_with(interpreter: &mut Interpreter, f: Value, n: i64) -> Result<Value, RuntimeError> {
+        let results = interpreter.call(f, &[Value::Number(n)])?;
+        Ok(results.first().copied().unwrap_or(Value::Nil))
+    }
+
+    #[interpreter_function]
+    fn shout(gc: &mut GarbageCollector, s: String) -> Result<Value, RuntimeError> {
+        gc.create_string(&s.to_uppercase())
+    }
+
+    #[test]
+    fn host_functions() {
+        let code =
+            r#"
+            function double(n)
+                return n * 2
+            end
+            clamped = clamp(15, 0, 10)
+            low = clamp(-5, 0)
+            unclamped = clamp("2.5")
+            joined = join(", ", "a", 1, "c")
+            nothing = join("-")
+            counted = count(1, 2, nil, 4)
+            quotient = div(7, 2)
+            doubled = call_with(double, 21)
+            shouted = shout("hey")
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        interpreter.add_global_interpreter_function("clamp", clamp).unwrap();
+        interpreter.add_global_interpreter_function("join", join).unwrap();
+        interpreter.add_global_interpreter_function("count", count).unwrap();
+        interpreter.add_global_interpreter_function("div", checked_div).unwrap();
+        interpreter.add_global_interpreter_function("call_with", call_with).unwrap();
+        interpreter.add_global_interpreter_function("shout", shout).unwrap();
+        run(&mut interpreter, code).unwrap();
+
+        let get = |name: &str| interpreter.get_global(name).to_string(&interpreter.gc);
+        assert_eq!(interpreter.get_global("clamped"), Value::Float(10.0));
+        assert_eq!(interpreter.get_global("low"), Value::Float(0.0));
+        assert_eq!(interpreter.get_global("unclamped"), Value::Float(2.5));
+        assert_eq!(get("joined"), "a, 1, c");
+        assert_eq!(get("nothing"), "");
+        assert_eq!(interpreter.get_global("counted"), Value::Number(3));
+        assert_eq!(interpreter.get_global("quotient"), Value::Number(3));
+        assert_eq!(interpreter.get_global("doubled"), Value::Number(42));
+        assert_eq!(get("shouted"), "HEY");
+
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+        assert_eq!(error("x = clamp()"), "bad argument #1 to 'clamp' (number expected, got no value)");
+        assert_eq!(error("x = clamp(1, {})"), "bad argument #2 to 'clamp' (number expected, got table)");
+        assert_eq!(error(r#"x = join(",", "a", {})"#), "bad argument #3 to 'join' (string expected, got table)");
+        assert_eq!(error("x = div(1.5, 1)"), "bad argument #1 to 'div' (number has no integer representation)");
+        assert_eq!(error("x = div(1, 0)"), "division by zero");
+        assert_eq!(error("x = call_with(1, 2)"), "attempt to call a number value");
+    }
+
+    #[interpreter_function]
+    fn min_max(values: Vec<f64>) -> (f64, f64) {
+        let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        (min, max)
+    }
+
+    #[interpreter_function]
+    fn word_counts(words: Vec<String>) -> HashMap<String, u32> {
+        let mut counts = HashMap::new();
+        for word in words {
+            *counts.entry(word).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    #[interpreter_function]
+    fn next_byte(n: u8) -> Option<u8> {
+        n.checked_add(1)
+    }
+
+    #[test]
+    fn conversions() {
+        let code =
+            r#"
+            low, high = min_max({3, 1.5, 7})
+            counts = word_counts({"a", "b", "a"})
+            a_count = counts.a
+            after = next_byte(254)
+            overflow = next_byte(255)
+            list = {1, 2, 3}
+            mixed = {1, "two"}
+            map = {x = 1, y = 2}
+            name = "lua"
+            big = 300
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        interpreter.add_global_interpreter_function("min_max", min_max).unwrap();
+        interpreter.add_global_interpreter_function("word_counts", word_counts).unwrap();
+        interpreter.add_global_interpreter_function("next_byte", next_byte).unwrap();
+        run(&mut interpreter, code).unwrap();
+
+        assert_eq!(interpreter.get_global("low"), Value::Float(1.5));
+        assert_eq!(interpreter.get_global("high"), Value::Float(7.0));
+        assert_eq!(interpreter.get_global("a_count"), Value::Number(2));
+        assert_eq!(interpreter.get_global("after"), Value::Number(255));
+        assert_eq!(interpreter.get_global("overflow"), Value::Nil);
+
+        assert_eq!(interpreter.get_global_as::<Vec<i32>>("list").unwrap(), vec![1, 2, 3]);
+        assert_eq!(
+            interpreter.get_global_as::<HashMap<String, f64>>("map").unwrap(),
+            HashMap::from([("x".to_string(), 1.0), ("y".to_string(), 2.0)])
+        );
+        assert_eq!(interpreter.get_global_as::<String>("name").unwrap(), "lua");
+        assert_eq!(interpreter.get_global_as::<Option<i64>>("missing").unwrap(), None);
+        assert_eq!(interpreter.get_global_as::<u16>("big").unwrap(), 300);
+        let error = |result: Result<(), RuntimeError>| result.unwrap_err().get_message();
+        assert_eq!(
+            error(interpreter.get_global_as::<u8>("big").map(|_| ())),
+            "global 'big': number 300 out of range for u8"
+        );
+        assert_eq!(
+            error(interpreter.get_global_as::<Vec<i64>>("mixed").map(|_| ())),
+            "global 'mixed': number expected, got string at index 2"
+        );
+        assert_eq!(
+            error(interpreter.get_global_as::<i64>("name").map(|_| ())),
+            "global 'name': number expected, got string"
+        );
+
+        let mut gc = GarbageCollector::new();
+        let values = (1, "two", vec![3.0]).into_lua_multi(&mut gc).unwrap();
+        assert_eq!(values.len(), 3);
+        let (a, b, c): (i64, String, Vec<f64>) = FromLuaMulti::from_lua_multi(&values, &gc).unwrap();
+        assert_eq!((a, b.as_str(), c), (1, "two", vec![3.0]));
+        let rest: Variadic<i64> = FromLuaMulti::from_lua_multi(&values[..1], &gc).unwrap();
+        assert_eq!(rest.0, vec![1]);
+        assert!(u64::MAX.into_lua(&mut gc).is_err());
+
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+        assert_eq!(
+            error("x = next_byte(256)"),
+            "bad argument #1 to 'next_byte' (number 256 out of range for u8)"
+        );
+        assert_eq!(
+            error(r#"x, y = min_max({1, "x"})"#),
+            "bad argument #1 to 'min_max' (number expected, got string at index 2)"
+        );
+        assert_eq!(
+            error("x, y = min_max(5)"),
+            "bad argument #1 to 'min_max' (table expected, got number)"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_values() {
+        use std::collections::BTreeMap;
+        use ::serde::{ Deserialize, Serialize };
+        use super::serde::{
+            from_value,
+            from_value_with,
+            to_value,
+            to_value_with,
+            EnumRepr,
+            MapRepr,
+            Options,
+        };
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Mode {
+            Off,
+            Fixed(u32),
+            Range {
+                low: u32,
+                high: u32,
+            },
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Server {
+            host: String,
+            port: u16,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Config {
+            name: String,
+            servers: Vec<Server>,
+            mode: Mode,
+            backup: Option<String>,
+            weights: HashMap<String, f64>,
+        }
+
+        let mut config = Config {
+            name: "main".to_string(),
+            servers: vec![
+                Server { host: "a.example".to_string(), port: 80 },
+                Server { host: "b.example".to_string(), port: 8080 }
+            ],
+            mode: Mode::Range { low: 1, high: 4 },
+            backup: None,
+            weights: HashMap::from([("a".to_string(), 0.5)]),
+        };
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        let value = to_value(interpreter.gc_mut(), &config).unwrap();
+        interpreter.set_global("config", value);
+        let code =
+            r#"
+            first = config.servers[1].host
+            high = config.mode.Range.high
+            no_backup = config.backup == nil
+            weight = config.weights.a
+            config.servers[2].port = 8081
+            config.mode = "Off"
+        "#;
+        run(&mut interpreter, code).unwrap();
+        assert_eq!(interpreter.get_global_as::<String>("first").unwrap(), "a.example");
+        assert_eq!(interpreter.get_global("high"), Value::Number(4));
+        assert_eq!(interpreter.get_global("no_backup"), Value::Bool(true));
+        assert_eq!(interpreter.get_global("weight"), Value::Float(0.5));
+
+        config.servers[1].port = 8081;
+        config.mode = Mode::Off;
+        let read = |interpreter: &Interpreter| {
+            from_value::<Config>(interpreter.gc(), interpreter.get_global("config"))
+        };
+        assert_eq!(read(&interpreter).unwrap(), config);
+        run(&mut interpreter, "config.mode = { Fixed = 3.0 }").unwrap();
+        assert_eq!(read(&interpreter).unwrap().mode, Mode::Fixed(3));
+
+        let mut error = |code: &str| {
+            run(&mut interpreter, code).unwrap();
+            read(&interpreter).unwrap_err().to_string()
+        };
+        assert_eq!(
+            error(r#"config.servers[2].port = "x""#),
+            r#"invalid type: string "x", expected u16 at $.servers[2].port"#
+        );
+        assert_eq!(
+            error("config.servers[2].port = 70000"),
+            "invalid value: integer `70000`, expected u16 at $.servers[2].port"
+        );
+        assert_eq!(
+            error("config.servers[2].port = 1 config.mode = { Range = { low = 1 } }"),
+            "missing field `high` at $.mode.Range"
+        );
+        assert_eq!(
+            error(r#"config.mode = { Fixed = 1, Off = true }"#),
+            "table with a single variant name as key expected at $.mode"
+        );
+        assert_eq!(error(r#"config.mode = "Off" config.name = nil"#), "missing field `name` at $");
+
+        run(&mut interpreter, "null = json.null").unwrap();
+        let options = Options {
+            none: interpreter.get_global("null"),
+            enums: EnumRepr::Adjacent { tag: "type", content: "value" },
+            maps: MapRepr::Pairs,
+        };
+        let list = vec![Some(1), None, Some(3)];
+        let names = BTreeMap::from([(2, "two".to_string()), (1, "one".to_string())]);
+        let value = to_value_with(interpreter.gc_mut(), &list, &options).unwrap();
+        interpreter.set_global("list", value);
+        let value = to_value_with(interpreter.gc_mut(), &Mode::Fixed(5), &options).unwrap();
+        interpreter.set_global("fixed", value);
+        let value = to_value_with(interpreter.gc_mut(), &Mode::Off, &options).unwrap();
+        interpreter.set_global("off", value);
+        let value = to_value_with(interpreter.gc_mut(), &names, &options).unwrap();
+        interpreter.set_global("names", value);
+        let code =
+            r#"
+            length = #list
+            hole = list[2] == json.null
+            fixed_type = fixed.type
+            fixed_value = fixed.value
+            off_type = off.type
+            first_name = names[1][2]
+        "#;
+        run(&mut interpreter, code).unwrap();
+        assert_eq!(interpreter.get_global("length"), Value::Number(3));
+        assert_eq!(interpreter.get_global("hole"), Value::Bool(true));
+        assert_eq!(interpreter.get_global_as::<String>("fixed_type").unwrap(), "Fixed");
+        assert_eq!(interpreter.get_global("fixed_value"), Value::Number(5));
+        assert_eq!(interpreter.get_global_as::<String>("off_type").unwrap(), "Off");
+        assert_eq!(interpreter.get_global_as::<String>("first_name").unwrap(), "one");
+
+        let gc = interpreter.gc();
+        let global = |name: &str| interpreter.get_global(name);
+        let read_list: Vec<Option<i64>> = from_value_with(gc, global("list"), &options).unwrap();
+        assert_eq!(read_list, list);
+        let read_fixed: Mode = from_value_with(gc, global("fixed"), &options).unwrap();
+        assert_eq!(read_fixed, Mode::Fixed(5));
+        let read_off: Mode = from_value_with(gc, global("off"), &options).unwrap();
+        assert_eq!(read_off, Mode::Off);
+        let read_names: BTreeMap<i64, String> = from_value_with(gc, global("names"), &options).unwrap();
+        assert_eq!(read_names, names);
+
+        let mut gc = GarbageCollector::new();
+        let error = to_value(&mut gc, &vec![HashMap::from([("big", u64::MAX)])]).unwrap_err();
+        assert_eq!(error.to_string(), "integer 18446744073709551615 out of range at $[1].big");
+        let value = to_value(&mut gc, &[[1, 2], [3, -4]]).unwrap();
+        assert_eq!(
+            from_value::<Vec<Vec<u8>>>(&gc, value).unwrap_err().to_string(),
+            "invalid value: integer `-4`, expected u8 at $[2][2]"
+        );
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct Vec2 {
+        x: f64,
+        y: f64,
+    }
+
+    #[user_data]
+    impl Vec2 {
+        fn new(x: f64, y: f64) -> Self {
+            Vec2 { x, y }
+        }
+
+        #[getter]
+        fn x(&self) -> f64 {
+            self.x
+        }
+
+        #[setter]
+        fn set_x(&mut self, x: f64) {
+            self.x = x;
+        }
+
+        #[getter]
+        fn y(&self) -> f64 {
+            self.y
+        }
+
+        fn length(&self) -> f64 {
+            self.x.hypot(self.y)
+        }
+
+        fn scale(&mut self, k: f64) {
+            self.x *= k;
+            self.y *= k;
+        }
+
+        fn copy_from(&mut self, other: &Vec2) {
+            *self = *other;
+        }
+
+        #[meta("__add")]
+        fn add(&self, other: Vec2) -> Vec2 {
+            Vec2::new(self.x + other.x, self.y + other.y)
+        }
+
+        #[meta("__eq")]
+        fn equals(&self, other: &Vec2) -> bool {
+            self == other
+        }
+
+        #[meta("__unm")]
+        fn negate(&self) -> Vec2 {
+            Vec2::new(-self.x, -self.y)
+        }
+
+        #[meta("__tostring")]
+        fn show(&self) -> String {
+            format!("({}, {})", self.x, self.y)
+        }
+
+        #[meta("__call")]
+        fn at(&self, t: f64) -> Vec2 {
+            Vec2::new(self.x * t, self.y * t)
+        }
+    }
+
+    #[interpreter_function]
+    fn vec2(x: f64, y: f64) -> Vec2 {
+        Vec2::new(x, y)
+    }
+
+    #[test]
+    fn user_data_values() {
+        let code =
+            r#"
+            v = vec2(3, 4)
+            length = v:length()
+            v:scale(2)
+            x = v.x
+            v.x = 1
+            w = v + vec2(1, 1)
+            wx = w.x
+            same = vec2(1, 2) == vec2(1, 2)
+            different = vec2(1, 2) ~= vec2(2, 1)
+            text = tostring(-w)
+            half = v(0.5).y
+            kind = type(v)
+            missing = v.z
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        interpreter.add_global_interpreter_function("vec2", vec2).unwrap();
+        run(&mut interpreter, code).unwrap();
+
+        assert_eq!(interpreter.get_global("length"), Value::Float(5.0));
+        assert_eq!(interpreter.get_global("x"), Value::Float(6.0));
+        assert_eq!(interpreter.get_global("wx"), Value::Float(2.0));
+        assert_eq!(interpreter.get_global("same"), Value::Bool(true));
+        assert_eq!(interpreter.get_global("different"), Value::Bool(true));
+        assert_eq!(interpreter.get_global_as::<String>("text").unwrap(), "(-2, -9)");
+        assert_eq!(interpreter.get_global("half"), Value::Float(4.0));
+        assert_eq!(interpreter.get_global_as::<String>("kind").unwrap(), "userdata");
+        assert_eq!(interpreter.get_global("missing"), Value::Nil);
+        assert_eq!(interpreter.get_global_as::<Vec2>("v").unwrap(), Vec2::new(1.0, 8.0));
+
+        // Values made by the host share the metatable, and survive collections
+        let value = Vec2::new(0.0, 1.0).into_lua(interpreter.gc_mut()).unwrap();
+        interpreter.set_global("up", value);
+        interpreter.collect_garbage();
+        run(&mut interpreter, "up:scale(3)").unwrap();
+        let up = interpreter.get_global_as::<UserDataRef<Vec2>>("up").unwrap();
+        assert_eq!(*up.borrow().unwrap(), Vec2::new(0.0, 3.0));
+        up.borrow_mut().unwrap().x = 2.0;
+        drop(up);
+        run(&mut interpreter, "up_x = up.x").unwrap();
+        assert_eq!(interpreter.get_global("up_x"), Value::Float(2.0));
+
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+        assert_eq!(error("v.z = 1"), "Vec2 has no field 'z'");
+        assert_eq!(error("v.y = 1"), "field 'y' of Vec2 is read-only");
+        assert_eq!(error(r#"v.x = "far""#), "bad argument #2 to 'x' (number expected, got string)");
+        assert_eq!(
+            error("f = v.length f(5)"),
+            "bad argument #1 to 'length' (Vec2 expected, got number)"
+        );
+        assert_eq!(error("v:copy_from({})"), "bad argument #2 to 'copy_from' (Vec2 expected, got table)");
+        assert_eq!(error("v:copy_from(v)"), "Vec2 already mutably borrowed");
+        assert_eq!(error("v:rotate()"), "attempt to call a nil value (method 'rotate')");
+        assert_eq!(error("x = v + 1"), "bad argument #2 to '__add' (Vec2 expected, got number)");
+        assert_eq!(
+            error("getmetatable(v).__index()"),
+            "bad argument #1 to '__index' (Vec2 expected, got no value)"
+        );
+        assert_eq!(
+            error("getmetatable(v).__newindex()"),
+            "bad argument #1 to '__newindex' (Vec2 expected, got no value)"
+        );
+        assert_eq!(
+            error("getmetatable(v).__index({}, \"x\")"),
+            "bad argument #1 to '__index' (Vec2 expected, got table)"
+        );
+        assert_eq!(error("getmetatable(v).__newindex(v)"), "attempt to index Vec2 with a nil");
+        assert_eq!(
+            interpreter.get_global_as::<Vec2>("length").unwrap_err().get_message(),
+            "global 'length': Vec2 expected, got number"
+        );
+    }
+
+    #[test]
+    fn closures() {
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+
+        let mut total = 0;
+        let add = interpreter.create_function(move |n: i64| {
+            total += n;
+            total
+        }).unwrap();
+        interpreter.set_global("add", add);
+        let apply = interpreter
+            .create_interpreter_function(|interpreter: &mut Interpreter, (f, x): (Value, i64)| {
+                interpreter.call(f, &[Value::Number(x)]).map(Variadic)
+            })
+            .unwrap();
+        interpreter.set_global("apply", apply);
+        let code =
+            r#"
+            add(1)
+            total = add(2)
+            function double(x) return x * 2 end
+            doubled = apply(double, 21)
+            function again() return apply(again, 1) end
+        "#;
+        run(&mut interpreter, code).unwrap();
+        assert_eq!(interpreter.get_global("total"), Value::Number(3));
+        assert_eq!(interpreter.get_global("doubled"), Value::Number(42));
+
+        // Scoped functions can borrow locals, and stop working once it ends
+        let mut log = vec![];
+        interpreter.scope(|scope| {
+            let push = scope.create_function(|line: String| log.push(line)).unwrap();
+            scope.set_global("log", push);
+            run(scope, r#"log("a") log("b")"#).unwrap();
+        });
+        assert_eq!(log, ["a", "b"]);
+
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+        assert_eq!(error("add({})"), "bad argument #1 to '?' (number expected, got table)");
+        assert_eq!(error("apply(again, 1)"), "host function is already running");
+        assert_eq!(error(r#"log("c")"#), "attempt to call a function whose scope has ended");
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_functions() {
+        use std::future::Future;
+        use std::pin::{ pin, Pin };
+        use std::task::{ Context, Poll, Waker };
+
+        // Ready once it was polled `pending` times
+        struct Delay {
+            pending: usize,
+            value: i64,
+        }
+        impl Future for Delay {
+            type Output = i64;
+            fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<i64> {
+                if self.pending == 0 {
+                    return Poll::Ready(self.value);
+                }
+                self.pending -= 1;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        let fetch = interpreter
+            .create_async_function(|n: i64| Delay { pending: 2, value: n * 10 })
+            .unwrap();
+        interpreter.set_global("fetch", fetch);
+        let chunk = Chunk::compile(
+            "function get(n) return fetch(n) end local a = fetch(1) return a + get(2)",
+            "async"
+        ).unwrap();
+        // Polls `future` until it is ready, counting how often it was pending
+        fn drive<F: Future>(future: F) -> (F::Output, usize) {
+            let mut context = Context::from_waker(Waker::noop());
+            let mut future = pin!(future);
+            let mut pending = 0;
+            loop {
+                match future.as_mut().poll(&mut context) {
+                    Poll::Ready(output) => return (output, pending),
+                    Poll::Pending => pending += 1,
+                }
+            }
+        }
+        let (results, pending) = drive(interpreter.exec_async(&chunk));
+        assert_eq!(results.unwrap(), [Value::Number(30)]);
+        assert_eq!(pending, 4);
+
+        // An abandoned script leaves the interpreter usable
+        let chunk = Chunk::compile("x = fetch(1)", "abandoned").unwrap();
+        let mut future = Box::pin(interpreter.exec_async(&chunk));
+        let mut context = Context::from_waker(Waker::noop());
+        assert!(future.as_mut().poll(&mut context).is_pending());
+        drop(future);
+        run(&mut interpreter, "function f(n) return n end y = f(2)").unwrap();
+        assert_eq!(interpreter.get_global("y"), Value::Number(2));
+        assert_eq!(interpreter.get_global("x"), Value::Nil);
+
+        // So does a leaked one, calls of async functions fail after it
+        let mut future = Box::pin(interpreter.exec_async(&chunk));
+        assert!(future.as_mut().poll(&mut context).is_pending());
+        std::mem::forget(future);
+        assert_eq!(
+            run(&mut interpreter, "x = fetch(1)").unwrap_err().get_message(),
+            "attempt to call an async function outside of exec_async"
+        );
+
+        let chunk = Chunk::compile("fetch({})", "bad").unwrap();
+        let (results, _) = drive(interpreter.exec_async(&chunk));
+        assert_eq!(
+            results.unwrap_err().get_message(),
+            "bad: bad argument #1 to '?' (number expected, got table)"
+        );
+        assert_eq!(
+            run(&mut interpreter, "fetch(1)").unwrap_err().get_message(),
+            "attempt to call an async function outside of exec_async"
+        );
+    }
+
+    #[cfg(feature = "send")]
+    #[test]
+    fn send_interpreters() {
+        use std::cell::Cell;
+
+        fn assert_send<T: Send>() {}
+        assert_send::<Interpreter>();
+        assert_send::<handle::ScriptFunction>();
+
+        // Send but not Sync, like a database connection
+        struct Connection(Cell<i64>);
+
+        let mut pool: Vec<Interpreter> = (0..4)
+            .map(|i| {
+                let mut interpreter = Interpreter::new();
+                interpreter.open_libs().unwrap();
+                interpreter.set_app_data(Connection(Cell::new(i)));
+                interpreter
+            })
+            .collect();
+        run(&mut pool[0], "function square(x) return x * x end").unwrap();
+        let square = pool[0].get_global_as::<handle::ScriptFunction>("square").unwrap();
+
+        // Handles can come and go on another thread while the interpreter collects
+        let cloning = {
+            let square = square.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    drop(square.clone());
+                }
+            })
+        };
+        let workers: Vec<_> = pool
+            .into_iter()
+            .enumerate()
+            .map(|(i, mut interpreter)| {
+                let square = (i == 0).then(|| square.clone());
+                std::thread::spawn(move || {
+                    let calls = Cell::new(0);
+                    let add = interpreter.create_interpreter_function(
+                        move |interpreter: &mut Interpreter, (a, b): (i64, i64)| {
+                            calls.set(calls.get() + 1);
+                            a + b + interpreter.app_data::<Connection>().unwrap().0.get()
+                        }
+                    );
+                    interpreter.set_global("add", add.unwrap());
+                    run(&mut interpreter, "total = add(1, 2)").unwrap();
+                    if let Some(square) = square {
+                        for _ in 0..100 {
+                            interpreter.collect_garbage();
+                        }
+                        assert_eq!(square.call::<_, i64>(&mut interpreter, 7).unwrap(), 49);
+                    }
+                    interpreter.get_global_as::<i64>("total").unwrap()
+                })
+            })
+            .collect();
+        drop(square);
+        cloning.join().unwrap();
+        let totals: Vec<i64> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+        assert_eq!(totals, vec![3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn registry_and_app_data() {
+        struct Tenant(String);
+        struct Requests(i64);
+
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        assert!(interpreter.set_app_data(Tenant("acme".to_string())).is_none());
+        interpreter.set_app_data(Requests(0));
+        let tenant = interpreter
+            .create_interpreter_function(|interpreter: &mut Interpreter, ()| {
+                interpreter.app_data_mut::<Requests>().unwrap().0 += 1;
+                interpreter.app_data::<Tenant>().unwrap().0.clone()
+            })
+            .unwrap();
+        interpreter.set_global("tenant", tenant);
+
+        let config = HashMap::from([("limit", 10)]).into_lua(interpreter.gc_mut()).unwrap();
+        interpreter.set_registry_value("myapp.config", config).unwrap();
+        interpreter.collect_garbage();
+
+        run(&mut interpreter, "a = tenant() b = tenant()").unwrap();
+        assert_eq!(interpreter.get_global_as::<String>("b").unwrap(), "acme");
+        assert_eq!(interpreter.app_data::<Requests>().unwrap().0, 2);
+        let replaced = interpreter.set_app_data(Tenant("globex".to_string())).unwrap();
+        assert_eq!(replaced.0, "acme");
+        assert_eq!(interpreter.remove_app_data::<Requests>().unwrap().0, 2);
+        assert!(interpreter.app_data::<Requests>().is_none());
+
+        let config = interpreter.registry_value("myapp.config");
+        let config = HashMap::<String, i64>::from_lua(config, interpreter.gc()).unwrap();
+        assert_eq!(config["limit"], 10);
+        assert_eq!(interpreter.registry_value("missing"), Value::Nil);
+        let registry = interpreter.registry().unwrap();
+        assert!(interpreter.retainer_path(registry).is_some());
+    }
+
+    #[test]
+    fn scoped_user_data() {
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        interpreter.add_global_interpreter_function("vec2", vec2).unwrap();
+
+        let mut position = Vec2::new(3.0, 4.0);
+        let length = interpreter.scope(|scope| {
+            let value = scope.borrow_mut(&mut position)?;
+            scope.set_global("position", value);
+            run(scope, "position:scale(2) length = position:length() position.x = 1")?;
+            run(scope, "kept = position")?;
+            scope.get_global_as::<f64>("length")
+        });
+        assert_eq!(length.unwrap(), 10.0);
+        assert_eq!(position, Vec2::new(1.0, 8.0));
+
+        // The script kept the value, but it can't reach the borrow any more
+        let mut error = |code: &str| run(&mut interpreter, code).unwrap_err().get_message();
+        assert_eq!(error("kept:scale(2)"), "attempt to use a Vec2 whose scope has ended");
+        assert_eq!(error("x = kept.x"), "attempt to use a Vec2 whose scope has ended");
+        assert_eq!(
+            error("x = vec2(1, 1) + kept"),
+            "bad argument #2 to '__add' (attempt to use a Vec2 whose scope has ended)"
+        );
+        assert_eq!(
+            interpreter.get_global_as::<Vec2>("kept").unwrap_err().get_message(),
+            "global 'kept': attempt to use a Vec2 whose scope has ended"
+        );
+        run(&mut interpreter, "kind = type(kept)").unwrap();
+        assert_eq!(interpreter.get_global_as::<String>("kind").unwrap(), "userdata");
+    }
+
+    #[test]
+    fn script_functions() {
+        let code =
+            r#"
+            function add(a, b) return a + b end
+            function greet(name) return "hello " .. name, #name end
+            function fail() assert(false, "broken") end
+            function twice(x) return x * 2 end
+            handlers = { twice = twice }
+        "#;
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        run(&mut interpreter, code).unwrap();
+
+        let add = interpreter.get_global_as::<ScriptFunction>("add").unwrap();
+        let greet = interpreter.get_global_as::<ScriptFunction>("greet").unwrap();
+        let handlers = interpreter
+            .get_global_as::<HashMap<String, ScriptFunction>>("handlers")
+            .unwrap();
+        // Handles keep the functions alive after the script dropped them
+        run(&mut interpreter, "add = nil greet = nil handlers = nil twice = nil").unwrap();
+        interpreter.collect_garbage();
+
+        assert_eq!(add.call::<_, i64>(&mut interpreter, (1, 2)).unwrap(), 3);
+        let (greeting, length) = greet
+            .call::<_, (String, i64)>(&mut interpreter, "world".to_string())
+            .unwrap();
+        assert_eq!((greeting.as_str(), length), ("hello world", 5));
+        assert_eq!(handlers["twice"].call::<_, f64>(&mut interpreter, 1.5).unwrap(), 3.0);
+
+        let fail = interpreter.get_global_as::<ScriptFunction>("fail").unwrap();
+        assert_eq!(fail.call::<_, ()>(&mut interpreter, ()).unwrap_err().get_message(), "broken");
+        assert_eq!(
+            add.call::<_, Vec<i64>>(&mut interpreter, (1, 2)).unwrap_err().get_message(),
+            "bad result of script function (table expected)"
+        );
+        assert_eq!(
+            interpreter.get_global_as::<ScriptFunction>("nothing").unwrap_err().get_message(),
+            "global 'nothing': function expected, got nil"
+        );
+        let mut other = Interpreter::new();
+        assert_eq!(
+            add.call::<_, i64>(&mut other, (1, 2)).unwrap_err().get_message(),
+            "attempt to call a script function of another interpreter"
+        );
+
+        // Once the last handle is gone the function can be collected
+        let snapshot = interpreter.heap_snapshot();
+        assert_eq!(snapshot.roots.iter().filter(|(name, _)| name == "<handle>").count(), 4);
+        drop((add, greet, handlers, fail));
+        assert!(interpreter.gc().handles.borrow().is_empty());
+    }
+
+    #[derive(Default)]
+    struct RecordingSink {
+        events: RefCell<Vec<(Category, String)>>,
+    }
+
+    impl EventSink for RecordingSink {
+        fn enabled(&self, category: Category) -> bool {
+            category != Category::Lexer
+        }
+        fn event(&self, event: &Event) {
+            self.events.borrow_mut().push((event.category, event.message.to_string()));
+        }
+    }
+
+    #[test]
+    fn event_sink() {
+        let sink = Rc::new(RecordingSink::default());
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.set_event_sink(sink.clone());
+        tokenizer.tokenize("t = {}\nt.x = 1\ncollectgarbage()".to_string());
+        let mut parser = Parser::new(tokenizer.get_tokens().to_vec());
+        parser.set_event_sink(sink.clone());
+        let parsed = parser.parse().unwrap();
+
+        let mut interpreter = Interpreter::new();
+        interpreter.open_libs().unwrap();
+        interpreter.set_event_sink(sink.clone());
+        interpreter.eval(&parsed).unwrap();
+
+        let events = sink.events.borrow();
+        let count = |category| events.iter().filter(|(c, _)| *c == category).count();
+        assert_eq!(count(Category::Lexer), 0);
+        assert_eq!(count(Category::Parser), 3);
+        assert!(count(Category::Interpreter) >= 2);
+        assert!(events.iter().any(|(c, m)| *c == Category::Gc && m.starts_with("allocated table")));
+        assert!(events.iter().any(|(c, m)| *c == Category::Gc && m.starts_with("collected")));
+    }
+
+    #[test]
+    fn heap_snapshot() {
+        let code =
+            r#"
+            config = {name = "app", inner = {1, 2}}
+            garbage = {}
+            garbage = nil
+        "#;
+        let mut interpreter = Interpreter::new();
+        run(&mut interpreter, code).unwrap();
+
+        let config = interpreter.get_global("config");
+        let key = interpreter.create_string("inner").unwrap();
+        let inner = match config {
+            Value::GcObject(r) => interpreter.gc.get(r).unwrap().borrow().index(key).unwrap(),
+            _ => panic!("config should be a table"),
+        };
+        let inner = inner.gc_ref().unwrap();
+
+        let path = interpreter.retainer_path(inner).unwrap();
+        assert_eq!(path.len(), 2);
+        assert_eq!(path[0].edge, "config");
+        assert_eq!(path[0].object, config.gc_ref().unwrap());
+        assert_eq!(path[1].edge, "['inner']");
+        assert_eq!(path[1].type_name, "table");
+
+        let snapshot = interpreter.heap_snapshot();
+        assert_eq!(snapshot.objects.iter().filter(|o| o.type_name == "table").count(), 3);
+        let garbage = snapshot.objects
+            .iter()
+            .find(|o| o.type_name == "table" && o.id != inner && Some(o.id) != config.gc_ref())
+            .unwrap();
+        assert_eq!(snapshot.retainer_path(garbage.id), None);
+
+        let json = snapshot.to_json();
+        assert!(json.starts_with("{\"roots\":[{\"name\":\"config\""));
+        assert!(json.contains("\"name\":\"app\""));
+        let chrome = snapshot.to_chrome_heap_snapshot();
+        assert!(chrome.contains(&format!("\"node_count\":{}", snapshot.objects.len() + 1)));
+    }
 }
+
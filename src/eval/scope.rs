@@ -0,0 +1,116 @@
+// Host functions and values that borrow from the caller's stack. A scope
+// hands them to the script as ordinary function and userdata values and
+// empties them when it ends, so a script holding on to one gets an error
+// instead of a dangling borrow.
+
+use std::{
+    marker::PhantomData,
+    mem,
+    ops::{ Deref, DerefMut },
+    process,
+};
+
+use crate::{ errors::RuntimeError, sync::{ MaybeSend, Rc, RefCell } };
+
+use super::{
+    convert::{ FromLuaMulti, IntoLuaMulti },
+    gc::GcValue,
+    interpreter::{ host_function, Interpreter },
+    types::{ Function, HostFunction },
+    userdata::{ self, ScopedCell, UserData },
+    value::Value,
+};
+
+/// Made by `Interpreter::scope`, it derefs to the interpreter so scripts can
+/// run inside it
+pub struct Scope<'scope, 'env: 'scope> {
+    interpreter: &'scope mut Interpreter,
+    closures: Vec<Rc<RefCell<Option<HostFunction<'static>>>>>,
+    userdata: Vec<Lent>,
+    // Invariant, so `'env` can't shrink to something shorter than the scope
+    env: PhantomData<&'scope mut &'env ()>,
+}
+
+// A userdata object lent by the scope, with what empties it
+struct Lent {
+    object: Rc<RefCell<Box<dyn GcValue>>>,
+    end: fn(&mut dyn GcValue),
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    pub(crate) fn new(interpreter: &'scope mut Interpreter) -> Self {
+        Scope { interpreter, closures: vec![], userdata: vec![], env: PhantomData }
+    }
+
+    /// Like `Interpreter::create_function`, but `f` only has to live as long
+    /// as the scope
+    pub fn create_function<A: FromLuaMulti, R: IntoLuaMulti>(
+        &mut self,
+        mut f: impl FnMut(A) -> R + MaybeSend + 'env
+    ) -> Result<Value, RuntimeError> {
+        self.create_interpreter_function(move |_: &mut Interpreter, args| f(args))
+    }
+
+    /// Like `Interpreter::create_interpreter_function`, but `f` only has to
+    /// live as long as the scope
+    pub fn create_interpreter_function<A: FromLuaMulti, R: IntoLuaMulti>(
+        &mut self,
+        f: impl FnMut(&mut Interpreter, A) -> R + MaybeSend + 'env
+    ) -> Result<Value, RuntimeError> {
+        let f = host_function(f);
+        // SAFETY: only the lifetime changes. The function is dropped when the
+        // scope is, before anything borrowed for 'env, and the script's value
+        // fails to call after that.
+        let f: HostFunction<'static> = unsafe { mem::transmute(f) };
+        let closure = Rc::new(RefCell::new(Some(f)));
+        self.closures.push(Rc::clone(&closure));
+        let r = self.interpreter.allocate(Box::new(Function::Closure(closure)))?;
+        Ok(Value::GcObject(r))
+    }
+
+    /// Lends `value` to the script as userdata, with the methods and fields
+    /// of `T`. Using it after the scope ended is an error. The value isn't
+    /// rooted.
+    pub fn borrow_mut<T: UserData>(&mut self, value: &'env mut T) -> Result<Value, RuntimeError> {
+        // SAFETY: as for functions, the cell is emptied when the scope ends
+        let value: &'static mut T = unsafe { mem::transmute(value) };
+        let metatable = userdata::metatable::<T>(&mut self.interpreter.gc)?;
+        let cell = ScopedCell { value: Some(value), metatable };
+        let r = self.interpreter.allocate(Box::new(cell))?;
+        let object = self.interpreter.gc.get(r).unwrap();
+        self.userdata.push(Lent { object, end: userdata::end_scope::<T> });
+        Ok(Value::GcObject(r))
+    }
+}
+
+impl Deref for Scope<'_, '_> {
+    type Target = Interpreter;
+
+    fn deref(&self) -> &Interpreter {
+        self.interpreter
+    }
+}
+
+impl DerefMut for Scope<'_, '_> {
+    fn deref_mut(&mut self) -> &mut Interpreter {
+        self.interpreter
+    }
+}
+
+impl Drop for Scope<'_, '_> {
+    fn drop(&mut self) {
+        for closure in &self.closures {
+            // None of them can be running, the scope outlives their calls
+            closure.borrow_mut().take();
+        }
+        for Lent { object, end } in &self.userdata {
+            // Only a guard the host kept from inside the scope can still hold
+            // it. Unwinding would let the caller use its value next to that.
+            let Ok(mut object) = object.try_borrow_mut() else {
+                eprintln!("userdata still borrowed when its scope ended");
+                process::abort();
+            };
+            end(&mut **object);
+        }
+    }
+}
@@ -0,0 +1,1023 @@
+// Serde support: any `Serialize` type becomes a graph of tables in the
+// collector and tables deserialize back into Rust types. Structs and maps
+// are tables keyed by name, sequences and tuples are sequences.
+
+use std::{ collections::HashMap, fmt, vec };
+
+use ::serde::{
+    de::{ self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Unexpected, Visitor },
+    ser::{ self, Serialize },
+};
+
+use crate::errors::RuntimeError;
+
+use super::{ gc::GarbageCollector, stdlib, types::Table, value::Value };
+
+/// How values without a Lua counterpart are written, reading them back
+/// expects the same options
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// What `None` and `()` become. Nil drops struct fields and leaves holes
+    /// in sequences, a sentinel like `json.null` keeps them.
+    pub none: Value,
+    pub enums: EnumRepr,
+    pub maps: MapRepr,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { none: Value::Nil, enums: EnumRepr::External, maps: MapRepr::Table }
+    }
+}
+
+/// How enum variants are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnumRepr {
+    /// Unit variants as their name, others as `{ Variant = content }`
+    External,
+    /// `{ [tag] = "Variant", [content] = content }`, without content for unit
+    /// variants
+    Adjacent {
+        tag: &'static str,
+        content: &'static str,
+    },
+}
+
+/// How maps are written, structs are always tables keyed by field name
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MapRepr {
+    /// A table keyed by the map's keys
+    Table,
+    /// A sequence of `{ key, value }` pairs, which keeps their order and
+    /// allows keys that can't index a table
+    Pairs,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Index(usize),
+    Key(String),
+}
+
+/// A value that couldn't be converted, with the path to it like
+/// "$.servers[2].port"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    message: String,
+    path: Vec<Segment>,
+}
+
+impl Error {
+    fn new(message: String) -> Self {
+        Error { message, path: vec![] }
+    }
+
+    // Errors are made where the value is and get their path on the way out
+    fn at(mut self, segment: Segment) -> Self {
+        self.path.insert(0, segment);
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn path(&self) -> String {
+        let mut path = "$".to_string();
+        for segment in &self.path {
+            match segment {
+                Segment::Index(i) => path.push_str(&format!("[{i}]")),
+                Segment::Key(key) if stdlib::is_identifier(key) => {
+                    path.push_str(&format!(".{key}"));
+                }
+                Segment::Key(key) => path.push_str(&format!("[{key:?}]")),
+            }
+        }
+        path
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.path())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error::new(message.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error::new(message.to_string())
+    }
+}
+
+// Out of memory while building tables
+impl From<RuntimeError> for Error {
+    fn from(error: RuntimeError) -> Self {
+        Error::new(error.get_message())
+    }
+}
+
+impl From<Error> for RuntimeError {
+    fn from(error: Error) -> Self {
+        RuntimeError::new(error.to_string())
+    }
+}
+
+/// Serializes `value` with the default options. The tables are allocated
+/// but not rooted, like `IntoLua` values.
+pub fn to_value<T: Serialize + ?Sized>(gc: &mut GarbageCollector, value: &T) -> Result<Value, Error> {
+    to_value_with(gc, value, &Options::default())
+}
+
+pub fn to_value_with<T: Serialize + ?Sized>(
+    gc: &mut GarbageCollector,
+    value: &T,
+    options: &Options
+) -> Result<Value, Error> {
+    value.serialize(Serializer { gc, options })
+}
+
+/// Deserializes `value` with the default options
+pub fn from_value<T: DeserializeOwned>(gc: &GarbageCollector, value: Value) -> Result<T, Error> {
+    from_value_with(gc, value, &Options::default())
+}
+
+pub fn from_value_with<T: DeserializeOwned>(
+    gc: &GarbageCollector,
+    value: Value,
+    options: &Options
+) -> Result<T, Error> {
+    T::deserialize(Deserializer { value, gc, options })
+}
+
+// How a key shows in an error path
+fn segment(key: Value, gc: &GarbageCollector) -> Segment {
+    match key {
+        Value::Number(n) if n >= 1 => Segment::Index(n as usize),
+        Value::String(r) => Segment::Key(gc.get_string(r).map(|s| s.to_string()).unwrap_or_default()),
+        _ => Segment::Key(key.dbg_string(gc)),
+    }
+}
+
+// Collecting never happens while allocating, so the values built so far
+// don't need to be rooted
+struct Serializer<'a> {
+    gc: &'a mut GarbageCollector,
+    options: &'a Options,
+}
+
+impl Serializer<'_> {
+    fn table(&mut self, array: Vec<Value>, map: HashMap<Value, Value>) -> Result<Value, Error> {
+        let table = self.gc.allocate(Box::new(Table::new(array, map)))?;
+        Ok(Value::GcObject(table))
+    }
+
+    fn string(&mut self, s: &str) -> Result<Value, Error> {
+        Ok(self.gc.create_string(s)?)
+    }
+
+    fn variant(&mut self, variant: &'static str, content: Option<Value>) -> Result<Value, Error> {
+        let name = self.string(variant)?;
+        match (self.options.enums, content) {
+            (EnumRepr::External, None) => Ok(name),
+            (EnumRepr::External, Some(content)) => self.table(vec![], HashMap::from([(name, content)])),
+            (EnumRepr::Adjacent { tag, content: key }, content) => {
+                let mut map = HashMap::from([(self.string(tag)?, name)]);
+                if let Some(content) = content {
+                    map.insert(self.string(key)?, content);
+                }
+                self.table(vec![], map)
+            }
+        }
+    }
+
+    // The content of a variant shows under its name in error paths
+    fn variant_path(&self, variant: &'static str) -> Segment {
+        match self.options.enums {
+            EnumRepr::External => Segment::Key(variant.to_string()),
+            EnumRepr::Adjacent { content, .. } => Segment::Key(content.to_string()),
+        }
+    }
+
+    fn reborrow(&mut self) -> Serializer<'_> {
+        Serializer { gc: self.gc, options: self.options }
+    }
+}
+
+impl<'a> ser::Serializer for Serializer<'a> {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = SerializeSeq<'a>;
+    type SerializeTuple = SerializeSeq<'a>;
+    type SerializeTupleStruct = SerializeSeq<'a>;
+    type SerializeTupleVariant = SerializeSeq<'a>;
+    type SerializeMap = SerializeMap<'a>;
+    type SerializeStruct = SerializeMap<'a>;
+    type SerializeStructVariant = SerializeMap<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        Ok(Value::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        Ok(Value::Number(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        Ok(Value::Number(v.into()))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        match i64::try_from(v) {
+            Ok(n) => Ok(Value::Number(n)),
+            Err(_) => Err(Error::new(format!("integer {v} out of range"))),
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        Ok(Value::Float(v.into()))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        Ok(Value::Float(v))
+    }
+
+    fn serialize_char(mut self, v: char) -> Result<Value, Error> {
+        self.string(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(mut self, v: &str) -> Result<Value, Error> {
+        self.string(v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        Ok(self.gc.create_byte_string(v)?)
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(self.options.none)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(self.options.none)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        Ok(self.options.none)
+    }
+
+    fn serialize_unit_variant(
+        mut self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str
+    ) -> Result<Value, Error> {
+        self.variant(variant, None)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T
+    ) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        mut self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T
+    ) -> Result<Value, Error> {
+        let content = value
+            .serialize(self.reborrow())
+            .map_err(|e| e.at(self.variant_path(variant)))?;
+        self.variant(variant, Some(content))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeSeq<'a>, Error> {
+        Ok(SerializeSeq { ser: self, values: Vec::with_capacity(len.unwrap_or(0)), variant: None })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeSeq<'a>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize
+    ) -> Result<SerializeSeq<'a>, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize
+    ) -> Result<SerializeSeq<'a>, Error> {
+        Ok(SerializeSeq { ser: self, values: Vec::with_capacity(len), variant: Some(variant) })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeMap<'a>, Error> {
+        let pairs = self.options.maps == MapRepr::Pairs;
+        Ok(SerializeMap {
+            ser: self,
+            entries: Vec::with_capacity(len.unwrap_or(0)),
+            key: None,
+            pairs,
+            variant: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeMap<'a>, Error> {
+        Ok(SerializeMap {
+            ser: self,
+            entries: Vec::with_capacity(len),
+            key: None,
+            pairs: false,
+            variant: None,
+        })
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize
+    ) -> Result<SerializeMap<'a>, Error> {
+        Ok(SerializeMap {
+            ser: self,
+            entries: Vec::with_capacity(len),
+            key: None,
+            pairs: false,
+            variant: Some(variant),
+        })
+    }
+}
+
+// Sequences, tuples and the content of tuple variants
+pub struct SerializeSeq<'a> {
+    ser: Serializer<'a>,
+    values: Vec<Value>,
+    variant: Option<&'static str>,
+}
+
+impl SerializeSeq<'_> {
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let index = self.values.len() + 1;
+        let value = value.serialize(self.ser.reborrow()).map_err(|e| e.at(Segment::Index(index)))?;
+        self.values.push(value);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Value, Error> {
+        let values = std::mem::take(&mut self.values);
+        let table = self.ser.table(values, HashMap::new())?;
+        match self.variant {
+            Some(variant) => self.ser.variant(variant, Some(table)),
+            None => Ok(table),
+        }
+    }
+}
+
+impl ser::SerializeSeq for SerializeSeq<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeSeq<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeSeq<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeSeq<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let variant = self.variant.expect("A tuple variant has a name");
+        let path = self.ser.variant_path(variant);
+        self.element(value).map_err(|e| e.at(path))
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+// Maps, structs and the content of struct variants
+pub struct SerializeMap<'a> {
+    ser: Serializer<'a>,
+    entries: Vec<(Value, Value)>,
+    // Waiting for its value
+    key: Option<Value>,
+    // A sequence of `{ key, value }` for `MapRepr::Pairs`
+    pairs: bool,
+    variant: Option<&'static str>,
+}
+
+impl SerializeMap<'_> {
+    fn field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        let value = value
+            .serialize(self.ser.reborrow())
+            .map_err(|e| e.at(Segment::Key(key.to_string())))?;
+        let key = self.ser.string(key)?;
+        self.entries.push((key, value));
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Value, Error> {
+        let entries = std::mem::take(&mut self.entries);
+        let table = if self.pairs {
+            let mut pairs = Vec::with_capacity(entries.len());
+            for (key, value) in entries {
+                pairs.push(self.ser.table(vec![key, value], HashMap::new())?);
+            }
+            self.ser.table(pairs, HashMap::new())?
+        } else {
+            let mut map = HashMap::with_capacity(entries.len());
+            for (key, value) in entries {
+                match key {
+                    Value::Nil => {
+                        return Err(Error::new("map key is nil".to_string()));
+                    }
+                    Value::Float(f) if f.is_nan() => {
+                        return Err(Error::new("map key is NaN".to_string()));
+                    }
+                    _ => {}
+                }
+                map.insert(key, value);
+            }
+            self.ser.table(vec![], map)?
+        };
+        match self.variant {
+            Some(variant) => self.ser.variant(variant, Some(table)),
+            None => Ok(table),
+        }
+    }
+}
+
+impl ser::SerializeMap for SerializeMap<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(key.serialize(self.ser.reborrow())?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self.key.take().expect("serialize_value is called after serialize_key");
+        let value = value
+            .serialize(self.ser.reborrow())
+            .map_err(|e| e.at(segment(key, self.ser.gc)))?;
+        self.entries.push((key, value));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T
+    ) -> Result<(), Error> {
+        self.field(key, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeMap<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T
+    ) -> Result<(), Error> {
+        let variant = self.variant.expect("A struct variant has a name");
+        let path = self.ser.variant_path(variant);
+        self.field(key, value).map_err(|e| e.at(path))
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Deserializer<'a> {
+    value: Value,
+    gc: &'a GarbageCollector,
+    options: &'a Options,
+}
+
+// The array part, without holes, and the hash part of a table
+struct Entries {
+    array: Vec<Value>,
+    map: Vec<(Value, Value)>,
+}
+
+impl<'a> Deserializer<'a> {
+    fn with(&self, value: Value) -> Deserializer<'a> {
+        Deserializer { value, gc: self.gc, options: self.options }
+    }
+
+    fn is_none(&self) -> bool {
+        matches!(self.value, Value::Nil) || self.value == self.options.none
+    }
+
+    // Copied out so that no table is borrowed while visiting
+    fn entries(&self) -> Option<Entries> {
+        let Value::GcObject(r) = self.value else {
+            return None;
+        };
+        let object = self.gc.get(r)?;
+        let object = object.borrow();
+        let table = object.downcast_ref::<Table>()?;
+        Some(Entries {
+            array: table.array_part().to_vec(),
+            map: table
+                .map_part()
+                .iter()
+                .map(|(k, v)| (*k, *v))
+                .collect(),
+        })
+    }
+
+    fn unexpected(&self) -> Unexpected<'static> {
+        match self.value {
+            Value::Bool(b) => Unexpected::Bool(b),
+            Value::Number(n) => Unexpected::Signed(n),
+            Value::Float(f) => Unexpected::Float(f),
+            _ => Unexpected::Other(self.value.type_name(self.gc)),
+        }
+    }
+
+    fn invalid_type(&self, expected: &dyn de::Expected) -> Error {
+        de::Error::invalid_type(self.unexpected(), expected)
+    }
+
+    fn visit_sequence<'de, V: Visitor<'de>>(
+        &self,
+        array: Vec<Value>,
+        visitor: V
+    ) -> Result<V::Value, Error> {
+        let mut access = SeqAccess { values: array.into_iter(), index: 0, de: *self };
+        let value = visitor.visit_seq(&mut access)?;
+        if access.values.len() > 0 {
+            return Err(de::Error::invalid_length(access.index + access.values.len(), &"fewer elements"));
+        }
+        Ok(value)
+    }
+
+    fn visit_entries<'de, V: Visitor<'de>>(
+        &self,
+        entries: Entries,
+        visitor: V
+    ) -> Result<V::Value, Error> {
+        let sequence = entries.array
+            .into_iter()
+            .enumerate()
+            .filter(|(_, v)| !matches!(v, Value::Nil))
+            .map(|(i, v)| (Value::Number((i as i64) + 1), v));
+        let entries = sequence.chain(entries.map).collect::<Vec<_>>();
+        visitor.visit_map(MapAccess { entries: entries.into_iter(), value: None, de: *self })
+    }
+
+    // `{ { key, value }, ... }` for `MapRepr::Pairs`
+    fn visit_pairs<'de, V: Visitor<'de>>(
+        &self,
+        entries: Entries,
+        visitor: V
+    ) -> Result<V::Value, Error> {
+        if !entries.map.is_empty() {
+            return Err(Error::new("table of pairs is not a sequence".to_string()));
+        }
+        let mut pairs = Vec::with_capacity(entries.array.len());
+        for (i, pair) in entries.array.into_iter().enumerate() {
+            match self.with(pair).entries() {
+                Some(Entries { array, map }) if array.len() == 2 && map.is_empty() => {
+                    pairs.push((array[0], array[1]));
+                }
+                _ => {
+                    let error = Error::new("{ key, value } pair expected".to_string());
+                    return Err(error.at(Segment::Index(i + 1)));
+                }
+            }
+        }
+        visitor.visit_map(MapAccess { entries: pairs.into_iter(), value: None, de: *self })
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.is_none() {
+            return visitor.visit_unit();
+        }
+        match self.value {
+            Value::Bool(b) => visitor.visit_bool(b),
+            Value::Number(n) => visitor.visit_i64(n),
+            Value::Float(f) => visitor.visit_f64(f),
+            Value::String(r) => {
+                let bytes = self.gc.get_bytes(r).unwrap_or_default();
+                match String::from_utf8(bytes.to_vec()) {
+                    Ok(s) => visitor.visit_string(s),
+                    Err(e) => visitor.visit_byte_buf(e.into_bytes()),
+                }
+            }
+            _ => {
+                match self.entries() {
+                    Some(entries) if entries.map.is_empty() && !entries.array.is_empty() => {
+                        self.visit_sequence(entries.array, visitor)
+                    }
+                    Some(entries) => self.visit_entries(entries, visitor),
+                    None => Err(self.invalid_type(&visitor)),
+                }
+            }
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    // Floats with an integer value are integers, like in `math.tointeger`
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Float(f) => {
+                match stdlib::float_to_integer(f) {
+                    Some(n) => visitor.visit_i64(n),
+                    None => Err(self.invalid_type(&visitor)),
+                }
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.is_none() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.is_none() {
+            visitor.visit_unit()
+        } else {
+            Err(self.invalid_type(&visitor))
+        }
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V
+    ) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::String(r) => {
+                visitor.visit_byte_buf(self.gc.get_bytes(r).unwrap_or_default().to_vec())
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.entries() {
+            Some(entries) if entries.map.is_empty() => self.visit_sequence(entries.array, visitor),
+            Some(_) => Err(Error::new("table is not a sequence".to_string())),
+            None => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.entries() {
+            Some(entries) if self.options.maps == MapRepr::Pairs => self.visit_pairs(entries, visitor),
+            Some(entries) => self.visit_entries(entries, visitor),
+            None => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V
+    ) -> Result<V::Value, Error> {
+        match self.entries() {
+            Some(entries) => self.visit_entries(entries, visitor),
+            None => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V
+    ) -> Result<V::Value, Error> {
+        let name = |value: Value| {
+            match value {
+                Value::String(r) => self.gc.get_string(r).map(|s| s.to_string()),
+                _ => None,
+            }
+        };
+        // Unit variants may be written as their name either way
+        if let Some(variant) = name(self.value) {
+            return visitor.visit_enum(EnumAccess { variant, content: None, de: self });
+        }
+        let Some(entries) = self.entries() else {
+            return Err(self.invalid_type(&visitor));
+        };
+        match self.options.enums {
+            EnumRepr::External => {
+                match entries.map.as_slice() {
+                    [(key, content)] if entries.array.is_empty() && name(*key).is_some() => {
+                        let variant = name(*key).unwrap();
+                        let path = Segment::Key(variant.clone());
+                        let content = Some((*content, path));
+                        visitor.visit_enum(EnumAccess { variant, content, de: self })
+                    }
+                    _ => Err(Error::new("table with a single variant name as key expected".to_string())),
+                }
+            }
+            EnumRepr::Adjacent { tag, content: key } => {
+                let field = |field: &str| {
+                    entries.map
+                        .iter()
+                        .find(|(k, _)| name(*k).as_deref() == Some(field))
+                        .map(|(_, v)| *v)
+                };
+                let Some(variant) = field(tag).and_then(name) else {
+                    return Err(Error::new(format!("missing variant name in field `{tag}`")));
+                };
+                let content = field(key).map(|v| (v, Segment::Key(key.to_string())));
+                visitor.visit_enum(EnumAccess { variant, content, de: self })
+            }
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    ::serde::forward_to_deserialize_any! {
+        bool f32 f64 char str string identifier
+    }
+}
+
+struct SeqAccess<'a> {
+    values: vec::IntoIter<Value>,
+    index: usize,
+    de: Deserializer<'a>,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Error> {
+        let Some(value) = self.values.next() else {
+            return Ok(None);
+        };
+        self.index += 1;
+        let index = self.index;
+        seed.deserialize(self.de.with(value))
+            .map(Some)
+            .map_err(|e| e.at(Segment::Index(index)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.values.len())
+    }
+}
+
+struct MapAccess<'a> {
+    entries: vec::IntoIter<(Value, Value)>,
+    // The key and value of the entry whose key was just read
+    value: Option<(Value, Value)>,
+    de: Deserializer<'a>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        let Some((key, value)) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.value = Some((key, value));
+        seed.deserialize(self.de.with(key))
+            .map(Some)
+            .map_err(|e| e.at(segment(key, self.de.gc)))
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (key, value) = self.value.take().expect("next_value_seed is called after next_key_seed");
+        seed.deserialize(self.de.with(value)).map_err(|e| e.at(segment(key, self.de.gc)))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+struct EnumAccess<'a> {
+    variant: String,
+    // The content and where it is, none for unit variants
+    content: Option<(Value, Segment)>,
+    de: Deserializer<'a>,
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for EnumAccess<'a> {
+    type Error = Error;
+    type Variant = VariantAccess<'a>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V
+    ) -> Result<(V::Value, VariantAccess<'a>), Error> {
+        let deserializer: de::value::StringDeserializer<Error> = self.variant.into_deserializer();
+        let variant = seed.deserialize(deserializer)?;
+        Ok((variant, VariantAccess { content: self.content, de: self.de }))
+    }
+}
+
+struct VariantAccess<'a> {
+    content: Option<(Value, Segment)>,
+    de: Deserializer<'a>,
+}
+
+impl VariantAccess<'_> {
+    fn content<T>(
+        self,
+        expected: &dyn de::Expected,
+        read: impl FnOnce(Deserializer<'_>) -> Result<T, Error>
+    ) -> Result<T, Error> {
+        match self.content {
+            Some((value, path)) => read(self.de.with(value)).map_err(|e| e.at(path)),
+            None => Err(de::Error::invalid_type(Unexpected::UnitVariant, expected)),
+        }
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for VariantAccess<'_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        self.content(&"newtype variant", |de| seed.deserialize(de))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        self.content(&"tuple variant", |de| de::Deserializer::deserialize_seq(de, visitor))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V
+    ) -> Result<V::Value, Error> {
+        self.content(&"struct variant", |de| {
+            match de.entries() {
+                Some(entries) => de.visit_entries(entries, visitor),
+                None => Err(de.invalid_type(&"struct variant")),
+            }
+        })
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::{ HashMap, VecDeque };
+
+use super::gc::GcRef;
+
+/// Every object on the heap at one point in time, see
+/// `GarbageCollector::heap_snapshot`
+#[derive(Debug, Clone)]
+pub struct HeapSnapshot {
+    pub roots: Vec<(String, GcRef)>,
+    pub objects: Vec<HeapObject>,
+    index: HashMap<GcRef, usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeapObject {
+    pub id: GcRef,
+    /// `GcValue::name`, or "string"
+    pub type_name: &'static str,
+    /// The contents for strings, the type name for everything else
+    pub name: String,
+    pub size: usize,
+    pub references: Vec<(String, GcRef)>,
+}
+
+/// One hop on the way from a root to a retained object
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetainerStep {
+    /// Root variable name for the first step, the edge label after that
+    pub edge: String,
+    pub object: GcRef,
+    pub type_name: &'static str,
+}
+
+impl HeapSnapshot {
+    pub fn new(roots: Vec<(String, GcRef)>, objects: Vec<HeapObject>) -> Self {
+        let index = objects
+            .iter()
+            .enumerate()
+            .map(|(i, o)| (o.id, i))
+            .collect();
+        HeapSnapshot { roots, objects, index }
+    }
+
+    pub fn get(&self, id: GcRef) -> Option<&HeapObject> {
+        self.index.get(&id).map(|i| &self.objects[*i])
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.objects
+            .iter()
+            .map(|o| o.size)
+            .sum()
+    }
+
+    /// Answers "why is this object still alive?" with the shortest chain of
+    /// references from a root to `target`, or None if it is garbage.
+    pub fn retainer_path(&self, target: GcRef) -> Option<Vec<RetainerStep>> {
+        // Breadth first, so the first path found is the shortest
+        let mut previous: HashMap<GcRef, Option<(GcRef, &str)>> = HashMap::new();
+        let mut root_names: HashMap<GcRef, &str> = HashMap::new();
+        let mut queue = VecDeque::new();
+
+        for (name, root) in self.roots.iter() {
+            if self.get(*root).is_some() && !previous.contains_key(root) {
+                previous.insert(*root, None);
+                root_names.insert(*root, name.as_str());
+                queue.push_back(*root);
+            }
+        }
+
+        while let Some(current) = queue.pop_front() {
+            if current == target {
+                break;
+            }
+            for (edge, child) in self.get(current).unwrap().references.iter() {
+                if self.get(*child).is_some() && !previous.contains_key(child) {
+                    previous.insert(*child, Some((current, edge.as_str())));
+                    queue.push_back(*child);
+                }
+            }
+        }
+
+        if !previous.contains_key(&target) {
+            return None;
+        }
+
+        let mut path = vec![];
+        let mut current = target;
+        loop {
+            let object = self.get(current).unwrap();
+            match previous[&current] {
+                Some((parent, edge)) => {
+                    path.push(RetainerStep {
+                        edge: edge.to_string(),
+                        object: current,
+                        type_name: object.type_name,
+                    });
+                    current = parent;
+                }
+                None => {
+                    path.push(RetainerStep {
+                        edge: root_names[&current].to_string(),
+                        object: current,
+                        type_name: object.type_name,
+                    });
+                    break;
+                }
+            }
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    /// Plain JSON: `{"roots": [...], "objects": [{"id", "type", "name", "size", "references"}]}`
+    pub fn to_json(&self) -> String {
+        let roots = self.roots
+            .iter()
+            .map(|(name, r)| format!("{{\"name\":{},\"id\":{}}}", escape_json(name), r.id()))
+            .collect::<Vec<String>>()
+            .join(",");
+        let objects = self.objects
+            .iter()
+            .map(|o| {
+                let references = o.references
+                    .iter()
+                    .map(|(edge, r)| format!("{{\"edge\":{},\"id\":{}}}", escape_json(edge), r.id()))
+                    .collect::<Vec<String>>()
+                    .join(",");
+                format!(
+                    "{{\"id\":{},\"type\":{},\"name\":{},\"size\":{},\"references\":[{}]}}",
+                    o.id.id(),
+                    escape_json(o.type_name),
+                    escape_json(&o.name),
+                    o.size,
+                    references
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+
+        format!("{{\"roots\":[{roots}],\"objects\":[{objects}]}}")
+    }
+
+    /// The `.heapsnapshot` format loaded by the memory tab of Chrome DevTools.
+    /// Roots hang off a synthetic "(GC roots)" node.
+    pub fn to_chrome_heap_snapshot(&self) -> String {
+        const NODE_FIELDS: usize = 6;
+        // Indices into the node and edge type lists declared in the meta
+        const NODE_STRING: usize = 2;
+        const NODE_OBJECT: usize = 3;
+        const NODE_CLOSURE: usize = 5;
+        const NODE_NATIVE: usize = 8;
+        const NODE_SYNTHETIC: usize = 9;
+        const EDGE_ELEMENT: usize = 1;
+        const EDGE_PROPERTY: usize = 2;
+
+        let mut strings: Vec<String> = vec![];
+        let mut string_ids: HashMap<String, usize> = HashMap::new();
+        let mut intern = |s: &str| -> usize {
+            if let Some(id) = string_ids.get(s) {
+                return *id;
+            }
+            strings.push(s.to_string());
+            string_ids.insert(s.to_string(), strings.len() - 1);
+            strings.len() - 1
+        };
+
+        let mut nodes: Vec<usize> = vec![];
+        let mut edges: Vec<usize> = vec![];
+
+        // Node offsets are shifted by one for the synthetic root
+        let to_node = |r: &GcRef| (self.index[r] + 1) * NODE_FIELDS;
+
+        let roots: Vec<&(String, GcRef)> = self.roots
+            .iter()
+            .filter(|(_, r)| self.index.contains_key(r))
+            .collect();
+        nodes.extend([NODE_SYNTHETIC, intern("(GC roots)"), 0, 0, roots.len(), 0]);
+        for (name, r) in roots {
+            edges.extend([EDGE_PROPERTY, intern(name), to_node(r)]);
+        }
+
+        for object in self.objects.iter() {
+            let node_type = match object.type_name {
+                "string" => NODE_STRING,
+                "table" => NODE_OBJECT,
+                "function" => NODE_CLOSURE,
+                _ => NODE_NATIVE,
+            };
+            let references: Vec<&(String, GcRef)> = object.references
+                .iter()
+                .filter(|(_, r)| self.index.contains_key(r))
+                .collect();
+            nodes.extend([
+                node_type,
+                intern(&object.name),
+                (object.id.id() as usize) + 1,
+                object.size,
+                references.len(),
+                0,
+            ]);
+            for (edge, r) in references {
+                // Array slots are labeled "[n]" and exported as element edges
+                let index = edge
+                    .strip_prefix('[')
+                    .and_then(|e| e.strip_suffix(']'))
+                    .and_then(|e| e.parse::<usize>().ok());
+                match index {
+                    Some(i) => edges.extend([EDGE_ELEMENT, i, to_node(r)]),
+                    None => edges.extend([EDGE_PROPERTY, intern(edge), to_node(r)]),
+                }
+            }
+        }
+
+        let join = |v: &[usize]| {
+            v.iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        let strings = strings
+            .iter()
+            .map(|s| escape_json(s))
+            .collect::<Vec<String>>()
+            .join(",");
+
+        format!(
+            concat!(
+                "{{\"snapshot\":{{\"meta\":{{",
+                "\"node_fields\":[\"type\",\"name\",\"id\",\"self_size\",\"edge_count\",\"trace_node_id\"],",
+                "\"node_types\":[[\"hidden\",\"array\",\"string\",\"object\",\"code\",\"closure\",\"regexp\",",
+                "\"number\",\"native\",\"synthetic\",\"concatenated string\",\"sliced string\"],",
+                "\"string\",\"number\",\"number\",\"number\",\"number\"],",
+                "\"edge_fields\":[\"type\",\"name_or_index\",\"to_node\"],",
+                "\"edge_types\":[[\"context\",\"element\",\"property\",\"internal\",\"hidden\",\"shortcut\",\"weak\"],",
+                "\"string_or_number\",\"node\"],",
+                "\"trace_function_info_fields\":[],\"trace_node_fields\":[],",
+                "\"sample_fields\":[],\"location_fields\":[]}},",
+                "\"node_count\":{},\"edge_count\":{},\"trace_function_count\":0}},",
+                "\"nodes\":[{}],\"edges\":[{}],\"trace_function_info\":[],\"trace_tree\":[],",
+                "\"samples\":[],\"locations\":[],\"strings\":[{}]}}"
+            ),
+            nodes.len() / NODE_FIELDS,
+            edges.len() / 3,
+            join(&nodes),
+            join(&edges),
+            strings
+        )
+    }
+}
+
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,117 @@
+//! Standard streams of an interpreter. `print`, `input` and the `io`
+//! library use them instead of the process' stdio, embedders can point them
+//! at any [`Write`]/[`BufRead`], a closure or a [`Captured`] buffer.
+
+use std::io::{ self, BufRead, Read, Write };
+
+use crate::sync::{ MaybeSend, Rc, RefCell };
+
+// The streams as the interpreter keeps them
+pub(crate) trait Output: Write + MaybeSend {}
+impl<T: Write + MaybeSend> Output for T {}
+pub(crate) trait Input: BufRead + MaybeSend {}
+impl<T: BufRead + MaybeSend> Input for T {}
+
+/// Everything written to it is kept in memory, clones share the buffer.
+/// Handy to check what a script printed, see [`Interpreter::capture_stdout`].
+///
+/// [`Interpreter::capture_stdout`]: super::interpreter::Interpreter::capture_stdout
+#[derive(Debug, Clone, Default)]
+pub struct Captured {
+    buffer: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Captured {
+    pub fn new() -> Self {
+        Captured::default()
+    }
+
+    /// What was written so far, invalid UTF-8 is replaced
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.buffer.borrow()).into_owned()
+    }
+
+    /// Like `contents`, but empties the buffer
+    pub fn take(&self) -> String {
+        let bytes = std::mem::take(&mut *self.buffer.borrow_mut());
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An output stream calling `f` with each chunk of text, e.g. to append to
+/// a GUI console
+pub fn writer_fn<F: FnMut(&str)>(f: F) -> FnWriter<F> {
+    FnWriter(f)
+}
+
+pub struct FnWriter<F>(F);
+
+impl<F: FnMut(&str)> Write for FnWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (self.0)(&String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An input stream asking `f` for more text whenever it runs dry, None
+/// ends the input
+pub fn reader_fn<F: FnMut() -> Option<String>>(f: F) -> FnReader<F> {
+    FnReader { f, buffer: vec![], position: 0, done: false }
+}
+
+pub struct FnReader<F> {
+    f: F,
+    buffer: Vec<u8>,
+    position: usize,
+    done: bool,
+}
+
+impl<F: FnMut() -> Option<String>> Read for FnReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<F: FnMut() -> Option<String>> BufRead for FnReader<F> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // An empty chunk isn't the end, ask again
+        while self.position >= self.buffer.len() && !self.done {
+            match (self.f)() {
+                Some(text) => {
+                    self.buffer = text.into_bytes();
+                    self.position = 0;
+                }
+                None => {
+                    self.done = true;
+                }
+            }
+        }
+        if self.done && self.position >= self.buffer.len() {
+            return Ok(&[]);
+        }
+        Ok(&self.buffer[self.position..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position = (self.position + amount).min(self.buffer.len());
+    }
+}
@@ -0,0 +1,551 @@
+use std::{ collections::HashMap, fs, io::{ BufRead, Read, Write } };
+
+use crate::{
+    errors::RuntimeError,
+    eval::{
+        chunk::{ chunk_id, syntax_error, Chunk },
+        gc::{ GarbageCollector, GcRef },
+        interpreter::Interpreter,
+        types::{ Function, InterpreterFunction, Table },
+        value::Value,
+    },
+};
+
+use super::{
+    bad_argument,
+    check_bytes,
+    check_integer,
+    check_string,
+    require,
+    str_to_number,
+    string,
+    table,
+    type_error,
+    Capabilities,
+};
+
+const FUNCTIONS: &[(&str, InterpreterFunction)] = &[
+    ("assert", assert),
+    ("collectgarbage", collectgarbage),
+    ("dofile", dofile),
+    ("error", error),
+    ("getmetatable", getmetatable),
+    ("input", input),
+    ("load", load),
+    ("loadstring", loadstring),
+    ("next", next),
+    ("pcall", pcall),
+    ("print", print),
+    ("rawequal", rawequal),
+    ("rawget", rawget),
+    ("rawlen", rawlen),
+    ("rawset", rawset),
+    ("select", select),
+    ("setmetatable", setmetatable),
+    ("tonumber", tonumber),
+    ("tostring", tostring),
+    ("type", lua_type),
+    ("unpack", table::unpack),
+];
+
+pub fn open(interpreter: &mut Interpreter) -> Result<(), RuntimeError> {
+    for (name, function) in FUNCTIONS {
+        interpreter.add_global_interpreter_function(name, *function)?;
+    }
+    let version = interpreter.create_string("Lua 5.4")?;
+    interpreter.set_global("_VERSION", version);
+    open_globals_table(interpreter)
+}
+
+// Globals live in the environment rather than a table, so `_G` is an empty
+// proxy whose metatable forwards string keys to them. Other keys are stored
+// in the proxy itself.
+fn open_globals_table(interpreter: &mut Interpreter) -> Result<(), RuntimeError> {
+    let proxy = interpreter.allocate(Box::new(Table::new(vec![], HashMap::new())))?;
+    interpreter.set_global("_G", Value::GcObject(proxy));
+    let metatable = interpreter.allocate(Box::new(Table::new(vec![], HashMap::new())))?;
+    let object = interpreter.gc.get(proxy).unwrap();
+    object.borrow_mut().downcast_mut::<Table>().unwrap().set_metatable(Some(metatable));
+
+    let index = interpreter.allocate(Box::new(Function::FnPointerInterpreter(global_index)))?;
+    interpreter.set_field(metatable, "__index", Value::GcObject(index))?;
+    let newindex = interpreter.allocate(Box::new(Function::FnPointerInterpreter(global_newindex)))?;
+    interpreter.set_field(metatable, "__newindex", Value::GcObject(newindex))?;
+    Ok(())
+}
+
+fn global_index(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    match args.get(1) {
+        Some(Value::String(r)) => {
+            let name = interpreter.gc.get_string(*r).unwrap();
+            Ok(vec![interpreter.get_global(&name)])
+        }
+        _ => Ok(vec![Value::Nil]),
+    }
+}
+
+fn global_newindex(
+    interpreter: &mut Interpreter,
+    args: &[Value]
+) -> Result<Vec<Value>, RuntimeError> {
+    let (Some(Value::GcObject(proxy)), Some(key)) = (args.first(), args.get(1)) else {
+        return Ok(vec![]);
+    };
+    let value = args.get(2).copied().unwrap_or(Value::Nil);
+    match key {
+        Value::String(r) => {
+            let name = interpreter.gc.get_string(*r).unwrap();
+            interpreter.set_global(&name, value);
+        }
+        _ => interpreter.raw_set(*proxy, *key, value)?,
+    }
+    Ok(vec![])
+}
+
+/// Converts any value to a string like Lua's `tostring`, calling its
+/// `__tostring` metamethod or naming it after `__name`
+pub(crate) fn tostring_bytes(
+    interpreter: &mut Interpreter,
+    value: Value
+) -> Result<Vec<u8>, RuntimeError> {
+    let handler = interpreter.get_metamethod(value, "__tostring");
+    if !matches!(handler, Value::Nil) {
+        return match interpreter.call(handler, &[value])?.first() {
+            Some(Value::String(r)) => Ok(interpreter.gc.get_bytes(*r).unwrap().to_vec()),
+            _ => Err(RuntimeError::new("'__tostring' must return a string".to_string())),
+        };
+    }
+    Ok(match value {
+        Value::Nil => b"nil".to_vec(),
+        Value::Bool(b) => b.to_string().into_bytes(),
+        Value::Number(n) => n.to_string().into_bytes(),
+        Value::Float(f) => float_to_string(f).into_bytes(),
+        Value::String(r) => interpreter.gc.get_bytes(r).unwrap().to_vec(),
+        Value::GcObject(r) => {
+            let name = match interpreter.get_metamethod(value, "__name") {
+                Value::String(name) => interpreter.gc.get_string(name).unwrap().to_string(),
+                _ => value.type_name(&interpreter.gc).to_string(),
+            };
+            format!("{name}: 0x{:08x}", r.id()).into_bytes()
+        }
+    })
+}
+
+// Lua's "%.14g", with ".0" added when it would read back as an integer
+fn float_to_string(f: f64) -> String {
+    if f.is_nan() {
+        return if f.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if f.is_infinite() {
+        return if f < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    let formatted = string::format_general(f, 14, false);
+    if formatted.bytes().all(|c| c.is_ascii_digit() || c == b'-') {
+        return formatted + ".0";
+    }
+    formatted
+}
+
+// Writes its arguments to the interpreter's stdout, failures are ignored like
+// Lua's print does
+fn print(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let mut line = vec![];
+    for (i, value) in args.iter().enumerate() {
+        if i > 0 {
+            line.push(b'\t');
+        }
+        let text = tostring_bytes(interpreter, *value)?;
+        line.extend_from_slice(&text);
+    }
+    line.push(b'\n');
+    let _ = interpreter.stdout.write_all(&line);
+    let _ = interpreter.stdout.flush();
+    Ok(vec![])
+}
+
+// input([prompt]) reads a line from stdin without its newline, nil at the end
+fn input(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    if !args.is_empty() {
+        let prompt = check_bytes(&interpreter.gc, args, 0, "input")?;
+        let _ = interpreter.stdout.write_all(&prompt);
+        let _ = interpreter.stdout.flush();
+    }
+    let mut line = vec![];
+    match interpreter.stdin.read_until(b'\n', &mut line) {
+        Ok(0) => Ok(vec![Value::Nil]),
+        Ok(_) => {
+            let line = line.strip_suffix(b"\n").unwrap_or(&line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            Ok(vec![interpreter.create_byte_string(line)?])
+        }
+        Err(e) => Err(RuntimeError::new(format!("input: {e}"))),
+    }
+}
+
+fn collectgarbage(
+    interpreter: &mut Interpreter,
+    args: &[Value]
+) -> Result<Vec<Value>, RuntimeError> {
+    let option = match args.first() {
+        None | Some(Value::Nil) => "collect".to_string(),
+        Some(v @ Value::String(_)) => v.to_string(&interpreter.gc),
+        Some(v) =>
+            {
+                return Err(
+                    RuntimeError::new(
+                        format!(
+                            "bad argument #1 to 'collectgarbage' (string expected, got {})",
+                            v.type_name(&interpreter.gc)
+                        )
+                    )
+                );
+            }
+    };
+
+    match option.as_str() {
+        "collect" => {
+            interpreter.collect_garbage();
+            Ok(vec![Value::Number(0)])
+        }
+        "step" => {
+            interpreter.collect_garbage();
+            Ok(vec![Value::Bool(true)])
+        }
+        "count" => Ok(vec![Value::Float((interpreter.gc.bytes_in_use() as f64) / 1024.0)]),
+        "isrunning" => Ok(vec![Value::Bool(true)]),
+        _ =>
+            Err(
+                RuntimeError::new(
+                    format!("bad argument #1 to 'collectgarbage' (invalid option '{option}')")
+                )
+            ),
+    }
+}
+
+fn getmetatable(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let value = args.first().copied().unwrap_or(Value::Nil);
+    let Some(metatable) = interpreter.metatable_of(value) else {
+        return Ok(vec![Value::Nil]);
+    };
+    // A `__metatable` field hides the real metatable
+    match interpreter.get_metamethod(value, "__metatable") {
+        Value::Nil => Ok(vec![Value::GcObject(metatable)]),
+        protected => Ok(vec![protected]),
+    }
+}
+
+fn setmetatable(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let gc = &interpreter.gc;
+    let table = match args.first() {
+        Some(Value::GcObject(r)) if gc.get(*r).is_some_and(|t| t.borrow().is::<Table>()) => *r,
+        _ => {
+            return Err(type_error(gc, args, 0, "setmetatable", "table"));
+        }
+    };
+    let metatable = match args.get(1) {
+        Some(Value::Nil) => None,
+        Some(Value::GcObject(r)) if gc.get(*r).is_some_and(|t| t.borrow().is::<Table>()) => {
+            Some(*r)
+        }
+        _ => {
+            return Err(type_error(gc, args, 1, "setmetatable", "nil or table"));
+        }
+    };
+    if !matches!(interpreter.get_metamethod(args[0], "__metatable"), Value::Nil) {
+        return Err(RuntimeError::new("cannot change a protected metatable".to_string()));
+    }
+
+    let object = interpreter.gc.get(table).unwrap();
+    let mut object = object.borrow_mut();
+    object.downcast_mut::<Table>().unwrap().set_metatable(metatable);
+    Ok(vec![args[0]])
+}
+
+fn check_table(
+    gc: &GarbageCollector,
+    args: &[Value],
+    i: usize,
+    function: &str
+) -> Result<GcRef, RuntimeError> {
+    match args.get(i) {
+        Some(Value::GcObject(r)) if gc.get(*r).is_some_and(|t| t.borrow().is::<Table>()) => Ok(*r),
+        _ => Err(type_error(gc, args, i, function, "table")),
+    }
+}
+
+// Like Lua's luaL_checkany, nil is fine but the argument has to be there
+fn check_any(args: &[Value], i: usize, function: &str) -> Result<Value, RuntimeError> {
+    args.get(i).copied().ok_or_else(|| bad_argument(i, function, "value expected"))
+}
+
+fn lua_type(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let value = check_any(args, 0, "type")?;
+    let name = value.type_name(&interpreter.gc);
+    Ok(vec![interpreter.create_string(name)?])
+}
+
+fn tostring(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let value = check_any(args, 0, "tostring")?;
+    let text = tostring_bytes(interpreter, value)?;
+    Ok(vec![interpreter.create_byte_string(&text)?])
+}
+
+// tonumber(v [, base]) converts strings, anything else that isn't a number
+// gives nil. With a base the string has to be an integer in that base.
+fn tonumber(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let gc = &interpreter.gc;
+    if let None | Some(Value::Nil) = args.get(1) {
+        return match check_any(args, 0, "tonumber")? {
+            v @ (Value::Number(_) | Value::Float(_)) => Ok(vec![v]),
+            Value::String(r) => {
+                Ok(vec![str_to_number(&gc.get_string(r).unwrap()).unwrap_or(Value::Nil)])
+            }
+            _ => Ok(vec![Value::Nil]),
+        };
+    }
+
+    let base = check_integer(gc, args, 1, "tonumber")?;
+    let s = match args.first() {
+        Some(Value::String(r)) => gc.get_string(*r).unwrap(),
+        _ => {
+            return Err(type_error(gc, args, 0, "tonumber", "string"));
+        }
+    };
+    if !(2..=36).contains(&base) {
+        return Err(bad_argument(1, "tonumber", "base out of range"));
+    }
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s),
+    };
+    if digits.is_empty() {
+        return Ok(vec![Value::Nil]);
+    }
+    let mut n: i64 = 0;
+    for c in digits.chars() {
+        let Some(digit) = c.to_digit(base as u32) else {
+            return Ok(vec![Value::Nil]);
+        };
+        // Overflow wraps around like Lua
+        n = n.wrapping_mul(base).wrapping_add(digit as i64);
+    }
+    Ok(vec![Value::Number(if negative { n.wrapping_neg() } else { n })])
+}
+
+// select(n, ...) returns the arguments after the n-th, negative n count from
+// the end. select("#", ...) counts them.
+fn select(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let gc = &interpreter.gc;
+    if let Some(Value::String(r)) = args.first() {
+        if &*gc.get_string(*r).unwrap() == "#" {
+            return Ok(vec![Value::Number((args.len() as i64) - 1)]);
+        }
+    }
+    // Indices into `args`, where the selector itself is 0
+    let top = args.len() as i64;
+    let mut n = check_integer(gc, args, 0, "select")?;
+    if n < 0 {
+        n += top;
+    } else if n > top {
+        n = top;
+    }
+    if n < 1 {
+        return Err(bad_argument(0, "select", "index out of range"));
+    }
+    Ok(args[n as usize..].to_vec())
+}
+
+// assert(v [, message, ...]) returns all its arguments when v is truthy
+fn assert(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    if check_any(args, 0, "assert")?.is_truthy() {
+        return Ok(args.to_vec());
+    }
+    match args.get(1) {
+        None | Some(Value::Nil) => Err(RuntimeError::new("assertion failed!".to_string())),
+        Some(message) => Err(RuntimeError::new(message.to_string(&interpreter.gc))),
+    }
+}
+
+// error(message) raises an error that `pcall` can catch. Without positions
+// to add, a string or number is the message as is.
+fn error(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let message = match args.first() {
+        None | Some(Value::Nil) => "nil".to_string(),
+        Some(v @ (Value::String(_) | Value::Number(_) | Value::Float(_))) => v.to_string(&interpreter.gc),
+        Some(v) => format!("(error object is a {} value)", v.type_name(&interpreter.gc)),
+    };
+    Err(RuntimeError::new(message))
+}
+
+// pcall(f, ...) calls f, giving true and its results, or false and the
+// message of the error it raised, running out of memory included
+fn pcall(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let function = check_any(args, 0, "pcall")?;
+    let depth = interpreter.depth();
+    match interpreter.call(function, &args[1..]) {
+        Ok(results) => Ok([&[Value::Bool(true)], &results[..]].concat()),
+        Err(e) => {
+            // What the failed call left behind is garbage now, so the
+            // message can be made even if memory ran out
+            interpreter.unwind_to(depth);
+            Ok(vec![Value::Bool(false), interpreter.create_string(&e.get_message())?])
+        }
+    }
+}
+
+fn rawequal(_interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let a = check_any(args, 0, "rawequal")?;
+    let b = check_any(args, 1, "rawequal")?;
+    Ok(vec![a.equal(&b)])
+}
+
+fn rawlen(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let gc = &interpreter.gc;
+    match args.first() {
+        Some(Value::String(r)) => Ok(vec![Value::Number(gc.get_bytes(*r).unwrap().len() as i64)]),
+        Some(Value::GcObject(r)) => {
+            let object = gc.get(*r).unwrap();
+            let length = object.borrow().downcast_ref::<Table>().map(Table::length);
+            length
+                .map(|n| vec![Value::Number(n)])
+                .ok_or_else(|| bad_argument(0, "rawlen", "table or string expected"))
+        }
+        _ => Err(bad_argument(0, "rawlen", "table or string expected")),
+    }
+}
+
+fn rawget(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let table = check_table(&interpreter.gc, args, 0, "rawget")?;
+    let key = check_any(args, 1, "rawget")?;
+    let object = interpreter.gc.get(table).unwrap();
+    let value = object.borrow().index(key).unwrap_or(Value::Nil);
+    Ok(vec![value])
+}
+
+fn rawset(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let table = check_table(&interpreter.gc, args, 0, "rawset")?;
+    let key = check_any(args, 1, "rawset")?;
+    let value = check_any(args, 2, "rawset")?;
+    interpreter.raw_set(table, key, value)?;
+    Ok(vec![args[0]])
+}
+
+// next(t [, k]) the entry after k, or the first one without k. Generic
+// `for` doesn't pass state yet, so this is mostly for checking emptiness
+// and walking tables by hand.
+fn next(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let table = check_table(&interpreter.gc, args, 0, "next")?;
+    let key = args.get(1).copied().unwrap_or(Value::Nil);
+    let object = interpreter.gc.get(table).unwrap();
+    let object = object.borrow();
+    match object.downcast_ref::<Table>().unwrap().next(key) {
+        Ok(Some((key, value))) => Ok(vec![key, value]),
+        Ok(None) => Ok(vec![Value::Nil]),
+        Err(()) => Err(RuntimeError::new("invalid key to 'next'".to_string())),
+    }
+}
+
+// load(chunk [, chunkname [, mode [, env]]]) compiles a string, or the
+// concatenated pieces returned by a reader function, into a function. A
+// chunk that doesn't parse gives nil and the error.
+fn load(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let (source, default_name) = match args.first() {
+        Some(Value::String(r)) => {
+            let source = interpreter.gc.get_string(*r).unwrap().to_string();
+            (source.clone(), source)
+        }
+        Some(reader) if interpreter.is_function(*reader) => {
+            match read_pieces(interpreter, *reader) {
+                Ok(source) => (source, "=(load)".to_string()),
+                Err(e) => {
+                    return Ok(vec![Value::Nil, interpreter.create_string(&e.get_message())?]);
+                }
+            }
+        }
+        _ => {
+            return Err(type_error(&interpreter.gc, args, 0, "load", "string"));
+        }
+    };
+    let name = match args.get(1) {
+        None | Some(Value::Nil) => default_name,
+        _ => check_string(&interpreter.gc, args, 1, "load")?.to_string(),
+    };
+    let mode = match args.get(2) {
+        None | Some(Value::Nil) => "bt".to_string(),
+        _ => check_string(&interpreter.gc, args, 2, "load")?.to_string(),
+    };
+    let env = match args.get(3) {
+        None | Some(Value::Nil) => None,
+        Some(Value::GcObject(r)) if interpreter.is_table(Value::GcObject(*r)) => Some(*r),
+        _ => {
+            return Err(type_error(&interpreter.gc, args, 3, "load", "table"));
+        }
+    };
+
+    // There are no binary chunks
+    if !mode.contains('t') {
+        let message = format!("attempt to load a text chunk (mode is '{mode}')");
+        return Ok(vec![Value::Nil, interpreter.create_string(&message)?]);
+    }
+    let name = chunk_id(&name);
+    match Chunk::compile(&source, &name) {
+        Ok(chunk) => Ok(vec![interpreter.load(&chunk, env)?]),
+        Err(e) => Ok(vec![Value::Nil, interpreter.create_string(&syntax_error(&name, &e))?]),
+    }
+}
+
+// Calls `reader` until it returns nil or an empty string
+fn read_pieces(interpreter: &mut Interpreter, reader: Value) -> Result<String, RuntimeError> {
+    let mut source = vec![];
+    loop {
+        match interpreter.call(reader, &[])?.first() {
+            None | Some(Value::Nil) => {
+                break;
+            }
+            Some(Value::String(r)) => {
+                let piece = interpreter.gc.get_bytes(*r).unwrap();
+                if piece.is_empty() {
+                    break;
+                }
+                source.extend_from_slice(&piece);
+            }
+            Some(_) => {
+                return Err(RuntimeError::new("reader function must return a string".to_string()));
+            }
+        }
+    }
+    Ok(String::from_utf8_lossy(&source).into_owned())
+}
+
+// loadstring(s [, chunkname]) is Lua 5.1's name for loading a string
+fn loadstring(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    check_string(&interpreter.gc, args, 0, "loadstring")?;
+    load(interpreter, &args[..args.len().min(2)])
+}
+
+// dofile([filename]) runs a file, or stdin without one, and returns its
+// results. Errors are raised rather than returned.
+fn dofile(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let (source, name) = match args.first() {
+        None | Some(Value::Nil) => {
+            let mut source = String::new();
+            interpreter.stdin
+                .read_to_string(&mut source)
+                .map_err(|e| RuntimeError::new(format!("cannot read stdin: {e}")))?;
+            (source, "stdin".to_string())
+        }
+        _ => {
+            let file = check_string(&interpreter.gc, args, 0, "dofile")?;
+            require(interpreter, Capabilities::READ_FILES, "dofile")?;
+            let source = fs::read_to_string(&*file).map_err(|e|
+                RuntimeError::new(format!("cannot open {file}: {e}"))
+            )?;
+            (source, file.to_string())
+        }
+    };
+    let chunk = Chunk::compile(&source, &name).map_err(|e|
+        RuntimeError::new(syntax_error(&name, &e))
+    )?;
+    interpreter.eval_chunk(&chunk, None)
+}
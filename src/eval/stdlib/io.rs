@@ -0,0 +1,567 @@
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    fs::{ File, OpenOptions },
+    io::{ self, BufRead, BufReader, Read, Seek, SeekFrom, Write },
+    mem,
+};
+
+use crate::{
+    errors::RuntimeError,
+    eval::{
+        gc::{ GarbageCollector, GcRef, GcValue },
+        interpreter::Interpreter,
+        types::{ Function, InterpreterFunction, Table },
+        value::Value,
+    },
+};
+
+use super::{ bad_argument, check_bytes, check_integer, check_string, opt_integer, require, type_error, Capabilities };
+
+const FUNCTIONS: &[(&str, InterpreterFunction)] = &[
+    ("close", io_close),
+    ("lines", io_lines),
+    ("open", io_open),
+    ("read", io_read),
+    ("type", io_type),
+    ("write", io_write),
+];
+
+// The methods of file handles, `f:read()` finds them through `__index`
+const METHODS: &[(&str, InterpreterFunction)] = &[
+    ("close", file_close),
+    ("flush", file_flush),
+    ("lines", file_lines),
+    ("read", file_read),
+    ("seek", file_seek),
+    ("write", file_write),
+];
+
+// Like Lua's L_MAXLENNUM, longer numerals aren't read by "n"
+const MAX_NUMERAL: usize = 200;
+
+// The errno C Lua reports when a standard file is used the wrong way
+const EBADF: i32 = 9;
+const ESPIPE: i32 = 29;
+
+// Indices into `interpreter.standard_files`
+const STDIN: usize = 0;
+const STDOUT: usize = 1;
+
+pub fn open(interpreter: &mut Interpreter) -> Result<(), RuntimeError> {
+    let io = interpreter.add_library("io", FUNCTIONS)?;
+
+    // Rooted as soon as it is registered, everything else hangs off it
+    let metatable = interpreter.allocate(Box::new(Table::new(vec![], HashMap::new())))?;
+    interpreter.metatables.insert(TypeId::of::<FileHandle>(), metatable);
+    let methods = interpreter.allocate(Box::new(Table::new(vec![], HashMap::new())))?;
+    interpreter.set_field(metatable, "__index", Value::GcObject(methods))?;
+    let name = interpreter.create_string("FILE*")?;
+    interpreter.set_field(metatable, "__name", name)?;
+    for (name, method) in METHODS {
+        let method = interpreter.allocate(Box::new(Function::FnPointerInterpreter(*method)))?;
+        interpreter.set_field(methods, name, Value::GcObject(method))?;
+    }
+
+    interpreter.standard_files.clear();
+    for (name, stream) in [("stdin", Stream::Stdin), ("stdout", Stream::Stdout), ("stderr", Stream::Stderr)] {
+        let handle = interpreter.allocate(Box::new(FileHandle { stream: Some(stream), metatable }))?;
+        interpreter.standard_files.push(handle);
+        interpreter.set_field(io, name, Value::GcObject(handle))?;
+    }
+    Ok(())
+}
+
+/// An open file, the userdata behind `io.open` and the standard files.
+/// Collecting it closes the file.
+pub struct FileHandle {
+    // None once closed
+    stream: Option<Stream>,
+    metatable: GcRef,
+}
+
+// The standard files go to the interpreter's streams, see `set_stdout`
+enum Stream {
+    File(BufReader<File>),
+    Stdin,
+    Stdout,
+    Stderr,
+}
+
+impl GcValue for FileHandle {
+    fn get_referenced_children(&self, _gc: &GarbageCollector) -> Vec<GcRef> {
+        vec![self.metatable]
+    }
+
+    fn get_referenced_edges(&self, _gc: &GarbageCollector) -> Vec<(String, GcRef)> {
+        vec![("<metatable>".to_string(), self.metatable)]
+    }
+
+    fn name(&self) -> &'static str {
+        "userdata"
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>() +
+            (match &self.stream {
+                Some(Stream::File(file)) => file.capacity(),
+                _ => 0,
+            })
+    }
+
+    fn str(&self, _gc: &GarbageCollector) -> String {
+        match self.stream {
+            Some(_) => "file".to_string(),
+            None => "file (closed)".to_string(),
+        }
+    }
+
+    fn metatable(&self) -> Option<GcRef> {
+        Some(self.metatable)
+    }
+}
+
+/// The `nil, message, code` an io function returns when the system call
+/// failed, `name` is the file it was about
+pub(crate) fn failure(
+    interpreter: &mut Interpreter,
+    name: Option<&str>,
+    error: &io::Error
+) -> Result<Vec<Value>, RuntimeError> {
+    let message = interpreter.create_string(&error_message(name, error))?;
+    Ok(vec![Value::Nil, message, Value::Number(error.raw_os_error().unwrap_or(0) as i64)])
+}
+
+// "name: No such file or directory", without Rust's "(os error 2)"
+fn error_message(name: Option<&str>, error: &io::Error) -> String {
+    let message = error.to_string();
+    let message = match message.find(" (os error") {
+        Some(end) => &message[..end],
+        None => &message,
+    };
+    match name {
+        Some(name) => format!("{name}: {message}"),
+        None => message.to_string(),
+    }
+}
+
+fn new_file(interpreter: &mut Interpreter, file: File) -> Result<Value, RuntimeError> {
+    let metatable = interpreter.metatables[&TypeId::of::<FileHandle>()];
+    let handle = FileHandle { stream: Some(Stream::File(BufReader::new(file))), metatable };
+    Ok(Value::GcObject(interpreter.allocate(Box::new(handle))?))
+}
+
+// Runs `f` on the stream of the open file argument `i`
+fn with_stream<T>(
+    interpreter: &mut Interpreter,
+    args: &[Value],
+    i: usize,
+    function: &str,
+    f: impl FnOnce(&mut Interpreter, &mut Stream) -> T
+) -> Result<T, RuntimeError> {
+    let handle = args
+        .get(i)
+        .and_then(|v| v.gc_ref())
+        .and_then(|r| interpreter.gc.get(r))
+        .filter(|o| o.borrow().is::<FileHandle>());
+    let Some(handle) = handle else {
+        return Err(type_error(&interpreter.gc, args, i, function, "FILE*"));
+    };
+    let mut handle = handle.borrow_mut();
+    match &mut handle.downcast_mut::<FileHandle>().unwrap().stream {
+        Some(stream) => Ok(f(interpreter, stream)),
+        None => Err(RuntimeError::new("attempt to use a closed file".to_string())),
+    }
+}
+
+fn reader<'a>(interpreter: &'a mut Interpreter, stream: &'a mut Stream) -> io::Result<&'a mut dyn BufRead> {
+    match stream {
+        Stream::File(file) => Ok(file),
+        Stream::Stdin => Ok(&mut *interpreter.stdin),
+        Stream::Stdout | Stream::Stderr => Err(io::Error::from_raw_os_error(EBADF)),
+    }
+}
+
+fn writer<'a>(interpreter: &'a mut Interpreter, stream: &'a mut Stream) -> io::Result<&'a mut dyn Write> {
+    match stream {
+        Stream::File(file) => {
+            // Seeking drops what was read ahead so the write lands at the
+            // logical position
+            let position = file.stream_position()?;
+            file.seek(SeekFrom::Start(position))?;
+            Ok(file.get_mut())
+        }
+        Stream::Stdout => Ok(&mut *interpreter.stdout),
+        Stream::Stderr => Ok(&mut *interpreter.stderr),
+        Stream::Stdin => Err(io::Error::from_raw_os_error(EBADF)),
+    }
+}
+
+fn standard_file(interpreter: &Interpreter, i: usize) -> Value {
+    Value::GcObject(interpreter.standard_files[i])
+}
+
+// io.open(filename [, mode]) with C's fopen modes
+fn io_open(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let name = check_string(&interpreter.gc, args, 0, "open")?;
+    let mode = match args.get(1) {
+        None | Some(Value::Nil) => "r".into(),
+        _ => check_string(&interpreter.gc, args, 1, "open")?,
+    };
+    // [rwa]+? followed by any number of 'b's
+    let (kind, rest) = mode.split_at(mode.len().min(1));
+    let update = rest.starts_with('+');
+    let rest = rest.strip_prefix('+').unwrap_or(rest);
+    if !matches!(kind, "r" | "w" | "a") || rest.chars().any(|c| c != 'b') {
+        return Err(bad_argument(1, "open", "invalid mode"));
+    }
+
+    let reads = kind == "r" || update;
+    let writes = kind != "r" || update;
+    if reads {
+        require(interpreter, Capabilities::READ_FILES, "io.open")?;
+    }
+    if writes {
+        require(interpreter, Capabilities::WRITE_FILES, "io.open")?;
+    }
+    let mut options = OpenOptions::new();
+    match kind {
+        "r" => options.read(true).write(update),
+        "w" => options.write(true).read(update).create(true).truncate(true),
+        _ => options.append(true).read(update).create(true),
+    };
+    match options.open(&*name) {
+        Ok(file) => Ok(vec![new_file(interpreter, file)?]),
+        Err(e) => failure(interpreter, Some(&name), &e),
+    }
+}
+
+// io.lines(filename, ...) reads the file with the given formats and closes
+// it at the end, without a filename it reads the standard input
+fn io_lines(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    if let None | Some(Value::Nil) = args.first() {
+        let stdin = standard_file(interpreter, STDIN);
+        let formats = read_formats(interpreter, args, 1, "lines")?;
+        let lines = LinesIterator { file: stdin, formats, close: false };
+        return Ok(vec![Value::GcObject(interpreter.allocate(Box::new(lines))?)]);
+    }
+    require(interpreter, Capabilities::READ_FILES, "io.lines")?;
+    let name = check_string(&interpreter.gc, args, 0, "lines")?;
+    let file = match File::open(&*name) {
+        Ok(file) => new_file(interpreter, file)?,
+        Err(e) => {
+            return Err(RuntimeError::new(error_message(Some(&name), &e)));
+        }
+    };
+    interpreter.keep_alive(file);
+    let formats = read_formats(interpreter, args, 1, "lines")?;
+    let lines = LinesIterator { file, formats, close: true };
+    Ok(vec![Value::GcObject(interpreter.allocate(Box::new(lines))?)])
+}
+
+fn io_type(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let Some(value) = args.first() else {
+        return Err(bad_argument(0, "type", "value expected"));
+    };
+    let handle = value.gc_ref().and_then(|r| interpreter.gc.get(r));
+    let open = match handle {
+        Some(handle) => handle.borrow().downcast_ref::<FileHandle>().map(|h| h.stream.is_some()),
+        None => None,
+    };
+    match open {
+        Some(true) => Ok(vec![interpreter.create_string("file")?]),
+        Some(false) => Ok(vec![interpreter.create_string("closed file")?]),
+        None => Ok(vec![Value::Nil]),
+    }
+}
+
+// io.read(...) reads the standard input like file:read
+fn io_read(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let formats = read_formats(interpreter, args, 0, "read")?;
+    read(interpreter, standard_file(interpreter, STDIN), &formats, "read")
+}
+
+// io.write(...) writes to the standard output like file:write
+fn io_write(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    write(interpreter, standard_file(interpreter, STDOUT), args, 0)
+}
+
+// io.close([file]) closes the standard output without a file, which fails
+fn io_close(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    match args.first() {
+        None => file_close(interpreter, &[standard_file(interpreter, STDOUT)]),
+        Some(_) => file_close(interpreter, args),
+    }
+}
+
+fn file_close(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let standard = with_stream(interpreter, args, 0, "close", |_, stream| !matches!(stream, Stream::File(_)))?;
+    if standard {
+        return Ok(vec![Value::Nil, interpreter.create_string("cannot close standard file")?]);
+    }
+    let handle = interpreter.gc.get(args[0].gc_ref().unwrap()).unwrap();
+    // Dropping the file closes it
+    handle.borrow_mut().downcast_mut::<FileHandle>().unwrap().stream = None;
+    Ok(vec![Value::Bool(true)])
+}
+
+fn file_flush(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let flushed = with_stream(interpreter, args, 0, "flush", |interpreter, stream| {
+        writer(interpreter, stream)?.flush()
+    })?;
+    match flushed {
+        Ok(()) => Ok(vec![args[0]]),
+        Err(e) => failure(interpreter, None, &e),
+    }
+}
+
+// file:write(...) writes strings and numbers, returning the file
+fn file_write(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    with_stream(interpreter, args, 0, "write", |_, _| ())?;
+    write(interpreter, args[0], args, 1)
+}
+
+// Writes the arguments from `first` on to `file`
+fn write(
+    interpreter: &mut Interpreter,
+    file: Value,
+    args: &[Value],
+    first: usize
+) -> Result<Vec<Value>, RuntimeError> {
+    let mut data = Vec::new();
+    for i in first..args.len() {
+        data.extend_from_slice(&check_bytes(&interpreter.gc, args, i, "write")?);
+    }
+    let written = with_stream(interpreter, &[file], 0, "write", |interpreter, stream| {
+        writer(interpreter, stream)?.write_all(&data)
+    })?;
+    match written {
+        Ok(()) => Ok(vec![file]),
+        Err(e) => failure(interpreter, None, &e),
+    }
+}
+
+// file:seek([whence [, offset]]) returns the new position from the start
+fn file_seek(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let whence = match args.get(1) {
+        None | Some(Value::Nil) => "cur".into(),
+        _ => check_string(&interpreter.gc, args, 1, "seek")?,
+    };
+    let offset = opt_integer(&interpreter.gc, args, 2, "seek", 0)?;
+    let position = match &*whence {
+        "set" if offset >= 0 => SeekFrom::Start(offset as u64),
+        "set" => {
+            return failure(interpreter, None, &io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        "cur" => SeekFrom::Current(offset),
+        "end" => SeekFrom::End(offset),
+        _ => {
+            return Err(bad_argument(1, "seek", &format!("invalid option '{whence}'")));
+        }
+    };
+    let sought = with_stream(interpreter, args, 0, "seek", |_, stream| {
+        match stream {
+            Stream::File(file) => file.seek(position),
+            _ => Err(io::Error::from_raw_os_error(ESPIPE)),
+        }
+    })?;
+    match sought {
+        Ok(position) => Ok(vec![Value::Number(position as i64)]),
+        Err(e) => failure(interpreter, None, &e),
+    }
+}
+
+fn file_read(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    with_stream(interpreter, args, 0, "read", |_, _| ())?;
+    let formats = read_formats(interpreter, args, 1, "read")?;
+    read(interpreter, args[0], &formats, "read")
+}
+
+// file:lines(...) is an iterator reading with the given formats, the file
+// stays open
+fn file_lines(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    with_stream(interpreter, args, 0, "lines", |_, _| ())?;
+    let formats = read_formats(interpreter, args, 1, "lines")?;
+    let lines = LinesIterator { file: args[0], formats, close: false };
+    Ok(vec![Value::GcObject(interpreter.allocate(Box::new(lines))?)])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Number,
+    All,
+    Line,
+    LineWithNewline,
+    Bytes(u64),
+}
+
+// The formats starting at argument `first`, a line without any
+fn read_formats(
+    interpreter: &Interpreter,
+    args: &[Value],
+    first: usize,
+    function: &str
+) -> Result<Vec<Format>, RuntimeError> {
+    if args.len() <= first {
+        return Ok(vec![Format::Line]);
+    }
+    (first..args.len())
+        .map(|i| {
+            if let Value::Number(_) | Value::Float(_) = args[i] {
+                let count = check_integer(&interpreter.gc, args, i, function)?;
+                return Ok(Format::Bytes(count.max(0) as u64));
+            }
+            let format = check_string(&interpreter.gc, args, i, function)?;
+            // Lua 5.1 wrote the formats with a '*'
+            match format.strip_prefix('*').unwrap_or(&format).chars().next() {
+                Some('n') => Ok(Format::Number),
+                Some('a') => Ok(Format::All),
+                Some('l') => Ok(Format::Line),
+                Some('L') => Ok(Format::LineWithNewline),
+                _ => Err(bad_argument(i, function, "invalid format")),
+            }
+        })
+        .collect()
+}
+
+// Reads each format in turn, stopping with a nil at the first that fails
+fn read(
+    interpreter: &mut Interpreter,
+    file: Value,
+    formats: &[Format],
+    function: &str
+) -> Result<Vec<Value>, RuntimeError> {
+    let mut results = vec![];
+    for format in formats {
+        let read = with_stream(interpreter, &[file], 0, function, |interpreter, stream| {
+            read_format(reader(interpreter, stream)?, *format)
+        })?;
+        let value = match read {
+            Ok(Some(Data::Bytes(bytes))) => interpreter.create_byte_string(&bytes)?,
+            Ok(Some(Data::Number(number))) => number,
+            Ok(None) => Value::Nil,
+            Err(e) => {
+                return failure(interpreter, None, &e);
+            }
+        };
+        // Earlier results are only held here while the next is allocated
+        interpreter.keep_alive(value);
+        results.push(value);
+        if let Value::Nil = value {
+            break;
+        }
+    }
+    Ok(results)
+}
+
+enum Data {
+    Bytes(Vec<u8>),
+    Number(Value),
+}
+
+fn read_format(file: &mut dyn BufRead, format: Format) -> io::Result<Option<Data>> {
+    Ok(match format {
+        Format::All => {
+            let mut bytes = vec![];
+            file.read_to_end(&mut bytes)?;
+            Some(Data::Bytes(bytes))
+        }
+        Format::Line | Format::LineWithNewline => {
+            let mut bytes = vec![];
+            if file.read_until(b'\n', &mut bytes)? == 0 {
+                return Ok(None);
+            }
+            if format == Format::Line && bytes.last() == Some(&b'\n') {
+                bytes.pop();
+            }
+            Some(Data::Bytes(bytes))
+        }
+        // Zero bytes tests for the end of the file
+        Format::Bytes(0) => {
+            if file.fill_buf()?.is_empty() { None } else { Some(Data::Bytes(vec![])) }
+        }
+        Format::Bytes(count) => {
+            let mut bytes = vec![];
+            (&mut *file).take(count).read_to_end(&mut bytes)?;
+            if bytes.is_empty() { None } else { Some(Data::Bytes(bytes)) }
+        }
+        Format::Number => read_number(file)?.map(Data::Number),
+    })
+}
+
+// Skips whitespace and reads the longest prefix that looks like a numeral
+fn read_number(file: &mut dyn BufRead) -> io::Result<Option<Value>> {
+    let mut numeral = String::new();
+    loop {
+        let buffer = file.fill_buf()?;
+        let Some(&c) = buffer.first() else {
+            break;
+        };
+        let c = c as char;
+        let accepted = if numeral.is_empty() && c.is_ascii_whitespace() {
+            true
+        } else if numeral.len() < MAX_NUMERAL && (c.is_ascii_hexdigit() || "+-.xXpP".contains(c)) {
+            numeral.push(c);
+            true
+        } else {
+            false
+        };
+        if !accepted {
+            break;
+        }
+        file.consume(1);
+    }
+    let numeral = numeral.as_str();
+    let hex = numeral.trim_start_matches(['+', '-']);
+    if let Some(digits) = hex.strip_prefix("0x").or_else(|| hex.strip_prefix("0X")) {
+        let negative = numeral.starts_with('-');
+        return Ok(
+            i64
+                ::from_str_radix(digits, 16)
+                .ok()
+                .map(|n| Value::Number(if negative { n.wrapping_neg() } else { n }))
+        );
+    }
+    if let Ok(n) = numeral.parse::<i64>() {
+        return Ok(Some(Value::Number(n)));
+    }
+    Ok(numeral.parse::<f64>().ok().map(Value::Float))
+}
+
+/// Returned by `lines`, each call reads the next values from the file
+struct LinesIterator {
+    file: Value,
+    formats: Vec<Format>,
+    // `io.lines` closes the file it opened once it is read
+    close: bool,
+}
+
+impl GcValue for LinesIterator {
+    fn get_referenced_children(&self, _gc: &GarbageCollector) -> Vec<GcRef> {
+        self.file.gc_ref().into_iter().collect()
+    }
+
+    fn name(&self) -> &'static str {
+        "function"
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>() + self.formats.capacity() * mem::size_of::<Format>()
+    }
+
+    fn str(&self, _gc: &GarbageCollector) -> String {
+        "function".to_string()
+    }
+
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        _args: &[Value]
+    ) -> Result<Vec<Value>, RuntimeError> {
+        let values = read(interpreter, self.file, &self.formats, "lines")?;
+        if self.close && matches!(values.first(), None | Some(Value::Nil)) {
+            file_close(interpreter, &[self.file])?;
+        }
+        Ok(values)
+    }
+}
@@ -0,0 +1,589 @@
+// The json library. Tables with only an array part become arrays, any other
+// table an object, an empty one `{}`. JSON's null is the `json.null`
+// sentinel so it can be stored in tables and compared against.
+
+use std::{ collections::HashMap, mem };
+
+use crate::{
+    errors::RuntimeError,
+    eval::{
+        gc::{ GarbageCollector, GcRef, GcValue },
+        interpreter::Interpreter,
+        types::{ InterpreterFunction, Table },
+        value::Value,
+    },
+};
+
+use super::{ bad_argument, check_bytes, type_error };
+
+const FUNCTIONS: &[(&str, InterpreterFunction)] = &[
+    ("decode", decode),
+    ("encode", encode),
+];
+
+// Both directions recurse, deeper documents are refused
+const MAX_DEPTH: usize = 1000;
+
+pub fn open(interpreter: &mut Interpreter) -> Result<(), RuntimeError> {
+    let json = interpreter.add_library("json", FUNCTIONS)?;
+    let null = interpreter.allocate(Box::new(JsonNull))?;
+    interpreter.json_null = Some(null);
+    interpreter.set_field(json, "null", Value::GcObject(null))
+}
+
+/// The value of `json.null`, there is only one per interpreter
+struct JsonNull;
+
+impl GcValue for JsonNull {
+    fn get_referenced_children(&self, _gc: &GarbageCollector) -> Vec<GcRef> {
+        vec![]
+    }
+
+    fn name(&self) -> &'static str {
+        "userdata"
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>()
+    }
+
+    fn str(&self, _gc: &GarbageCollector) -> String {
+        "json.null".to_string()
+    }
+}
+
+// json.encode(value [, options]) with the options `pretty`, `indent` (a
+// string or a number of spaces, implies pretty) and `sort_keys`
+fn encode(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let Some(value) = args.first().copied() else {
+        return Err(bad_argument(0, "encode", "value expected"));
+    };
+    let mut indent = None;
+    let mut sort_keys = false;
+    match args.get(1) {
+        None | Some(Value::Nil) => {}
+        Some(options) if options.type_name(&interpreter.gc) == "table" => {
+            let options = *options;
+            if option(interpreter, options, "pretty")?.is_truthy() {
+                indent = Some(b"  ".to_vec());
+            }
+            match option(interpreter, options, "indent")? {
+                Value::Nil => {}
+                Value::Number(n) => {
+                    indent = Some(vec![b' '; n.clamp(0, 16) as usize]);
+                }
+                v @ Value::String(_) => {
+                    indent = Some(v.to_bytes(&interpreter.gc).to_vec());
+                }
+                _ => {
+                    return Err(bad_argument(1, "encode", "'indent' must be a string or a number"));
+                }
+            }
+            sort_keys = option(interpreter, options, "sort_keys")?.is_truthy();
+        }
+        Some(_) => {
+            return Err(type_error(&interpreter.gc, args, 1, "encode", "table"));
+        }
+    }
+
+    let mut encoder = Encoder {
+        gc: &interpreter.gc,
+        null: interpreter.json_null,
+        indent,
+        sort_keys,
+        out: vec![],
+        path: vec![],
+        visiting: vec![],
+    };
+    encoder.value(value)?;
+    let out = encoder.out;
+    Ok(vec![interpreter.create_byte_string(&out)?])
+}
+
+fn option(interpreter: &mut Interpreter, options: Value, name: &str) -> Result<Value, RuntimeError> {
+    let key = interpreter.create_string(name)?;
+    interpreter.index_value(options, key)
+}
+
+// Where in the encoded value an error happened, like $.users[2].name
+enum Segment {
+    Index(usize),
+    Key(String),
+}
+
+struct Encoder<'a> {
+    gc: &'a GarbageCollector,
+    null: Option<GcRef>,
+    indent: Option<Vec<u8>>,
+    sort_keys: bool,
+    out: Vec<u8>,
+    path: Vec<Segment>,
+    // The tables being encoded, finding one again means a cycle
+    visiting: Vec<GcRef>,
+}
+
+impl Encoder<'_> {
+    fn error(&self, message: &str) -> RuntimeError {
+        let mut path = "$".to_string();
+        for segment in &self.path {
+            match segment {
+                Segment::Index(i) => path.push_str(&format!("[{i}]")),
+                Segment::Key(key) if is_identifier(key) => path.push_str(&format!(".{key}")),
+                Segment::Key(key) => path.push_str(&format!("[{key:?}]")),
+            }
+        }
+        RuntimeError::new(format!("{message} at {path}"))
+    }
+
+    fn value(&mut self, value: Value) -> Result<(), RuntimeError> {
+        match value {
+            Value::Nil => self.out.extend_from_slice(b"null"),
+            Value::Bool(b) => self.out.extend_from_slice(b.to_string().as_bytes()),
+            Value::Number(n) => self.out.extend_from_slice(n.to_string().as_bytes()),
+            Value::Float(f) if !f.is_finite() => {
+                return Err(self.error(&format!("cannot encode {f}")));
+            }
+            // Debug formatting keeps the fraction, 1.0 stays a float
+            Value::Float(f) => self.out.extend_from_slice(format!("{f:?}").as_bytes()),
+            Value::String(r) => {
+                let bytes = self.gc.get_bytes(r).unwrap();
+                self.string(&bytes)?;
+            }
+            Value::GcObject(r) if Some(r) == self.null => self.out.extend_from_slice(b"null"),
+            Value::GcObject(r) => {
+                let object = self.gc.get(r).unwrap();
+                let object = object.borrow();
+                let Some(table) = object.downcast_ref::<Table>() else {
+                    return Err(self.error(&format!("cannot encode a {} value", object.name())));
+                };
+                if self.visiting.contains(&r) {
+                    return Err(self.error("cannot encode a table with a cycle"));
+                }
+                if self.visiting.len() >= MAX_DEPTH {
+                    return Err(self.error("cannot encode a table nested this deep"));
+                }
+                self.visiting.push(r);
+                if table.map_part().is_empty() && !table.array_part().is_empty() {
+                    self.array(table.array_part())?;
+                } else {
+                    self.object(table)?;
+                }
+                self.visiting.pop();
+            }
+        }
+        Ok(())
+    }
+
+    fn string(&mut self, bytes: &[u8]) -> Result<(), RuntimeError> {
+        let Ok(s) = std::str::from_utf8(bytes) else {
+            return Err(self.error("cannot encode a string that isn't valid UTF-8"));
+        };
+        self.out.push(b'"');
+        for c in s.chars() {
+            match c {
+                '"' => self.out.extend_from_slice(b"\\\""),
+                '\\' => self.out.extend_from_slice(b"\\\\"),
+                '\n' => self.out.extend_from_slice(b"\\n"),
+                '\r' => self.out.extend_from_slice(b"\\r"),
+                '\t' => self.out.extend_from_slice(b"\\t"),
+                '\u{8}' => self.out.extend_from_slice(b"\\b"),
+                '\u{c}' => self.out.extend_from_slice(b"\\f"),
+                c if (c as u32) < 0x20 => {
+                    self.out.extend_from_slice(format!("\\u{:04x}", c as u32).as_bytes());
+                }
+                c => self.out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        self.out.push(b'"');
+        Ok(())
+    }
+
+    fn array(&mut self, values: &[Value]) -> Result<(), RuntimeError> {
+        self.out.push(b'[');
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                self.out.push(b',');
+            }
+            self.newline();
+            self.path.push(Segment::Index(i + 1));
+            self.value(*value)?;
+            self.path.pop();
+        }
+        self.close(b']', values.is_empty());
+        Ok(())
+    }
+
+    fn object(&mut self, table: &Table) -> Result<(), RuntimeError> {
+        let mut entries = Vec::with_capacity(table.array_part().len() + table.map_part().len());
+        for (i, value) in table.array_part().iter().enumerate() {
+            entries.push(((i + 1).to_string().into_bytes(), *value));
+        }
+        for (key, value) in table.map_part() {
+            entries.push((self.key(*key)?, *value));
+        }
+        if self.sort_keys {
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+        }
+
+        self.out.push(b'{');
+        for (i, (key, value)) in entries.iter().enumerate() {
+            if i > 0 {
+                self.out.push(b',');
+            }
+            self.newline();
+            self.path.push(Segment::Key(String::from_utf8_lossy(key).into_owned()));
+            self.string(key)?;
+            self.out.push(b':');
+            if self.indent.is_some() {
+                self.out.push(b' ');
+            }
+            self.value(*value)?;
+            self.path.pop();
+        }
+        self.close(b'}', entries.is_empty());
+        Ok(())
+    }
+
+    // Object keys are strings, numbers are written like values
+    fn key(&self, key: Value) -> Result<Vec<u8>, RuntimeError> {
+        match key {
+            Value::String(r) => Ok(self.gc.get_bytes(r).unwrap().to_vec()),
+            Value::Number(n) => Ok(n.to_string().into_bytes()),
+            Value::Float(f) if f.is_finite() => Ok(format!("{f:?}").into_bytes()),
+            _ => Err(self.error(&format!("cannot encode a {} key", key.type_name(self.gc)))),
+        }
+    }
+
+    // Before each element when pretty printing
+    fn newline(&mut self) {
+        if let Some(indent) = &self.indent {
+            self.out.push(b'\n');
+            for _ in 0..self.visiting.len() {
+                self.out.extend_from_slice(indent);
+            }
+        }
+    }
+
+    fn close(&mut self, bracket: u8, empty: bool) {
+        if let (Some(indent), false) = (&self.indent, empty) {
+            self.out.push(b'\n');
+            for _ in 1..self.visiting.len() {
+                self.out.extend_from_slice(indent);
+            }
+        }
+        self.out.push(bracket);
+    }
+}
+
+pub(crate) fn is_identifier(key: &str) -> bool {
+    let mut chars = key.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') &&
+        chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// json.decode(s) objects and arrays become tables, null is `json.null`
+fn decode(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let src = check_bytes(&interpreter.gc, args, 0, "decode")?;
+    let Some(null) = interpreter.json_null else {
+        return Err(RuntimeError::new("the json library isn't open".to_string()));
+    };
+    let mut decoder = Decoder { interpreter, src: &src, position: 0, depth: 0, null: Value::GcObject(null) };
+    decoder.skip_whitespace();
+    let value = decoder.value()?;
+    decoder.skip_whitespace();
+    if decoder.position < src.len() {
+        return Err(decoder.unexpected());
+    }
+    Ok(vec![value])
+}
+
+struct Decoder<'a> {
+    interpreter: &'a mut Interpreter,
+    src: &'a [u8],
+    position: usize,
+    depth: usize,
+    null: Value,
+}
+
+impl Decoder<'_> {
+    // Positions in messages are 1-based like Lua's
+    fn error_at(&self, position: usize, message: &str) -> RuntimeError {
+        RuntimeError::new(format!("{message} at byte {}", position + 1))
+    }
+
+    fn unexpected(&self) -> RuntimeError {
+        match self.src.get(self.position) {
+            None => self.error_at(self.position, "unexpected end of input"),
+            Some(c) if c.is_ascii_graphic() => {
+                self.error_at(self.position, &format!("unexpected character '{}'", *c as char))
+            }
+            Some(c) => self.error_at(self.position, &format!("unexpected byte 0x{c:02x}")),
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), RuntimeError> {
+        self.skip_whitespace();
+        if self.peek() != Some(c) {
+            return Err(self.unexpected());
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Value, RuntimeError> {
+        let value = match self.peek() {
+            Some(b'{') => self.object()?,
+            Some(b'[') => self.array()?,
+            Some(b'"') => {
+                let bytes = self.string()?;
+                self.interpreter.create_byte_string(&bytes)?
+            }
+            Some(b'-' | b'0'..=b'9') => self.number()?,
+            Some(b't') => self.literal("true", Value::Bool(true))?,
+            Some(b'f') => self.literal("false", Value::Bool(false))?,
+            Some(b'n') => self.literal("null", self.null)?,
+            _ => {
+                return Err(self.unexpected());
+            }
+        };
+        // Only reachable from here until the enclosing table is allocated
+        self.interpreter.keep_alive(value);
+        Ok(value)
+    }
+
+    fn literal(&mut self, literal: &str, value: Value) -> Result<Value, RuntimeError> {
+        if !self.src[self.position..].starts_with(literal.as_bytes()) {
+            return Err(self.error_at(self.position, "invalid literal"));
+        }
+        self.position += literal.len();
+        Ok(value)
+    }
+
+    fn nested(&mut self) -> Result<(), RuntimeError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(self.error_at(self.position, "too deeply nested"));
+        }
+        self.position += 1;
+        self.skip_whitespace();
+        Ok(())
+    }
+
+    fn array(&mut self) -> Result<Value, RuntimeError> {
+        self.nested()?;
+        let mut values = vec![];
+        if self.peek() == Some(b']') {
+            self.position += 1;
+        } else {
+            loop {
+                self.skip_whitespace();
+                values.push(self.value()?);
+                self.skip_whitespace();
+                match self.peek() {
+                    Some(b',') => {
+                        self.position += 1;
+                    }
+                    Some(b']') => {
+                        self.position += 1;
+                        break;
+                    }
+                    _ => {
+                        return Err(self.unexpected());
+                    }
+                }
+            }
+        }
+        self.depth -= 1;
+        let table = self.interpreter.allocate(Box::new(Table::new(values, HashMap::new())))?;
+        Ok(Value::GcObject(table))
+    }
+
+    fn object(&mut self) -> Result<Value, RuntimeError> {
+        self.nested()?;
+        let mut map = HashMap::new();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+        } else {
+            loop {
+                self.skip_whitespace();
+                if self.peek() != Some(b'"') {
+                    return Err(
+                        match self.peek() {
+                            None => self.unexpected(),
+                            _ => self.error_at(self.position, "expected a string key"),
+                        }
+                    );
+                }
+                let key = self.string()?;
+                let key = self.interpreter.create_byte_string(&key)?;
+                self.interpreter.keep_alive(key);
+                self.expect(b':')?;
+                self.skip_whitespace();
+                let value = self.value()?;
+                // Later duplicates win
+                map.insert(key, value);
+                self.skip_whitespace();
+                match self.peek() {
+                    Some(b',') => {
+                        self.position += 1;
+                    }
+                    Some(b'}') => {
+                        self.position += 1;
+                        break;
+                    }
+                    _ => {
+                        return Err(self.unexpected());
+                    }
+                }
+            }
+        }
+        self.depth -= 1;
+        let table = self.interpreter.allocate(Box::new(Table::new(vec![], map)))?;
+        Ok(Value::GcObject(table))
+    }
+
+    fn number(&mut self) -> Result<Value, RuntimeError> {
+        let start = self.position;
+        let digits = |decoder: &mut Self| {
+            let first = decoder.position;
+            while let Some(b'0'..=b'9') = decoder.peek() {
+                decoder.position += 1;
+            }
+            decoder.position > first
+        };
+        if self.peek() == Some(b'-') {
+            self.position += 1;
+        }
+        let integral = self.position;
+        // No leading zeros, "0" is a number on its own
+        if !digits(self) || (self.src[integral] == b'0' && self.position - integral > 1) {
+            return Err(self.error_at(start, "invalid number"));
+        }
+        let mut integer = true;
+        if self.peek() == Some(b'.') {
+            self.position += 1;
+            integer = false;
+            if !digits(self) {
+                return Err(self.error_at(start, "invalid number"));
+            }
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.position += 1;
+            integer = false;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.position += 1;
+            }
+            if !digits(self) {
+                return Err(self.error_at(start, "invalid number"));
+            }
+        }
+
+        let text = std::str::from_utf8(&self.src[start..self.position]).unwrap();
+        if integer {
+            // Too large integers are kept as floats, like Lua's tonumber
+            if let Ok(n) = text.parse::<i64>() {
+                return Ok(Value::Number(n));
+            }
+        }
+        Ok(Value::Float(text.parse::<f64>().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, RuntimeError> {
+        self.position += 1;
+        let mut bytes = vec![];
+        loop {
+            let Some(c) = self.peek() else {
+                return Err(self.error_at(self.position, "unterminated string"));
+            };
+            match c {
+                b'"' => {
+                    self.position += 1;
+                    return Ok(bytes);
+                }
+                b'\\' => {
+                    self.escape(&mut bytes)?;
+                }
+                0..0x20 => {
+                    return Err(self.error_at(self.position, "control character in string"));
+                }
+                _ => {
+                    bytes.push(c);
+                    self.position += 1;
+                }
+            }
+        }
+    }
+
+    fn escape(&mut self, bytes: &mut Vec<u8>) -> Result<(), RuntimeError> {
+        let start = self.position;
+        self.position += 1;
+        let escaped = match self.peek() {
+            Some(b'"') => b'"',
+            Some(b'\\') => b'\\',
+            Some(b'/') => b'/',
+            Some(b'b') => 0x8,
+            Some(b'f') => 0xc,
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b't') => b'\t',
+            Some(b'u') => {
+                let c = self.unicode_escape(start)?;
+                bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                return Ok(());
+            }
+            _ => {
+                return Err(self.error_at(start, "invalid escape"));
+            }
+        };
+        bytes.push(escaped);
+        self.position += 1;
+        Ok(())
+    }
+
+    // \uXXXX, characters outside the BMP as a surrogate pair
+    fn unicode_escape(&mut self, start: usize) -> Result<char, RuntimeError> {
+        let high = self.hex4(start)?;
+        let code = match high {
+            0xd800..=0xdbff => {
+                if !self.src[self.position..].starts_with(b"\\u") {
+                    return Err(self.error_at(start, "invalid unicode escape"));
+                }
+                self.position += 1;
+                let low = self.hex4(start)?;
+                if !(0xdc00..=0xdfff).contains(&low) {
+                    return Err(self.error_at(start, "invalid unicode escape"));
+                }
+                0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+            }
+            0xdc00..=0xdfff => {
+                return Err(self.error_at(start, "invalid unicode escape"));
+            }
+            _ => high,
+        };
+        Ok(char::from_u32(code).unwrap())
+    }
+
+    // The 4 hex digits after a 'u', leaves the position after them
+    fn hex4(&mut self, start: usize) -> Result<u32, RuntimeError> {
+        let digits = self.src
+            .get(self.position + 1..self.position + 5)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .filter(|digits| digits.bytes().all(|c| c.is_ascii_hexdigit()))
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok());
+        let Some(code) = digits else {
+            return Err(self.error_at(start, "invalid unicode escape"));
+        };
+        self.position += 5;
+        Ok(code)
+    }
+}
@@ -0,0 +1,311 @@
+use std::f64::consts::PI;
+
+use rand::{ rngs::SmallRng, Rng, RngCore, SeedableRng };
+
+use crate::{
+    errors::RuntimeError,
+    eval::{ interpreter::{ time_seed, Interpreter }, types::InterpreterFunction, value::Value },
+};
+
+use super::{ bad_argument, check_integer, check_number, float_to_integer, type_error };
+
+const FUNCTIONS: &[(&str, InterpreterFunction)] = &[
+    ("abs", abs),
+    ("acos", acos),
+    ("asin", asin),
+    ("atan", atan),
+    ("ceil", ceil),
+    ("cos", cos),
+    ("exp", exp),
+    ("floor", floor),
+    ("fmod", fmod),
+    ("log", log),
+    ("max", max),
+    ("min", min),
+    ("modf", modf),
+    ("random", random),
+    ("randomseed", randomseed),
+    ("sin", sin),
+    ("sqrt", sqrt),
+    ("tan", tan),
+    ("tointeger", tointeger),
+    ("type", math_type),
+    ("ult", ult),
+];
+
+pub fn open(interpreter: &mut Interpreter) -> Result<(), RuntimeError> {
+    let math = interpreter.add_library("math", FUNCTIONS)?;
+    let constants = [
+        ("huge", Value::Float(f64::INFINITY)),
+        ("pi", Value::Float(PI)),
+        ("maxinteger", Value::Number(i64::MAX)),
+        ("mininteger", Value::Number(i64::MIN)),
+    ];
+    for (name, value) in constants {
+        interpreter.set_field(math, name, value)?;
+    }
+    Ok(())
+}
+
+// A number argument keeping integers as they are
+fn check_numeric(
+    interpreter: &Interpreter,
+    args: &[Value],
+    i: usize,
+    function: &str
+) -> Result<Value, RuntimeError> {
+    match args.get(i) {
+        Some(v @ (Value::Number(_) | Value::Float(_))) => Ok(*v),
+        _ => Ok(Value::Float(check_number(&interpreter.gc, args, i, function)?)),
+    }
+}
+
+fn to_float(value: Value) -> f64 {
+    match value {
+        Value::Number(n) => n as f64,
+        Value::Float(f) => f,
+        _ => unreachable!("{:?} is not a number", value),
+    }
+}
+
+// Float results that fit are turned back into integers, like `math.floor`
+fn integer_or_float(f: f64) -> Value {
+    match float_to_integer(f) {
+        Some(n) => Value::Number(n),
+        None => Value::Float(f),
+    }
+}
+
+fn abs(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    Ok(
+        vec![match check_numeric(interpreter, args, 0, "abs")? {
+            Value::Number(n) => Value::Number(n.wrapping_abs()),
+            v => Value::Float(to_float(v).abs()),
+        }]
+    )
+}
+
+fn ceil(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    Ok(
+        vec![match check_numeric(interpreter, args, 0, "ceil")? {
+            n @ Value::Number(_) => n,
+            v => integer_or_float(to_float(v).ceil()),
+        }]
+    )
+}
+
+fn floor(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    Ok(
+        vec![match check_numeric(interpreter, args, 0, "floor")? {
+            n @ Value::Number(_) => n,
+            v => integer_or_float(to_float(v).floor()),
+        }]
+    )
+}
+
+fn fmod(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let a = check_numeric(interpreter, args, 0, "fmod")?;
+    let b = check_numeric(interpreter, args, 1, "fmod")?;
+    Ok(
+        vec![match (a, b) {
+            (Value::Number(_), Value::Number(0)) => {
+                return Err(bad_argument(1, "fmod", "zero"));
+            }
+            // Avoids the overflow of i64::MIN % -1
+            (Value::Number(_), Value::Number(-1)) => Value::Number(0),
+            // Truncated like C's fmod, not floored like `%`
+            (Value::Number(a), Value::Number(b)) => Value::Number(a % b),
+            (a, b) => Value::Float(to_float(a) % to_float(b)),
+        }]
+    )
+}
+
+// math.modf(x) is the integral and the fractional part of x
+fn modf(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    match check_numeric(interpreter, args, 0, "modf")? {
+        n @ Value::Number(_) => Ok(vec![n, Value::Float(0.0)]),
+        v => {
+            let f = to_float(v);
+            let integral = f.trunc();
+            // Infinity has no fractional part rather than a NaN one
+            let fractional = if f == integral { 0.0 } else { f - integral };
+            Ok(vec![Value::Float(integral), Value::Float(fractional)])
+        }
+    }
+}
+
+fn sqrt(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    Ok(vec![Value::Float(check_number(&interpreter.gc, args, 0, "sqrt")?.sqrt())])
+}
+
+fn exp(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    Ok(vec![Value::Float(check_number(&interpreter.gc, args, 0, "exp")?.exp())])
+}
+
+fn log(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let x = check_number(&interpreter.gc, args, 0, "log")?;
+    let result = match args.get(1) {
+        None | Some(Value::Nil) => x.ln(),
+        _ => {
+            let base = check_number(&interpreter.gc, args, 1, "log")?;
+            // The exact versions for the common bases
+            if base == 2.0 {
+                x.log2()
+            } else if base == 10.0 {
+                x.log10()
+            } else {
+                x.ln() / base.ln()
+            }
+        }
+    };
+    Ok(vec![Value::Float(result)])
+}
+
+fn sin(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    Ok(vec![Value::Float(check_number(&interpreter.gc, args, 0, "sin")?.sin())])
+}
+
+fn cos(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    Ok(vec![Value::Float(check_number(&interpreter.gc, args, 0, "cos")?.cos())])
+}
+
+fn tan(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    Ok(vec![Value::Float(check_number(&interpreter.gc, args, 0, "tan")?.tan())])
+}
+
+fn asin(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    Ok(vec![Value::Float(check_number(&interpreter.gc, args, 0, "asin")?.asin())])
+}
+
+fn acos(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    Ok(vec![Value::Float(check_number(&interpreter.gc, args, 0, "acos")?.acos())])
+}
+
+// math.atan(y [, x]) uses the signs of both to find the quadrant
+fn atan(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let y = check_number(&interpreter.gc, args, 0, "atan")?;
+    let x = match args.get(1) {
+        None | Some(Value::Nil) => 1.0,
+        _ => check_number(&interpreter.gc, args, 1, "atan")?,
+    };
+    Ok(vec![Value::Float(y.atan2(x))])
+}
+
+fn max(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    extreme(interpreter, args, "max", |a, b| b.less(&a).is_truthy())
+}
+
+fn min(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    extreme(interpreter, args, "min", |a, b| a.less(&b).is_truthy())
+}
+
+// The first argument `better` prefers over all others
+fn extreme(
+    interpreter: &mut Interpreter,
+    args: &[Value],
+    function: &str,
+    better: fn(Value, Value) -> bool
+) -> Result<Vec<Value>, RuntimeError> {
+    let mut best = check_numeric(interpreter, args, 0, function)?;
+    for i in 1..args.len() {
+        let value = check_numeric(interpreter, args, i, function)?;
+        if better(value, best) {
+            best = value;
+        }
+    }
+    Ok(vec![best])
+}
+
+// math.ult(m, n) compares m and n as unsigned integers
+fn ult(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let m = check_integer(&interpreter.gc, args, 0, "ult")?;
+    let n = check_integer(&interpreter.gc, args, 1, "ult")?;
+    Ok(vec![Value::Bool((m as u64) < (n as u64))])
+}
+
+// math.tointeger(x) converts integral floats, anything else but an integer
+// gives fail, strings too
+fn tointeger(_interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let result = match args.first() {
+        None => {
+            return Err(bad_argument(0, "tointeger", "value expected"));
+        }
+        Some(Value::Number(n)) => Value::Number(*n),
+        Some(Value::Float(f)) => float_to_integer(*f).map_or(Value::Nil, Value::Number),
+        Some(_) => Value::Nil,
+    };
+    Ok(vec![result])
+}
+
+fn math_type(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let name = match args.first() {
+        None => {
+            return Err(bad_argument(0, "type", "value expected"));
+        }
+        Some(Value::Number(_)) => "integer",
+        Some(Value::Float(_)) => "float",
+        Some(_) => {
+            return Ok(vec![Value::Nil]);
+        }
+    };
+    Ok(vec![interpreter.create_string(name)?])
+}
+
+// math.random() is a float in [0, 1), math.random(m) an integer in [1, m]
+// and math.random(m, n) one in [m, n]. math.random(0) is any integer.
+fn random(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let (low, up) = match args.len() {
+        0 => {
+            return Ok(vec![Value::Float(interpreter.rng.random::<f64>())]);
+        }
+        1 => {
+            let up = check_integer(&interpreter.gc, args, 0, "random")?;
+            if up == 0 {
+                return Ok(vec![Value::Number(interpreter.rng.next_u64() as i64)]);
+            }
+            (1, up)
+        }
+        2 =>
+            (
+                check_integer(&interpreter.gc, args, 0, "random")?,
+                check_integer(&interpreter.gc, args, 1, "random")?,
+            ),
+        _ => {
+            return Err(RuntimeError::new("wrong number of arguments".to_string()));
+        }
+    };
+    if low > up {
+        return Err(bad_argument(0, "random", "interval is empty"));
+    }
+    // The span is computed unsigned so the whole integer range works
+    let span = (up as u64).wrapping_sub(low as u64);
+    let offset = interpreter.rng.random_range(0..=span);
+    Ok(vec![Value::Number(low.wrapping_add(offset as i64))])
+}
+
+// math.randomseed([x [, y]]) returns the two seed components it used, a
+// call without arguments picks a time based seed
+fn randomseed(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let (x, y) = match args.first() {
+        None => (time_seed() as i64, 0),
+        Some(_) => {
+            let x = seed_component(interpreter, args, 0)?;
+            let y = match args.get(1) {
+                None | Some(Value::Nil) => 0,
+                _ => seed_component(interpreter, args, 1)?,
+            };
+            (x, y)
+        }
+    };
+    interpreter.rng = SmallRng::seed_from_u64((x as u64) ^ (y as u64).rotate_left(32));
+    Ok(vec![Value::Number(x), Value::Number(y)])
+}
+
+// Floats seed with their bits so 0.5 and 0.25 give different sequences
+fn seed_component(interpreter: &Interpreter, args: &[Value], i: usize) -> Result<i64, RuntimeError> {
+    match args[i] {
+        Value::Number(n) => Ok(n),
+        Value::Float(f) => Ok(f.to_bits() as i64),
+        _ => Err(type_error(&interpreter.gc, args, i, "randomseed", "number")),
+    }
+}
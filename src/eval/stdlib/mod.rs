@@ -0,0 +1,288 @@
+use std::ops::{ BitOr, BitOrAssign };
+
+use crate::{ errors::RuntimeError, sync::Rc };
+
+use super::{ gc::GarbageCollector, interpreter::Interpreter, value::Value };
+
+mod base;
+mod io;
+mod json;
+mod math;
+mod os;
+mod package;
+mod pattern;
+mod string;
+mod table;
+mod utf8;
+
+pub use package::{ Module, Searcher };
+pub(crate) use string::MAX_STRING_SIZE;
+#[cfg(feature = "serde")]
+pub(crate) use json::is_identifier;
+
+pub fn open_libs(interpreter: &mut Interpreter) -> Result<(), RuntimeError> {
+    base::open(interpreter)?;
+    string::open(interpreter)?;
+    table::open(interpreter)?;
+    utf8::open(interpreter)?;
+    math::open(interpreter)?;
+    os::open(interpreter)?;
+    io::open(interpreter)?;
+    json::open(interpreter)?;
+    package::open(interpreter)
+}
+
+/// What the `os` and `io` libraries may touch. Interpreters start with none
+/// so untrusted scripts can't reach the host, embedders grant them with
+/// [`Interpreter::set_capabilities`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// `os.time`, `os.clock` and `os.date`
+    pub const TIME: Capabilities = Capabilities(1);
+    /// `os.getenv`
+    pub const ENV: Capabilities = Capabilities(1 << 1);
+    /// Opening files for reading with `io.open` and `io.lines`
+    pub const READ_FILES: Capabilities = Capabilities(1 << 2);
+    /// Creating, writing and deleting files with `io.open`, `os.remove`,
+    /// `os.rename` and `os.tmpname`
+    pub const WRITE_FILES: Capabilities = Capabilities(1 << 3);
+    pub const ALL: Capabilities = Capabilities(0b1111);
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Capabilities::TIME => "TIME",
+            Capabilities::ENV => "ENV",
+            Capabilities::READ_FILES => "READ_FILES",
+            Capabilities::WRITE_FILES => "WRITE_FILES",
+            _ => "ALL",
+        }
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for Capabilities {
+    fn bitor_assign(&mut self, other: Capabilities) {
+        self.0 |= other.0;
+    }
+}
+
+/// Fails unless the embedder granted `capability`, `function` is the
+/// qualified name like "os.getenv"
+pub(crate) fn require(
+    interpreter: &Interpreter,
+    capability: Capabilities,
+    function: &str
+) -> Result<(), RuntimeError> {
+    if interpreter.capabilities().contains(capability) {
+        return Ok(());
+    }
+    Err(
+        RuntimeError::new(
+            format!("'{function}' is not allowed without the {} capability", capability.name())
+        )
+    )
+}
+
+// Argument checking for built-ins. `i` is the 0-based index into the
+// arguments, messages use Lua's 1-based positions.
+
+/// "bad argument #1 to 'sub' (message)"
+pub(crate) fn bad_argument(i: usize, function: &str, message: &str) -> RuntimeError {
+    RuntimeError::new(format!("bad argument #{} to '{function}' ({message})", i + 1))
+}
+
+/// "bad argument #1 to 'sub' (string expected, got nil)"
+pub(crate) fn type_error(
+    gc: &GarbageCollector,
+    args: &[Value],
+    i: usize,
+    function: &str,
+    expected: &str
+) -> RuntimeError {
+    let got = match args.get(i) {
+        Some(value) => value.type_name(gc),
+        None => "no value",
+    };
+    bad_argument(i, function, &format!("{expected} expected, got {got}"))
+}
+
+/// A string argument, numbers are converted like Lua does
+pub(crate) fn check_string(
+    gc: &GarbageCollector,
+    args: &[Value],
+    i: usize,
+    function: &str
+) -> Result<Rc<str>, RuntimeError> {
+    match args.get(i) {
+        Some(Value::String(r)) => Ok(gc.get_string(*r).unwrap()),
+        Some(v @ (Value::Number(_) | Value::Float(_))) => Ok(Rc::from(v.to_string(gc))),
+        _ => Err(type_error(gc, args, i, function, "string")),
+    }
+}
+
+/// Like `check_string`, but keeps bytes that aren't valid UTF-8
+pub(crate) fn check_bytes(
+    gc: &GarbageCollector,
+    args: &[Value],
+    i: usize,
+    function: &str
+) -> Result<Rc<[u8]>, RuntimeError> {
+    match args.get(i) {
+        Some(v @ (Value::String(_) | Value::Number(_) | Value::Float(_))) => Ok(v.to_bytes(gc)),
+        _ => Err(type_error(gc, args, i, function, "string")),
+    }
+}
+
+/// An integer argument, floats with an exact integer value and numeric
+/// strings are accepted
+pub(crate) fn check_integer(
+    gc: &GarbageCollector,
+    args: &[Value],
+    i: usize,
+    function: &str
+) -> Result<i64, RuntimeError> {
+    let float = match args.get(i) {
+        Some(Value::Number(n)) => {
+            return Ok(*n);
+        }
+        Some(Value::Float(f)) => *f,
+        Some(Value::String(r)) => {
+            let s = gc.get_string(*r).unwrap();
+            let s = s.trim();
+            if let Ok(n) = s.parse::<i64>() {
+                return Ok(n);
+            }
+            match s.parse::<f64>() {
+                Ok(f) => f,
+                Err(_) => {
+                    return Err(type_error(gc, args, i, function, "number"));
+                }
+            }
+        }
+        _ => {
+            return Err(type_error(gc, args, i, function, "number"));
+        }
+    };
+    float_to_integer(float).ok_or_else(||
+        bad_argument(i, function, "number has no integer representation")
+    )
+}
+
+/// `f` as an integer if it has an exact integer value in range
+pub(crate) fn float_to_integer(f: f64) -> Option<i64> {
+    // 2^63 itself doesn't fit, so the upper bound is exclusive
+    if f.fract() == 0.0 && f >= (i64::MIN as f64) && f < -(i64::MIN as f64) {
+        return Some(f as i64);
+    }
+    None
+}
+
+/// Parses a numeral like Lua's lexer: decimal or hexadecimal integers and
+/// floats, surrounded by optional whitespace. Decimal integers that don't fit
+/// become floats, hexadecimal ones wrap around.
+pub(crate) fn str_to_number(s: &str) -> Option<Value> {
+    let s = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    let (negative, unsigned) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    if let Some(hex) = unsigned.strip_prefix("0x").or_else(|| unsigned.strip_prefix("0X")) {
+        return hex_to_number(hex, negative);
+    }
+    // Rust would also accept "inf" and "nan"
+    if unsigned.is_empty() || !unsigned.bytes().all(|c| c.is_ascii_digit() || b".eE+-".contains(&c)) {
+        return None;
+    }
+    if unsigned.bytes().all(|c| c.is_ascii_digit()) {
+        if let Ok(n) = s.parse::<i64>() {
+            return Some(Value::Number(n));
+        }
+    }
+    s.parse::<f64>().ok().map(Value::Float)
+}
+
+// The digits after "0x", with an optional fraction and binary exponent
+fn hex_to_number(hex: &str, negative: bool) -> Option<Value> {
+    let (mantissa, exponent) = match hex.find(['p', 'P']) {
+        Some(i) => (&hex[..i], Some(&hex[i + 1..])),
+        None => (hex, None),
+    };
+    let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if integer.is_empty() && fraction.is_empty() {
+        return None;
+    }
+    if !integer.bytes().chain(fraction.bytes()).all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    if exponent.is_none() && !mantissa.contains('.') {
+        let mut n: i64 = 0;
+        for c in integer.chars() {
+            n = n.wrapping_mul(16).wrapping_add(c.to_digit(16).unwrap() as i64);
+        }
+        return Some(Value::Number(if negative { n.wrapping_neg() } else { n }));
+    }
+
+    let mut value = 0.0;
+    for c in integer.chars() {
+        value = value * 16.0 + (c.to_digit(16).unwrap() as f64);
+    }
+    let mut scale = 0;
+    for c in fraction.chars() {
+        value = value * 16.0 + (c.to_digit(16).unwrap() as f64);
+        scale -= 4;
+    }
+    let exponent: i32 = match exponent {
+        Some(e) => e.parse().ok()?,
+        None => 0,
+    };
+    let value = value * (2.0f64).powi(exponent.saturating_add(scale));
+    Some(Value::Float(if negative { -value } else { value }))
+}
+
+/// Like `check_integer`, but nil or a missing argument give `default`
+pub(crate) fn opt_integer(
+    gc: &GarbageCollector,
+    args: &[Value],
+    i: usize,
+    function: &str,
+    default: i64
+) -> Result<i64, RuntimeError> {
+    match args.get(i) {
+        None | Some(Value::Nil) => Ok(default),
+        _ => check_integer(gc, args, i, function),
+    }
+}
+
+pub(crate) fn check_number(
+    gc: &GarbageCollector,
+    args: &[Value],
+    i: usize,
+    function: &str
+) -> Result<f64, RuntimeError> {
+    match args.get(i) {
+        Some(Value::Number(n)) => Ok(*n as f64),
+        Some(Value::Float(f)) => Ok(*f),
+        Some(Value::String(r)) =>
+            gc
+                .get_string(*r)
+                .unwrap()
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| type_error(gc, args, i, function, "number")),
+        _ => Err(type_error(gc, args, i, function, "number")),
+    }
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    env,
+    fs::{ self, OpenOptions },
+    sync::OnceLock,
+    time::{ Instant, SystemTime, UNIX_EPOCH },
+};
+
+use rand::RngCore;
+
+use crate::{
+    errors::RuntimeError,
+    eval::{ interpreter::Interpreter, types::{ InterpreterFunction, Table }, value::Value },
+};
+
+use super::{ bad_argument, check_integer, check_string, io::failure, require, type_error, Capabilities };
+
+const FUNCTIONS: &[(&str, InterpreterFunction)] = &[
+    ("clock", clock),
+    ("date", date),
+    ("getenv", getenv),
+    ("remove", remove),
+    ("rename", rename),
+    ("time", time),
+    ("tmpname", tmpname),
+];
+
+// `os.clock` counts from here, there is no portable way to get the CPU time
+static START: OnceLock<Instant> = OnceLock::new();
+
+const DAYS: [&str; 7] = ["Sunday", "Monday", "Tuesday", "Wednesday", "Thursday", "Friday", "Saturday"];
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+pub fn open(interpreter: &mut Interpreter) -> Result<(), RuntimeError> {
+    START.get_or_init(Instant::now);
+    interpreter.add_library("os", FUNCTIONS)?;
+    Ok(())
+}
+
+fn clock(interpreter: &mut Interpreter, _args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    require(interpreter, Capabilities::TIME, "os.clock")?;
+    Ok(vec![Value::Float(START.get_or_init(Instant::now).elapsed().as_secs_f64())])
+}
+
+fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+// os.time([t]) is the current time, or the time described by the date
+// table t. There is no time zone database, local time is UTC.
+fn time(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    require(interpreter, Capabilities::TIME, "os.time")?;
+    let table = match args.first() {
+        None | Some(Value::Nil) => {
+            return Ok(vec![Value::Number(now())]);
+        }
+        Some(table) if table.type_name(&interpreter.gc) == "table" => *table,
+        Some(_) => {
+            return Err(type_error(&interpreter.gc, args, 0, "time", "table"));
+        }
+    };
+
+    // Like Lua, fields have to fit in a C int once `delta` is taken off,
+    // as they would in a `struct tm`
+    let mut field = |name: &str, default: Option<i64>, delta: i64| -> Result<i64, RuntimeError> {
+        let key = interpreter.create_string(name)?;
+        let value = match interpreter.index_value(table, key)? {
+            Value::Nil => default.ok_or_else(||
+                RuntimeError::new(format!("field '{name}' missing in date table"))
+            )?,
+            value =>
+                check_integer(&interpreter.gc, &[value], 0, "time").map_err(|_|
+                    RuntimeError::new(format!("field '{name}' is not an integer"))
+                )?,
+        };
+        let fits = if value >= 0 {
+            value - delta <= i32::MAX as i64
+        } else {
+            i32::MIN as i64 + delta <= value
+        };
+        if !fits {
+            return Err(RuntimeError::new(format!("field '{name}' is out-of-bound")));
+        }
+        Ok(value)
+    };
+    let year = field("year", None, 1900)?;
+    let month = field("month", None, 1)?;
+    let day = field("day", None, 0)?;
+    let hour = field("hour", Some(12), 0)?;
+    let min = field("min", Some(0), 0)?;
+    let sec = field("sec", Some(0), 0)?;
+
+    // Out of range fields carry over like mktime, month 13 is next January
+    let year = year + (month - 1).div_euclid(12);
+    let month = (month - 1).rem_euclid(12) + 1;
+    let time = days_from_civil(year, month, 1)
+        .and_then(|days| days.checked_add(day - 1))
+        .and_then(|days| days.checked_mul(86400))
+        .and_then(|time| time.checked_add(hour.checked_mul(3600)?))
+        .and_then(|time| time.checked_add(min.checked_mul(60)?))
+        .and_then(|time| time.checked_add(sec));
+    match time {
+        Some(time) => Ok(vec![Value::Number(time)]),
+        None => {
+            let message = "time result cannot be represented in this installation";
+            Err(RuntimeError::new(message.to_string()))
+        }
+    }
+}
+
+// os.date([format [, time]]) formats like C's strftime, "*t" gives a table
+fn date(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    require(interpreter, Capabilities::TIME, "os.date")?;
+    let format = match args.first() {
+        None | Some(Value::Nil) => "%c".into(),
+        _ => check_string(&interpreter.gc, args, 0, "date")?,
+    };
+    let time = match args.get(1) {
+        None | Some(Value::Nil) => now(),
+        _ => check_integer(&interpreter.gc, args, 1, "date")?,
+    };
+    // "!" asks for UTC, which is what local time is here anyway
+    let format = format.strip_prefix('!').unwrap_or(&format);
+    let date = Date::new(time);
+
+    if format.starts_with("*t") {
+        return Ok(vec![date_table(interpreter, &date)?]);
+    }
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(conversion) if date.format(conversion, &mut out) => {}
+            conversion => {
+                let conversion = conversion.map(String::from).unwrap_or_default();
+                return Err(
+                    bad_argument(
+                        0,
+                        "date",
+                        &format!("invalid conversion specifier '%{conversion}'")
+                    )
+                );
+            }
+        }
+    }
+    Ok(vec![interpreter.create_string(&out)?])
+}
+
+fn date_table(interpreter: &mut Interpreter, date: &Date) -> Result<Value, RuntimeError> {
+    let table = interpreter.allocate(Box::new(Table::new(vec![], HashMap::new())))?;
+    interpreter.keep_alive(Value::GcObject(table));
+    let fields = [
+        ("year", date.year),
+        ("month", date.month),
+        ("day", date.day),
+        ("hour", date.hour),
+        ("min", date.min),
+        ("sec", date.sec),
+        ("wday", date.wday),
+        ("yday", date.yday),
+    ];
+    for (name, value) in fields {
+        interpreter.set_field(table, name, Value::Number(value))?;
+    }
+    interpreter.set_field(table, "isdst", Value::Bool(false))?;
+    Ok(Value::GcObject(table))
+}
+
+fn getenv(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    require(interpreter, Capabilities::ENV, "os.getenv")?;
+    let name = check_string(&interpreter.gc, args, 0, "getenv")?;
+    match env::var_os(&*name) {
+        Some(value) => Ok(vec![interpreter.create_string(&value.to_string_lossy())?]),
+        None => Ok(vec![Value::Nil]),
+    }
+}
+
+// Removes a file or an empty directory
+fn remove(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    require(interpreter, Capabilities::WRITE_FILES, "os.remove")?;
+    let name = check_string(&interpreter.gc, args, 0, "remove")?;
+    let removed = match fs::symlink_metadata(&*name) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir(&*name),
+        _ => fs::remove_file(&*name),
+    };
+    match removed {
+        Ok(()) => Ok(vec![Value::Bool(true)]),
+        Err(e) => failure(interpreter, Some(&name), &e),
+    }
+}
+
+fn rename(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    require(interpreter, Capabilities::WRITE_FILES, "os.rename")?;
+    let from = check_string(&interpreter.gc, args, 0, "rename")?;
+    let to = check_string(&interpreter.gc, args, 1, "rename")?;
+    match fs::rename(&*from, &*to) {
+        Ok(()) => Ok(vec![Value::Bool(true)]),
+        Err(e) => failure(interpreter, Some(&from), &e),
+    }
+}
+
+// Creates an empty file with a fresh name in the temp directory
+fn tmpname(interpreter: &mut Interpreter, _args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    require(interpreter, Capabilities::WRITE_FILES, "os.tmpname")?;
+    for _ in 0..16 {
+        let path = env::temp_dir().join(format!("lua_{:016x}", interpreter.rng.next_u64()));
+        if OpenOptions::new().write(true).create_new(true).open(&path).is_ok() {
+            return Ok(vec![interpreter.create_string(&path.to_string_lossy())?]);
+        }
+    }
+    Err(RuntimeError::new("unable to generate a unique filename".to_string()))
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date, from Howard Hinnant's
+// date algorithms. None if it doesn't fit in an i64.
+fn days_from_civil(year: i64, month: i64, day: i64) -> Option<i64> {
+    let year = if month <= 2 { year.checked_sub(1)? } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = ((153 * ((month + 9) % 12) + 2) / 5).checked_add(day - 1)?;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era.checked_mul(146097)?.checked_add(day_of_era)?.checked_sub(719468)
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+// The fields of `os.date("*t")`, weekdays start at 1 for Sunday
+struct Date {
+    year: i64,
+    month: i64,
+    day: i64,
+    hour: i64,
+    min: i64,
+    sec: i64,
+    wday: i64,
+    yday: i64,
+}
+
+impl Date {
+    fn new(time: i64) -> Self {
+        let days = time.div_euclid(86400);
+        let seconds = time.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        Date {
+            year,
+            month,
+            day,
+            hour: seconds / 3600,
+            min: (seconds / 60) % 60,
+            sec: seconds % 60,
+            // 1970-01-01 was a Thursday
+            wday: (days + 4).rem_euclid(7) + 1,
+            // The year of an i64 time is far from overflowing
+            yday: days - days_from_civil(year, 1, 1).unwrap() + 1,
+        }
+    }
+
+    // Appends one strftime conversion in the C locale, false if unknown
+    fn format(&self, conversion: char, out: &mut String) -> bool {
+        let day_name = DAYS[(self.wday - 1) as usize];
+        let month_name = MONTHS[(self.month - 1) as usize];
+        let hour12 = if self.hour % 12 == 0 { 12 } else { self.hour % 12 };
+        let formatted = match conversion {
+            'a' => day_name[..3].to_string(),
+            'A' => day_name.to_string(),
+            'b' | 'h' => month_name[..3].to_string(),
+            'B' => month_name.to_string(),
+            'c' =>
+                format!(
+                    "{} {} {:2} {:02}:{:02}:{:02} {}",
+                    &day_name[..3],
+                    &month_name[..3],
+                    self.day,
+                    self.hour,
+                    self.min,
+                    self.sec,
+                    self.year
+                ),
+            'C' => format!("{:02}", self.year.div_euclid(100)),
+            'd' => format!("{:02}", self.day),
+            'D' | 'x' => format!("{:02}/{:02}/{:02}", self.month, self.day, self.year.rem_euclid(100)),
+            'e' => format!("{:2}", self.day),
+            'F' => format!("{}-{:02}-{:02}", self.year, self.month, self.day),
+            'H' => format!("{:02}", self.hour),
+            'I' => format!("{hour12:02}"),
+            'j' => format!("{:03}", self.yday),
+            'm' => format!("{:02}", self.month),
+            'M' => format!("{:02}", self.min),
+            'n' => "\n".to_string(),
+            'p' => (if self.hour < 12 { "AM" } else { "PM" }).to_string(),
+            'r' => format!("{hour12:02}:{:02}:{:02} {}", self.min, self.sec, if self.hour < 12 { "AM" } else { "PM" }),
+            'R' => format!("{:02}:{:02}", self.hour, self.min),
+            'S' => format!("{:02}", self.sec),
+            't' => "\t".to_string(),
+            'T' | 'X' => format!("{:02}:{:02}:{:02}", self.hour, self.min, self.sec),
+            'u' => format!("{}", (self.wday + 5) % 7 + 1),
+            'w' => format!("{}", self.wday - 1),
+            'y' => format!("{:02}", self.year.rem_euclid(100)),
+            'Y' => format!("{}", self.year),
+            'z' => "+0000".to_string(),
+            'Z' => "UTC".to_string(),
+            '%' => "%".to_string(),
+            _ => {
+                return false;
+            }
+        };
+        out.push_str(&formatted);
+        true
+    }
+}
@@ -0,0 +1,206 @@
+// `require` and the package library. Modules are looked up in
+// `package.preload`, then by the embedder's searchers and finally as files
+// along `package.path`. Whatever a module returns is cached in
+// `package.loaded`.
+
+use std::{ collections::HashMap, fs };
+
+use crate::{
+    errors::RuntimeError,
+    eval::{
+        chunk::{ syntax_error, Chunk },
+        interpreter::Interpreter,
+        types::Table,
+        value::Value,
+    },
+    sync::MaybeSend,
+};
+
+use super::{ check_string, Capabilities };
+
+const DEFAULT_PATH: &str = "?.lua;?/init.lua";
+
+// Tables opened before the package library that `require` should return
+const LIBRARIES: &[&str] = &["_G", "string", "table", "utf8", "math", "os", "io", "json"];
+
+/// Source code of a module found by a [`Searcher`]
+pub struct Module {
+    pub source: String,
+    /// Names the module in error messages, like its path
+    pub chunk_name: String,
+}
+
+/// Finds modules for `require`, like one from an asset archive. Register
+/// them with [`Interpreter::add_searcher`].
+pub trait Searcher: MaybeSend {
+    /// The module called `name`, or an explanation of why it isn't there
+    /// like "no file 'assets/name.lua'" that is added to the error when no
+    /// searcher finds it
+    fn search(&mut self, name: &str) -> Result<Module, String>;
+}
+
+/// Modules kept in memory, keyed by name
+impl Searcher for HashMap<String, String> {
+    fn search(&mut self, name: &str) -> Result<Module, String> {
+        match self.get(name) {
+            Some(source) => Ok(Module { source: source.clone(), chunk_name: name.to_string() }),
+            None => Err(format!("no module '{name}' in memory")),
+        }
+    }
+}
+
+pub fn open(interpreter: &mut Interpreter) -> Result<(), RuntimeError> {
+    let package = interpreter.add_library("package", &[])?;
+    interpreter.package = Some(package);
+    let loaded = interpreter.allocate(Box::new(Table::new(vec![], HashMap::new())))?;
+    interpreter.set_field(package, "loaded", Value::GcObject(loaded))?;
+    let preload = interpreter.allocate(Box::new(Table::new(vec![], HashMap::new())))?;
+    interpreter.set_field(package, "preload", Value::GcObject(preload))?;
+    let path = interpreter.create_string(DEFAULT_PATH)?;
+    interpreter.set_field(package, "path", path)?;
+
+    for name in LIBRARIES.iter().chain(&["package"]) {
+        let library = interpreter.get_global(name);
+        if !matches!(library, Value::Nil) {
+            interpreter.set_field(loaded, name, library)?;
+        }
+    }
+    interpreter.add_global_interpreter_function("require", require)
+}
+
+// package.loaded, package.preload or package.path
+fn package_field(interpreter: &mut Interpreter, field: &str) -> Result<Value, RuntimeError> {
+    let package = interpreter.package.expect("The package library isn't open");
+    let key = interpreter.create_string(field)?;
+    interpreter.index_value(Value::GcObject(package), key)
+}
+
+// require(name) runs the module the first time and returns what it
+// returned, or true if it returned nothing. The second result says where
+// the module came from.
+fn require(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let name = check_string(&interpreter.gc, args, 0, "require")?;
+    let key = interpreter.create_string(&name)?;
+    interpreter.keep_alive(key);
+    let loaded = package_field(interpreter, "loaded")?;
+    if !interpreter.is_table(loaded) {
+        return Err(RuntimeError::new("'package.loaded' must be a table".to_string()));
+    }
+    interpreter.keep_alive(loaded);
+    let cached = interpreter.index_value(loaded, key)?;
+    if cached.is_truthy() {
+        return Ok(vec![cached]);
+    }
+
+    if let Some(i) = interpreter.loading.iter().position(|m| **m == *name) {
+        let mut cycle = interpreter.loading[i..].join(" -> ");
+        cycle.push_str(" -> ");
+        cycle.push_str(&name);
+        return Err(RuntimeError::new(format!("circular require of module '{name}' ({cycle})")));
+    }
+
+    interpreter.loading.push(name.to_string());
+    let result = load(interpreter, &name);
+    interpreter.loading.pop();
+    let (value, origin) = result?;
+
+    interpreter.keep_alive(value);
+    // The module may have filled `package.loaded` itself
+    if !matches!(value, Value::Nil) {
+        interpreter.set_value(loaded, key, value)?;
+    }
+    let mut result = interpreter.index_value(loaded, key)?;
+    if matches!(result, Value::Nil) {
+        result = Value::Bool(true);
+        interpreter.set_value(loaded, key, result)?;
+    }
+    let origin = interpreter.create_string(&origin)?;
+    Ok(vec![result, origin])
+}
+
+// Finds and runs module `name`, returning its first result and where it
+// was found
+fn load(interpreter: &mut Interpreter, name: &str) -> Result<(Value, String), RuntimeError> {
+    let mut reasons = String::new();
+
+    let preload = package_field(interpreter, "preload")?;
+    if !interpreter.is_table(preload) {
+        return Err(RuntimeError::new("'package.preload' must be a table".to_string()));
+    }
+    let key = interpreter.create_string(name)?;
+    let loader = interpreter.index_value(preload, key)?;
+    if !matches!(loader, Value::Nil) {
+        interpreter.keep_alive(loader);
+        let origin = interpreter.create_string(":preload:")?;
+        interpreter.keep_alive(origin);
+        let results = interpreter.call(loader, &[key, origin])?;
+        return Ok((results.first().copied().unwrap_or(Value::Nil), ":preload:".to_string()));
+    }
+    reasons.push_str(&format!("\n\tno field package.preload['{name}']"));
+
+    let mut found = None;
+    for searcher in interpreter.searchers.iter_mut() {
+        match searcher.search(name) {
+            Ok(module) => {
+                found = Some(module);
+                break;
+            }
+            Err(reason) => {
+                reasons.push_str("\n\t");
+                reasons.push_str(&reason);
+            }
+        }
+    }
+    if found.is_none() {
+        found = search_path(interpreter, name, &mut reasons)?;
+    }
+    let Some(module) = found else {
+        return Err(RuntimeError::new(format!("module '{name}' not found:{reasons}")));
+    };
+
+    let chunk = Chunk::compile(&module.source, &module.chunk_name).map_err(|e| {
+        RuntimeError::new(
+            format!(
+                "error loading module '{name}' from '{}':\n\t{}",
+                module.chunk_name,
+                syntax_error(&module.chunk_name, &e)
+            )
+        )
+    })?;
+    let results = interpreter.eval_chunk(&chunk, None)?;
+    Ok((results.first().copied().unwrap_or(Value::Nil), module.chunk_name))
+}
+
+// Tries each template of `package.path` with the dots in `name` replaced by
+// directory separators
+fn search_path(
+    interpreter: &mut Interpreter,
+    name: &str,
+    reasons: &mut String
+) -> Result<Option<Module>, RuntimeError> {
+    if !interpreter.capabilities().contains(Capabilities::READ_FILES) {
+        reasons.push_str("\n\tno file search without the READ_FILES capability");
+        return Ok(None);
+    }
+    let path = match package_field(interpreter, "path")? {
+        Value::String(r) => interpreter.gc.get_string(r).unwrap(),
+        _ => {
+            return Err(RuntimeError::new("'package.path' must be a string".to_string()));
+        }
+    };
+
+    let file_name = name.replace('.', "/");
+    for template in path.split(';').filter(|t| !t.is_empty()) {
+        let file = template.replace('?', &file_name);
+        if !fs::metadata(&file).is_ok_and(|m| m.is_file()) {
+            reasons.push_str(&format!("\n\tno file '{file}'"));
+            continue;
+        }
+        return match fs::read_to_string(&file) {
+            Ok(source) => Ok(Some(Module { source, chunk_name: file })),
+            Err(e) =>
+                Err(RuntimeError::new(format!("error loading module '{name}' from '{file}':\n\t{e}"))),
+        };
+    }
+    Ok(None)
+}
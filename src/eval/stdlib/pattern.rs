@@ -0,0 +1,462 @@
+// Lua pattern matching, a port of the matcher in Lua's lstrlib.c working on
+// bytes. Positions are 0-based byte offsets, the library converts them.
+
+const MAX_CAPTURES: usize = 32;
+const MAX_MATCH_DEPTH: usize = 200;
+const ESCAPE: u8 = b'%';
+const SPECIALS: &[u8] = b"^$*+?.()[%-";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    /// `start..end` of the subject
+    Slice(usize, usize),
+    /// A `()` capture
+    Position(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CaptureLen {
+    Unfinished,
+    Position,
+    Len(usize),
+}
+
+pub struct Matcher<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    level: usize,
+    captures: [(usize, CaptureLen); MAX_CAPTURES],
+    depth: usize,
+}
+
+/// True if `pattern` has no magic characters and can be searched for as is
+pub fn is_plain(pattern: &[u8]) -> bool {
+    !pattern.iter().any(|c| SPECIALS.contains(c))
+}
+
+/// Plain substring search starting at `init`
+pub fn find_plain(src: &[u8], pattern: &[u8], init: usize) -> Option<usize> {
+    if pattern.is_empty() {
+        return Some(init);
+    }
+    if pattern.len() > src.len() {
+        return None;
+    }
+    (init..=src.len() - pattern.len()).find(|i| &src[*i..*i + pattern.len()] == pattern)
+}
+
+impl<'a> Matcher<'a> {
+    pub fn new(src: &'a [u8], pat: &'a [u8]) -> Self {
+        Matcher {
+            src,
+            pat,
+            level: 0,
+            captures: [(0, CaptureLen::Unfinished); MAX_CAPTURES],
+            depth: MAX_MATCH_DEPTH,
+        }
+    }
+
+    /// Checks the whole pattern from byte `p` on, so that a malformed one
+    /// is an error even where matching never gets to the broken part
+    pub fn check(&self, mut p: usize) -> Result<(), String> {
+        // Whether each capture opened so far is closed
+        let mut closed: Vec<bool> = vec![];
+        while p < self.pat.len() {
+            match self.pat[p] {
+                b'(' => {
+                    if closed.len() >= MAX_CAPTURES {
+                        return Err("too many captures".to_string());
+                    }
+                    let position = self.pat.get(p + 1) == Some(&b')');
+                    closed.push(position);
+                    p += if position { 2 } else { 1 };
+                    continue;
+                }
+                b')' => {
+                    let l = closed
+                        .iter()
+                        .rposition(|c| !c)
+                        .ok_or_else(|| "invalid pattern capture".to_string())?;
+                    closed[l] = true;
+                    p += 1;
+                    continue;
+                }
+                b'$' if p + 1 == self.pat.len() => {
+                    break;
+                }
+                ESCAPE if self.pat.get(p + 1) == Some(&b'b') => {
+                    if p + 3 >= self.pat.len() {
+                        return Err("malformed pattern (missing arguments to '%b')".to_string());
+                    }
+                    p += 4;
+                    continue;
+                }
+                ESCAPE if self.pat.get(p + 1) == Some(&b'f') => {
+                    p += 2;
+                    if self.pat.get(p) != Some(&b'[') {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    p = self.class_end(p)?;
+                    continue;
+                }
+                ESCAPE if self.pat.get(p + 1).is_some_and(|c| c.is_ascii_digit()) => {
+                    let l = (self.pat[p + 1] as usize).wrapping_sub(b'1' as usize);
+                    if !closed.get(l).copied().unwrap_or(false) {
+                        return Err(format!("invalid capture index %{}", l.wrapping_add(1)));
+                    }
+                    p += 2;
+                    continue;
+                }
+                _ => {}
+            }
+            p = self.class_end(p)?;
+            if matches!(self.pat.get(p), Some(b'*' | b'+' | b'-' | b'?')) {
+                p += 1;
+            }
+        }
+        if closed.contains(&false) {
+            return Err("unfinished capture".to_string());
+        }
+        Ok(())
+    }
+
+    /// Tries to match the pattern starting at byte `p` against the subject
+    /// at `s`, returning where the match ends
+    pub fn match_at(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        self.level = 0;
+        self.depth = MAX_MATCH_DEPTH;
+        self.do_match(s, p)
+    }
+
+    /// Captures of the last successful match. A pattern without captures
+    /// captures the whole match if `whole` is set.
+    pub fn captures(&self, s: usize, e: usize, whole: bool) -> Result<Vec<Capture>, String> {
+        let count = if self.level == 0 && whole { 1 } else { self.level };
+        (0..count).map(|i| self.get_capture(i, s, e)).collect()
+    }
+
+    /// Capture `i`, or the whole match for `i == 0` in a pattern without
+    /// captures
+    pub fn get_capture(&self, i: usize, s: usize, e: usize) -> Result<Capture, String> {
+        if i >= self.level {
+            if i != 0 {
+                return Err(format!("invalid capture index %{}", i + 1));
+            }
+            return Ok(Capture::Slice(s, e));
+        }
+        match self.captures[i] {
+            (_, CaptureLen::Unfinished) => Err("unfinished capture".to_string()),
+            (start, CaptureLen::Position) => Ok(Capture::Position(start)),
+            (start, CaptureLen::Len(len)) => Ok(Capture::Slice(start, start + len)),
+        }
+    }
+
+    pub fn src(&self) -> &'a [u8] {
+        self.src
+    }
+
+    fn do_match(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, String> {
+        if self.depth == 0 {
+            return Err("pattern too complex".to_string());
+        }
+        self.depth -= 1;
+
+        let result = loop {
+            if p == self.pat.len() {
+                break Some(s);
+            }
+            match self.pat[p] {
+                b'(' => {
+                    if self.pat.get(p + 1) == Some(&b')') {
+                        break self.start_capture(s, p + 2, CaptureLen::Position)?;
+                    }
+                    break self.start_capture(s, p + 1, CaptureLen::Unfinished)?;
+                }
+                b')' => {
+                    break self.end_capture(s, p + 1)?;
+                }
+                b'$' if p + 1 == self.pat.len() => {
+                    break if s == self.src.len() { Some(s) } else { None };
+                }
+                ESCAPE if self.pat.get(p + 1) == Some(&b'b') => {
+                    match self.match_balance(s, p + 2)? {
+                        Some(end) => {
+                            s = end;
+                            p += 4;
+                            continue;
+                        }
+                        None => {
+                            break None;
+                        }
+                    }
+                }
+                ESCAPE if self.pat.get(p + 1) == Some(&b'f') => {
+                    p += 2;
+                    if self.pat.get(p) != Some(&b'[') {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    let current = self.src.get(s).copied().unwrap_or(0);
+                    if
+                        !self.match_bracket_class(previous, p, ep - 1) &&
+                        self.match_bracket_class(current, p, ep - 1)
+                    {
+                        p = ep;
+                        continue;
+                    }
+                    break None;
+                }
+                ESCAPE if self.pat.get(p + 1).is_some_and(|c| c.is_ascii_digit()) => {
+                    match self.match_capture(s, self.pat[p + 1])? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                            continue;
+                        }
+                        None => {
+                            break None;
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            let ep = self.class_end(p)?;
+            let repetition = self.pat.get(ep).copied();
+            if !self.single_match(s, p, ep) {
+                // Accept the empty match of an optional item
+                if matches!(repetition, Some(b'*' | b'?' | b'-')) {
+                    p = ep + 1;
+                    continue;
+                }
+                break None;
+            }
+            match repetition {
+                Some(b'?') => {
+                    if let Some(end) = self.do_match(s + 1, ep + 1)? {
+                        break Some(end);
+                    }
+                    p = ep + 1;
+                }
+                Some(b'+') => {
+                    break self.max_expand(s + 1, p, ep)?;
+                }
+                Some(b'*') => {
+                    break self.max_expand(s, p, ep)?;
+                }
+                Some(b'-') => {
+                    break self.min_expand(s, p, ep)?;
+                }
+                _ => {
+                    s += 1;
+                    p = ep;
+                }
+            }
+        };
+
+        self.depth += 1;
+        Ok(result)
+    }
+
+    // Index just past the single character class starting at `p`
+    fn class_end(&self, mut p: usize) -> Result<usize, String> {
+        let c = self.pat[p];
+        p += 1;
+        if c == ESCAPE {
+            if p >= self.pat.len() {
+                return Err("malformed pattern (ends with '%')".to_string());
+            }
+            return Ok(p + 1);
+        }
+        if c == b'[' {
+            if self.pat.get(p) == Some(&b'^') {
+                p += 1;
+            }
+            // The first character is never the closing ']'
+            loop {
+                if p >= self.pat.len() {
+                    return Err("malformed pattern (missing ']')".to_string());
+                }
+                let c = self.pat[p];
+                p += 1;
+                if c == ESCAPE && p < self.pat.len() {
+                    p += 1;
+                }
+                if self.pat.get(p) == Some(&b']') {
+                    return Ok(p + 1);
+                }
+            }
+        }
+        Ok(p)
+    }
+
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        let Some(&c) = self.src.get(s) else {
+            return false;
+        };
+        match self.pat[p] {
+            b'.' => true,
+            ESCAPE => match_class(c, self.pat[p + 1]),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    // `p` is at the '[' and `ec` at the closing ']'
+    fn match_bracket_class(&self, c: u8, mut p: usize, ec: usize) -> bool {
+        let mut matched = true;
+        p += 1;
+        if self.pat[p] == b'^' {
+            matched = false;
+            p += 1;
+        }
+        while p < ec {
+            if self.pat[p] == ESCAPE {
+                p += 1;
+                if match_class(c, self.pat[p]) {
+                    return matched;
+                }
+                p += 1;
+            } else if self.pat[p + 1] == b'-' && p + 2 < ec {
+                if self.pat[p] <= c && c <= self.pat[p + 2] {
+                    return matched;
+                }
+                p += 3;
+            } else {
+                if self.pat[p] == c {
+                    return matched;
+                }
+                p += 1;
+            }
+        }
+        !matched
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        let mut i = 0;
+        while self.single_match(s + i, p, ep) {
+            i += 1;
+        }
+        // Try the longest repetition first and back off one at a time
+        loop {
+            if let Some(end) = self.do_match(s + i, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if i == 0 {
+                return Ok(None);
+            }
+            i -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(end) = self.do_match(s, ep + 1)? {
+                return Ok(Some(end));
+            }
+            if !self.single_match(s, p, ep) {
+                return Ok(None);
+            }
+            s += 1;
+        }
+    }
+
+    fn start_capture(
+        &mut self,
+        s: usize,
+        p: usize,
+        what: CaptureLen
+    ) -> Result<Option<usize>, String> {
+        if self.level >= MAX_CAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.captures[self.level] = (s, what);
+        self.level += 1;
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.level -= 1;
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        let l = self.capture_to_close()?;
+        let start = self.captures[l].0;
+        self.captures[l].1 = CaptureLen::Len(s - start);
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures[l].1 = CaptureLen::Unfinished;
+        }
+        Ok(result)
+    }
+
+    fn capture_to_close(&self) -> Result<usize, String> {
+        (0..self.level)
+            .rev()
+            .find(|l| self.captures[*l].1 == CaptureLen::Unfinished)
+            .ok_or_else(|| "invalid pattern capture".to_string())
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if p + 1 >= self.pat.len() {
+            return Err("malformed pattern (missing arguments to '%b')".to_string());
+        }
+        if self.src.get(s) != Some(&self.pat[p]) {
+            return Ok(None);
+        }
+        let (open, close) = (self.pat[p], self.pat[p + 1]);
+        let mut depth = 1;
+        for (i, c) in self.src.iter().enumerate().skip(s + 1) {
+            if *c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if *c == open {
+                depth += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    // A back reference like %1
+    fn match_capture(&self, s: usize, l: u8) -> Result<Option<usize>, String> {
+        let l = (l as usize).wrapping_sub(b'1' as usize);
+        if l >= self.level || self.captures[l].1 == CaptureLen::Unfinished {
+            return Err(format!("invalid capture index %{}", l.wrapping_add(1)));
+        }
+        let (start, CaptureLen::Len(len)) = self.captures[l] else {
+            return Ok(None);
+        };
+        if self.src.len() - s >= len && self.src[start..start + len] == self.src[s..s + len] {
+            return Ok(Some(s + len));
+        }
+        Ok(None)
+    }
+}
+
+// %a, %d, ... and their complements %A, %D, ...
+fn match_class(c: u8, class: u8) -> bool {
+    let matched = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        // C's isspace, which unlike is_ascii_whitespace includes \v
+        b's' => c == b' ' || (b'\t'..=b'\r').contains(&c),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => {
+            return class == c;
+        }
+    };
+    if class.is_ascii_uppercase() {
+        !matched
+    } else {
+        matched
+    }
+}
@@ -0,0 +1,776 @@
+use std::{ collections::HashMap, io::Write, iter::{ Copied, Peekable }, mem, slice::Iter };
+
+use crate::{
+    errors::RuntimeError,
+    eval::{
+        gc::{ GarbageCollector, GcRef, GcValue },
+        interpreter::Interpreter,
+        types::{ InterpreterFunction, Iterable, Table },
+        value::Value,
+    },
+    sync::{ Cell, Rc },
+};
+
+use super::{
+    bad_argument,
+    base::tostring_bytes,
+    check_bytes,
+    check_integer,
+    check_number,
+    opt_integer,
+    pattern::{ self, Capture, Matcher },
+    type_error,
+};
+
+// Larger results can't be allocated anyway, refuse before trying
+pub(crate) const MAX_STRING_SIZE: usize = i32::MAX as usize;
+
+const FUNCTIONS: &[(&str, InterpreterFunction)] = &[
+    ("byte", str_byte),
+    ("char", str_char),
+    ("find", str_find),
+    ("format", str_format),
+    ("gmatch", str_gmatch),
+    ("gsub", str_gsub),
+    ("len", str_len),
+    ("lower", str_lower),
+    ("match", str_match),
+    ("rep", str_rep),
+    ("reverse", str_reverse),
+    ("sub", str_sub),
+    ("upper", str_upper),
+];
+
+pub fn open(interpreter: &mut Interpreter) -> Result<(), RuntimeError> {
+    let string = interpreter.add_library("string", FUNCTIONS)?;
+
+    // Every string shares this metatable, `s:upper()` looks `upper` up in it
+    let metatable = interpreter.allocate(Box::new(Table::new(vec![], HashMap::new())))?;
+    interpreter.string_metatable = Some(metatable);
+    interpreter.set_field(metatable, "__index", Value::GcObject(string))
+}
+
+// Strings are sliced by bytes like in Lua, even through a multi byte
+// character, the `utf8` library knows about characters
+fn create_string(interpreter: &mut Interpreter, bytes: &[u8]) -> Result<Value, RuntimeError> {
+    interpreter.create_byte_string(bytes)
+}
+
+// Lua's 1-based string positions, negative ones count from the end
+fn start_position(position: i64, len: usize) -> usize {
+    if position > 0 {
+        position as usize
+    } else if position == 0 || position < -(len as i64) {
+        1
+    } else {
+        ((len as i64) + position + 1) as usize
+    }
+}
+
+fn end_position(position: i64, len: usize) -> usize {
+    if position > (len as i64) {
+        len
+    } else if position >= 0 {
+        position as usize
+    } else if position < -(len as i64) {
+        0
+    } else {
+        ((len as i64) + position + 1) as usize
+    }
+}
+
+fn str_len(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let s = check_bytes(&interpreter.gc, args, 0, "len")?;
+    Ok(vec![Value::Number(s.len() as i64)])
+}
+
+fn str_sub(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let gc = &interpreter.gc;
+    let s = check_bytes(gc, args, 0, "sub")?;
+    let start = start_position(opt_integer(gc, args, 1, "sub", 1)?, s.len());
+    let end = end_position(opt_integer(gc, args, 2, "sub", -1)?, s.len());
+
+    if start > end {
+        return Ok(vec![interpreter.create_string("")?]);
+    }
+    Ok(vec![create_string(interpreter, &s[start - 1..end])?])
+}
+
+fn str_upper(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let s = check_bytes(&interpreter.gc, args, 0, "upper")?;
+    Ok(vec![create_string(interpreter, &s.to_ascii_uppercase())?])
+}
+
+fn str_lower(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let s = check_bytes(&interpreter.gc, args, 0, "lower")?;
+    Ok(vec![create_string(interpreter, &s.to_ascii_lowercase())?])
+}
+
+fn str_rep(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let gc = &interpreter.gc;
+    let s = check_bytes(gc, args, 0, "rep")?;
+    let n = check_integer(gc, args, 1, "rep")?;
+    let separator = match args.get(2) {
+        None | Some(Value::Nil) => Rc::from(&[][..]),
+        _ => check_bytes(gc, args, 2, "rep")?,
+    };
+    if n <= 0 {
+        return Ok(vec![interpreter.create_string("")?]);
+    }
+
+    let n = n as usize;
+    let size = s
+        .len()
+        .checked_mul(n)
+        .and_then(|size| size.checked_add(separator.len().checked_mul(n - 1)?));
+    let size = match size {
+        Some(size) if size < MAX_STRING_SIZE => size,
+        _ => {
+            return Err(RuntimeError::new("resulting string too large".to_string()));
+        }
+    };
+    // Don't build a string that could never be allocated
+    if !interpreter.gc.can_allocate(size) {
+        interpreter.collect_garbage();
+        if !interpreter.gc.can_allocate(size) {
+            return Err(RuntimeError::out_of_memory());
+        }
+    }
+
+    let mut repeated = Vec::with_capacity(size);
+    for i in 0..n {
+        if i > 0 {
+            repeated.extend_from_slice(&separator);
+        }
+        repeated.extend_from_slice(&s);
+    }
+    Ok(vec![create_string(interpreter, &repeated)?])
+}
+
+fn str_reverse(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let s = check_bytes(&interpreter.gc, args, 0, "reverse")?;
+    let reversed: Vec<u8> = s.iter().rev().copied().collect();
+    Ok(vec![create_string(interpreter, &reversed)?])
+}
+
+fn str_byte(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let gc = &interpreter.gc;
+    let s = check_bytes(gc, args, 0, "byte")?;
+    let start = start_position(opt_integer(gc, args, 1, "byte", 1)?, s.len());
+    let end = end_position(opt_integer(gc, args, 2, "byte", start as i64)?, s.len());
+
+    if start > end {
+        return Ok(vec![]);
+    }
+    Ok(
+        s[start - 1..end]
+            .iter()
+            .map(|b| Value::Number(*b as i64))
+            .collect()
+    )
+}
+
+fn str_char(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let mut bytes = Vec::with_capacity(args.len());
+    for i in 0..args.len() {
+        let c = check_integer(&interpreter.gc, args, i, "char")?;
+        if !(0..=255).contains(&c) {
+            return Err(bad_argument(i, "char", "value out of range"));
+        }
+        bytes.push(c as u8);
+    }
+    Ok(vec![create_string(interpreter, &bytes)?])
+}
+
+fn str_find(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    find(interpreter, args, true)
+}
+
+fn str_match(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    find(interpreter, args, false)
+}
+
+// `find` returns the positions followed by the captures, `match` only the
+// captures or the whole match
+fn find(
+    interpreter: &mut Interpreter,
+    args: &[Value],
+    is_find: bool
+) -> Result<Vec<Value>, RuntimeError> {
+    let name = if is_find { "find" } else { "match" };
+    let gc = &interpreter.gc;
+    let s = check_bytes(gc, args, 0, name)?;
+    let p = check_bytes(gc, args, 1, name)?;
+    let (src, pat) = (&s[..], &p[..]);
+    let init = start_position(opt_integer(gc, args, 2, name, 1)?, src.len()) - 1;
+    if init > src.len() {
+        return Ok(vec![Value::Nil]);
+    }
+
+    let plain = args.get(3).is_some_and(|v| v.is_truthy());
+    if is_find && (plain || pattern::is_plain(pat)) {
+        return Ok(match pattern::find_plain(src, pat, init) {
+            Some(start) =>
+                vec![Value::Number((start as i64) + 1), Value::Number((start + pat.len()) as i64)],
+            None => vec![Value::Nil],
+        });
+    }
+
+    let anchor = pat.first() == Some(&b'^');
+    let mut matcher = Matcher::new(src, pat);
+    matcher.check(anchor as usize).map_err(RuntimeError::new)?;
+    let mut start = init;
+    loop {
+        if let Some(end) = matcher.match_at(start, anchor as usize).map_err(RuntimeError::new)? {
+            let captures = matcher.captures(start, end, !is_find).map_err(RuntimeError::new)?;
+            let mut results = vec![];
+            if is_find {
+                results.push(Value::Number((start as i64) + 1));
+                results.push(Value::Number(end as i64));
+            }
+            push_captures(interpreter, src, &captures, &mut results)?;
+            return Ok(results);
+        }
+        start += 1;
+        if anchor || start > src.len() {
+            return Ok(vec![Value::Nil]);
+        }
+    }
+}
+
+fn push_captures(
+    interpreter: &mut Interpreter,
+    src: &[u8],
+    captures: &[Capture],
+    results: &mut Vec<Value>
+) -> Result<(), RuntimeError> {
+    for capture in captures {
+        let value = match *capture {
+            Capture::Slice(start, end) => create_string(interpreter, &src[start..end])?,
+            Capture::Position(position) => Value::Number((position as i64) + 1),
+        };
+        interpreter.keep_alive(value);
+        results.push(value);
+    }
+    Ok(())
+}
+
+fn str_gmatch(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let gc = &interpreter.gc;
+    let s = check_bytes(gc, args, 0, "gmatch")?;
+    let p = check_bytes(gc, args, 1, "gmatch")?;
+    let (src, pat) = (&s[..], &p[..]);
+    let mut start = start_position(opt_integer(gc, args, 2, "gmatch", 1)?, src.len()) - 1;
+
+    // Matched up front, the iterator only hands the results out
+    let mut matches = vec![];
+    let mut matcher = Matcher::new(src, pat);
+    matcher.check(0).map_err(RuntimeError::new)?;
+    let mut last = None;
+    while start <= src.len() {
+        match matcher.match_at(start, 0).map_err(RuntimeError::new)? {
+            Some(end) if Some(end) != last => {
+                let captures = matcher.captures(start, end, true).map_err(RuntimeError::new)?;
+                let mut values = vec![];
+                push_captures(interpreter, src, &captures, &mut values)?;
+                matches.push(values);
+                start = end;
+                last = Some(end);
+            }
+            _ => {
+                start += 1;
+            }
+        }
+    }
+
+    let iterator = GmatchIterator { matches, position: Cell::new(0) };
+    Ok(vec![Value::GcObject(interpreter.allocate(Box::new(iterator))?)])
+}
+
+/// Returned by `string.gmatch`. Calling it returns the captures of the next
+/// match, a generic `for` gets the first capture of each match.
+struct GmatchIterator {
+    matches: Vec<Vec<Value>>,
+    position: Cell<usize>,
+}
+
+impl GcValue for GmatchIterator {
+    fn get_referenced_children(&self, _gc: &GarbageCollector) -> Vec<GcRef> {
+        self.matches
+            .iter()
+            .flatten()
+            .filter_map(|v| v.gc_ref())
+            .collect()
+    }
+
+    fn name(&self) -> &'static str {
+        "function"
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>() +
+            self.matches
+                .iter()
+                .map(|m| mem::size_of::<Vec<Value>>() + m.capacity() * mem::size_of::<Value>())
+                .sum::<usize>()
+    }
+
+    fn str(&self, _gc: &GarbageCollector) -> String {
+        "function".to_string()
+    }
+
+    fn iter(&self) -> Result<Iterable, RuntimeError> {
+        Ok(Iterable::new(
+            self.matches[self.position.get()..]
+                .iter()
+                .map(|m| m[0])
+                .collect()
+        ))
+    }
+
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        _args: &[Value]
+    ) -> Result<Vec<Value>, RuntimeError> {
+        let position = self.position.get();
+        match self.matches.get(position) {
+            Some(captures) => {
+                self.position.set(position + 1);
+                Ok(captures.clone())
+            }
+            None => Ok(vec![]),
+        }
+    }
+}
+
+fn str_gsub(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let gc = &interpreter.gc;
+    let s = check_bytes(gc, args, 0, "gsub")?;
+    let p = check_bytes(gc, args, 1, "gsub")?;
+    let replacement = match args.get(2).map(|v| v.type_name(gc)) {
+        Some("string" | "number") => Replacement::Template(check_bytes(gc, args, 2, "gsub")?),
+        Some("table" | "function") => Replacement::Value(args[2]),
+        _ => {
+            return Err(type_error(gc, args, 2, "gsub", "string/function/table"));
+        }
+    };
+    let (src, pat) = (&s[..], &p[..]);
+    let max = opt_integer(gc, args, 3, "gsub", (src.len() as i64) + 1)?;
+
+    let anchor = pat.first() == Some(&b'^');
+    let mut matcher = Matcher::new(src, pat);
+    matcher.check(anchor as usize).map_err(RuntimeError::new)?;
+    let mut out = Vec::with_capacity(src.len());
+    let mut start = 0;
+    let mut last = None;
+    let mut count = 0;
+    while count < max {
+        match matcher.match_at(start, anchor as usize).map_err(RuntimeError::new)? {
+            Some(end) if Some(end) != last => {
+                count += 1;
+                replacement.apply(interpreter, &matcher, start, end, &mut out)?;
+                start = end;
+                last = Some(end);
+            }
+            _ if start < src.len() => {
+                out.push(src[start]);
+                start += 1;
+            }
+            _ => {
+                break;
+            }
+        }
+        if anchor {
+            break;
+        }
+    }
+    out.extend_from_slice(&src[start..]);
+
+    Ok(vec![create_string(interpreter, &out)?, Value::Number(count)])
+}
+
+enum Replacement {
+    // A string where %0 to %9 stand for captures
+    Template(Rc<[u8]>),
+    // A table indexed by, or a function called with, the captures
+    Value(Value),
+}
+
+impl Replacement {
+    fn apply(
+        &self,
+        interpreter: &mut Interpreter,
+        matcher: &Matcher,
+        start: usize,
+        end: usize,
+        out: &mut Vec<u8>
+    ) -> Result<(), RuntimeError> {
+        let src = matcher.src();
+        let function = match self {
+            Replacement::Template(template) => {
+                let mut bytes = template.iter().copied();
+                while let Some(c) = bytes.next() {
+                    if c != b'%' {
+                        out.push(c);
+                        continue;
+                    }
+                    match bytes.next() {
+                        Some(b'%') => out.push(b'%'),
+                        Some(d) if d.is_ascii_digit() => {
+                            let capture = if d == b'0' {
+                                Capture::Slice(start, end)
+                            } else {
+                                matcher
+                                    .get_capture((d - b'1') as usize, start, end)
+                                    .map_err(RuntimeError::new)?
+                            };
+                            match capture {
+                                Capture::Slice(s, e) => out.extend_from_slice(&src[s..e]),
+                                Capture::Position(p) =>
+                                    out.extend_from_slice((p + 1).to_string().as_bytes()),
+                            }
+                        }
+                        _ => {
+                            return Err(
+                                RuntimeError::new(
+                                    "invalid use of '%' in replacement string".to_string()
+                                )
+                            );
+                        }
+                    }
+                }
+                return Ok(());
+            }
+            Replacement::Value(function) => *function,
+        };
+
+        let captures = matcher.captures(start, end, true).map_err(RuntimeError::new)?;
+        let mut values = vec![];
+        push_captures(interpreter, src, &captures, &mut values)?;
+
+        let value = match function {
+            Value::GcObject(_) if function.type_name(&interpreter.gc) == "table" => {
+                interpreter.index_value(function, values[0])?
+            }
+            _ => interpreter.call(function, &values)?.first().copied().unwrap_or(Value::Nil),
+        };
+        match value {
+            // Keeps the original match
+            Value::Nil | Value::Bool(false) => out.extend_from_slice(&src[start..end]),
+            Value::String(_) | Value::Number(_) | Value::Float(_) => {
+                out.extend_from_slice(&value.to_bytes(&interpreter.gc));
+            }
+            _ => {
+                return Err(
+                    RuntimeError::new(
+                        format!(
+                            "invalid replacement value (a {})",
+                            value.type_name(&interpreter.gc)
+                        )
+                    )
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct FormatSpec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alt: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+impl FormatSpec {
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative {
+            "-"
+        } else if self.plus {
+            "+"
+        } else if self.space {
+            " "
+        } else {
+            ""
+        }
+    }
+
+    // Zeros go between the sign or prefix and the digits
+    fn pad(&self, prefix: &str, body: &str, zero_pad: bool) -> String {
+        let fill = self.width.saturating_sub(prefix.len() + body.len());
+        if self.left {
+            format!("{prefix}{body}{}", " ".repeat(fill))
+        } else if self.zero && zero_pad {
+            format!("{prefix}{}{body}", "0".repeat(fill))
+        } else {
+            format!("{}{prefix}{body}", " ".repeat(fill))
+        }
+    }
+
+    // Strings are padded with spaces only
+    fn pad_bytes(&self, body: &[u8]) -> Vec<u8> {
+        let fill = vec![b' '; self.width.saturating_sub(body.len())];
+        if self.left { [body, &fill].concat() } else { [&fill, body].concat() }
+    }
+
+    // The minimum number of digits of an integer
+    fn integer_digits(&self, digits: String, zero: bool) -> String {
+        match self.precision {
+            Some(0) if zero => String::new(),
+            Some(precision) => format!("{digits:0>precision$}"),
+            None => digits,
+        }
+    }
+}
+
+fn str_format(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let format = check_bytes(&interpreter.gc, args, 0, "format")?;
+    let mut out = Vec::with_capacity(format.len());
+    let mut bytes = format.iter().copied().peekable();
+    let mut arg = 0;
+
+    while let Some(c) = bytes.next() {
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        if bytes.next_if_eq(&b'%').is_some() {
+            out.push(b'%');
+            continue;
+        }
+
+        let mut spec = FormatSpec::default();
+        let mut raw = String::from("%");
+        while let Some(flag) = bytes.next_if(|c| b"-+ #0".contains(c)).map(char::from) {
+            match flag {
+                '-' => {
+                    spec.left = true;
+                }
+                '+' => {
+                    spec.plus = true;
+                }
+                ' ' => {
+                    spec.space = true;
+                }
+                '#' => {
+                    spec.alt = true;
+                }
+                _ => {
+                    spec.zero = true;
+                }
+            }
+            raw.push(flag);
+        }
+        let width = take_digits(&mut bytes, &mut raw);
+        let precision = match bytes.next_if_eq(&b'.') {
+            Some(_) => {
+                raw.push('.');
+                Some(take_digits(&mut bytes, &mut raw))
+            }
+            None => None,
+        };
+        let conversion = bytes.next().map(char::from);
+        raw.extend(conversion);
+        let invalid = || RuntimeError::new(format!("invalid conversion '{raw}' to 'format'"));
+
+        // Like C, at most two digits each
+        if width.len() > 2 || precision.as_ref().is_some_and(|p| p.len() > 2) {
+            return Err(invalid());
+        }
+        spec.width = width.parse().unwrap_or(0);
+        spec.precision = precision.map(|p| p.parse().unwrap_or(0));
+
+        let gc = &interpreter.gc;
+        arg += 1;
+        if arg >= args.len() && conversion.is_some() {
+            return Err(bad_argument(arg, "format", "no value"));
+        }
+        let formatted = match conversion {
+            Some('c') => {
+                let c = check_integer(gc, args, arg, "format")?;
+                spec.pad_bytes(&[c as u8])
+            }
+            Some('d' | 'i') => {
+                let n = check_integer(gc, args, arg, "format")?;
+                let digits = spec.integer_digits(n.unsigned_abs().to_string(), n == 0);
+                spec.pad(spec.sign(n < 0), &digits, spec.precision.is_none()).into_bytes()
+            }
+            Some(conversion @ ('o' | 'x' | 'X')) => {
+                // Formatted as unsigned, like C does
+                let n = check_integer(gc, args, arg, "format")? as u64;
+                let (digits, prefix) = match conversion {
+                    'o' => (format!("{n:o}"), "0"),
+                    'x' => (format!("{n:x}"), "0x"),
+                    _ => (format!("{n:X}"), "0X"),
+                };
+                let digits = spec.integer_digits(digits, n == 0);
+                let prefix = if spec.alt && n != 0 { prefix } else { "" };
+                spec.pad(prefix, &digits, spec.precision.is_none()).into_bytes()
+            }
+            Some(conversion @ ('e' | 'E' | 'f' | 'F' | 'g' | 'G')) => {
+                format_float(check_number(gc, args, arg, "format")?, conversion, &spec).into_bytes()
+            }
+            Some('q') => {
+                if raw.len() != 2 {
+                    return Err(RuntimeError::new("specifier '%q' cannot have modifiers".to_string()));
+                }
+                quote(gc, args, arg)?
+            }
+            Some('s') => {
+                // Like `tostring`, so `__tostring` and `__name` are used
+                let s = tostring_bytes(interpreter, args[arg])?;
+                // The precision and width count bytes
+                let end = spec.precision.map_or(s.len(), |precision| precision.min(s.len()));
+                spec.pad_bytes(&s[..end])
+            }
+            _ => {
+                return Err(invalid());
+            }
+        };
+        out.extend_from_slice(&formatted);
+    }
+
+    Ok(vec![create_string(interpreter, &out)?])
+}
+
+fn take_digits(bytes: &mut Peekable<Copied<Iter<u8>>>, raw: &mut String) -> String {
+    let mut digits = String::new();
+    while let Some(d) = bytes.next_if(|c| c.is_ascii_digit()).map(char::from) {
+        digits.push(d);
+        raw.push(d);
+    }
+    digits
+}
+
+fn format_float(x: f64, conversion: char, spec: &FormatSpec) -> String {
+    let sign = spec.sign(x.is_sign_negative());
+    let body = if x.is_nan() {
+        "nan".to_string()
+    } else if x.is_infinite() {
+        "inf".to_string()
+    } else {
+        let precision = spec.precision.unwrap_or(6);
+        match conversion.to_ascii_lowercase() {
+            'f' => {
+                let mut f = format!("{:.*}", precision, x.abs());
+                if spec.alt && precision == 0 {
+                    f.push('.');
+                }
+                f
+            }
+            'e' => format_exponent(x.abs(), precision, spec.alt),
+            _ => format_general(x.abs(), precision, spec.alt),
+        }
+    };
+    let body = if conversion.is_ascii_uppercase() { body.to_ascii_uppercase() } else { body };
+    spec.pad(sign, &body, x.is_finite())
+}
+
+// C's %e, the exponent has a sign and at least two digits
+fn format_exponent(x: f64, precision: usize, alt: bool) -> String {
+    let formatted = format!("{:.*e}", precision, x);
+    let (mantissa, exponent) = formatted.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let point = if alt && precision == 0 { "." } else { "" };
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{mantissa}{point}e{sign}{:02}", exponent.abs())
+}
+
+// C's %g, %e for very large or small numbers and %f otherwise, without
+// trailing zeros unless '#' is given
+pub(crate) fn format_general(x: f64, precision: usize, alt: bool) -> String {
+    let precision = precision.max(1);
+    // The exponent after rounding to `precision` significant digits
+    let exponent = if x == 0.0 {
+        0
+    } else {
+        let formatted = format!("{:.*e}", precision - 1, x);
+        formatted.split_once('e').unwrap().1.parse::<i32>().unwrap()
+    };
+
+    let mut formatted = if exponent < -4 || exponent >= (precision as i32) {
+        format_exponent(x, precision - 1, alt)
+    } else {
+        let decimals = ((precision as i32) - 1 - exponent) as usize;
+        let mut f = format!("{:.*}", decimals, x);
+        if alt && decimals == 0 {
+            f.push('.');
+        }
+        f
+    };
+    if !alt {
+        let (mantissa, exponent) = formatted.split_at(formatted.find('e').unwrap_or(formatted.len()));
+        if mantissa.contains('.') {
+            formatted = format!("{}{exponent}", mantissa.trim_end_matches('0').trim_end_matches('.'));
+        }
+    }
+    formatted
+}
+
+// %q, a literal that reads back as the same value
+fn quote(gc: &GarbageCollector, args: &[Value], i: usize) -> Result<Vec<u8>, RuntimeError> {
+    Ok(match args[i] {
+        Value::String(r) => {
+            let s = gc.get_bytes(r).unwrap();
+            let mut quoted = Vec::with_capacity(s.len() + 2);
+            quoted.push(b'"');
+            let mut bytes = s.iter().copied().peekable();
+            while let Some(c) = bytes.next() {
+                match c {
+                    b'"' | b'\\' | b'\n' => {
+                        quoted.push(b'\\');
+                        quoted.push(c);
+                    }
+                    c if c.is_ascii_control() => {
+                        // Padded so a following digit isn't read as part of it
+                        if bytes.peek().is_some_and(|n| n.is_ascii_digit()) {
+                            let _ = write!(quoted, "\\{c:03}");
+                        } else {
+                            let _ = write!(quoted, "\\{c}");
+                        }
+                    }
+                    c => quoted.push(c),
+                }
+            }
+            quoted.push(b'"');
+            quoted
+        }
+        // Written in hex, it doesn't fit a decimal literal
+        Value::Number(i64::MIN) => b"0x8000000000000000".to_vec(),
+        Value::Number(n) => n.to_string().into_bytes(),
+        Value::Float(f) if f == f64::INFINITY => b"1e9999".to_vec(),
+        Value::Float(f) if f == f64::NEG_INFINITY => b"-1e9999".to_vec(),
+        Value::Float(f) if f.is_nan() => b"(0/0)".to_vec(),
+        Value::Float(f) => hex_float(f).into_bytes(),
+        Value::Nil => b"nil".to_vec(),
+        Value::Bool(b) => b.to_string().into_bytes(),
+        Value::GcObject(_) => {
+            return Err(bad_argument(i, "format", "value has no literal form"));
+        }
+    })
+}
+
+// C's %a, floats are quoted exactly
+fn hex_float(x: f64) -> String {
+    let sign = if x.is_sign_negative() { "-" } else { "" };
+    if x == 0.0 {
+        return format!("{sign}0x0p+0");
+    }
+    let bits = x.to_bits();
+    let exponent = ((bits >> 52) & 0x7ff) as i64;
+    let mantissa = bits & ((1 << 52) - 1);
+    // Subnormals have no implicit leading one
+    let (lead, exponent) = if exponent == 0 { (0, -1022) } else { (1, exponent - 1023) };
+    let digits = format!("{mantissa:013x}");
+    let digits = digits.trim_end_matches('0');
+    if digits.is_empty() {
+        format!("{sign}0x{lead}p{exponent:+}")
+    } else {
+        format!("{sign}0x{lead}.{digits}p{exponent:+}")
+    }
+}
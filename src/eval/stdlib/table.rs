@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+
+use crate::{
+    errors::RuntimeError,
+    eval::{ interpreter::Interpreter, types::{ InterpreterFunction, Table }, value::Value },
+    sync::Rc,
+};
+
+use super::{ bad_argument, check_bytes, check_integer, opt_integer, type_error };
+
+// Like Lua's stack limit, `unpack` refuses to return more values
+const MAX_RESULTS: u64 = 1_000_000;
+
+const FUNCTIONS: &[(&str, InterpreterFunction)] = &[
+    ("concat", concat),
+    ("insert", insert),
+    ("move", table_move),
+    ("pack", pack),
+    ("remove", remove),
+    ("sort", sort),
+    ("unpack", unpack),
+];
+
+// What a non-table argument has to support through its metatable
+const READ: &[&str] = &["__index"];
+const WRITE: &[&str] = &["__newindex"];
+const LENGTH: &[&str] = &["__len"];
+
+pub fn open(interpreter: &mut Interpreter) -> Result<(), RuntimeError> {
+    interpreter.add_library("table", FUNCTIONS)?;
+    Ok(())
+}
+
+// A table, or any value with the metamethods the function uses
+fn check_table(
+    interpreter: &Interpreter,
+    args: &[Value],
+    i: usize,
+    function: &str,
+    needs: &[&[&str]]
+) -> Result<Value, RuntimeError> {
+    let value = args.get(i).copied().unwrap_or(Value::Nil);
+    if value.type_name(&interpreter.gc) == "table" {
+        return Ok(value);
+    }
+    let supported = interpreter.metatable_of(value).is_some() &&
+        needs
+            .iter()
+            .flat_map(|events| events.iter())
+            .all(|event| !matches!(interpreter.get_metamethod(value, event), Value::Nil));
+    if supported {
+        return Ok(value);
+    }
+    Err(type_error(&interpreter.gc, args, i, function, "table"))
+}
+
+// `#t` which has to be an integer, even when `__len` made it up
+fn length(interpreter: &mut Interpreter, table: Value) -> Result<i64, RuntimeError> {
+    match interpreter.length(table)? {
+        Value::Number(n) => Ok(n),
+        Value::Float(f) if f.fract() == 0.0 => Ok(f as i64),
+        _ => Err(RuntimeError::new("object length is not an integer".to_string())),
+    }
+}
+
+fn get(interpreter: &mut Interpreter, table: Value, i: i64) -> Result<Value, RuntimeError> {
+    interpreter.index_value(table, Value::Number(i))
+}
+
+fn set(interpreter: &mut Interpreter, table: Value, i: i64, value: Value) -> Result<(), RuntimeError> {
+    interpreter.set_value(table, Value::Number(i), value)
+}
+
+fn insert(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let table = check_table(interpreter, args, 0, "insert", &[READ, WRITE, LENGTH])?;
+    // First empty position
+    let end = length(interpreter, table)?.wrapping_add(1);
+    let (position, value) = match args.len() {
+        2 => (end, args[1]),
+        3 => {
+            let position = check_integer(&interpreter.gc, args, 1, "insert")?;
+            // Unsigned so 0 and negative positions are out of bounds too
+            if (position as u64).wrapping_sub(1) >= (end as u64) {
+                return Err(bad_argument(1, "insert", "position out of bounds"));
+            }
+            for i in (position + 1..=end).rev() {
+                let moved = get(interpreter, table, i - 1)?;
+                set(interpreter, table, i, moved)?;
+            }
+            (position, args[2])
+        }
+        _ => {
+            return Err(RuntimeError::new("wrong number of arguments to 'insert'".to_string()));
+        }
+    };
+    set(interpreter, table, position, value)?;
+    Ok(vec![])
+}
+
+fn remove(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let table = check_table(interpreter, args, 0, "remove", &[READ, WRITE, LENGTH])?;
+    let size = length(interpreter, table)?;
+    let mut position = opt_integer(&interpreter.gc, args, 1, "remove", size)?;
+    // Removing from an empty table is allowed at 0 and at size + 1
+    if position != size && (position as u64).wrapping_sub(1) > (size as u64) {
+        return Err(bad_argument(1, "remove", "position out of bounds"));
+    }
+    let removed = get(interpreter, table, position)?;
+    interpreter.keep_alive(removed);
+    while position < size {
+        let moved = get(interpreter, table, position + 1)?;
+        set(interpreter, table, position, moved)?;
+        position += 1;
+    }
+    set(interpreter, table, position, Value::Nil)?;
+    Ok(vec![removed])
+}
+
+fn concat(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let table = check_table(interpreter, args, 0, "concat", &[READ, LENGTH])?;
+    let separator = match args.get(1) {
+        None | Some(Value::Nil) => Rc::from(&[][..]),
+        _ => check_bytes(&interpreter.gc, args, 1, "concat")?,
+    };
+    let first = opt_integer(&interpreter.gc, args, 2, "concat", 1)?;
+    let last = match args.get(3) {
+        None | Some(Value::Nil) => length(interpreter, table)?,
+        _ => check_integer(&interpreter.gc, args, 3, "concat")?,
+    };
+
+    let mut result = vec![];
+    for i in first..=last {
+        let value = get(interpreter, table, i)?;
+        match value {
+            Value::String(_) | Value::Number(_) | Value::Float(_) => {
+                result.extend_from_slice(&value.to_bytes(&interpreter.gc));
+            }
+            _ => {
+                return Err(
+                    RuntimeError::new(
+                        format!(
+                            "invalid value (at index {i}) in table for 'concat' ({} found)",
+                            value.type_name(&interpreter.gc)
+                        )
+                    )
+                );
+            }
+        }
+        if i != last {
+            result.extend_from_slice(&separator);
+        }
+    }
+    Ok(vec![interpreter.create_byte_string(&result)?])
+}
+
+fn pack(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let n = interpreter.create_string("n")?;
+    interpreter.keep_alive(n);
+    let map = HashMap::from([(n, Value::Number(args.len() as i64))]);
+    let table = interpreter.allocate(Box::new(Table::new(args.to_vec(), map)))?;
+    Ok(vec![Value::GcObject(table)])
+}
+
+pub(crate) fn unpack(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let table = args.first().copied().unwrap_or(Value::Nil);
+    let first = opt_integer(&interpreter.gc, args, 1, "unpack", 1)?;
+    let last = match args.get(2) {
+        None | Some(Value::Nil) => length(interpreter, table)?,
+        _ => check_integer(&interpreter.gc, args, 2, "unpack")?,
+    };
+    if first > last {
+        return Ok(vec![]);
+    }
+    if (last as u64).wrapping_sub(first as u64) >= MAX_RESULTS {
+        return Err(RuntimeError::new("too many results to unpack".to_string()));
+    }
+
+    let mut values = Vec::with_capacity((last - first + 1) as usize);
+    for i in first..=last {
+        let value = get(interpreter, table, i)?;
+        // `__index` may run a collection while the rest is fetched
+        interpreter.keep_alive(value);
+        values.push(value);
+    }
+    Ok(values)
+}
+
+// table.move(a1, f, e, t [, a2]) copies a1[f..=e] to a2[t..]
+fn table_move(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let source = check_table(interpreter, args, 0, "move", &[READ])?;
+    let first = check_integer(&interpreter.gc, args, 1, "move")?;
+    let last = check_integer(&interpreter.gc, args, 2, "move")?;
+    let target = check_integer(&interpreter.gc, args, 3, "move")?;
+    let destination = match args.get(4) {
+        None | Some(Value::Nil) => source,
+        _ => check_table(interpreter, args, 4, "move", &[WRITE])?,
+    };
+
+    if last >= first {
+        if first <= 0 && last >= i64::MAX.wrapping_add(first) {
+            return Err(bad_argument(2, "move", "too many elements to move"));
+        }
+        let n = last - first + 1;
+        if target > i64::MAX - n + 1 {
+            return Err(bad_argument(3, "move", "destination wrap around"));
+        }
+        // Copy backwards when the ranges overlap with the target after the start
+        if target > last || target <= first || destination != source {
+            for i in 0..n {
+                let value = get(interpreter, source, first + i)?;
+                set(interpreter, destination, target + i, value)?;
+            }
+        } else {
+            for i in (0..n).rev() {
+                let value = get(interpreter, source, first + i)?;
+                set(interpreter, destination, target + i, value)?;
+            }
+        }
+    }
+    Ok(vec![destination])
+}
+
+fn sort(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let table = check_table(interpreter, args, 0, "sort", &[READ, WRITE, LENGTH])?;
+    let n = length(interpreter, table)?;
+    if n <= 1 {
+        return Ok(vec![]);
+    }
+    if n >= (i32::MAX as i64) {
+        return Err(bad_argument(0, "sort", "array too big"));
+    }
+    let comparator = match args.get(1) {
+        None | Some(Value::Nil) => None,
+        Some(f) if f.type_name(&interpreter.gc) == "function" => Some(*f),
+        _ => {
+            return Err(type_error(&interpreter.gc, args, 1, "sort", "function"));
+        }
+    };
+
+    let mut values = Vec::with_capacity(n as usize);
+    for i in 1..=n {
+        let value = get(interpreter, table, i)?;
+        // Only reachable from here while the comparator runs
+        interpreter.keep_alive(value);
+        values.push(value);
+    }
+    let mut sorter = Sorter { interpreter, comparator, values };
+    sorter.sort(0, (n - 1) as usize)?;
+
+    let Sorter { interpreter, values, .. } = sorter;
+    for (i, value) in values.into_iter().enumerate() {
+        set(interpreter, table, (i as i64) + 1, value)?;
+    }
+    Ok(vec![])
+}
+
+// The quicksort of Lua's ltablib.c, which notices an inconsistent comparator
+// when a partition runs past its bounds
+struct Sorter<'a> {
+    interpreter: &'a mut Interpreter,
+    comparator: Option<Value>,
+    values: Vec<Value>,
+}
+
+impl Sorter<'_> {
+    fn less(&mut self, a: Value, b: Value) -> Result<bool, RuntimeError> {
+        match self.comparator {
+            Some(comparator) => {
+                let results = self.interpreter.call(comparator, &[a, b])?;
+                Ok(results.first().is_some_and(|v| v.is_truthy()))
+            }
+            None => self.interpreter.less_than(a, b),
+        }
+    }
+
+    fn sort(&mut self, mut lo: usize, mut up: usize) -> Result<(), RuntimeError> {
+        while lo < up {
+            // Sorts lo, the middle and up, the median becomes the pivot
+            if self.less(self.values[up], self.values[lo])? {
+                self.values.swap(lo, up);
+            }
+            if up - lo == 1 {
+                break;
+            }
+            let mut p = lo + (up - lo) / 2;
+            if self.less(self.values[p], self.values[lo])? {
+                self.values.swap(p, lo);
+            } else if self.less(self.values[up], self.values[p])? {
+                self.values.swap(p, up);
+            }
+            if up - lo == 2 {
+                break;
+            }
+            let pivot = self.values[p];
+            self.values.swap(p, up - 1);
+            p = self.partition(lo, up, pivot)?;
+
+            // Recurse into the smaller half so the depth stays logarithmic
+            if p - lo < up - p {
+                self.sort(lo, p - 1)?;
+                lo = p + 1;
+            } else {
+                self.sort(p + 1, up)?;
+                up = p - 1;
+            }
+        }
+        Ok(())
+    }
+
+    // values[lo..=up] around `pivot`, which sits at up - 1
+    fn partition(&mut self, lo: usize, up: usize, pivot: Value) -> Result<usize, RuntimeError> {
+        let (mut i, mut j) = (lo, up - 1);
+        loop {
+            i += 1;
+            while self.less(self.values[i], pivot)? {
+                if i == up - 1 {
+                    return Err(invalid_order());
+                }
+                i += 1;
+            }
+            j -= 1;
+            while self.less(pivot, self.values[j])? {
+                if j < i {
+                    return Err(invalid_order());
+                }
+                j -= 1;
+            }
+            if j < i {
+                self.values.swap(up - 1, i);
+                return Ok(i);
+            }
+            self.values.swap(i, j);
+        }
+    }
+}
+
+fn invalid_order() -> RuntimeError {
+    RuntimeError::new("invalid order function for sorting".to_string())
+}
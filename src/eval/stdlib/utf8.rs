@@ -0,0 +1,289 @@
+// The utf8 library, a port of Lua's lutf8lib.c. Strings are bytes, these
+// functions find the characters in them. Strict decoding only accepts code
+// points up to 0x10FFFF that aren't surrogates, the lax one anything that
+// fits the original 6 byte encoding.
+
+use std::mem;
+
+use crate::{
+    errors::RuntimeError,
+    eval::{ gc::{ GarbageCollector, GcRef, GcValue }, interpreter::Interpreter, types::InterpreterFunction, value::Value },
+    sync::{ Cell, Rc },
+};
+
+use super::{ bad_argument, check_bytes, check_integer, opt_integer };
+
+const FUNCTIONS: &[(&str, InterpreterFunction)] = &[
+    ("char", utf8_char),
+    ("codepoint", codepoint),
+    ("codes", codes),
+    ("len", len),
+    ("offset", offset),
+];
+
+const MAX_UNICODE: u32 = 0x10ffff;
+const MAX_UTF: u32 = 0x7fffffff;
+
+// Matches exactly one encoded character, assuming the string is valid
+const CHAR_PATTERN: &[u8] = b"[\0-\x7F\xC2-\xFD][\x80-\xBF]*";
+
+const INVALID: &str = "invalid UTF-8 code";
+
+pub fn open(interpreter: &mut Interpreter) -> Result<(), RuntimeError> {
+    let utf8 = interpreter.add_library("utf8", FUNCTIONS)?;
+    let pattern = interpreter.create_byte_string(CHAR_PATTERN)?;
+    interpreter.set_field(utf8, "charpattern", pattern)
+}
+
+fn is_continuation(s: &[u8], i: usize) -> bool {
+    s.get(i).is_some_and(|b| b & 0xc0 == 0x80)
+}
+
+// Lua's u_posrelat, negative positions count from the end
+fn relative_position(position: i64, len: usize) -> i64 {
+    if position >= 0 {
+        position
+    } else if position.unsigned_abs() > (len as u64) {
+        0
+    } else {
+        (len as i64) + position + 1
+    }
+}
+
+/// The code point starting at `s[0]` and its length in bytes, None if it
+/// isn't a valid sequence
+fn decode(s: &[u8], strict: bool) -> Option<(u32, usize)> {
+    // The smallest code point that needs each length, shorter encodings
+    // are overlong
+    const LIMITS: [u32; 6] = [!0, 0x80, 0x800, 0x10000, 0x200000, 0x4000000];
+    let mut c = *s.first()? as u32;
+    if c < 0x80 {
+        return Some((c, 1));
+    }
+    let mut code = 0;
+    let mut count = 0;
+    // Each leading 1 bit after the first is a continuation byte
+    while c & 0x40 != 0 {
+        count += 1;
+        let cc = *s.get(count)? as u32;
+        if cc & 0xc0 != 0x80 {
+            return None;
+        }
+        code = (code << 6) | (cc & 0x3f);
+        c <<= 1;
+    }
+    if count > 5 {
+        return None;
+    }
+    code |= (c & 0x7f) << (count * 5);
+    if code > MAX_UTF || code < LIMITS[count] {
+        return None;
+    }
+    if strict && (code > MAX_UNICODE || (0xd800..=0xdfff).contains(&code)) {
+        return None;
+    }
+    Some((code, count + 1))
+}
+
+// Up to 6 bytes like Lua's luaO_utf8esc, so lax code points encode too
+fn encode(code: u32, out: &mut Vec<u8>) {
+    if code < 0x80 {
+        out.push(code as u8);
+        return;
+    }
+    let mut bytes = vec![];
+    let mut code = code;
+    // Largest value that still fits the first byte
+    let mut first_max = 0x3f;
+    while code > first_max {
+        bytes.push(0x80 | ((code & 0x3f) as u8));
+        code >>= 6;
+        first_max >>= 1;
+    }
+    out.push(((!first_max << 1) as u8) | (code as u8));
+    out.extend(bytes.iter().rev());
+}
+
+fn lax(args: &[Value], i: usize) -> bool {
+    args.get(i).is_some_and(|v| v.is_truthy())
+}
+
+// utf8.char(...) encodes each code point and concatenates them
+fn utf8_char(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let mut bytes = vec![];
+    for i in 0..args.len() {
+        let code = check_integer(&interpreter.gc, args, i, "char")?;
+        if (code as u64) > (MAX_UTF as u64) {
+            return Err(bad_argument(i, "char", "value out of range"));
+        }
+        encode(code as u32, &mut bytes);
+    }
+    Ok(vec![interpreter.create_byte_string(&bytes)?])
+}
+
+// utf8.codepoint(s [, i [, j [, lax]]]) the code points of the characters
+// starting in s[i..=j]
+fn codepoint(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let gc = &interpreter.gc;
+    let s = check_bytes(gc, args, 0, "codepoint")?;
+    let start = relative_position(opt_integer(gc, args, 1, "codepoint", 1)?, s.len());
+    let end = relative_position(opt_integer(gc, args, 2, "codepoint", start)?, s.len());
+    let strict = !lax(args, 3);
+    if start < 1 {
+        return Err(bad_argument(1, "codepoint", "out of bounds"));
+    }
+    if end > (s.len() as i64) {
+        return Err(bad_argument(2, "codepoint", "out of bounds"));
+    }
+    if start > end {
+        return Ok(vec![]);
+    }
+
+    let mut codes = vec![];
+    let mut i = (start - 1) as usize;
+    while i < (end as usize) {
+        let Some((code, len)) = decode(&s[i..], strict) else {
+            return Err(RuntimeError::new(INVALID.to_string()));
+        };
+        codes.push(Value::Number(code as i64));
+        i += len;
+    }
+    Ok(codes)
+}
+
+// utf8.len(s [, i [, j [, lax]]]) counts the characters starting in
+// s[i..=j], or returns nil and the position of the first invalid byte
+fn len(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let gc = &interpreter.gc;
+    let s = check_bytes(gc, args, 0, "len")?;
+    let start = relative_position(opt_integer(gc, args, 1, "len", 1)?, s.len());
+    let end = relative_position(opt_integer(gc, args, 2, "len", -1)?, s.len());
+    let strict = !lax(args, 3);
+    if start < 1 || start - 1 > (s.len() as i64) {
+        return Err(bad_argument(1, "len", "initial position out of bounds"));
+    }
+    if end > (s.len() as i64) {
+        return Err(bad_argument(2, "len", "final position out of bounds"));
+    }
+
+    let mut count = 0;
+    let mut i = start - 1;
+    while i < end {
+        match decode(&s[i as usize..], strict) {
+            Some((_, len)) => {
+                i += len as i64;
+                count += 1;
+            }
+            None => {
+                return Ok(vec![Value::Nil, Value::Number(i + 1)]);
+            }
+        }
+    }
+    Ok(vec![Value::Number(count)])
+}
+
+// utf8.offset(s, n [, i]) the byte position where the n-th character
+// counting from position i starts. n = 0 finds the start of the character
+// containing i, negative n count backwards.
+fn offset(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let gc = &interpreter.gc;
+    let s = check_bytes(gc, args, 0, "offset")?;
+    let mut n = check_integer(gc, args, 1, "offset")?;
+    let default = if n >= 0 { 1 } else { (s.len() as i64) + 1 };
+    let position = relative_position(opt_integer(gc, args, 2, "offset", default)?, s.len());
+    if position < 1 || position - 1 > (s.len() as i64) {
+        return Err(bad_argument(2, "offset", "position out of bounds"));
+    }
+
+    let mut i = (position - 1) as usize;
+    if n == 0 {
+        while i > 0 && is_continuation(&s, i) {
+            i -= 1;
+        }
+    } else {
+        if is_continuation(&s, i) {
+            return Err(RuntimeError::new("initial position is a continuation byte".to_string()));
+        }
+        if n < 0 {
+            while n < 0 && i > 0 {
+                i -= 1;
+                while i > 0 && is_continuation(&s, i) {
+                    i -= 1;
+                }
+                n += 1;
+            }
+        } else {
+            n -= 1;
+            while n > 0 && i < s.len() {
+                i += 1;
+                while is_continuation(&s, i) {
+                    i += 1;
+                }
+                n -= 1;
+            }
+        }
+    }
+    if n == 0 {
+        Ok(vec![Value::Number((i as i64) + 1)])
+    } else {
+        Ok(vec![Value::Nil])
+    }
+}
+
+// utf8.codes(s [, lax]) iterates over the position and code point of each
+// character, raising an error at an invalid one
+fn codes(interpreter: &mut Interpreter, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+    let s = check_bytes(&interpreter.gc, args, 0, "codes")?;
+    if is_continuation(&s, 0) {
+        return Err(bad_argument(0, "codes", INVALID));
+    }
+    let iterator = CodesIterator { string: s, strict: !lax(args, 1), position: Cell::new(0) };
+    Ok(vec![Value::GcObject(interpreter.allocate(Box::new(iterator))?)])
+}
+
+/// Returned by `utf8.codes`, each call returns the next position and code
+/// point, a generic `for` gets the positions
+struct CodesIterator {
+    string: Rc<[u8]>,
+    strict: bool,
+    // Where the next character starts, 0-based
+    position: Cell<usize>,
+}
+
+impl GcValue for CodesIterator {
+    fn get_referenced_children(&self, _gc: &GarbageCollector) -> Vec<GcRef> {
+        vec![]
+    }
+
+    fn name(&self) -> &'static str {
+        "function"
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>() + self.string.len()
+    }
+
+    fn str(&self, _gc: &GarbageCollector) -> String {
+        "function".to_string()
+    }
+
+    fn call(
+        &self,
+        _interpreter: &mut Interpreter,
+        _args: &[Value]
+    ) -> Result<Vec<Value>, RuntimeError> {
+        let s = &self.string;
+        let i = self.position.get();
+        if i >= s.len() {
+            return Ok(vec![Value::Nil]);
+        }
+        match decode(&s[i..], self.strict) {
+            // A stray continuation byte after the character is invalid too
+            Some((code, len)) if !is_continuation(s, i + len) => {
+                self.position.set(i + len);
+                Ok(vec![Value::Number((i as i64) + 1), Value::Number(code as i64)])
+            }
+            _ => Err(RuntimeError::new(INVALID.to_string())),
+        }
+    }
+}
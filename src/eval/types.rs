@@ -1,31 +1,153 @@
-use std::{ collections::HashMap, fmt::format, num::NonZeroUsize, path::Iter };
+use std::{ collections::HashMap, mem };
 
-use crate::parser::AstNode;
+use crate::{ errors::RuntimeError, parser::AstNode, sync::{ Rc, RefCell } };
 
+#[cfg(feature = "async")]
+use super::async_host::AsyncFunction;
 use super::{
-    gc::{ self, GarbageCollector, GcRef, GcValue },
+    gc::{ GarbageCollector, GcRef, GcValue },
     interpreter::Interpreter,
     value::Value,
 };
 
+// Like Lua, keys 1..=n live in `array` and everything else in `map`. The
+// last array slot is never nil so its length is always a border.
 pub struct Table {
     array: Vec<Value>,
     map: HashMap<Value, Value>,
+    metatable: Option<GcRef>,
 }
 
 impl Table {
     pub fn new(array: Vec<Value>, map: HashMap<Value, Value>) -> Self {
-        Table { array, map }
+        let mut table = Table { array, map: HashMap::new(), metatable: None };
+        table.trim();
+        for (k, v) in map {
+            table.set_index(k, v);
+        }
+        table
+    }
+
+    /// `#t` without `__len`
+    pub fn length(&self) -> i64 {
+        self.array.len() as i64
+    }
+
+    pub fn set_metatable(&mut self, metatable: Option<GcRef>) {
+        self.metatable = metatable;
+    }
+
+    /// The values of keys 1..=n
+    pub(crate) fn array_part(&self) -> &[Value] {
+        &self.array
+    }
+
+    /// Every other key, in no particular order
+    pub(crate) fn map_part(&self) -> &HashMap<Value, Value> {
+        &self.map
+    }
+
+    // Nested tables are written inline, one that is already being written
+    // further up as "{...}" so cycles like `package.loaded.package` end
+    fn render(&self, gc: &GarbageCollector, open: &mut Vec<*const Table>) -> String {
+        let arr_part = self.array
+            .iter()
+            .map(|x| render_value(gc, *x, open))
+            .collect::<Vec<String>>()
+            .join(", ");
+        let map_part = self.map
+            .iter()
+            .map(|(k, v)| format!("[{}]={}", k.dbg_string(gc), render_value(gc, *v, open)))
+            .collect::<Vec<String>>()
+            .join(", ");
+        if !arr_part.is_empty() {
+            if !map_part.is_empty() {
+                format!("{{{arr_part}, {map_part}}}")
+            } else {
+                format!("{{{arr_part}}}")
+            }
+        } else {
+            format!("{{{map_part}}}")
+        }
+    }
+
+    /// The entry following `key` when traversing the array part and then
+    /// the hash part, None at the end and Err if `key` isn't in the table.
+    /// Finding a key of the hash part is a linear scan.
+    pub(crate) fn next(&self, key: Value) -> Result<Option<(Value, Value)>, ()> {
+        let key = normalize_key(key);
+        let start = match key {
+            Value::Nil => 0,
+            Value::Number(n) if n >= 1 && n <= self.length() => n as usize,
+            _ => {
+                let mut entries = self.map.iter();
+                if !entries.any(|(k, _)| *k == key) {
+                    return Err(());
+                }
+                return Ok(entries.next().map(|(k, v)| (*k, *v)));
+            }
+        };
+        // Assigning nil inside the array leaves holes to skip
+        let element = self.array
+            .iter()
+            .enumerate()
+            .skip(start)
+            .find(|(_, v)| !matches!(v, Value::Nil));
+        if let Some((i, v)) = element {
+            return Ok(Some((Value::Number((i as i64) + 1), *v)));
+        }
+        Ok(self.map.iter().next().map(|(k, v)| (*k, *v)))
+    }
+
+    // Drops trailing nils from the array part
+    fn trim(&mut self) {
+        while let Some(Value::Nil) = self.array.last() {
+            self.array.pop();
+        }
     }
 }
 
 impl Table {
-    pub fn append(&mut self, gc: &mut GarbageCollector, args: &[Value]) -> Value {
-        assert_eq!(args.len(), 1, "Insert expected 1 arguments got {}", args.len());
+    pub fn append(&mut self, _gc: &mut GarbageCollector, args: &[Value]) -> Result<Value, RuntimeError> {
+        if args.len() != 1 {
+            return Err(
+                RuntimeError::new(format!("append expected 1 argument, got {}", args.len()))
+            );
+        }
+
+        let key = Value::Number(self.length() + 1);
+        self.set_index(key, args[0]);
 
-        self.array.push(args[0].clone());
+        Ok(Value::Nil)
+    }
+}
+
+// 2.0 and 2 are the same key
+fn render_value(gc: &GarbageCollector, value: Value, open: &mut Vec<*const Table>) -> String {
+    if let Value::GcObject(r) = value {
+        if let Some(object) = gc.get(r) {
+            let object = object.borrow();
+            if let Some(table) = object.downcast_ref::<Table>() {
+                let id = table as *const Table;
+                if open.contains(&id) {
+                    return "{...}".to_string();
+                }
+                open.push(id);
+                let rendered = table.render(gc, open);
+                open.pop();
+                return rendered;
+            }
+        }
+    }
+    value.dbg_string(gc)
+}
 
-        Value::Nil
+fn normalize_key(key: Value) -> Value {
+    match key {
+        Value::Float(f) if f.fract() == 0.0 && f >= (i64::MIN as f64) && f < -(i64::MIN as f64) => {
+            Value::Number(f as i64)
+        }
+        _ => key,
     }
 }
 
@@ -35,36 +157,58 @@ impl GcValue for Table {
         name: &str,
         gc: &mut GarbageCollector,
         args: &[Value]
-    ) -> Value {
+    ) -> Result<Value, RuntimeError> {
         match name {
             "append" => self.append(gc, args),
-            _ => panic!("Method {name} does not exist on type {}!", self.name()),
+            _ => Err(RuntimeError::new(format!("attempt to call a nil value (method '{name}')"))),
         }
     }
-    fn get_referenced_children(&self, gc: &GarbageCollector) -> Vec<GcRef> {
+
+    fn metatable(&self) -> Option<GcRef> {
+        self.metatable
+    }
+    fn get_referenced_children(&self, _gc: &GarbageCollector) -> Vec<GcRef> {
         let mut r = vec![];
 
         for element in self.array.iter() {
-            if let Value::GcObject(obj) = element {
-                r.push(*obj);
-                if let Some(g) = gc.get(*obj) {
-                    for e in g.borrow().get_referenced_children(gc) {
-                        r.push(e);
-                    }
-                }
+            if let Some(obj) = element.gc_ref() {
+                r.push(obj);
             }
         }
 
-        for (_, v) in self.map.iter() {
-            if let Value::GcObject(obj) = v {
-                r.push(*obj);
-                if let Some(g) = gc.get(*obj) {
-                    for e in g.borrow().get_referenced_children(gc) {
-                        r.push(e);
-                    }
-                }
+        for (k, v) in self.map.iter() {
+            if let Some(obj) = k.gc_ref() {
+                r.push(obj);
+            }
+            if let Some(obj) = v.gc_ref() {
+                r.push(obj);
             }
         }
+        r.extend(self.metatable);
+
+        r
+    }
+
+    fn get_referenced_edges(&self, gc: &GarbageCollector) -> Vec<(String, GcRef)> {
+        let mut r = vec![];
+
+        for (i, element) in self.array.iter().enumerate() {
+            if let Some(obj) = element.gc_ref() {
+                r.push((format!("[{}]", i + 1), obj));
+            }
+        }
+
+        for (k, v) in self.map.iter() {
+            if let Some(obj) = k.gc_ref() {
+                r.push(("<key>".to_string(), obj));
+            }
+            if let Some(obj) = v.gc_ref() {
+                r.push((format!("[{}]", k.dbg_string(gc)), obj));
+            }
+        }
+        if let Some(metatable) = self.metatable {
+            r.push(("<metatable>".to_string(), metatable));
+        }
 
         r
     }
@@ -73,53 +217,58 @@ impl GcValue for Table {
         "table"
     }
 
+    fn size(&self) -> usize {
+        // Hash part entries also pay for the stored hash
+        let entry = 2 * mem::size_of::<Value>() + mem::size_of::<u64>();
+
+        mem::size_of::<Self>() +
+            self.array.capacity() * mem::size_of::<Value>() +
+            self.map.capacity() * entry
+    }
+
     fn index(&self, index: Value) -> Option<Value> {
-        if let Some(v) = self.map.get(&index) {
-            return Some(v.clone());
-        } else if let Value::Number(n) = index {
-            if n >= 0 && n < (self.array.len() as i64) {
-                return Some(self.array[n as usize].clone());
+        let index = normalize_key(index);
+        if let Value::Number(n) = index {
+            if n >= 1 && n <= self.length() {
+                return Some(self.array[(n - 1) as usize]);
             }
         }
-
-        None
+        self.map.get(&index).copied()
     }
 
     fn set_index(&mut self, index: Value, new_value: Value) {
+        let index = normalize_key(index);
         if let Value::Number(n) = index {
-            if n >= 0 && n < (self.array.len() as i64) {
-                self.array[n as usize] = new_value;
+            let len = self.length();
+            if n >= 1 && n <= len {
+                self.array[(n - 1) as usize] = new_value;
+                if n == len {
+                    self.trim();
+                }
                 return;
             }
+            if n == len + 1 && !matches!(new_value, Value::Nil) {
+                self.array.push(new_value);
+                // The keys following it move over from the hash part
+                while let Some(v) = self.map.remove(&Value::Number(self.length() + 1)) {
+                    self.array.push(v);
+                }
+                return;
+            }
+        }
+        if let Value::Nil = new_value {
+            self.map.remove(&index);
+        } else {
+            self.map.insert(index, new_value);
         }
-        self.map.insert(index, new_value);
     }
 
     fn str(&self, gc: &GarbageCollector) -> String {
-        let arr_part = self.array
-            .iter()
-            .map(|x| x.dbg_string(gc))
-            .collect::<Vec<String>>()
-            .join(", ");
-        let map_part = self.map
-            .iter()
-            .map(|(k, v)| format!("[{}]={}", k.dbg_string(gc), v.dbg_string(gc)))
-            .collect::<Vec<String>>()
-            .join(", ");
-        if arr_part.len() > 0 {
-            if map_part.len() > 0 {
-                format!("{{{arr_part}, {map_part}}}")
-            } else {
-                format!("{{{arr_part}}}")
-            }
-        } else {
-            format!("{{{map_part}}}")
-        }
+        self.render(gc, &mut vec![self as *const Table])
     }
 
-    fn iter(&self) -> Iterable {
-        let iterable = Iterable::new(self.array.clone());
-        iterable
+    fn iter(&self) -> Result<Iterable, RuntimeError> {
+        Ok(Iterable::new(self.array.clone()))
     }
 }
 
@@ -136,17 +285,12 @@ impl Iterable {
 }
 
 impl GcValue for Iterable {
-    fn get_referenced_children(&self, gc: &GarbageCollector) -> Vec<GcRef> {
+    fn get_referenced_children(&self, _gc: &GarbageCollector) -> Vec<GcRef> {
         let mut r = vec![];
 
         for element in self.values.iter() {
-            if let Value::GcObject(obj) = element {
-                r.push(*obj);
-                if let Some(g) = gc.get(*obj) {
-                    for e in g.borrow().get_referenced_children(gc) {
-                        r.push(e);
-                    }
-                }
+            if let Some(obj) = element.gc_ref() {
+                r.push(obj);
             }
         }
         r
@@ -156,18 +300,42 @@ impl GcValue for Iterable {
         "iterable"
     }
 
+    fn size(&self) -> usize {
+        mem::size_of::<Self>() + self.values.capacity() * mem::size_of::<Value>()
+    }
+
     fn next(&mut self) -> Option<Value> {
         self.values.pop()
     }
 }
 
+/// Built-ins that need the whole interpreter, e.g. to run a collection or to
+/// call back into the script. They can return any number of values.
+pub type InterpreterFunction = fn(&mut Interpreter, &[Value]) -> Result<Vec<Value>, RuntimeError>;
+
+/// A host function that can capture state, see `Interpreter::create_function`.
+/// Only a `Scope` makes ones that live for less than `'static`.
+#[cfg(not(feature = "send"))]
+pub type HostFunction<'a> = Box<
+    dyn FnMut(&mut Interpreter, &[Value]) -> Result<Vec<Value>, RuntimeError> + 'a
+>;
+#[cfg(feature = "send")]
+pub type HostFunction<'a> = Box<
+    dyn FnMut(&mut Interpreter, &[Value]) -> Result<Vec<Value>, RuntimeError> + Send + 'a
+>;
+
 pub enum Function {
     UserDefined {
         args: Vec<String>,
         body: AstNode,
     },
-    FnPointer(fn(&mut GarbageCollector, &[Value]) -> Value),
-    FnPointerNoGc(fn(&[Value]) -> Value),
+    FnPointer(fn(&mut GarbageCollector, &[Value]) -> Result<Value, RuntimeError>),
+    FnPointerNoGc(fn(&[Value]) -> Result<Value, RuntimeError>),
+    FnPointerInterpreter(InterpreterFunction),
+    // Shared with the `Scope` that made it, which empties it when it ends
+    Closure(Rc<RefCell<Option<HostFunction<'static>>>>),
+    #[cfg(feature = "async")]
+    Async(AsyncFunction),
 }
 
 impl Function {
@@ -180,32 +348,78 @@ impl Function {
 }
 
 impl GcValue for Function {
-    fn str(&self, gc: &GarbageCollector) -> String {
+    fn str(&self, _gc: &GarbageCollector) -> String {
         "function".to_string()
     }
     fn name(&self) -> &'static str {
         "function"
     }
 
-    fn get_referenced_children(&self, gc: &GarbageCollector) -> Vec<GcRef> {
+    fn size(&self) -> usize {
+        match self {
+            Function::UserDefined { args, body } => {
+                let statements = match body {
+                    AstNode::Scope { stmts } => stmts.len(),
+                    _ => 0,
+                };
+                mem::size_of::<Self>() +
+                    args
+                        .iter()
+                        .map(|a| mem::size_of::<String>() + a.capacity())
+                        .sum::<usize>() +
+                    statements * mem::size_of::<AstNode>()
+            }
+            _ => mem::size_of::<Self>(),
+        }
+    }
+
+    fn get_referenced_children(&self, _gc: &GarbageCollector) -> Vec<GcRef> {
         vec![] // TODO: Idk what is should do here
     }
 
-    fn call(&self, interpreter: &mut Interpreter, values: &[Value]) -> Value {
+    fn call(
+        &self,
+        interpreter: &mut Interpreter,
+        values: &[Value]
+    ) -> Result<Vec<Value>, RuntimeError> {
         match self {
             Function::UserDefined { args, body } => {
-                if values.len() != args.len() {
-                    panic!("Expected {} args found {}", args.len(), values.len());
-                }
-                return interpreter
-                    .eval_function_scope(body, args.iter().zip(values.iter()).collect())
-                    .get_normal();
+                // Missing arguments are nil and extra ones are dropped
+                let args = args
+                    .iter()
+                    .enumerate()
+                    .map(|(i, name)| (name, values.get(i).copied().unwrap_or(Value::Nil)))
+                    .collect();
+                interpreter.eval_function_scope(body, args)
             }
             Function::FnPointer(ptr) => {
-                return ptr(&mut interpreter.gc, values);
+                Ok(vec![ptr(&mut interpreter.gc, values)?])
             }
             Function::FnPointerNoGc(ptr) => {
-                return ptr(values);
+                Ok(vec![ptr(values)?])
+            }
+            Function::FnPointerInterpreter(ptr) => {
+                ptr(interpreter, values)
+            }
+            Function::Closure(closure) => {
+                // An `FnMut` can't be called again while it is running
+                let Ok(mut closure) = closure.try_borrow_mut() else {
+                    let message = "host function is already running";
+                    return Err(RuntimeError::new(message.to_string()));
+                };
+                match closure.as_mut() {
+                    Some(f) => f(interpreter, values),
+                    None => {
+                        let message = "attempt to call a function whose scope has ended";
+                        Err(RuntimeError::new(message.to_string()))
+                    }
+                }
+            }
+            #[cfg(feature = "async")]
+            Function::Async(f) => {
+                let future = f(&interpreter.gc, values)?;
+                let completion = interpreter.suspend(future)?;
+                completion(&mut interpreter.gc)
             }
         }
     }
@@ -0,0 +1,332 @@
+// Rust values scripts can use like objects. A type implementing `UserData`
+// registers its methods, fields and metamethods once per collector, the
+// first time a value of it is made.
+
+use std::{
+    any::TypeId,
+    collections::HashMap,
+    marker::PhantomData,
+    mem,
+};
+
+use crate::{ errors::RuntimeError, sync::{ MaybeSend, Rc, Ref, RefCell, RefMut } };
+
+use super::{
+    convert::{ FromLua, FromLuaError, IntoLua },
+    gc::{ GarbageCollector, GcRef, GcValue },
+    interpreter::Interpreter,
+    stdlib::type_error,
+    types::{ Function, InterpreterFunction, Table },
+    value::Value,
+};
+
+/// A Rust type scripts can hold, index and call methods on. Implement it
+/// with `#[user_data]` on the type's `impl` block or by hand, registering
+/// built-in functions that take the value as their first argument.
+/// Values are made with `IntoLua` and read back with [`UserDataRef`], or
+/// with `FromLua` for types that are `Clone`.
+pub trait UserData: Sized + MaybeSend + 'static {
+    /// Names the type in errors and `tostring`, like "Vec2"
+    const NAME: &'static str;
+
+    fn register(registry: &mut Registry);
+}
+
+/// The functions of a `UserData` type, each gets the value as its first
+/// argument
+#[derive(Default)]
+pub struct Registry {
+    methods: Vec<(&'static str, InterpreterFunction)>,
+    getters: Vec<(&'static str, InterpreterFunction)>,
+    setters: Vec<(&'static str, InterpreterFunction)>,
+    meta_methods: Vec<(&'static str, InterpreterFunction)>,
+}
+
+impl Registry {
+    /// Called as `value:name(...)`
+    pub fn add_method(&mut self, name: &'static str, method: InterpreterFunction) {
+        self.methods.push((name, method));
+    }
+
+    /// Reading `value.name` calls `getter(value)`
+    pub fn add_getter(&mut self, name: &'static str, getter: InterpreterFunction) {
+        self.getters.push((name, getter));
+    }
+
+    /// Assigning `value.name = x` calls `setter(value, x)`
+    pub fn add_setter(&mut self, name: &'static str, setter: InterpreterFunction) {
+        self.setters.push((name, setter));
+    }
+
+    /// An entry of the metatable like "__add" or "__tostring". Binary
+    /// operators get the operands in order, the value isn't always first.
+    pub fn add_meta_method(&mut self, name: &'static str, method: InterpreterFunction) {
+        self.meta_methods.push((name, method));
+    }
+}
+
+/// What the collector keeps for each `UserData` type
+pub(crate) struct UserDataType {
+    pub(crate) metatable: GcRef,
+    // Name to function, a table so that the functions stay alive
+    pub(crate) methods: GcRef,
+    getters: HashMap<&'static str, InterpreterFunction>,
+    setters: HashMap<&'static str, InterpreterFunction>,
+}
+
+// The metatable of `T`, made the first time it is needed
+pub(crate) fn metatable<T: UserData>(gc: &mut GarbageCollector) -> Result<GcRef, RuntimeError> {
+    if let Some(ty) = gc.user_data.get(&TypeId::of::<T>()) {
+        return Ok(ty.metatable);
+    }
+    let mut registry = Registry::default();
+    T::register(&mut registry);
+
+    let function = |gc: &mut GarbageCollector, f: InterpreterFunction| {
+        gc.allocate(Box::new(Function::FnPointerInterpreter(f))).map(Value::GcObject)
+    };
+    let mut methods = HashMap::new();
+    for (name, method) in registry.methods {
+        methods.insert(gc.create_string(name)?, function(gc, method)?);
+    }
+    let mut fields = HashMap::new();
+    fields.insert(gc.create_string("__name")?, gc.create_string(T::NAME)?);
+    fields.insert(gc.create_string("__index")?, function(gc, index::<T>)?);
+    fields.insert(gc.create_string("__newindex")?, function(gc, new_index::<T>)?);
+    for (name, method) in registry.meta_methods {
+        fields.insert(gc.create_string(name)?, function(gc, method)?);
+    }
+    // Nothing is collected while allocating, so none of this needs rooting
+    // until it is registered
+    let methods = gc.allocate(Box::new(Table::new(vec![], methods)))?;
+    let metatable = gc.allocate(Box::new(Table::new(vec![], fields)))?;
+    gc.user_data.insert(TypeId::of::<T>(), UserDataType {
+        metatable,
+        methods,
+        getters: registry.getters.into_iter().collect(),
+        setters: registry.setters.into_iter().collect(),
+    });
+    Ok(metatable)
+}
+
+// `__index`: a field with a getter, or a method
+fn index<T: UserData>(
+    interpreter: &mut Interpreter,
+    args: &[Value]
+) -> Result<Vec<Value>, RuntimeError> {
+    let object = check_self::<T>(&interpreter.gc, args, "__index")?;
+    let key = args.get(1).copied().unwrap_or(Value::Nil);
+    let ty = &interpreter.gc.user_data[&TypeId::of::<T>()];
+    let name = match key {
+        Value::String(r) => interpreter.gc.get_string(r),
+        _ => None,
+    };
+    if let Some(getter) = name.and_then(|name| ty.getters.get(&*name).copied()) {
+        let results = getter(interpreter, &[object])?;
+        return Ok(results.into_iter().take(1).collect());
+    }
+    let methods = interpreter.gc.get(ty.methods).unwrap();
+    let method = methods.borrow().index(key).unwrap_or(Value::Nil);
+    Ok(vec![method])
+}
+
+// `__newindex`: only fields with a setter can be assigned
+fn new_index<T: UserData>(
+    interpreter: &mut Interpreter,
+    args: &[Value]
+) -> Result<Vec<Value>, RuntimeError> {
+    let object = check_self::<T>(&interpreter.gc, args, "__newindex")?;
+    let key = args.get(1).copied().unwrap_or(Value::Nil);
+    let value = args.get(2).copied().unwrap_or(Value::Nil);
+    let ty = &interpreter.gc.user_data[&TypeId::of::<T>()];
+    let name = match key {
+        Value::String(r) => interpreter.gc.get_string(r),
+        _ => None,
+    };
+    let Some(name) = name else {
+        let key = key.type_name(&interpreter.gc);
+        return Err(RuntimeError::new(format!("attempt to index {} with a {key}", T::NAME)));
+    };
+    match ty.setters.get(&*name).copied() {
+        Some(setter) => {
+            setter(interpreter, &[object, value])?;
+            Ok(vec![])
+        }
+        None if ty.getters.contains_key(&*name) => {
+            Err(RuntimeError::new(format!("field '{name}' of {} is read-only", T::NAME)))
+        }
+        None => Err(RuntimeError::new(format!("{} has no field '{name}'", T::NAME))),
+    }
+}
+
+// The first argument of a metamethod, which scripts can also call directly
+// with anything or nothing
+fn check_self<T: UserData>(
+    gc: &GarbageCollector,
+    args: &[Value],
+    function: &str
+) -> Result<Value, RuntimeError> {
+    match args.first() {
+        Some(&object) if UserDataRef::<T>::from_lua(object, gc).is_ok() => Ok(object),
+        _ => Err(type_error(gc, args, 0, function, T::NAME)),
+    }
+}
+
+// The object behind a `UserData` value
+struct UserDataCell<T> {
+    value: T,
+    metatable: GcRef,
+}
+
+impl<T: UserData> GcValue for UserDataCell<T> {
+    fn get_referenced_children(&self, _gc: &GarbageCollector) -> Vec<GcRef> {
+        vec![self.metatable]
+    }
+
+    fn get_referenced_edges(&self, _gc: &GarbageCollector) -> Vec<(String, GcRef)> {
+        vec![("<metatable>".to_string(), self.metatable)]
+    }
+
+    fn name(&self) -> &'static str {
+        "userdata"
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>()
+    }
+
+    fn str(&self, _gc: &GarbageCollector) -> String {
+        T::NAME.to_string()
+    }
+
+    fn metatable(&self) -> Option<GcRef> {
+        Some(self.metatable)
+    }
+}
+
+// A `UserData` value lent to scripts by a `Scope`, emptied when it ends
+pub(crate) struct ScopedCell<T: 'static> {
+    pub(crate) value: Option<&'static mut T>,
+    pub(crate) metatable: GcRef,
+}
+
+impl<T: UserData> GcValue for ScopedCell<T> {
+    fn get_referenced_children(&self, _gc: &GarbageCollector) -> Vec<GcRef> {
+        vec![self.metatable]
+    }
+
+    fn get_referenced_edges(&self, _gc: &GarbageCollector) -> Vec<(String, GcRef)> {
+        vec![("<metatable>".to_string(), self.metatable)]
+    }
+
+    fn name(&self) -> &'static str {
+        "userdata"
+    }
+
+    fn size(&self) -> usize {
+        mem::size_of::<Self>()
+    }
+
+    fn str(&self, _gc: &GarbageCollector) -> String {
+        T::NAME.to_string()
+    }
+
+    fn metatable(&self) -> Option<GcRef> {
+        Some(self.metatable)
+    }
+}
+
+/// Empties a `ScopedCell<T>`, for the `Scope` that made it
+pub(crate) fn end_scope<T: UserData>(object: &mut dyn GcValue) {
+    if let Some(cell) = object.downcast_mut::<ScopedCell<T>>() {
+        cell.value = None;
+    }
+}
+
+// The `T` in a userdata object, owned or lent by a scope that is still open
+fn value_ref<T: UserData>(object: &dyn GcValue) -> Result<&T, RuntimeError> {
+    if let Some(cell) = object.downcast_ref::<UserDataCell<T>>() {
+        return Ok(&cell.value);
+    }
+    match object.downcast_ref::<ScopedCell<T>>() {
+        Some(ScopedCell { value: Some(value), .. }) => Ok(&**value),
+        Some(_) => Err(scope_ended::<T>()),
+        None => Err(RuntimeError::new(format!("{} expected", T::NAME))),
+    }
+}
+
+fn value_mut<T: UserData>(object: &mut dyn GcValue) -> Result<&mut T, RuntimeError> {
+    if object.is::<UserDataCell<T>>() {
+        return Ok(&mut object.downcast_mut::<UserDataCell<T>>().unwrap().value);
+    }
+    match object.downcast_mut::<ScopedCell<T>>() {
+        Some(ScopedCell { value: Some(value), .. }) => Ok(&mut **value),
+        Some(_) => Err(scope_ended::<T>()),
+        None => Err(RuntimeError::new(format!("{} expected", T::NAME))),
+    }
+}
+
+fn scope_ended<T: UserData>() -> RuntimeError {
+    RuntimeError::new(format!("attempt to use a {} whose scope has ended", T::NAME))
+}
+
+/// Allocated but not rooted, like other `IntoLua` values
+impl<T: UserData> IntoLua for T {
+    fn into_lua(self, gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        let metatable = metatable::<T>(gc)?;
+        let object = gc.allocate(Box::new(UserDataCell { value: self, metatable }))?;
+        Ok(Value::GcObject(object))
+    }
+}
+
+/// A copy of the value
+impl<T: UserData + Clone> FromLua for T {
+    fn from_lua(value: Value, gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        let value = UserDataRef::<T>::from_lua(value, gc)?;
+        let value = value.borrow().map_err(|e| FromLuaError::Invalid(e.get_message()))?;
+        Ok(value.clone())
+    }
+}
+
+/// A `UserData` value of type `T`, borrowed like a `RefCell`. Borrowing it
+/// while a method has it mutably borrowed is an error rather than a panic.
+/// A collection reads every object, so no script may run while it is
+/// mutably borrowed.
+pub struct UserDataRef<T> {
+    object: Rc<RefCell<Box<dyn GcValue>>>,
+    marker: PhantomData<T>,
+}
+
+impl<T: UserData> UserDataRef<T> {
+    pub fn borrow(&self) -> Result<Ref<'_, T>, RuntimeError> {
+        let object = self.object
+            .try_borrow()
+            .map_err(|_| RuntimeError::new(format!("{} already mutably borrowed", T::NAME)))?;
+        value_ref::<T>(&**object)?;
+        Ok(Ref::map(object, |o| value_ref::<T>(&**o).unwrap()))
+    }
+
+    pub fn borrow_mut(&self) -> Result<RefMut<'_, T>, RuntimeError> {
+        let object = self.object
+            .try_borrow_mut()
+            .map_err(|_| RuntimeError::new(format!("{} already borrowed", T::NAME)))?;
+        value_ref::<T>(&**object)?;
+        Ok(RefMut::map(object, |o| value_mut::<T>(&mut **o).unwrap()))
+    }
+}
+
+impl<T: UserData> FromLua for UserDataRef<T> {
+    fn from_lua(value: Value, gc: &GarbageCollector) -> Result<Self, FromLuaError> {
+        // One that is mutably borrowed is checked when it is borrowed
+        let object = value
+            .gc_ref()
+            .and_then(|r| gc.get(r))
+            .filter(|o| {
+                o.try_borrow().map_or(true, |o| o.is::<UserDataCell<T>>() || o.is::<ScopedCell<T>>())
+            });
+        match object {
+            Some(object) => Ok(UserDataRef { object, marker: PhantomData }),
+            None => Err(FromLuaError::Expected(T::NAME)),
+        }
+    }
+}
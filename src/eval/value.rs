@@ -1,20 +1,26 @@
-use std::{ collections::HashMap, fmt::format, hash::Hash };
+use std::hash::Hash;
 
-use crate::{ eval::{ types, value }, parser::ParsedValue };
+use crate::{ errors::RuntimeError, eval::types, parser::ParsedValue, sync::Rc };
 
-use super::{ gc::{ GarbageCollector, GcObject, GcRef }, types::Iterable };
+use super::{ gc::{ GarbageCollector, GcRef }, stdlib::MAX_STRING_SIZE };
 
-#[derive(Clone, Debug)]
+/// A script value, two words wide and `Copy`. Strings, tables and functions
+/// are handles into the `GarbageCollector`.
+#[derive(Clone, Copy, Debug)]
 pub enum Value {
     Nil,
     Number(i64),
     Float(f64),
-    String(String),
+    // Interned, see `GarbageCollector::intern`
+    String(GcRef),
     Bool(bool),
     GcObject(GcRef),
 
     // Not yet implemented
 }
+
+const _: () = assert!(std::mem::size_of::<Value>() == 16);
+
 impl Hash for Value {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         match self {
@@ -34,9 +40,12 @@ impl Hash for Value {
                 state.write_u8(3);
                 b.hash(state);
             }
-            _ => {
+            Value::GcObject(r) => {
                 state.write_u8(4);
-                (0).hash(state);
+                r.hash(state);
+            }
+            Value::Nil => {
+                state.write_u8(5);
             }
         }
     }
@@ -49,7 +58,7 @@ impl PartialEq for Value {
             (Value::Float(a), Value::Float(b)) => a == b,
             (Value::Bool(a), Value::Bool(b)) => a == b,
             (Value::String(a), Value::String(b)) => a == b,
-            // TODO: Add eq for table
+            (Value::GcObject(a), Value::GcObject(b)) => a == b,
             _ => false,
         }
     }
@@ -62,115 +71,104 @@ impl From<ParsedValue> for Value {
             ParsedValue::Bool(b) => Value::Bool(b),
             ParsedValue::Float(f) => Value::Float(f),
             ParsedValue::Int(i) => Value::Number(i),
-            ParsedValue::String(s) => Value::String(s),
-            ParsedValue::Table { array, map } =>
+            ParsedValue::String(_) =>
+                panic!("Cant just convert Parsed Value to Value for String, it must be interned"),
+            ParsedValue::Table { .. } =>
                 panic!("Cant just convert Parsed Value to Value for Table"),
         }
     }
 }
 
-impl Into<String> for Value {
-    fn into(self) -> String {
-        if let Value::String(s) = self {
-            return s;
-        }
-        panic!("Cannot convert {:?} to String", self)
-    }
-}
-impl Into<i64> for Value {
-    fn into(self) -> i64 {
-        if let Value::Number(s) = self {
-            return s;
-        }
-        panic!("Cannot convert {:?} to i64", self)
-    }
-}
-impl Into<f64> for Value {
-    fn into(self) -> f64 {
-        if let Value::Float(s) = self {
-            return s;
-        }
-        panic!("Cannot convert {:?} to f64", self)
-    }
-}
-impl Into<bool> for Value {
-    fn into(self) -> bool {
-        if let Value::Bool(s) = self {
-            return s;
-        }
-        panic!("Cannot convert {:?} to bool", self)
-    }
-}
-impl Into<()> for Value {
-    fn into(self) -> () {
-        if let Value::Nil = self {
-            return ();
-        }
-        panic!("Cannot convert {:?} to nil", self)
-    }
-}
-
-impl From<String> for Value {
-    fn from(value: String) -> Self {
-        return Value::String(value);
-    }
-}
+#[allow(clippy::needless_return)]
 impl From<i64> for Value {
     fn from(value: i64) -> Self {
         return Value::Number(value);
     }
 }
+#[allow(clippy::needless_return)]
 impl From<f64> for Value {
     fn from(value: f64) -> Self {
         return Value::Float(value);
     }
 }
+#[allow(clippy::needless_return)]
 impl From<bool> for Value {
     fn from(value: bool) -> Self {
         return Value::Bool(value);
     }
 }
+#[allow(clippy::needless_return)]
 impl From<()> for Value {
     fn from(_value: ()) -> Self {
         return Value::Nil;
     }
 }
 
+// `s * n`, refused before the bytes are built if the result can't fit in
+// the memory limit or couldn't be allocated at all
+fn repeat(gc: &mut GarbageCollector, s: GcRef, n: i64) -> Result<Value, RuntimeError> {
+    let bytes = gc.get_bytes(s).unwrap();
+    let n = n.max(0) as usize;
+    match bytes.len().checked_mul(n) {
+        Some(size) if size < MAX_STRING_SIZE && gc.can_allocate(size) => {
+            gc.create_byte_string(&bytes.repeat(n))
+        }
+        _ => Err(RuntimeError::out_of_memory()),
+    }
+}
+
 impl Value {
-    pub fn iter(&self, gc: &mut GarbageCollector) -> GcRef {
-        if let Value::String(s) = self {
-            let iterable = types::Iterable::new(
-                s
-                    .chars()
-                    .map(|c| Value::String(c.to_string()))
-                    .collect()
-            );
-            return gc.allocate(Box::new(iterable));
+    pub fn iter(&self, gc: &mut GarbageCollector) -> Result<GcRef, RuntimeError> {
+        if let Value::String(r) = self {
+            let s = gc.get_string(*r).unwrap();
+            let mut chars = vec![];
+            for c in s.chars() {
+                chars.push(gc.create_string(c.encode_utf8(&mut [0; 4]))?);
+            }
+            return gc.allocate(Box::new(types::Iterable::new(chars)));
         }
         if let Value::GcObject(r) = self {
             let obj = gc.get(*r).unwrap();
 
             if obj.borrow().name() != "iterable" {
-                return gc.allocate(Box::new(obj.borrow().iter()));
+                let iterable = obj.borrow().iter()?;
+                return gc.allocate(Box::new(iterable));
             } else {
-                return *r;
+                return Ok(*r);
             }
         }
-        panic!("Iter not implemented on {:?}", self)
+        Err(RuntimeError::new(format!("attempt to iterate a {} value", self.type_name(gc))))
+    }
+
+    /// The gc object or string this value refers to
+    pub fn gc_ref(&self) -> Option<GcRef> {
+        match self {
+            Value::String(r) | Value::GcObject(r) => Some(*r),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self, gc: &GarbageCollector) -> Option<Rc<str>> {
+        match self {
+            Value::String(r) => gc.get_string(*r),
+            _ => None,
+        }
     }
     //Returns owned Value because it works like that
 
-    pub fn add(&self, other: &Value) -> Value {
-        match (self, other) {
+    pub fn add(&self, other: &Value, gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        Ok(match (self, other) {
             (Value::Nil, _) => Value::Nil,
             (_, Value::Nil) => Value::Nil,
-            (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+            (Value::Number(a), Value::Number(b)) => Value::Number(a.wrapping_add(*b)),
             (Value::Number(a), Value::Float(b)) => Value::Float((*a as f64) + b),
             (Value::Number(a), Value::Bool(b)) => Value::Number(a + (*b as i64)),
             (Value::Float(a), Value::Float(b)) => Value::Float(a + b),
             (Value::Float(a), Value::Number(b)) => Value::Float(a + (*b as f64)),
             (Value::Float(a), Value::Bool(b)) => Value::Float(a + (*b as u8 as f64)),
-            (Value::String(a), Value::String(b)) => Value::String(format!("{a}{b}")),
+            (Value::String(_), Value::String(_)) => {
+                return self.concat(other, gc);
+            }
             (Value::Bool(a), Value::Bool(b)) => Value::Number((*a as i64) + (*b as i64)),
 
             _ =>
@@ -179,13 +177,13 @@ impl Value {
                     self,
                     other
                 ),
-        }
+        })
     }
     pub fn sub(&self, other: &Value) -> Value {
         match (self, other) {
             (Value::Nil, _) => Value::Nil,
             (_, Value::Nil) => Value::Nil,
-            (Value::Number(a), Value::Number(b)) => Value::Number(a - b),
+            (Value::Number(a), Value::Number(b)) => Value::Number(a.wrapping_sub(*b)),
             (Value::Number(a), Value::Float(b)) => Value::Float((*a as f64) - b),
             (Value::Number(a), Value::Bool(b)) => Value::Number(a - (*b as i64)),
             (Value::Float(a), Value::Float(b)) => Value::Float(a - b),
@@ -201,19 +199,23 @@ impl Value {
                 ),
         }
     }
-    pub fn mul(&self, other: &Value) -> Value {
-        match (self, other) {
+    pub fn mul(&self, other: &Value, gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
+        Ok(match (self, other) {
             (Value::Nil, _) => Value::Nil,
             (_, Value::Nil) => Value::Nil,
-            (Value::Number(a), Value::Number(b)) => Value::Number(a * b),
+            (Value::Number(a), Value::Number(b)) => Value::Number(a.wrapping_mul(*b)),
             (Value::Number(a), Value::Float(b)) => Value::Float((*a as f64) * b),
             (Value::Number(a), Value::Bool(b)) => Value::Number(a * (*b as i64)),
-            (Value::Number(a), Value::String(b)) => Value::String(b.repeat(*a as usize)),
+            (Value::Number(a), Value::String(b)) => {
+                return repeat(gc, *b, *a);
+            }
             (Value::Float(a), Value::Float(b)) => Value::Float(a * b),
             (Value::Float(a), Value::Number(b)) => Value::Float(a * (*b as f64)),
             (Value::Float(a), Value::Bool(b)) => Value::Float(a * (*b as u8 as f64)),
             (Value::Bool(a), Value::Bool(b)) => Value::Number((*a as i64) * (*b as i64)),
-            (Value::String(a), Value::Number(b)) => Value::String(a.repeat(*b as usize)),
+            (Value::String(a), Value::Number(b)) => {
+                return repeat(gc, *a, *b);
+            }
 
             _ =>
                 unimplemented!(
@@ -221,7 +223,7 @@ impl Value {
                     self,
                     other
                 ),
-        }
+        })
     }
     pub fn div(&self, other: &Value) -> Value {
         match (self, other) {
@@ -283,7 +285,7 @@ impl Value {
                 Value::Float(f64::powi(*a as f64, *b as i32)),
 
             (Value::Number(a), Value::Float(b)) => Value::Float(f64::powf(*a as f64, *b)),
-            (Value::Float(a), Value::Number(b)) => Value::Float(f64::powi(*a as f64, *b as i32)),
+            (Value::Float(a), Value::Number(b)) => Value::Float(f64::powi(*a, *b as i32)),
             (Value::Float(a), Value::Float(b)) => Value::Float(f64::powf(*a, *b)),
 
             _ =>
@@ -294,11 +296,11 @@ impl Value {
                 ),
         }
     }
-    pub fn concat(&self, other: &Value, gc: &GarbageCollector) -> Value {
+    pub fn concat(&self, other: &Value, gc: &mut GarbageCollector) -> Result<Value, RuntimeError> {
         // Maybe more
-        match (self, other) {
-            (a, b) => Value::String(format!("{}{}", a.to_string(gc), b.to_string(gc))),
-        }
+        let mut concatenated = self.to_bytes(gc).to_vec();
+        concatenated.extend_from_slice(&other.to_bytes(gc));
+        gc.create_byte_string(&concatenated)
     }
     pub fn equal(&self, other: &Value) -> Value {
         // Maybe more
@@ -307,13 +309,11 @@ impl Value {
             (Value::Float(a), Value::Float(b)) => Value::Bool(a == b),
             (Value::Bool(a), Value::Bool(b)) => Value::Bool(a == b),
             (Value::String(a), Value::String(b)) => Value::Bool(a == b),
+            (Value::Number(a), Value::Float(b)) => Value::Bool((*a as f64) == *b),
+            (Value::Float(a), Value::Number(b)) => Value::Bool(*a == (*b as f64)),
 
-            _ =>
-                unimplemented!(
-                    "The div op between {:?} and {:?} is not yet implemented",
-                    self,
-                    other
-                ),
+            // Values of different types are never equal, objects by identity
+            _ => Value::Bool(self == other),
         }
     }
     pub fn not_equal(&self, other: &Value) -> Value {
@@ -325,19 +325,11 @@ impl Value {
 
     pub fn and(&self, other: &Value) -> Value {
         // Maybe more
-        if self.is_truthy() {
-            return other.clone();
-        } else {
-            return self.clone();
-        }
+        if self.is_truthy() { *other } else { *self }
     }
     pub fn or(&self, other: &Value) -> Value {
         // Maybe more
-        if self.is_truthy() {
-            return self.clone();
-        } else {
-            return other.clone();
-        }
+        if self.is_truthy() { *self } else { *other }
     }
 
     pub fn bitwise_and(&self, other: &Value) -> Value {
@@ -422,11 +414,10 @@ impl Value {
             _ => unimplemented!("The unary not op for {:?} is not yet implemented", self),
         }
     }
-    pub fn unary_length(&self) -> Value {
-        println!("Operating len");
+    pub fn unary_length(&self, gc: &GarbageCollector) -> Value {
         match self {
-            Value::String(a) => {
-                println!("Hello a is {a} and len is {}", a.len());
+            Value::String(r) => {
+                let a = gc.get_string(*r).unwrap();
                 Value::Number(a.len() as i64)
             }
 
@@ -434,15 +425,13 @@ impl Value {
         }
     }
 
+    #[allow(clippy::needless_return)]
     pub fn unary_not(&self) -> Value {
         return Value::Bool(!self.is_truthy());
     }
 
     pub fn is_truthy(&self) -> bool {
-        match self {
-            Value::Nil | Value::Bool(false) => false,
-            _ => true,
-        }
+        !matches!(self, Value::Nil | Value::Bool(false))
     }
 
     pub fn less(&self, other: &Value) -> Value {
@@ -511,17 +500,37 @@ impl Value {
             Value::Nil => String::from("Nil"),
             Value::Number(a) => a.to_string(),
             Value::Float(a) => a.to_string(),
-            Value::String(a) => a.clone(),
+            Value::String(a) => gc.get_string(*a).map(|s| s.to_string()).unwrap_or_default(),
             Value::Bool(a) => a.to_string(),
             Value::GcObject(r) => gc.get_str(*r).unwrap_or("Nil".to_string()),
+        }
+    }
 
-            _ => "<str not implemented>".to_string(),
+    /// Like `to_string`, but strings keep bytes that aren't valid UTF-8
+    pub fn to_bytes(&self, gc: &GarbageCollector) -> Rc<[u8]> {
+        match self {
+            Value::String(r) => gc.get_bytes(*r).unwrap_or_else(|| Rc::from(&[][..])),
+            _ => Rc::from(self.to_string(gc).as_bytes()),
+        }
+    }
+
+    pub fn type_name(&self, gc: &GarbageCollector) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Number(_) | Value::Float(_) => "number",
+            Value::String(_) => "string",
+            Value::Bool(_) => "boolean",
+            Value::GcObject(r) =>
+                match gc.get(*r) {
+                    Some(v) => v.borrow().name(),
+                    None => "nil",
+                }
         }
     }
 
     pub fn dbg_string(&self, gc: &GarbageCollector) -> String {
         match self {
-            Value::String(a) => format!("'{}'", a),
+            Value::String(_) => format!("'{}'", self.to_string(gc)),
 
             _ => self.to_string(gc),
         }
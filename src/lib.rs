@@ -1,7 +1,14 @@
-mod parser;
-mod tokenizer;
-mod errors;
-mod eval;
+pub mod parser;
+pub mod tokenizer;
+pub mod errors;
+pub mod eval;
+pub mod trace;
+pub mod sync;
+
+pub use function_macro::{ interpreter_function, user_data };
+
+// Lets `#[interpreter_function]` name this crate as `lua_rs` from inside it
+extern crate self as lua_rs;
 
 #[cfg(test)]
 mod tests {
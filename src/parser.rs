@@ -1,5 +1,7 @@
 use crate::errors::ParserError;
+use crate::sync::Rc;
 use crate::tokenizer::{ Operator, Token, Value };
+use crate::trace::{ trace, Category, EventSink };
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum AstNode {
@@ -14,6 +16,12 @@ pub enum AstNode {
         target: Box<AstNode>,
         rhs: Box<AstNode>,
     },
+    // `a, b = f()`, a single target with a single value is an Assignment
+    MultipleAssignment {
+        is_local: bool,
+        targets: Vec<AstNode>,
+        values: Vec<AstNode>,
+    },
 
     FunctionCall {
         target: Box<AstNode>,
@@ -66,7 +74,7 @@ pub enum AstNode {
     Break,
     Continue,
     Return {
-        expr: Box<AstNode>,
+        exprs: Vec<AstNode>,
     },
 }
 
@@ -123,9 +131,11 @@ pub struct Parser {
     tokens: Vec<Token>,
     index: usize,
     line_count: u32,
+    sink: Option<Rc<dyn EventSink>>,
 }
 
 #[derive(PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 enum Associative {
     LEFT,
     RIGHT,
@@ -133,14 +143,20 @@ enum Associative {
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Parser { tokens, index: 0, line_count: 1 }
+        Parser { tokens, index: 0, line_count: 1, sink: None }
+    }
+    pub fn set_event_sink(&mut self, sink: Rc<dyn EventSink>) {
+        self.sink = Some(sink);
     }
+    #[allow(clippy::needless_return)]
     fn peek(&self) -> Option<&Token> {
         return self.tokens.get(self.index + 1);
     }
+    #[allow(clippy::needless_return)]
     fn peek_at(&self, ahead: usize) -> Option<&Token> {
         return self.tokens.get(self.index + ahead);
     }
+    #[allow(clippy::needless_return)]
     fn get_current_token(&self) -> Option<&Token> {
         return self.tokens.get(self.index);
     }
@@ -151,6 +167,7 @@ impl Parser {
             match self.parse_statement() {
                 Ok(stmt) => {
                     if let Some(stmt) = stmt {
+                        trace!(self.sink, Category::Parser, "line {}: {:?}", self.line_count, stmt);
                         statements.push(stmt);
                     }
                 }
@@ -176,6 +193,7 @@ impl Parser {
         }
         Err(format!("Expected {:?}, got {:?}", token, self.get_current_token()))
     }
+    #[allow(clippy::needless_return)]
     fn parse_statement(&mut self) -> Result<Option<AstNode>, String> {
         return match self.get_current_token() {
             Some(Token::VariableOrFunction(_)) => {
@@ -470,15 +488,38 @@ impl Parser {
                 rhs: Box::new(expr),
             });
         }
-        if let Some(Token::Set) = self.get_current_token() {
-            self.advance();
+        if let Some(Token::Comma) = self.get_current_token() {
+            let mut targets = vec![target];
+            while let Some(Token::Comma) = self.get_current_token() {
+                self.advance();
+                match self.parse_target()? {
+                    Some(t) => targets.push(t),
+                    None => {
+                        return Err("Target could not be parsed".to_string());
+                    }
+                }
+            }
+            self.advance_token(Token::Set)?;
 
-            return Ok(AstNode::Assignment {
+            return Ok(AstNode::MultipleAssignment {
                 is_local,
-                target: Box::new(target),
-                rhs: Box::new(self.parse_expression()?),
+                targets,
+                values: self.parse_expression_list()?,
             });
         }
+        if let Some(Token::Set) = self.get_current_token() {
+            self.advance();
+
+            let mut values = self.parse_expression_list()?;
+            if values.len() == 1 {
+                return Ok(AstNode::Assignment {
+                    is_local,
+                    target: Box::new(target),
+                    rhs: Box::new(values.remove(0)),
+                });
+            }
+            return Ok(AstNode::MultipleAssignment { is_local, targets: vec![target], values });
+        }
 
         Ok(target)
 
@@ -505,6 +546,14 @@ impl Parser {
         // return node;
         self.parse_precedence_climbing(0)
     }
+    fn parse_expression_list(&mut self) -> Result<Vec<AstNode>, String> {
+        let mut exprs = vec![self.parse_expression()?];
+        while let Some(Token::Comma) = self.get_current_token() {
+            self.advance();
+            exprs.push(self.parse_expression()?);
+        }
+        Ok(exprs)
+    }
     fn parse_precedence_climbing(&mut self, min_prec: u8) -> Result<AstNode, String> {
         let mut res = self.parse_factor()?;
         let mut next_min_precedence;
@@ -570,12 +619,6 @@ impl Parser {
                             return Ok(AstNode::Literal(ParsedValue::Float(a as f64)));
                         }
                         // Oh it's a float
-                        println!(
-                            "Not whole part: {}",
-                            ((b as f64) /
-                                (10.0f64).powi((b as f64).log10().ceil() as i32).max(10.0f64)) *
-                                (10.0f64 * (zeros as f64))
-                        );
                         let decimal_part =
                             (b as f64) /
                             (10.0f64).powi((b as f64).log10().ceil() as i32).max(10.0f64) /
@@ -757,6 +800,7 @@ impl Parser {
         unreachable!("Nononon")
     }
 
+    #[allow(clippy::needless_return)]
     fn parse_target(&mut self) -> Result<Option<AstNode>, String> {
         // FIXME: Fix this shit
 
@@ -888,7 +932,18 @@ impl Parser {
     fn parse_return(&mut self) -> Result<AstNode, String> {
         if let Some(Token::Return) = self.get_current_token() {
             self.advance();
-            return Ok(AstNode::Return { expr: Box::new(self.parse_expression()?) });
+            // A bare `return` ends the block
+            let exprs = match self.get_current_token() {
+                | None
+                | Some(Token::EndLine)
+                | Some(Token::End)
+                | Some(Token::Else)
+                | Some(Token::ElseIf)
+                | Some(Token::Until)
+                | Some(Token::Semicolon) => vec![],
+                _ => self.parse_expression_list()?,
+            };
+            return Ok(AstNode::Return { exprs });
         }
         Err("Invalid call to parse return".to_string())
     }
@@ -0,0 +1,158 @@
+//! The shared pointer and cell behind the interpreter's objects. They are
+//! `std::rc::Rc` and `std::cell::RefCell` unless the `send` feature is
+//! enabled, which swaps in thread-safe ones so that an [`Interpreter`] can
+//! move to another thread. Everything the host stores in it then has to be
+//! `Send`, which is what [`MaybeSend`] asks for.
+//!
+//! [`Interpreter`]: crate::eval::interpreter::Interpreter
+
+#[cfg(not(feature = "send"))]
+pub use std::{ cell::{ Cell, Ref, RefCell, RefMut }, rc::Rc };
+
+#[cfg(feature = "send")]
+pub use std::sync::Arc as Rc;
+
+/// `Send` with the `send` feature, implemented by every type without
+#[cfg(feature = "send")]
+pub trait MaybeSend: Send {}
+#[cfg(feature = "send")]
+impl<T: Send + ?Sized> MaybeSend for T {}
+
+#[cfg(not(feature = "send"))]
+pub trait MaybeSend {}
+#[cfg(not(feature = "send"))]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// `Send + Sync` with the `send` feature, for what the host keeps sharing
+/// with the interpreter after handing it over, like event sinks
+#[cfg(feature = "send")]
+pub trait MaybeSync: Send + Sync {}
+#[cfg(feature = "send")]
+impl<T: Send + Sync + ?Sized> MaybeSync for T {}
+
+#[cfg(not(feature = "send"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "send"))]
+impl<T: ?Sized> MaybeSync for T {}
+
+/// `RefCell` for the `send` feature. One thread at a time can borrow it,
+/// another one waits until all of its borrows ended. Borrows on the same
+/// thread conflict like `RefCell`'s do.
+#[cfg(feature = "send")]
+#[derive(Debug, Default)]
+pub struct RefCell<T: ?Sized> {
+    lock: parking_lot::ReentrantMutex<()>,
+    cell: std::cell::RefCell<T>,
+}
+
+// SAFETY: the cell is only used by the thread holding `lock`, and the guards
+// that keep it borrowed hold the lock too and can't leave that thread
+#[cfg(feature = "send")]
+unsafe impl<T: Send + ?Sized> Sync for RefCell<T> {}
+
+#[cfg(feature = "send")]
+impl<T> RefCell<T> {
+    pub fn new(value: T) -> Self {
+        RefCell { lock: parking_lot::ReentrantMutex::new(()), cell: std::cell::RefCell::new(value) }
+    }
+}
+
+#[cfg(feature = "send")]
+impl<T: ?Sized> RefCell<T> {
+    pub fn borrow(&self) -> Ref<'_, T> {
+        let lock = self.lock.lock();
+        Ref { value: self.cell.borrow(), _lock: lock }
+    }
+
+    pub fn borrow_mut(&self) -> RefMut<'_, T> {
+        let lock = self.lock.lock();
+        RefMut { value: self.cell.borrow_mut(), _lock: lock }
+    }
+
+    pub fn try_borrow(&self) -> Result<Ref<'_, T>, std::cell::BorrowError> {
+        let lock = self.lock.lock();
+        Ok(Ref { value: self.cell.try_borrow()?, _lock: lock })
+    }
+
+    pub fn try_borrow_mut(&self) -> Result<RefMut<'_, T>, std::cell::BorrowMutError> {
+        let lock = self.lock.lock();
+        Ok(RefMut { value: self.cell.try_borrow_mut()?, _lock: lock })
+    }
+}
+
+/// A shared borrow of a [`RefCell`], `std::cell::Ref` without the `send`
+/// feature
+#[cfg(feature = "send")]
+pub struct Ref<'a, T: ?Sized> {
+    // Released before the lock
+    value: std::cell::Ref<'a, T>,
+    _lock: parking_lot::ReentrantMutexGuard<'a, ()>,
+}
+
+#[cfg(feature = "send")]
+impl<'a, T: ?Sized> Ref<'a, T> {
+    pub fn map<U: ?Sized>(r: Self, f: impl FnOnce(&T) -> &U) -> Ref<'a, U> {
+        Ref { value: std::cell::Ref::map(r.value, f), _lock: r._lock }
+    }
+}
+
+#[cfg(feature = "send")]
+impl<T: ?Sized> std::ops::Deref for Ref<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+/// A mutable borrow of a [`RefCell`], `std::cell::RefMut` without the
+/// `send` feature
+#[cfg(feature = "send")]
+pub struct RefMut<'a, T: ?Sized> {
+    // Released before the lock
+    value: std::cell::RefMut<'a, T>,
+    _lock: parking_lot::ReentrantMutexGuard<'a, ()>,
+}
+
+#[cfg(feature = "send")]
+impl<'a, T: ?Sized> RefMut<'a, T> {
+    pub fn map<U: ?Sized>(r: Self, f: impl FnOnce(&mut T) -> &mut U) -> RefMut<'a, U> {
+        RefMut { value: std::cell::RefMut::map(r.value, f), _lock: r._lock }
+    }
+}
+
+#[cfg(feature = "send")]
+impl<T: ?Sized> std::ops::Deref for RefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+#[cfg(feature = "send")]
+impl<T: ?Sized> std::ops::DerefMut for RefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+/// `Cell` for the `send` feature
+#[cfg(feature = "send")]
+#[derive(Debug, Default)]
+pub struct Cell<T>(parking_lot::Mutex<T>);
+
+#[cfg(feature = "send")]
+impl<T: Copy> Cell<T> {
+    pub fn new(value: T) -> Self {
+        Cell(parking_lot::Mutex::new(value))
+    }
+
+    pub fn get(&self) -> T {
+        *self.0.lock()
+    }
+
+    pub fn set(&self, value: T) {
+        *self.0.lock() = value;
+    }
+}
@@ -1,7 +1,11 @@
-use std::{ vec };
+use std::vec;
+
+use crate::sync::Rc;
+use crate::trace::{ trace, Category, EventSink };
 
 pub struct Tokenizer {
     tokens: Vec<Token>,
+    sink: Option<Rc<dyn EventSink>>,
 }
 
 pub type MapEntry = (Value, Value);
@@ -111,8 +115,12 @@ const OPERATORS: &[&str] = &[
 ];
 const NON_EXTENDABLE: &[&str] = &[")", "(", ",", "[", "]", "{", "}"];
 impl Tokenizer {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
-        Tokenizer { tokens: vec![] }
+        Tokenizer { tokens: vec![], sink: None }
+    }
+    pub fn set_event_sink(&mut self, sink: Rc<dyn EventSink>) {
+        self.sink = Some(sink);
     }
     pub fn tokenize(&mut self, input: String) {
         let mut buf = String::new();
@@ -200,7 +208,6 @@ impl Tokenizer {
                     break;
                 }
 
-                eprintln!("Buf: {}", buf);
                 buf.clear();
 
                 continue;
@@ -211,8 +218,8 @@ impl Tokenizer {
 
                 for c in iterator.by_ref() {
                     if c == '\"' {
-                        self.add_token(Some(Token::Value(Value::String(buf.to_string()))));
-                        buf.clear();
+                        let s = std::mem::take(&mut buf);
+                        self.add_token(Some(Token::Value(Value::String(s))));
                         break;
                     }
                     buf.push(c);
@@ -249,8 +256,7 @@ impl Tokenizer {
             return Some(t);
         }
         if let Ok(t) = token.parse::<i64>() {
-            println!("token: {}, count: {}", token, count_leading_zeros(token));
-            return Some(Token::Value(Value::Int(t, count_leading_zeros(&token))));
+            return Some(Token::Value(Value::Int(t, count_leading_zeros(token))));
         }
         if let Ok(t) = token.parse::<f64>() {
             return Some(Token::Value(Value::Float(t)));
@@ -267,6 +273,7 @@ impl Tokenizer {
 
     fn add_token(&mut self, token: Option<Token>) {
         if let Some(token) = token {
+            trace!(self.sink, Category::Lexer, "token {:?}", token);
             self.tokens.push(token);
         }
     }
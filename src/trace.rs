@@ -0,0 +1,83 @@
+use std::fmt;
+
+use crate::sync::MaybeSync;
+
+/// Which part of the crate an [`Event`] comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    Lexer,
+    Parser,
+    Gc,
+    Interpreter,
+}
+
+impl Category {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Lexer => "lexer",
+            Category::Parser => "parser",
+            Category::Gc => "gc",
+            Category::Interpreter => "interpreter",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Event<'a> {
+    pub category: Category,
+    pub message: fmt::Arguments<'a>,
+}
+
+/// Receives internal diagnostics. Nothing is traced unless a sink is set on
+/// the `Tokenizer`, `Parser` or `Interpreter`. The `send` feature needs it to
+/// be `Sync`, the host can use it while the interpreter runs on another thread.
+pub trait EventSink: MaybeSync {
+    /// Lets a sink skip formatting of categories it doesn't care about
+    fn enabled(&self, _category: Category) -> bool {
+        true
+    }
+    fn event(&self, event: &Event);
+}
+
+/// Forwards events to the `log` crate with targets like `lua_rs::gc`
+#[cfg(feature = "log")]
+pub struct LogSink {
+    pub level: log::Level,
+}
+
+#[cfg(feature = "log")]
+impl EventSink for LogSink {
+    fn enabled(&self, category: Category) -> bool {
+        log::log_enabled!(target: LogSink::target(category), self.level)
+    }
+
+    fn event(&self, event: &Event) {
+        log::log!(target: LogSink::target(event.category), self.level, "{}", event.message);
+    }
+}
+
+#[cfg(feature = "log")]
+impl LogSink {
+    fn target(category: Category) -> &'static str {
+        match category {
+            Category::Lexer => "lua_rs::lexer",
+            Category::Parser => "lua_rs::parser",
+            Category::Gc => "lua_rs::gc",
+            Category::Interpreter => "lua_rs::interpreter",
+        }
+    }
+}
+
+/// `trace!(self.sink, Category::Gc, "format {}", args)` where the sink is an
+/// `Option<Rc<dyn EventSink>>`. Arguments aren't evaluated without a sink.
+macro_rules! trace {
+    ($sink:expr, $category:expr, $($arg:tt)+) => {
+        if let Some(sink) = &$sink {
+            if sink.enabled($category) {
+                sink.event(&$crate::trace::Event { category: $category, message: format_args!($($arg)+) });
+            }
+        }
+    };
+}
+
+pub(crate) use trace;